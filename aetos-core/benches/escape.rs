@@ -0,0 +1,51 @@
+use std::borrow::Cow;
+use std::hint::black_box;
+
+use aetos_core::escape_label_value;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+// The previous implementation, decoding every char
+fn escape_label_value_chars(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|ch| matches!(ch, '"' | '\\' | '\n')) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut result = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            _ => result.push(ch),
+        }
+    }
+    Cow::Owned(result)
+}
+
+fn bench_escape(c: &mut Criterion) {
+    let inputs = [
+        ("short", "GET".to_string()),
+        ("path", "/api/v1/users/42/orders".to_string()),
+        ("non_ascii", "zürich-日本-région".repeat(4)),
+        ("long", "a".repeat(256)),
+        (
+            "one_escape",
+            format!("{}\"{}", "a".repeat(128), "b".repeat(127)),
+        ),
+        ("many_escapes", "say \"hi\"\n".repeat(16)),
+    ];
+
+    let mut group = c.benchmark_group("escape_label_value");
+    for (name, input) in &inputs {
+        group.bench_with_input(BenchmarkId::new("bytes", name), input, |b, input| {
+            b.iter(|| escape_label_value(black_box(input)))
+        });
+        group.bench_with_input(BenchmarkId::new("chars", name), input, |b, input| {
+            b.iter(|| escape_label_value_chars(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_escape);
+criterion_main!(benches);
@@ -1,239 +1,2318 @@
+use std::any::TypeId;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hash;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
 
 pub trait Label {
     fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
+
+    /// Like `fmt_labels`, without escaping the label values. Used for the fields marked
+    /// `raw_labels`, whose values are known not to contain `"`, `\` or newlines.
+    ///
+    /// Defaults to `fmt_labels`, so implementations that do not override it keep escaping.
+    fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_labels(f)
+    }
+
+    /// Whether the label set writes no labels at all.
+    ///
+    /// Defaults to `false`, which `#[derive(Label)]` keeps. Used to skip rendering the
+    /// sort key of histogram series without labels.
+    ///
+    /// The separator before the `le` label or the constant labels is not decided by this
+    /// method but by whether the labels wrote anything. `false` is the default of every
+    /// implementation, including derived label sets without fields, which write nothing
+    /// and must not be followed by a comma.
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The names of the labels written by every value of the type, in output order.
+    ///
+    /// Defaults to none, for label sets only known at runtime (maps, `Vec`s, ...).
+    /// `#[derive(Label)]` returns the field names. Only used to document the metrics,
+    /// see `metrics_catalog()` generated by `#[metrics]`.
+    fn label_names() -> &'static [&'static str]
+    where
+        Self: Sized,
+    {
+        &[]
+    }
+}
+
+impl Label for () {
+    fn fmt_labels(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        true
+    }
+}
+
+/// Label sets known only at runtime, e.g. loaded from configuration.
+/// Entries are rendered in key order.
+impl<K: Display, V: Display> Label for BTreeMap<K, V> {
+    fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter(), f, true)
+    }
+
+    fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter(), f, false)
+    }
+
+    fn is_empty(&self) -> bool {
+        BTreeMap::is_empty(self)
+    }
+}
+
+/// Label sets known only at runtime. Entries are rendered in insertion order.
+impl<K: Display, V: Display> Label for Vec<(K, V)> {
+    fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter().map(|(k, v)| (k, v)), f, true)
+    }
+
+    fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter().map(|(k, v)| (k, v)), f, false)
+    }
+
+    fn is_empty(&self) -> bool {
+        <[(K, V)]>::is_empty(self)
+    }
+}
+
+/// Label sets known only at runtime. Entries are rendered in insertion order.
+#[cfg(feature = "indexmap")]
+impl<K: Display, V: Display, S> Label for indexmap::IndexMap<K, V, S> {
+    fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter(), f, true)
+    }
+
+    fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_label_pairs(self.iter(), f, false)
+    }
+
+    fn is_empty(&self) -> bool {
+        indexmap::IndexMap::is_empty(self)
+    }
+}
+
+fn fmt_label_pairs<'a, K: Display + 'a, V: Display + 'a>(
+    pairs: impl Iterator<Item = (&'a K, &'a V)>,
+    f: &mut std::fmt::Formatter<'_>,
+    escape: bool,
+) -> std::fmt::Result {
+    for (i, (k, v)) in pairs.enumerate() {
+        fmt_label_pair(f, i, k, v, escape)?;
+    }
+    Ok(())
+}
+
+// Writes `name="value"`, preceded by a comma unless it is the first pair of the set
+fn fmt_label_pair(
+    f: &mut Formatter<'_>,
+    index: usize,
+    name: impl Display,
+    value: impl Display,
+    escape: bool,
+) -> fmt::Result {
+    if index > 0 {
+        write!(f, ",")?;
+    }
+    if escape {
+        // Escaping needs the whole value
+        let value = value.to_string();
+        write!(f, "{}=\"{}\"", name, escape_label_value(&value))
+    } else {
+        write!(f, "{}=\"{}\"", name, value)
+    }
+}
+
+// `escape_label_value`, or the value as is when `escape` is false
+fn maybe_escape(value: &str, escape: bool) -> Cow<'_, str> {
+    if escape {
+        escape_label_value(value)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// Forwards to the wrapped writer, recording whether anything was written. Used to decide
+// whether a separator is needed after a label set, without rendering it to a `String`.
+struct TrackWritten<'a, W: ?Sized> {
+    inner: &'a mut W,
+    written: bool,
+}
+
+impl<'a, W: fmt::Write + ?Sized> TrackWritten<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        TrackWritten {
+            inner,
+            written: false,
+        }
+    }
+}
+
+impl<W: fmt::Write + ?Sized> fmt::Write for TrackWritten<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.written |= !s.is_empty();
+        self.inner.write_str(s)
+    }
+}
+
+// Adapter to render a `Label` through `Display`, with `fmt_labels_raw` when `raw` is set
+struct DisplayLabels<'a, L: ?Sized> {
+    labels: &'a L,
+    raw: bool,
+}
+
+impl<'a, L: ?Sized> DisplayLabels<'a, L> {
+    // The label set with its values escaped
+    fn escaped(labels: &'a L) -> Self {
+        DisplayLabels { labels, raw: false }
+    }
+}
+
+impl<L: Label + ?Sized> Display for DisplayLabels<'_, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.raw {
+            self.labels.fmt_labels_raw(f)
+        } else {
+            self.labels.fmt_labels(f)
+        }
+    }
+}
+
+/// Formats a tuple of `N` label values against `N` label names.
+///
+/// Implemented for tuples of 2 to 8 `Display` elements, so that maps keyed by tuples can
+/// be rendered with the `labels = ["a", "b"]` shorthand instead of a `Label` struct.
+pub trait TupleLabelValues<const N: usize> {
+    fn fmt_label_values(&self, names: &[&str; N], f: &mut Formatter<'_>) -> fmt::Result;
+
+    /// Like `fmt_label_values`, without escaping the label values, see
+    /// [`Label::fmt_labels_raw`]
+    fn fmt_label_values_raw(&self, names: &[&str; N], f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_label_values(names, f)
+    }
+}
+
+macro_rules! impl_tuple_label_values {
+    ($n:literal; $($idx:tt $T:ident),+) => {
+        impl<$($T: Display),+> TupleLabelValues<$n> for ($($T,)+) {
+            fn fmt_label_values(&self, names: &[&str; $n], f: &mut Formatter<'_>) -> fmt::Result {
+                $(fmt_label_pair(f, $idx, names[$idx], &self.$idx, true)?;)+
+                Ok(())
+            }
+
+            fn fmt_label_values_raw(
+                &self,
+                names: &[&str; $n],
+                f: &mut Formatter<'_>,
+            ) -> fmt::Result {
+                $(fmt_label_pair(f, $idx, names[$idx], &self.$idx, false)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_tuple_label_values!(2; 0 A, 1 B);
+impl_tuple_label_values!(3; 0 A, 1 B, 2 C);
+impl_tuple_label_values!(4; 0 A, 1 B, 2 C, 3 D);
+impl_tuple_label_values!(5; 0 A, 1 B, 2 C, 3 D, 4 E);
+impl_tuple_label_values!(6; 0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+impl_tuple_label_values!(7; 0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G);
+impl_tuple_label_values!(8; 0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H);
+
+/// Tuples used as keys without `labels = [...]` name their elements `field_0`,
+/// `field_1`, and so on.
+macro_rules! impl_tuple_label {
+    ($($idx:tt $T:ident),+) => {
+        impl<$($T: Display),+> Label for ($($T,)+) {
+            fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                $(fmt_label_pair(f, $idx, concat!("field_", $idx), &self.$idx, true)?;)+
+                Ok(())
+            }
+
+            fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                $(fmt_label_pair(f, $idx, concat!("field_", $idx), &self.$idx, false)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_tuple_label!(0 A);
+impl_tuple_label!(0 A, 1 B);
+impl_tuple_label!(0 A, 1 B, 2 C);
+
+/// Pairs a tuple of label values with their names, so it can be used as a `Label`.
+pub struct TupleLabels<'a, T: ?Sized, const N: usize> {
+    pub names: &'a [&'a str; N],
+    pub values: &'a T,
+}
+
+impl<T: TupleLabelValues<N> + ?Sized, const N: usize> Label for TupleLabels<'_, T, N> {
+    fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.values.fmt_label_values(self.names, f)
+    }
+
+    fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.values.fmt_label_values_raw(self.names, f)
+    }
+}
+
+pub const fn linear_buckets<const N: usize>(start: f64, width: f64) -> [f64; N] {
+    let mut buckets = [0.0; N];
+    let mut i = 0;
+
+    while i < N {
+        buckets[i] = start + width * (i as f64);
+        i += 1;
+    }
+    buckets
+}
+
+/// `N` buckets evenly spaced from `start` to `end`, both included.
+///
+/// Panics (at compile time in const contexts) if `start >= end` and `N > 1`.
+pub const fn linear_buckets_range<const N: usize>(start: f64, end: f64) -> [f64; N] {
+    let mut buckets = [start; N];
+    if N < 2 {
+        return buckets;
+    }
+    if start >= end {
+        panic!("linear_buckets_range requires start < end");
+    }
+
+    let width = (end - start) / ((N - 1) as f64);
+    let mut i = 1;
+    while i < N - 1 {
+        buckets[i] = start + width * (i as f64);
+        i += 1;
+    }
+    // Set exactly, rather than accumulating rounding errors
+    buckets[N - 1] = end;
+    buckets
+}
+
+pub const fn exponential_buckets<const N: usize>(start: f64, factor: f64) -> [f64; N] {
+    let mut buckets = [0.0; N];
+    let mut current = start;
+    let mut i = 0;
+    while i < N {
+        buckets[i] = current;
+        current *= factor;
+        i += 1;
+    }
+    buckets
+}
+
+/// Default buckets of the Go client (`prometheus.DefBuckets`), for HTTP request
+/// durations in seconds.
+///
+/// The Go client lists 11 boundaries; the `+Inf` bucket is always added when rendering.
+pub const DEFAULT_HTTP_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets for file and payload sizes in bytes, from 1 KiB to 2 GiB with a factor of 8.
+pub const DEFAULT_FILE_SIZE_BUCKETS: [f64; 8] = exponential_buckets(1024.0, 8.0);
+
+/// Buckets for RPC durations in seconds, from 1 ms to 1 s. Internal RPCs are usually
+/// an order of magnitude faster than HTTP requests served to clients.
+pub const fn default_rpc_duration_buckets() -> [f64; 10] {
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+}
+
+/// Whether `# UNIT` lines are written, with the `openmetrics` feature. Used by the
+/// `size_hint()` generated by `#[metrics]`.
+#[doc(hidden)]
+pub const UNIT_LINES: bool = cfg!(feature = "openmetrics");
+
+/// The `Content-Type` of the Prometheus text exposition format, for HTTP handlers
+/// serving the rendered metrics.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Implemented by `#[metrics]` structs.
+///
+/// The generated `Display` impl writes every family through [`render`]. Structs
+/// declared with `#[metrics(display = false)]` implement it without `Display`, and
+/// expose `fmt_prometheus()` and `render_prometheus()` instead.
+///
+/// The trait is object safe, and `dyn PrometheusMetric` implements `Display`, so
+/// differently typed metrics can be kept in a `Vec<Box<dyn PrometheusMetric>>`.
+///
+/// Manual implementations provide [`render`], and [`fmt_filtered`] when they can tell
+/// their families apart.
+///
+/// [`render`]: PrometheusMetric::render
+/// [`fmt_filtered`]: PrometheusMetric::fmt_filtered
+pub trait PrometheusMetric {
+    /// Writes every metric family.
+    fn render(&self, f: &mut Formatter<'_>) -> fmt::Result;
+
+    /// Writes only the metric families whose final name passes `filter`. `Display`
+    /// writes every family.
+    ///
+    /// Histograms are matched by their base name, not by their `_bucket`, `_sum` and
+    /// `_count` series. A group is written whole when the name of any of its members
+    /// passes.
+    ///
+    /// Defaults to `render`, ignoring `filter`, for implementations that cannot tell
+    /// their families apart.
+    fn fmt_filtered(&self, f: &mut Formatter<'_>, filter: &dyn Fn(&str) -> bool) -> fmt::Result {
+        let _ = filter;
+        self.render(f)
+    }
+
+    /// A lower bound of the length of the full output, for pre-allocating buffers.
+    ///
+    /// Never larger than the actual output. The default returns 0; `#[metrics]` counts
+    /// the header lines, and the samples of scalar fields, whose length is known without
+    /// inspecting collections.
+    fn size_hint(&self) -> usize {
+        0
+    }
+}
+
+macro_rules! impl_display_for_dyn_metric {
+    ($($ty:ty),*) => {
+        $(
+            impl Display for $ty {
+                fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                    self.render(f)
+                }
+            }
+        )*
+    };
+}
+
+impl_display_for_dyn_metric!(
+    dyn PrometheusMetric + '_,
+    dyn PrometheusMetric + Send + '_,
+    dyn PrometheusMetric + Send + Sync + '_
+);
+
+/// A metrics struct shared with the rest of the application, e.g. the `Arc<AppMetrics>`
+/// handed to HTTP handlers, or registered while kept for updates.
+impl<T: PrometheusMetric + ?Sized> PrometheusMetric for Arc<T> {
+    fn render(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        (**self).render(f)
+    }
+
+    fn fmt_filtered(&self, f: &mut Formatter<'_>, filter: &dyn Fn(&str) -> bool) -> fmt::Result {
+        (**self).fmt_filtered(f, filter)
+    }
+
+    fn size_hint(&self) -> usize {
+        (**self).size_hint()
+    }
+}
+
+/// Renders the field at `index` of a `#[metrics]` struct, in declaration order.
+///
+/// Implemented by the macro as a trait rather than inherent methods, so that the
+/// generated code never clashes with the methods of the struct.
+#[doc(hidden)]
+pub trait RenderField {
+    fn render_field(&self, index: usize, f: &mut Formatter<'_>) -> fmt::Result;
+}
+
+/// Renders only the metric families of `M` whose final name passes the predicate `F`,
+/// with the same rules as [`PrometheusMetric::fmt_filtered`].
+pub struct Filtered<'a, M: ?Sized, F>(pub &'a M, pub F);
+
+impl<M, F> Display for Filtered<'_, M, F>
+where
+    M: PrometheusMetric + ?Sized,
+    F: Fn(&str) -> bool,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt_filtered(f, &self.1)
+    }
+}
+
+/// A list of metrics rendered one after the other, e.g. the `#[metrics]` structs of
+/// several subsystems served on a single endpoint.
+///
+/// The registry is itself a [`PrometheusMetric`], so it can be filtered or registered in
+/// another registry. See [`SyncMetricRegistry`] for a registry shared between threads.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::{MetricRegistry, PrometheusMetric};
+/// use std::fmt;
+///
+/// struct Up;
+///
+/// impl PrometheusMetric for Up {
+///     fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+///         writeln!(f, "up 1")
+///     }
+/// }
+///
+/// let mut registry = MetricRegistry::new();
+/// registry.register(Box::new(Up));
+/// assert_eq!(registry.render_all(), "up 1\n");
+/// ```
+#[derive(Default)]
+pub struct MetricRegistry {
+    metrics: Vec<Box<dyn PrometheusMetric>>,
+}
+
+impl MetricRegistry {
+    pub const fn new() -> Self {
+        Self {
+            metrics: Vec::new(),
+        }
+    }
+
+    /// Adds `metric` after the metrics registered so far
+    pub fn register(&mut self, metric: Box<dyn PrometheusMetric>) {
+        self.metrics.push(metric);
+    }
+
+    /// Renders every registered metric, in registration order
+    pub fn render_all(&self) -> String {
+        render_to_string(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+}
+
+impl PrometheusMetric for MetricRegistry {
+    fn render(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_filtered(f, &|_| true)
+    }
+
+    fn fmt_filtered(&self, f: &mut Formatter<'_>, filter: &dyn Fn(&str) -> bool) -> fmt::Result {
+        self.metrics
+            .iter()
+            .try_for_each(|metric| metric.fmt_filtered(f, filter))
+    }
+
+    fn size_hint(&self) -> usize {
+        self.metrics.iter().map(|metric| metric.size_hint()).sum()
+    }
+}
+
+impl Display for MetricRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.render(f)
+    }
+}
+
+/// A [`MetricRegistry`] behind a `RwLock`, so that metrics can be registered and rendered
+/// from several threads, or from a `static`.
+///
+/// A poisoned lock is recovered from: a panic while registering or rendering leaves the
+/// list of metrics valid.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::{PrometheusMetric, SyncMetricRegistry};
+/// use std::fmt;
+///
+/// struct Up;
+///
+/// impl PrometheusMetric for Up {
+///     fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+///         writeln!(f, "up 1")
+///     }
+/// }
+///
+/// static REGISTRY: SyncMetricRegistry = SyncMetricRegistry::new();
+///
+/// std::thread::spawn(|| REGISTRY.register(Box::new(Up))).join().unwrap();
+/// assert_eq!(REGISTRY.render_all(), "up 1\n");
+/// ```
+#[derive(Default)]
+pub struct SyncMetricRegistry {
+    metrics: RwLock<Vec<Box<dyn PrometheusMetric + Send + Sync>>>,
+}
+
+impl SyncMetricRegistry {
+    pub const fn new() -> Self {
+        Self {
+            metrics: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Adds `metric` after the metrics registered so far
+    pub fn register(&self, metric: Box<dyn PrometheusMetric + Send + Sync>) {
+        self.metrics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(metric);
+    }
+
+    /// Renders every registered metric, in registration order
+    pub fn render_all(&self) -> String {
+        render_to_string(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<Box<dyn PrometheusMetric + Send + Sync>>> {
+        self.metrics.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PrometheusMetric for SyncMetricRegistry {
+    fn render(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_filtered(f, &|_| true)
+    }
+
+    fn fmt_filtered(&self, f: &mut Formatter<'_>, filter: &dyn Fn(&str) -> bool) -> fmt::Result {
+        self.read()
+            .iter()
+            .try_for_each(|metric| metric.fmt_filtered(f, filter))
+    }
+
+    fn size_hint(&self) -> usize {
+        self.read().iter().map(|metric| metric.size_hint()).sum()
+    }
+}
+
+impl Display for SyncMetricRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.render(f)
+    }
+}
+
+// Renders `metric` into a `String` sized by its `size_hint`
+fn render_to_string(metric: &(impl PrometheusMetric + Display)) -> String {
+    use fmt::Write;
+
+    let mut out = String::with_capacity(metric.size_hint());
+    write!(out, "{}", metric).expect("a Display implementation returned an error unexpectedly");
+    out
+}
+
+#[derive(Clone, Debug)]
+pub struct HistogramData<const N: usize> {
+    pub counts: [u64; N],
+    pub count: u64,
+    pub sum: f64,
+}
+
+// `sum` is compared bit by bit, so that equality is reflexive even for a NaN sum
+impl<const N: usize> PartialEq for HistogramData<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.counts == other.counts
+            && self.count == other.count
+            && self.sum.to_bits() == other.sum.to_bits()
+    }
+}
+
+impl<const N: usize> Eq for HistogramData<N> {}
+
+impl<const N: usize> HistogramData<N> {
+    /// Yields `(upper_bound, cumulative_count)` for every bucket, as rendered in the
+    /// `_bucket` series. The `+Inf` bucket is not included; its count is `count`.
+    pub fn iter_cumulative_buckets<'a>(
+        &'a self,
+        boundaries: &'a [f64; N],
+    ) -> impl Iterator<Item = (f64, u64)> + 'a {
+        self.iter_raw_buckets(boundaries)
+            .scan(0, |cumulative, (upper_bound, count)| {
+                *cumulative += count;
+                Some((upper_bound, *cumulative))
+            })
+    }
+
+    /// Yields `(upper_bound, count)` for every bucket, where `count` only includes the
+    /// observations above the previous bound.
+    pub fn iter_raw_buckets<'a>(
+        &'a self,
+        boundaries: &'a [f64; N],
+    ) -> impl Iterator<Item = (f64, u64)> + 'a {
+        boundaries.iter().copied().zip(self.counts.iter().copied())
+    }
+
+    fn record(&mut self, buckets: &[f64; N], value: f64) {
+        self.sum += value;
+        self.count += 1;
+
+        if let Some(i) = buckets.iter().position(|&upper_bound| value <= upper_bound) {
+            self.counts[i] += 1;
+        }
+    }
+}
+
+impl<const N: usize> Default for HistogramData<N> {
+    fn default() -> Self {
+        Self {
+            counts: [0; N],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+/// Validates that histogram buckets are in strictly ascending order.
+///
+/// This is a const function that panics at compile time if buckets are not ordered correctly.
+/// It is automatically called by the `define_histogram!` macro.
+///
+/// # Examples
+///
+/// Valid buckets compile successfully:
+/// ```
+/// use aetos_core::validate_histogram_buckets;
+///
+/// const _: () = {
+///     const BUCKETS: [f64; 3] = [0.1, 0.5, 1.0];
+///     validate_histogram_buckets(&BUCKETS);
+/// };
+/// ```
+///
+/// Invalid buckets cause a compile-time error:
+/// ```compile_fail
+/// use aetos_core::validate_histogram_buckets;
+///
+/// const _: () = {
+///     const BUCKETS: [f64; 3] = [1.0, 0.5, 2.0];
+///     validate_histogram_buckets(&BUCKETS);
+/// };
+/// ```
+pub const fn validate_histogram_buckets(buckets: &[f64]) {
+    let mut i = 1;
+    while i < buckets.len() {
+        if buckets[i - 1] >= buckets[i] {
+            panic!("Histogram buckets must be in strictly ascending order");
+        }
+        i += 1;
+    }
+}
+
+/// Why a set of histogram buckets was rejected by [`validate_histogram_buckets_checked`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistogramBucketError {
+    /// The bucket at `index` is not greater than the one before it
+    NotAscending {
+        index: usize,
+        previous: f64,
+        value: f64,
+    },
+    /// The bucket at `index` is NaN
+    NaN { index: usize },
+}
+
+impl Display for HistogramBucketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HistogramBucketError::NotAscending {
+                index,
+                previous,
+                value,
+            } => write!(
+                f,
+                "histogram buckets must be in strictly ascending order, but bucket {} ({}) \
+                 is not greater than bucket {} ({})",
+                index,
+                value,
+                index - 1,
+                previous
+            ),
+            HistogramBucketError::NaN { index } => {
+                write!(f, "histogram bucket {} is NaN", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HistogramBucketError {}
+
+/// Runtime counterpart of [`validate_histogram_buckets`], for buckets that are not
+/// known at compile time (e.g. loaded from a config file).
+///
+/// Returns an error instead of panicking, and also rejects NaN boundaries.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::{validate_histogram_buckets_checked, HistogramBucketError};
+///
+/// assert_eq!(validate_histogram_buckets_checked(&[0.1, 0.5, 1.0]), Ok(()));
+/// assert_eq!(
+///     validate_histogram_buckets_checked(&[1.0, 0.5]),
+///     Err(HistogramBucketError::NotAscending { index: 1, previous: 1.0, value: 0.5 })
+/// );
+/// ```
+pub fn validate_histogram_buckets_checked(buckets: &[f64]) -> Result<(), HistogramBucketError> {
+    for (index, &value) in buckets.iter().enumerate() {
+        if value.is_nan() {
+            return Err(HistogramBucketError::NaN { index });
+        }
+        if index > 0 && buckets[index - 1] >= value {
+            return Err(HistogramBucketError::NotAscending {
+                index,
+                previous: buckets[index - 1],
+                value,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a label name is valid in the Prometheus exposition format.
+///
+/// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*` and must not start with `__`,
+/// which is reserved for internal use. The `#[metrics]` macro performs this check at
+/// compile time for `label = "..."`; use this function when building
+/// `MetricMetadata` by hand.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::validate_label_name;
+///
+/// const _: () = assert!(validate_label_name("event_type"));
+/// assert!(!validate_label_name("0day"));
+/// assert!(!validate_label_name("http-method"));
+/// assert!(!validate_label_name("__name__"));
+/// ```
+pub const fn validate_label_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() {
+        return false;
+    }
+    if bytes.len() >= 2 && bytes[0] == b'_' && bytes[1] == b'_' {
+        return false;
+    }
+    if !(bytes[0].is_ascii_alphabetic() || bytes[0] == b'_') {
+        return false;
+    }
+
+    let mut i = 1;
+    while i < bytes.len() {
+        if !(bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Panics if `name` is not a valid label name, as checked by [`validate_label_name`].
+///
+/// Being a `const fn`, it turns an invalid label name into a compile error when called
+/// in a const context.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::assert_valid_label_name;
+///
+/// const _: () = assert_valid_label_name("event_type");
+/// ```
+///
+/// ```should_panic
+/// aetos_core::assert_valid_label_name("http-method");
+/// ```
+pub const fn assert_valid_label_name(name: &str) {
+    if !validate_label_name(name) {
+        panic!(
+            "invalid label name: must match [a-zA-Z_][a-zA-Z0-9_]* and must not start with '__'"
+        );
+    }
+}
+
+/// Checks that a metric name is valid in the Prometheus exposition format.
+///
+/// Metric names must match `[a-zA-Z_:][a-zA-Z0-9_:]*`. The `#[metrics]` macro performs
+/// this check at compile time on prefixes.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::validate_metric_name;
+///
+/// const _: () = assert!(validate_metric_name("http_requests"));
+/// assert!(validate_metric_name("job:requests:rate5m"));
+/// assert!(!validate_metric_name("my-app"));
+/// assert!(!validate_metric_name("2xx"));
+/// ```
+pub const fn validate_metric_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() {
+        return false;
+    }
+    if !(bytes[0].is_ascii_alphabetic() || bytes[0] == b'_' || bytes[0] == b':') {
+        return false;
+    }
+
+    let mut i = 1;
+    while i < bytes.len() {
+        if !(bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b':') {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// A metric name rejected by [`validate_metric_name_checked`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidMetricName(pub String);
+
+impl Display for InvalidMetricName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid metric name '{}': must match [a-zA-Z_:][a-zA-Z0-9_:]*",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidMetricName {}
+
+/// Runtime counterpart of [`validate_metric_name`], returning the offending name as an
+/// error. Useful when building `MetricMetadata` from names that are not known at compile
+/// time.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::{validate_metric_name_checked, InvalidMetricName};
+///
+/// assert_eq!(validate_metric_name_checked("job:requests:rate5m"), Ok(()));
+/// assert_eq!(
+///     validate_metric_name_checked("my-app"),
+///     Err(InvalidMetricName("my-app".to_string()))
+/// );
+/// ```
+pub fn validate_metric_name_checked(name: &str) -> Result<(), InvalidMetricName> {
+    if validate_metric_name(name) {
+        Ok(())
+    } else {
+        Err(InvalidMetricName(name.to_string()))
+    }
+}
+
+/// Turns `name` into a valid metric name, by replacing every character not allowed in
+/// metric names with `_`, and prepending `_` to a name starting with a digit or empty.
+/// Valid names are returned as is.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::sanitize_metric_name;
+///
+/// assert_eq!(sanitize_metric_name("lua.heap-size"), "lua_heap_size");
+/// assert_eq!(sanitize_metric_name("5xx"), "_5xx");
+/// assert_eq!(sanitize_metric_name("job:requests"), "job:requests");
+/// ```
+pub fn sanitize_metric_name(name: &str) -> Cow<'_, str> {
+    if validate_metric_name(name) {
+        return Cow::Borrowed(name);
+    }
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':') {
+        sanitized.push('_');
+    }
+    sanitized.extend(name.chars().map(|c| {
+        if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+            c
+        } else {
+            '_'
+        }
+    }));
+    Cow::Owned(sanitized)
+}
+
+/// Checks that a prefix is valid for `#[metrics(prefix = ...)]` and `prefix = ...` on fields.
+///
+/// Every `.`-separated part must be a valid metric name and must not end with `_`, since
+/// parts are joined with `_`. The `#[metrics]` macro checks literal prefixes when
+/// expanding, and prefixes given as expressions with this function in a const assertion.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::validate_metric_prefix;
+///
+/// const _: () = assert!(validate_metric_prefix("app.cache"));
+/// assert!(!validate_metric_prefix("app_"));
+/// assert!(!validate_metric_prefix("app..cache"));
+/// assert!(!validate_metric_prefix("my-app"));
+/// ```
+pub const fn validate_metric_prefix(prefix: &str) -> bool {
+    let bytes = prefix.as_bytes();
+    // Start of the current part
+    let mut start = 0;
+    let mut i = 0;
+    while i <= bytes.len() {
+        if i == bytes.len() || bytes[i] == b'.' {
+            if i == start || bytes[i - 1] == b'_' {
+                return false;
+            }
+            start = i + 1;
+        } else {
+            let valid = if i == start {
+                bytes[i].is_ascii_alphabetic() || bytes[i] == b'_' || bytes[i] == b':'
+            } else {
+                bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b':'
+            };
+            if !valid {
+                return false;
+            }
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Builds a metric name the way `#[metrics]` does.
+///
+/// The prefixes and the base name are joined with `_`, and dots in prefixes act as
+/// separators. The unit is appended unless the name already ends with it. With
+/// `total_suffix`, the name ends with `_total`, after the unit.
+///
+/// The macro builds names when expanding if all parts are literals, and calls this
+/// function once per field at runtime otherwise.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::build_metric_name;
+///
+/// assert_eq!(build_metric_name(&["app.http"], "requests", None, false), "app_http_requests");
+/// assert_eq!(
+///     build_metric_name(&[], "request_total", Some("bytes"), true),
+///     "request_bytes_total"
+/// );
+/// ```
+pub fn build_metric_name(
+    prefixes: &[&str],
+    base: &str,
+    unit: Option<&str>,
+    total_suffix: bool,
+) -> String {
+    let mut parts: Vec<&str> = prefixes.iter().flat_map(|p| p.split('.')).collect();
+    parts.push(base);
+
+    let mut name = parts.join("_");
+
+    if total_suffix {
+        if let Some(stripped) = name.strip_suffix("_total") {
+            name = stripped.to_string();
+        }
+    }
+
+    if let Some(unit) = unit {
+        let suffix = format!("_{}", unit);
+        if !name.ends_with(&suffix) {
+            name.push_str(&suffix);
+        }
+    }
+
+    if total_suffix {
+        name.push_str("_total");
+    }
+    name
+}
+
+/// Why a value was rejected by [`Histogram::try_observe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObserveError {
+    ValueIsNaN,
+    ValueIsNegativeInfinity,
+    /// The observation count of the series would exceed `u64::MAX`
+    BucketCountOverflow,
+}
+
+impl Display for ObserveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ObserveError::ValueIsNaN => write!(f, "cannot observe NaN"),
+            ObserveError::ValueIsNegativeInfinity => write!(f, "cannot observe -Inf"),
+            ObserveError::BucketCountOverflow => write!(f, "histogram count overflowed u64"),
+        }
+    }
+}
+
+impl std::error::Error for ObserveError {}
+
+#[derive(Clone, Debug)]
+pub struct Histogram<L, const N: usize> {
+    buckets: [f64; N],
+    pub data: HashMap<L, HistogramData<N>>,
+}
+
+impl<L: Hash + Eq, const N: usize> Histogram<L, N> {
+    pub fn new(buckets: [f64; N]) -> Self {
+        Self {
+            buckets,
+            data: HashMap::new(),
+        }
+    }
+
+    pub fn zero_initialize(&mut self, label: L) {
+        self.data.entry(label).or_default();
+    }
+
+    /// The label sets observed so far, or zero-initialized, in no particular order.
+    pub fn labels(&self) -> impl Iterator<Item = &L> {
+        self.data.keys()
+    }
+
+    /// The counts and sum of the series with `label`, or `None` if it has no series yet.
+    pub fn data_for(&self, label: &L) -> Option<&HistogramData<N>> {
+        self.data.get(label)
+    }
+
+    /// Keeps only the series for which `f` returns `true`, e.g. to evict stale label sets
+    /// of high-cardinality histograms.
+    pub fn retain<F: FnMut(&L, &mut HistogramData<N>) -> bool>(&mut self, f: F) {
+        self.data.retain(f);
+    }
+
+    /// Observes `value`, or returns an error without modifying the histogram when the
+    /// value would corrupt its sum or counts.
+    ///
+    /// `f64::INFINITY` is accepted and only counted in the `+Inf` bucket.
+    pub fn try_observe(&mut self, label: L, value: f64) -> Result<(), ObserveError> {
+        if value.is_nan() {
+            return Err(ObserveError::ValueIsNaN);
+        }
+        if value == f64::NEG_INFINITY {
+            return Err(ObserveError::ValueIsNegativeInfinity);
+        }
+        if self
+            .data
+            .get(&label)
+            .is_some_and(|entry| entry.count == u64::MAX)
+        {
+            return Err(ObserveError::BucketCountOverflow);
+        }
+
+        self.observe(label, value);
+        Ok(())
+    }
+
+    pub fn observe(&mut self, label: L, value: f64) {
+        self.data
+            .entry(label)
+            .or_default()
+            .record(&self.buckets, value);
+    }
+
+    /// Observes every value in `values` for the same label, looking up its series once.
+    pub fn observe_many(&mut self, label: L, values: impl IntoIterator<Item = f64>) {
+        let entry = self.data.entry(label).or_default();
+        for value in values {
+            entry.record(&self.buckets, value);
+        }
+    }
+
+    /// Runs `f` and observes its duration in seconds.
+    pub fn time<R>(&mut self, label: L, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.observe(label, start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Awaits `fut` and observes the time until it completes, in seconds.
+    #[cfg(feature = "async")]
+    pub async fn time_async<R>(&mut self, label: L, fut: impl Future<Output = R>) -> R {
+        let start = Instant::now();
+        let result = fut.await;
+        self.observe(label, start.elapsed().as_secs_f64());
+        result
+    }
+}
+
+#[cfg(not(feature = "no-escaping"))]
+pub fn escape_label_value(s: &str) -> Cow<'_, str> {
+    // The escaped characters are ASCII, and ASCII bytes never occur inside multi-byte
+    // UTF-8 sequences, so scanning bytes finds them without decoding and every match is
+    // a char boundary
+    let bytes = s.as_bytes();
+    let Some(first) = bytes.iter().position(|b| matches!(b, b'"' | b'\\' | b'\n')) else {
+        return Cow::Borrowed(s);
+    };
+
+    // Slow path: copy the runs between escaped characters
+    let mut result = String::with_capacity(s.len() + 8);
+    let mut start = 0;
+    for (i, b) in bytes.iter().enumerate().skip(first) {
+        let escaped = match b {
+            b'"' => "\\\"",
+            b'\\' => "\\\\",
+            b'\n' => "\\n",
+            _ => continue,
+        };
+        result.push_str(&s[start..i]);
+        result.push_str(escaped);
+        start = i + 1;
+    }
+    result.push_str(&s[start..]);
+    Cow::Owned(result)
+}
+
+#[cfg(feature = "no-escaping")]
+#[inline(always)]
+pub fn escape_label_value(s: &str) -> Cow<'_, str> {
+    Cow::Borrowed(s)
+}
+
+/// Escapes the text of a `# HELP` line: `\` becomes `\\` and a line feed `\n`, as
+/// required by the exposition format. Unlike label values, `"` is written as is.
+///
+/// Applied to the help text and the deprecation note of every family, regardless of
+/// the `no-escaping` feature.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::escape_help_text;
+///
+/// assert_eq!(escape_help_text("Path like C:\\temp"), "Path like C:\\\\temp");
+/// assert_eq!(escape_help_text("Two\nlines"), "Two\\nlines");
+/// assert_eq!(escape_help_text("Says \"hi\""), "Says \"hi\"");
+/// ```
+pub fn escape_help_text(s: &str) -> Cow<'_, str> {
+    // Same byte scan as `escape_label_value`
+    let bytes = s.as_bytes();
+    let Some(first) = bytes.iter().position(|b| matches!(b, b'\\' | b'\n')) else {
+        return Cow::Borrowed(s);
+    };
+
+    let mut result = String::with_capacity(s.len() + 8);
+    let mut start = 0;
+    for (i, b) in bytes.iter().enumerate().skip(first) {
+        let escaped = match b {
+            b'\\' => "\\\\",
+            b'\n' => "\\n",
+            _ => continue,
+        };
+        result.push_str(&s[start..i]);
+        result.push_str(escaped);
+        start = i + 1;
+    }
+    result.push_str(&s[start..]);
+    Cow::Owned(result)
+}
+
+/// Cuts `value` to its first `max_len` characters followed by `...` and a hash of the
+/// whole value, when it is longer. Characters are counted as `char`s, so a UTF-8 sequence
+/// is never split.
+///
+/// Used for the `max_label_len` of `#[metrics]` fields and the `#[label(max_len = N)]`
+/// of `#[derive(Label)]` fields, to bound the size of pathological label values such as
+/// full SQL queries. Escaping, if any, applies to the truncated value.
+///
+/// Values sharing their first `max_len` characters would otherwise render the same label
+/// set, and the exposition would contain duplicate series. The hash, 8 hex digits of the
+/// 32-bit FNV-1a hash of the UTF-8 bytes, keeps them apart. It is the same on every run,
+/// so a value keeps its series across restarts, but two values can still collide, with
+/// a probability of about one in 4 billion per pair.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::truncate_label_value;
+///
+/// assert_eq!(truncate_label_value("SELECT a", 6), "SELECT...#8ce99d22");
+/// assert_eq!(truncate_label_value("SELECT b", 6), "SELECT...#8be99b8f");
+/// assert_eq!(truncate_label_value("héllo", 2), "hé...#4aa48540");
+/// assert_eq!(truncate_label_value("short", 5), "short");
+/// ```
+pub fn truncate_label_value(value: &str, max_len: usize) -> Cow<'_, str> {
+    match value.char_indices().nth(max_len) {
+        Some((end, _)) => Cow::Owned(format!("{}...#{:08x}", &value[..end], fnv1a(value))),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// The 32-bit FNV-1a hash of the UTF-8 bytes of `value`
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// This abstracts away the difference between:
+/// - Vec/Slice iterator items: &(K, V)
+/// - HashMap iterator items:   (&K, &V)
+pub trait BorrowPair {
+    type Key: ?Sized;
+    type Value: ?Sized;
+
+    fn borrow_pair(&self) -> (&Self::Key, &Self::Value);
+}
+
+// Implementation for references to tuples (Vec, Slices, Arrays)
+// Iterating &[(K,V)] yields &(K,V)
+impl<K, V> BorrowPair for &(K, V) {
+    type Key = K;
+    type Value = V;
+
+    fn borrow_pair(&self) -> (&K, &V) {
+        (&self.0, &self.1)
+    }
+}
+
+// Implementation for tuples of references (HashMaps and BTreeMaps)
+// Iterating &HashMap<K,V> yields (&K, &V)
+impl<'a, K, V> BorrowPair for (&'a K, &'a V) {
+    type Key = K;
+    type Value = V;
+
+    fn borrow_pair(&self) -> (&K, &V) {
+        (self.0, self.1)
+    }
+}
+
+/// Implemented for every type whose references iterate over `(key, value)` pairs.
+///
+/// The `#[metrics]` macro requires it for fields with `label` or `labels`, so that a
+/// label on a scalar type is a compile error instead of being silently ignored.
+pub trait LabeledCollection {}
+
+impl<T: ?Sized> LabeledCollection for T
+where
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair,
+{
+}
+
+#[doc(hidden)]
+pub fn assert_labeled_collection<T: LabeledCollection + ?Sized>() {}
+
+/// Like `assert_labeled_collection`, for values whose type is not written out, such as
+/// the fields rendered by a `#[metrics]` method and the values of `compute` methods.
+#[doc(hidden)]
+pub fn assert_labeled_value<T: LabeledCollection + ?Sized>(_: &T) {}
+
+/// One metric family in the catalog returned by the `metrics_catalog()` function
+/// generated by `#[metrics]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CatalogEntry {
+    /// The full name of the family, with its prefix and suffixes
+    pub name: String,
+    pub kind: &'static str,
+    /// The label names known at compile time, followed by the constant labels. Empty
+    /// for label sets only known at runtime.
+    pub labels: Vec<&'static str>,
+    pub help: Option<&'static str>,
+}
+
+/// Formats `entries` as a markdown table with one row per family.
+pub fn catalog_markdown(entries: &[CatalogEntry]) -> String {
+    let mut out = String::from("| Name | Type | Labels | Help |\n| --- | --- | --- | --- |\n");
+    for entry in entries {
+        let labels: Vec<_> = entry.labels.iter().map(|l| format!("`{l}`")).collect();
+        // `|` would end the cell, and a newline the row
+        let help = entry
+            .help
+            .unwrap_or_default()
+            .replace('|', "\\|")
+            .replace('\n', " ");
+        out.push_str(&format!(
+            "| `{}` | {} | {} | {} |\n",
+            entry.name,
+            entry.kind,
+            labels.join(", "),
+            help
+        ));
+    }
+    out
+}
+
+/// Describes one metric family of a `MetricGroup`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupMember {
+    /// Appended to the name of the collection field, separated by `_`
+    pub name: &'static str,
+    pub help: Option<&'static str>,
+    pub kind: &'static str,
+}
+
+/// A struct of several values sharing the same labels, typically implemented with
+/// `#[derive(MetricGroup)]`.
+///
+/// A collection of `(labels, group)` pairs is rendered as one family per member,
+/// each containing one sample per entry of the collection.
+pub trait MetricGroup {
+    const MEMBERS: &'static [GroupMember];
+
+    /// Writes the value of the member at `index` in `MEMBERS`
+    fn fmt_member(&self, index: usize, f: &mut Formatter<'_>) -> fmt::Result;
+}
+
+/// Names of the families rendered for a collection of `MetricGroup` values, one per
+/// member. Used by the `metric_names()` function generated by `#[metrics]`.
+#[doc(hidden)]
+pub fn group_metric_names<T, V>(name: &str, total_suffix: bool) -> impl Iterator<Item = String> + '_
+where
+    T: ?Sized,
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair<Value = V>,
+    V: MetricGroup + ?Sized,
+{
+    V::MEMBERS
+        .iter()
+        .map(move |member| group_member_name(name, member, total_suffix))
+}
+
+/// The name of the family of `member` in a group rendered under `name`: both joined by
+/// `_`. With `total_suffix`, from `convention = "prometheus"`, counter members end in
+/// `_total`.
+#[doc(hidden)]
+pub fn group_member_name(name: &str, member: &GroupMember, total_suffix: bool) -> String {
+    let parts = member_name_parts(name, member, total_suffix);
+    // Made of the bytes of `&str`s, split after a `_`
+    String::from_utf8(parts.concat()).expect("member names are valid UTF-8")
+}
+
+// `group_member_name` as its parts, so that it can be compared in const contexts
+const fn member_name_parts<'a>(
+    name: &'a str,
+    member: &'a GroupMember,
+    total_suffix: bool,
+) -> [&'a [u8]; 4] {
+    let total = total_suffix && bytes_eq(member.kind.as_bytes(), b"counter");
+    let mut stem = member.name.as_bytes();
+    if total && stem.len() >= 6 && bytes_eq(stem.split_at(stem.len() - 6).1, b"_total") {
+        stem = stem.split_at(stem.len() - 6).0;
+    }
+    let suffix: &[u8] = if total { b"_total" } else { b"" };
+    [name.as_bytes(), b"_", stem, suffix]
+}
+
+const fn bytes_eq(a: &[u8], b: &[u8]) -> bool {
+    parts_eq([a, b"", b"", b""], [b, b"", b"", b""])
+}
+
+// Whether the concatenations of `a` and of `b` are equal
+const fn parts_eq(a: [&[u8]; 4], b: [&[u8]; 4]) -> bool {
+    let (mut a_part, mut a_byte, mut b_part, mut b_byte) = (0, 0, 0, 0);
+    loop {
+        while a_part < 4 && a_byte == a[a_part].len() {
+            a_part += 1;
+            a_byte = 0;
+        }
+        while b_part < 4 && b_byte == b[b_part].len() {
+            b_part += 1;
+            b_byte = 0;
+        }
+        if a_part == 4 || b_part == 4 {
+            return a_part == 4 && b_part == 4;
+        }
+        if a[a_part][a_byte] != b[b_part][b_byte] {
+            return false;
+        }
+        a_byte += 1;
+        b_byte += 1;
+    }
+}
+
+/// Panics when a family of the group stored in `T`, rendered under `name`, has one of
+/// `names`, the names of the other fields, or the name of another member. Evaluated in
+/// a const item by `#[metrics]`, so that the collision fails to compile.
+#[doc(hidden)]
+pub const fn check_group_names<T, V>(name: &str, total_suffix: bool, names: &[&str])
+where
+    T: ?Sized,
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair<Value = V>,
+    V: MetricGroup + ?Sized,
+{
+    let mut i = 0;
+    while i < V::MEMBERS.len() {
+        let member = member_name_parts(name, &V::MEMBERS[i], total_suffix);
+        let mut j = 0;
+        while j < names.len() {
+            if parts_eq(member, [names[j].as_bytes(), b"", b"", b""]) {
+                panic!("a member of this #[group] field has the metric name of another field");
+            }
+            j += 1;
+        }
+        // Members named apart can still collide once `_total` is added, e.g. `hits`
+        // and `hits_total`
+        let mut j = i + 1;
+        while j < V::MEMBERS.len() {
+            if parts_eq(
+                member,
+                member_name_parts(name, &V::MEMBERS[j], total_suffix),
+            ) {
+                panic!("two members of this #[group] field have the same metric name");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Panics when the groups stored in `T` and `U`, rendered under `name` and `other_name`,
+/// have a family name in common. See [`check_group_names`].
+#[doc(hidden)]
+pub const fn check_group_pair<T, V, U, W>(name: &str, other_name: &str, total_suffix: bool)
+where
+    T: ?Sized,
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair<Value = V>,
+    V: MetricGroup + ?Sized,
+    U: ?Sized,
+    for<'a> &'a U: IntoIterator,
+    for<'a> <&'a U as IntoIterator>::Item: BorrowPair<Value = W>,
+    W: MetricGroup + ?Sized,
+{
+    let mut i = 0;
+    while i < V::MEMBERS.len() {
+        let member = member_name_parts(name, &V::MEMBERS[i], total_suffix);
+        let mut j = 0;
+        while j < W::MEMBERS.len() {
+            if parts_eq(
+                member,
+                member_name_parts(other_name, &W::MEMBERS[j], total_suffix),
+            ) {
+                panic!("a member of this #[group] field has the metric name of another member");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Members of the `MetricGroup` stored in a collection. Used by the `describe()`
+/// function generated by `#[metrics]`.
+#[doc(hidden)]
+pub fn group_members<T, V>() -> &'static [GroupMember]
+where
+    T: ?Sized,
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair<Value = V>,
+    V: MetricGroup + ?Sized,
+{
+    V::MEMBERS
+}
+
+/// Label names of the keys of a collection. Used by the `metrics_catalog()` function
+/// generated by `#[metrics]`.
+#[doc(hidden)]
+pub fn key_label_names<T, K>() -> &'static [&'static str]
+where
+    T: ?Sized,
+    for<'a> &'a T: IntoIterator,
+    for<'a> <&'a T as IntoIterator>::Item: BorrowPair<Key = K>,
+    K: Label,
+{
+    K::label_names()
+}
+
+/// Values built once per type and leaked, for the caches of the functions generated by
+/// `#[metrics]` on generic structs. A `static` in a generic impl is shared by every
+/// instantiation, so the values are looked up by the `TypeId` of the instantiation.
+///
+/// The entries form a list whose links are each set once, so lookups take no lock.
+#[doc(hidden)]
+pub struct TypeCache<V: 'static> {
+    entry: OnceLock<(TypeId, &'static V)>,
+    next: OnceLock<Box<TypeCache<V>>>,
+}
+
+impl<V> TypeCache<V> {
+    pub const fn new() -> Self {
+        Self {
+            entry: OnceLock::new(),
+            next: OnceLock::new(),
+        }
+    }
+
+    /// The value for `T`, built by `init` on the first call for `T`. `init` runs before
+    /// the value is added, as it may use the caches of nested types.
+    pub fn get_or_init<T: ?Sized + 'static>(&self, init: impl FnOnce() -> V) -> &'static V {
+        let key = TypeId::of::<T>();
+        let mut node = self;
+        while let Some(&(id, value)) = node.entry.get() {
+            if id == key {
+                return value;
+            }
+            match node.next.get() {
+                Some(next) => node = next,
+                None => break,
+            }
+        }
+
+        // Concurrent first calls for `T` each build a value, and all return the first added
+        let value: &'static V = Box::leak(Box::new(init()));
+        let mut node = self;
+        loop {
+            let &(id, cached) = node.entry.get_or_init(|| (key, value));
+            if id == key {
+                return cached;
+            }
+            node = node.next.get_or_init(|| Box::new(Self::new()));
+        }
+    }
+}
+
+impl<V> Default for TypeCache<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The label type of a histogram, passed to its `observe`. Used by the `observe_*`
+/// helpers of `#[metrics(helpers)]`, and kept out of [`HistogramMetric`] so that
+/// implementing `HistogramMetric` does not require it.
+pub trait HistogramLabel {
+    type Label;
+}
+
+impl<L, const N: usize> HistogramLabel for Histogram<L, N> {
+    type Label = L;
+}
+
+pub trait HistogramMetric {
+    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result;
+
+    /// The names of the labels of every series, without `le`. Defaults to none.
+    fn label_names() -> &'static [&'static str]
+    where
+        Self: Sized,
+    {
+        &[]
+    }
+}
+
+impl<L, const N: usize> HistogramMetric for Histogram<L, N>
+where
+    L: Label + Hash + Eq,
+{
+    fn label_names() -> &'static [&'static str] {
+        L::label_names()
+    }
+
+    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
+        let mut header = FamilyHeader::start(f, meta, "histogram")?;
+        let series = self
+            .data
+            .iter()
+            .filter(|(_, data)| !(meta.skip_zero && data.count == 0));
+
+        // `data` is a `HashMap`, so its series are always sorted with `sorted-output`, by
+        // their rendered label set. An empty one needs no key.
+        #[cfg(feature = "sorted-output")]
+        let series = {
+            let mut series: Vec<(String, &L, &HistogramData<N>)> = series
+                .map(|(label, data)| {
+                    let key = if Label::is_empty(label) {
+                        String::new()
+                    } else {
+                        meta.label_set(label).to_string()
+                    };
+                    (key, label, data)
+                })
+                .collect();
+            series.sort_unstable_by(|(a, ..), (b, ..)| a.cmp(b));
+            series.into_iter().map(|(_, label, data)| (label, data))
+        };
+
+        for (label, data) in series {
+            header.write(f)?;
+            // Written on every line rather than rendered to a `String` once per series
+            let labels =
+                DisplayFn(|f: &mut Formatter<'_>| meta.fmt_labels(f, meta.label_set(label)));
+
+            for (upper_bound, cumulative_count) in data.iter_cumulative_buckets(&self.buckets) {
+                write!(f, "{}_bucket{{", meta.name)?;
+                fmt_labels_before_le(f, &labels)?;
+                writeln!(
+                    f,
+                    "le=\"{:.3}\"}} {}{}",
+                    upper_bound,
+                    cumulative_count,
+                    meta.timestamp_suffix()
+                )?;
+            }
+
+            write!(f, "{}_bucket{{", meta.name)?;
+            fmt_labels_before_le(f, &labels)?;
+            writeln!(f, "le=\"+Inf\"}} {}{}", data.count, meta.timestamp_suffix())?;
+            writeln!(
+                f,
+                "{}_sum{{{}}} {}{}",
+                meta.name,
+                labels,
+                data.sum,
+                meta.timestamp_suffix()
+            )?;
+            writeln!(
+                f,
+                "{}_count{{{}}} {}{}",
+                meta.name,
+                labels,
+                data.count,
+                meta.timestamp_suffix()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the labels of a histogram series, followed by a comma when there are any, so
+// that `le` can follow. Whether there are any is only known once they are written:
+// `Label::is_empty` is `false` for label sets that write nothing but keep the default.
+fn fmt_labels_before_le(f: &mut Formatter, labels: impl Display) -> fmt::Result {
+    let mut out = TrackWritten::new(f);
+    fmt::Write::write_fmt(&mut out, format_args!("{}", labels))?;
+    if out.written {
+        f.write_str(",")?;
+    }
+    Ok(())
+}
+
+/// A histogram shared between threads, e.g. `Arc<Mutex<Latency>>`, renders like the
+/// histogram inside it.
+impl<H: HistogramMetric> HistogramMetric for Arc<H> {
+    fn label_names() -> &'static [&'static str] {
+        H::label_names()
+    }
+
+    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
+        (**self).render_histogram(f, meta)
+    }
+}
+
+/// The lock is held while rendering, and a poisoned lock is rendered anyway.
+impl<H: HistogramMetric> HistogramMetric for Mutex<H> {
+    fn label_names() -> &'static [&'static str] {
+        H::label_names()
+    }
+
+    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .render_histogram(f, meta)
+    }
+}
+
+/// The lock is held while rendering, and a poisoned lock is rendered anyway.
+impl<H: HistogramMetric> HistogramMetric for RwLock<H> {
+    fn label_names() -> &'static [&'static str] {
+        H::label_names()
+    }
+
+    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
+        self.read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .render_histogram(f, meta)
+    }
+}
+
+/// The metadata of a metric family, passed to the rendering functions.
+///
+/// Fields are added as features grow, so the struct cannot be built with a literal
+/// outside of this crate; use [`MetricMetadata::builder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MetricMetadata<'a> {
+    pub name: &'a str,
+    /// When `None`, the `# HELP` line is omitted
+    pub help: Option<&'a str>,
+    pub kind: &'a str,
+    /// Unix timestamp in milliseconds, appended after the value of every sample
+    pub timestamp: Option<u64>,
+    /// When set, a `# UNIT` line is written after `# TYPE`, with the `openmetrics`
+    /// feature. The metric name carries the unit either way.
+    pub unit: Option<&'a str>,
+    /// When set, appended to the `# HELP` line as `(deprecated: <note>)`
+    pub deprecated: Option<&'a str>,
+    /// Label pairs appended after the labels of every sample, e.g. `service="api"`
+    pub const_labels: &'a [(&'a str, &'a str)],
+    /// When set, samples whose value renders as zero and histogram series with a count
+    /// of zero are omitted. The header is then written only before the first sample, so
+    /// a family without samples renders nothing.
+    pub skip_zero: bool,
+    /// When set, the label values of the samples are written without escaping. Constant
+    /// labels are always escaped.
+    pub raw_labels: bool,
+    /// When set, the samples of a collection are rendered sorted by their label values,
+    /// for collections without a stable iteration order such as `HashMap`. Has no effect
+    /// without the `sorted-output` feature.
+    pub sorted: bool,
+    /// When set, the values of a `label = "..."` label longer than this many characters
+    /// are cut to it and followed by `...` and a hash, see [`truncate_label_value`]. Labels of
+    /// `Label` keys and tuple keys are not affected.
+    pub max_label_len: Option<usize>,
+    /// When set, the header lines of the family are not written. Used for
+    /// families shared by several fields, whose header is written once with
+    /// [`MetricMetadata::write_header`] before the samples of every field.
+    pub no_header: bool,
+    /// Whether the counter members of a group get a `_total` suffix, see
+    /// [`group_member_name`]. Set by `convention = "prometheus"`; other families carry
+    /// the suffix in `name`.
+    pub total_suffix: bool,
+}
+
+impl<'a> MetricMetadata<'a> {
+    /// Starts building metadata, leaving the optional fields unset. `help` is a `&str`,
+    /// or `None` to omit the `# HELP` line.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use aetos_core::MetricMetadata;
+    ///
+    /// let meta = MetricMetadata::builder("requests", "Total requests", "counter")
+    ///     .unit("bytes")
+    ///     .build();
+    /// assert_eq!(meta.help, Some("Total requests"));
+    /// assert_eq!(meta.unit, Some("bytes"));
+    ///
+    /// let meta = MetricMetadata::builder("up", None, "gauge").build();
+    /// assert_eq!(meta.help, None);
+    /// ```
+    pub fn builder(
+        name: &'a str,
+        help: impl Into<Option<&'a str>>,
+        kind: &'a str,
+    ) -> MetricMetadataBuilder<'a> {
+        MetricMetadataBuilder::new(name, help, kind)
+    }
+}
+
+impl MetricMetadata<'_> {
+    fn fmt_header(&self, f: &mut Formatter, kind: &str) -> fmt::Result {
+        if self.no_header {
+            return Ok(());
+        }
+        MetricMetadata { kind, ..*self }.write_header(f)
+    }
+
+    /// Writes the `# HELP`, `# TYPE` and, with the `openmetrics` feature, `# UNIT` lines
+    /// of the family, even when `no_header` is set.
+    pub fn write_header(&self, f: &mut Formatter) -> fmt::Result {
+        let help = self.help.map(escape_help_text);
+        let note = self.deprecated.map(escape_help_text);
+        match (help, note) {
+            (Some(help), Some(note)) => {
+                writeln!(f, "# HELP {} {} (deprecated: {})", self.name, help, note)?
+            }
+            (Some(help), None) => writeln!(f, "# HELP {} {}", self.name, help)?,
+            (None, Some(note)) => writeln!(f, "# HELP {} (deprecated: {})", self.name, note)?,
+            (None, None) => {}
+        }
+        writeln!(f, "# TYPE {} {}", self.name, self.kind)?;
+        if let Some(unit) = self.unit.filter(|_| UNIT_LINES) {
+            writeln!(f, "# UNIT {} {}", self.name, unit)?;
+        }
+        Ok(())
+    }
+
+    fn timestamp_suffix(&self) -> TimestampSuffix {
+        TimestampSuffix(self.timestamp)
+    }
+
+    /// The label set of a sample, escaped unless `raw_labels` is set
+    fn label_set<'l, L: Label + ?Sized>(&self, label: &'l L) -> DisplayLabels<'l, L> {
+        DisplayLabels {
+            labels: label,
+            raw: self.raw_labels,
+        }
+    }
+
+    /// The value of a label, truncated to `max_label_len` and then escaped unless
+    /// `raw_labels` is set
+    fn label_value<'v>(&self, value: &'v str) -> Cow<'v, str> {
+        match self
+            .max_label_len
+            .map(|max_len| truncate_label_value(value, max_len))
+        {
+            Some(Cow::Owned(truncated)) => {
+                Cow::Owned(maybe_escape(&truncated, !self.raw_labels).into_owned())
+            }
+            _ => maybe_escape(value, !self.raw_labels),
+        }
+    }
+
+    /// Whether the sample with `value` is omitted, see `skip_zero`
+    fn skips(&self, value: &(impl Display + ?Sized)) -> bool {
+        self.skip_zero && is_zero(value)
+    }
+
+    /// Writes `labels` followed by the constant labels, separated by a comma when both
+    /// are present
+    fn fmt_labels(&self, f: &mut Formatter, labels: impl Display) -> fmt::Result {
+        if self.const_labels.is_empty() {
+            return write!(f, "{}", labels);
+        }
+
+        // Whether a separator is needed is only known once the labels are rendered,
+        // e.g. for `()` or an empty runtime label set
+        let mut out = TrackWritten::new(f);
+        fmt::Write::write_fmt(&mut out, format_args!("{}", labels))?;
+        let has_labels = out.written;
+        for (i, (name, value)) in self.const_labels.iter().enumerate() {
+            if i > 0 || has_labels {
+                write!(f, ",")?;
+            }
+            write!(f, "{}=\"{}\"", name, escape_label_value(value))?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`MetricMetadata`] without naming every optional field, so that call sites
+/// keep compiling as fields are added.
+#[derive(Clone, Copy, Debug)]
+pub struct MetricMetadataBuilder<'a> {
+    meta: MetricMetadata<'a>,
 }
 
-impl Label for () {
-    fn fmt_labels(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Ok(())
+impl<'a> MetricMetadataBuilder<'a> {
+    pub fn new(name: &'a str, help: impl Into<Option<&'a str>>, kind: &'a str) -> Self {
+        MetricMetadataBuilder {
+            meta: MetricMetadata {
+                name,
+                help: help.into(),
+                kind,
+                timestamp: None,
+                unit: None,
+                deprecated: None,
+                const_labels: &[],
+                skip_zero: false,
+                raw_labels: false,
+                sorted: false,
+                max_label_len: None,
+                no_header: false,
+                total_suffix: false,
+            },
+        }
+    }
+
+    /// Sets the unit, written as a `# UNIT` line with the `openmetrics` feature
+    pub fn unit(mut self, unit: &'a str) -> Self {
+        self.meta.unit = Some(unit);
+        self
+    }
+
+    /// Sets the Unix timestamp in milliseconds appended to every sample
+    pub fn timestamp(mut self, ts: u64) -> Self {
+        self.meta.timestamp = Some(ts);
+        self
+    }
+
+    /// Marks the metric as deprecated, with a note appended to the `# HELP` line
+    pub fn deprecated(mut self, note: &'a str) -> Self {
+        self.meta.deprecated = Some(note);
+        self
+    }
+
+    /// Sets the label pairs appended after the labels of every sample
+    pub fn const_labels(mut self, labels: &'a [(&'a str, &'a str)]) -> Self {
+        self.meta.const_labels = labels;
+        self
+    }
+
+    /// Omits the samples whose value is zero
+    pub fn skip_zero(mut self) -> Self {
+        self.meta.skip_zero = true;
+        self
+    }
+
+    /// Writes the label values without escaping them
+    pub fn raw_labels(mut self) -> Self {
+        self.meta.raw_labels = true;
+        self
+    }
+
+    /// Renders the samples of a collection sorted by their label values
+    pub fn sorted(mut self) -> Self {
+        self.meta.sorted = true;
+        self
+    }
+
+    /// Truncates the values of a `label = "..."` label to `max_len` characters
+    pub fn max_label_len(mut self, max_len: usize) -> Self {
+        self.meta.max_label_len = Some(max_len);
+        self
+    }
+
+    /// Leaves out the header lines, for a family whose header is written separately
+    pub fn no_header(mut self) -> Self {
+        self.meta.no_header = true;
+        self
+    }
+
+    pub fn build(self) -> MetricMetadata<'a> {
+        self.meta
     }
 }
 
-pub const fn linear_buckets<const N: usize>(start: f64, width: f64) -> [f64; N] {
-    let mut buckets = [0.0; N];
-    let mut i = 0;
+// Whether `value` renders as a zero, e.g. `0`, `-0` or `0.0`. Formatting stops at the
+// first character that is not part of a zero.
+fn is_zero(value: &(impl Display + ?Sized)) -> bool {
+    struct ZeroCheck {
+        digits: bool,
+    }
 
-    while i < N {
-        buckets[i] = start + width * (i as f64);
-        i += 1;
+    impl fmt::Write for ZeroCheck {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for c in s.chars() {
+                match c {
+                    '0' => self.digits = true,
+                    '-' | '.' => {}
+                    _ => return Err(fmt::Error),
+                }
+            }
+            Ok(())
+        }
     }
-    buckets
+
+    let mut check = ZeroCheck { digits: false };
+    fmt::write(&mut check, format_args!("{}", value)).is_ok() && check.digits
 }
 
-pub const fn exponential_buckets<const N: usize>(start: f64, factor: f64) -> [f64; N] {
-    let mut buckets = [0.0; N];
-    let mut current = start;
-    let mut i = 0;
-    while i < N {
-        buckets[i] = current;
-        current *= factor;
-        i += 1;
+// Calls `render` on each item, in the order of `key` when `sort` is set and the
+// `sorted-output` feature is enabled, and in iteration order otherwise
+fn render_ordered<I>(
+    items: impl IntoIterator<Item = I>,
+    sort: bool,
+    key: impl FnMut(&I) -> String,
+    render: impl FnMut(I) -> fmt::Result,
+) -> fmt::Result {
+    #[cfg(feature = "sorted-output")]
+    if sort {
+        let mut items: Vec<I> = items.into_iter().collect();
+        items.sort_by_cached_key(key);
+        return items.into_iter().try_for_each(render);
     }
-    buckets
+    #[cfg(not(feature = "sorted-output"))]
+    let _ = (sort, key);
+    items.into_iter().try_for_each(render)
 }
 
-pub trait PrometheusMetric: std::fmt::Display {}
+/// Renders every entry of `entries` as its own unlabeled family, named after its key.
+/// Used for `#[dynamic]` fields, whose metric names are only known at runtime.
+///
+/// The name is built from `prefixes` and the key like the names of other fields, then
+/// passed through [`sanitize_metric_name`]. `meta` gives the help, kind and timestamp of
+/// every family; its name is ignored. Families are rendered sorted by name, and only
+/// those passing `filter`. When several keys give the same name, the family is rendered
+/// once with the value of the last of them in iteration order.
+pub fn render_dynamic<I>(
+    entries: I,
+    f: &mut Formatter,
+    prefixes: &[&str],
+    total_suffix: bool,
+    meta: &MetricMetadata,
+    filter: &dyn Fn(&str) -> bool,
+) -> fmt::Result
+where
+    I: IntoIterator,
+    I::Item: BorrowPair,
+    <I::Item as BorrowPair>::Key: Display,
+    <I::Item as BorrowPair>::Value: Display,
+{
+    let mut families = BTreeMap::new();
+    for item in entries {
+        let (key, value) = item.borrow_pair();
+        let name = build_metric_name(prefixes, &key.to_string(), None, total_suffix);
+        families.insert(sanitize_metric_name(&name).into_owned(), value.to_string());
+    }
 
-#[derive(Clone, Debug)]
-pub struct HistogramData<const N: usize> {
-    pub counts: [u64; N],
-    pub count: u64,
-    pub sum: f64,
+    for (name, value) in families.iter().filter(|(name, _)| filter(name)) {
+        let meta = MetricMetadata { name, ..*meta };
+        MetricWrapper(value).render_scalar(f, &meta)?;
+    }
+    Ok(())
 }
 
-impl<const N: usize> Default for HistogramData<N> {
-    fn default() -> Self {
-        Self {
-            counts: [0; N],
-            count: 0,
-            sum: 0.0,
+// The header of a family, written right away unless `skip_zero` is set, and otherwise
+// before the first sample
+struct FamilyHeader<'m, 'a> {
+    meta: &'m MetricMetadata<'a>,
+    kind: &'m str,
+    written: bool,
+}
+
+impl<'m, 'a> FamilyHeader<'m, 'a> {
+    fn start(
+        f: &mut Formatter,
+        meta: &'m MetricMetadata<'a>,
+        kind: &'m str,
+    ) -> Result<Self, fmt::Error> {
+        let mut header = FamilyHeader {
+            meta,
+            kind,
+            written: false,
+        };
+        if !meta.skip_zero {
+            header.write(f)?;
         }
+        Ok(header)
+    }
+
+    fn write(&mut self, f: &mut Formatter) -> fmt::Result {
+        if !self.written {
+            self.written = true;
+            self.meta.fmt_header(f, self.kind)?;
+        }
+        Ok(())
     }
 }
 
-/// Validates that histogram buckets are in strictly ascending order.
+/// Displays through a closure. Used by the `render_one()` function generated by
+/// `#[metrics]` to collect the rendering of a single field into a `String`.
+#[doc(hidden)]
+pub struct DisplayFn<F>(pub F);
+
+impl<F: Fn(&mut Formatter<'_>) -> fmt::Result> Display for DisplayFn<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+/// Displays the value returned by a closure, calling it each time it is displayed.
 ///
-/// This is a const function that panics at compile time if buckets are not ordered correctly.
-/// It is automatically called by the `define_histogram!` macro.
+/// `#[metrics]` wraps counter and gauge fields holding a closure (`Box<dyn Fn() -> T>`,
+/// `Arc<dyn Fn() -> T>`, or a type parameter bounded by `Fn() -> T`) in it, so that the
+/// closure is called once per render. It can also be used as the field type directly.
 ///
 /// # Examples
 ///
-/// Valid buckets compile successfully:
 /// ```
-/// use aetos_core::validate_histogram_buckets;
+/// use aetos_core::LazyMetric;
 ///
-/// const _: () = {
-///     const BUCKETS: [f64; 3] = [0.1, 0.5, 1.0];
-///     validate_histogram_buckets(&BUCKETS);
-/// };
+/// let lazy = LazyMetric(|| 6 * 7);
+/// assert_eq!(lazy.to_string(), "42");
 /// ```
+pub struct LazyMetric<F>(pub F);
+
+impl<F, T> Display for LazyMetric<F>
+where
+    F: Fn() -> T,
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        (self.0)().fmt(f)
+    }
+}
+
+/// Loads the current value of a `std::sync::atomic` integer or bool with
+/// `Ordering::Relaxed`.
 ///
-/// Invalid buckets cause a compile-time error:
-/// ```compile_fail
-/// use aetos_core::validate_histogram_buckets;
+/// `#[metrics]` renders counter and gauge fields of these types through it, so an
+/// `AtomicU64` can be shared between threads and rendered without a wrapper.
+/// `AtomicBool` loads as `1` or `0`.
+///
+/// # Examples
 ///
-/// const _: () = {
-///     const BUCKETS: [f64; 3] = [1.0, 0.5, 2.0];
-///     validate_histogram_buckets(&BUCKETS);
-/// };
 /// ```
-pub const fn validate_histogram_buckets(buckets: &[f64]) {
-    let mut i = 1;
-    while i < buckets.len() {
-        if buckets[i - 1] >= buckets[i] {
-            panic!("Histogram buckets must be in strictly ascending order");
-        }
-        i += 1;
-    }
-}
+/// use aetos_core::AtomicLoad;
+/// use std::sync::atomic::{AtomicBool, AtomicU64};
+///
+/// assert_eq!(AtomicU64::new(42).load_relaxed(), 42);
+/// assert_eq!(AtomicBool::new(true).load_relaxed(), 1);
+/// ```
+pub trait AtomicLoad {
+    type Value: Display;
 
-#[derive(Clone, Debug)]
-pub struct Histogram<L, const N: usize> {
-    buckets: [f64; N],
-    pub data: HashMap<L, HistogramData<N>>,
+    fn load_relaxed(&self) -> Self::Value;
 }
 
-impl<L: Hash + Eq, const N: usize> Histogram<L, N> {
-    pub fn new(buckets: [f64; N]) -> Self {
-        Self {
-            buckets,
-            data: HashMap::new(),
-        }
-    }
+macro_rules! impl_atomic_load {
+    ($($atomic:ident => $value:ty),* $(,)?) => {
+        $(
+            impl AtomicLoad for std::sync::atomic::$atomic {
+                type Value = $value;
 
-    pub fn zero_initialize(&mut self, label: L) {
-        self.data.entry(label).or_default();
-    }
+                fn load_relaxed(&self) -> $value {
+                    self.load(std::sync::atomic::Ordering::Relaxed)
+                }
+            }
+        )*
+    };
+}
 
-    pub fn observe(&mut self, label: L, value: f64) {
-        let entry = self.data.entry(label).or_default();
+impl_atomic_load! {
+    AtomicU8 => u8,
+    AtomicU16 => u16,
+    AtomicU32 => u32,
+    AtomicU64 => u64,
+    AtomicUsize => usize,
+    AtomicI8 => i8,
+    AtomicI16 => i16,
+    AtomicI32 => i32,
+    AtomicI64 => i64,
+    AtomicIsize => isize,
+}
 
-        entry.sum += value;
-        entry.count += 1;
+impl AtomicLoad for std::sync::atomic::AtomicBool {
+    type Value = u8;
 
-        for i in 0..N {
-            if value <= self.buckets[i] {
-                entry.counts[i] += 1;
-                break;
-            }
-        }
+    fn load_relaxed(&self) -> u8 {
+        u8::from(self.load(std::sync::atomic::Ordering::Relaxed))
     }
 }
 
-#[cfg(not(feature = "no-escaping"))]
-pub fn escape_label_value(s: &str) -> Cow<'_, str> {
-    // Fast path: check if escaping is needed
-    if !s.chars().any(|ch| matches!(ch, '"' | '\\' | '\n')) {
-        return Cow::Borrowed(s);
-    }
+/// Time values rendered as fractional seconds by [`Seconds`].
+pub trait ToSeconds {
+    fn to_seconds(&self) -> f64;
+}
 
-    // Slow path: escape special characters
-    let mut result = String::with_capacity(s.len());
-    for ch in s.chars() {
-        match ch {
-            '"' => result.push_str("\\\""),
-            '\\' => result.push_str("\\\\"),
-            '\n' => result.push_str("\\n"),
-            _ => result.push(ch),
-        }
+impl ToSeconds for Duration {
+    fn to_seconds(&self) -> f64 {
+        self.as_secs_f64()
     }
-    Cow::Owned(result)
 }
 
-#[cfg(feature = "no-escaping")]
-#[inline(always)]
-pub fn escape_label_value(s: &str) -> Cow<'_, str> {
-    Cow::Borrowed(s)
+/// Seconds since the unix epoch. Times before the epoch are negative rather than
+/// clamped to 0, so that they stay distinguishable from the epoch itself.
+impl ToSeconds for SystemTime {
+    fn to_seconds(&self) -> f64 {
+        match self.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs_f64(),
+            Err(err) => -err.duration().as_secs_f64(),
+        }
+    }
 }
 
-/// This abstracts away the difference between:
-/// - Vec/Slice iterator items: &(K, V)
-/// - HashMap iterator items:   (&K, &V)
-pub trait BorrowPair {
-    type Key: ?Sized;
-    type Value: Display + ?Sized;
+/// Renders a `Duration` or `SystemTime`, or the values of a labeled collection of them,
+/// as fractional seconds.
+///
+/// Neither type has a `Display` impl, and the `Debug` output of `Duration` (`1.2s`) is not
+/// a valid sample value. `#[metrics]` wraps counter and gauge fields of type `Duration`,
+/// gauge fields of type `SystemTime`, and collections with such values, in it.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::Seconds;
+/// use std::time::{Duration, SystemTime};
+///
+/// assert_eq!(Seconds(&Duration::from_millis(1500)).to_string(), "1.5");
+///
+/// let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_699_999_999_123);
+/// assert_eq!(Seconds(&time).to_string(), "1699999999.123");
+/// ```
+pub struct Seconds<'a, T: ?Sized>(pub &'a T);
 
-    fn borrow_pair(&self) -> (&Self::Key, &Self::Value);
+impl<T: ToSeconds> Display for Seconds<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.to_seconds().fmt(f)
+    }
 }
 
-// Implementation for references to tuples (Vec, Slices, Arrays)
-// Iterating &[(K,V)] yields &(K,V)
-impl<K, V: Display> BorrowPair for &(K, V) {
-    type Key = K;
-    type Value = V;
-
-    fn borrow_pair(&self) -> (&K, &V) {
-        (&self.0, &self.1)
+impl<'a, T: ?Sized> IntoIterator for &Seconds<'a, T>
+where
+    &'a T: IntoIterator,
+    <&'a T as IntoIterator>::Item: BorrowPair,
+    <<&'a T as IntoIterator>::Item as BorrowPair>::Value: ToSeconds,
+{
+    type Item = ConvertedPair<<&'a T as IntoIterator>::Item, f64>;
+    type IntoIter = std::iter::Map<
+        <&'a T as IntoIterator>::IntoIter,
+        fn(<&'a T as IntoIterator>::Item) -> Self::Item,
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0
+            .into_iter()
+            .map(|item| ConvertedPair::new(item, ToSeconds::to_seconds))
     }
 }
 
-// Implementation for tuples of references (HashMaps and BTreeMaps)
-// Iterating &HashMap<K,V> yields (&K, &V)
-impl<'a, K, V: Display> BorrowPair for (&'a K, &'a V) {
-    type Key = K;
-    type Value = V;
+/// Renders a `bool`, or the `bool` values of a labeled collection, as `1` or `0`.
+///
+/// The `Display` output of `bool` (`true`) is not a valid sample value. `#[metrics]`
+/// wraps counter and gauge fields of type `bool`, and collections with `bool` values, in
+/// it.
+///
+/// # Examples
+///
+/// ```
+/// use aetos_core::Flag;
+///
+/// assert_eq!(Flag(&true).to_string(), "1");
+/// assert_eq!(Flag(&false).to_string(), "0");
+/// ```
+pub struct Flag<'a, T: ?Sized>(pub &'a T);
 
-    fn borrow_pair(&self) -> (&K, &V) {
-        (self.0, self.1)
+impl Display for Flag<'_, bool> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        u8::from(*self.0).fmt(f)
     }
 }
 
-pub trait HistogramMetric {
-    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result;
-}
-
-impl<L, const N: usize> HistogramMetric for Histogram<L, N>
+impl<'a, T: ?Sized> IntoIterator for &Flag<'a, T>
 where
-    L: Label + Hash + Eq,
+    &'a T: IntoIterator,
+    <&'a T as IntoIterator>::Item: BorrowPair<Value = bool>,
 {
-    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
-        write!(
-            f,
-            "# HELP {} {}\n# TYPE {} histogram\n",
-            meta.name, meta.help, meta.name
-        )?;
-
-        for (label, data) in &self.data {
-            let mut cumulative_count = 0;
-            for i in 0..N {
-                cumulative_count += data.counts[i];
-                let upper_bound = self.buckets[i];
+    type Item = ConvertedPair<<&'a T as IntoIterator>::Item, u8>;
+    type IntoIter = std::iter::Map<
+        <&'a T as IntoIterator>::IntoIter,
+        fn(<&'a T as IntoIterator>::Item) -> Self::Item,
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0
+            .into_iter()
+            .map(|item| ConvertedPair::new(item, |value| u8::from(*value)))
+    }
+}
 
-                write!(f, "{}_bucket{{", meta.name)?;
-                label.fmt_labels(f)?;
-                if std::mem::size_of::<L>() > 0 {
-                    write!(f, ",")?;
-                }
-                writeln!(f, "le=\"{:.3}\"}} {}", upper_bound, cumulative_count)?;
-            }
+/// An item of a collection iterated through [`Seconds`] or [`Flag`]: the key of the
+/// item, with its value converted to a number.
+pub struct ConvertedPair<I, V> {
+    item: I,
+    value: V,
+}
 
-            write!(f, "{}_bucket{{", meta.name)?;
-            label.fmt_labels(f)?;
-            if std::mem::size_of::<L>() > 0 {
-                write!(f, ",")?;
-            }
-            writeln!(f, "le=\"+Inf\"}} {}", data.count)?;
+impl<I: BorrowPair, V> ConvertedPair<I, V> {
+    fn new(item: I, convert: impl FnOnce(&I::Value) -> V) -> Self {
+        let value = convert(item.borrow_pair().1);
+        Self { item, value }
+    }
+}
 
-            write!(f, "{}_sum{{", meta.name)?;
-            label.fmt_labels(f)?;
-            writeln!(f, "}} {}", data.sum)?;
+impl<I: BorrowPair, V> BorrowPair for ConvertedPair<I, V> {
+    type Key = I::Key;
+    type Value = V;
 
-            write!(f, "{}_count{{", meta.name)?;
-            label.fmt_labels(f)?;
-            writeln!(f, "}} {}", data.count)?;
-        }
-        Ok(())
+    fn borrow_pair(&self) -> (&I::Key, &V) {
+        (self.item.borrow_pair().0, &self.value)
     }
 }
 
-pub struct MetricMetadata<'a> {
-    pub name: &'a str,
-    pub help: &'a str,
-    pub kind: &'a str,
+// Renders as ` <ts>` when a timestamp is present, and as nothing otherwise
+struct TimestampSuffix(Option<u64>);
+
+impl Display for TimestampSuffix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ts) => write!(f, " {}", ts),
+            None => Ok(()),
+        }
+    }
 }
 
 pub struct MetricWrapper<'a, T: ?Sized>(pub &'a T);
@@ -248,7 +2327,18 @@ pub trait RenderScalarFallback {
 
     fn render_with_struct_key(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result;
 
-    fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result;
+    fn render_with_label_names<const N: usize>(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+        _label_names: &[&str; N],
+    ) -> fmt::Result;
+
+    /// Renders nothing: the value is not a histogram
+    #[deprecated(note = "call `HistogramMetric::render_histogram` on the histogram instead")]
+    fn render_histogram(&self, _f: &mut Formatter, _meta: &MetricMetadata) -> fmt::Result {
+        Ok(())
+    }
 }
 
 // Blanket impl for anything Display (u64, f64, AtomicU64, etc.)
@@ -266,17 +2356,30 @@ impl<'a, T: Display + ?Sized> RenderScalarFallback for MetricWrapper<'a, T> {
         self.render_scalar(f, meta)
     }
 
-    fn render_histogram(&self, _f: &mut Formatter, _meta: &MetricMetadata) -> fmt::Result {
-        Ok(())
+    fn render_with_label_names<const N: usize>(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+        _label_names: &[&str; N],
+    ) -> fmt::Result {
+        self.render_scalar(f, meta)
     }
 }
 
 // Private helper for scalar rendering
 impl<'a, T: Display + ?Sized> MetricWrapper<'a, T> {
     fn render_scalar(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
-        writeln!(f, "# HELP {} {}", meta.name, meta.help)?;
-        writeln!(f, "# TYPE {} {}", meta.name, meta.kind)?;
-        writeln!(f, "{} {}", meta.name, self.0)
+        if meta.skips(self.0) {
+            return Ok(());
+        }
+        meta.fmt_header(f, meta.kind)?;
+        if meta.const_labels.is_empty() {
+            writeln!(f, "{} {}{}", meta.name, self.0, meta.timestamp_suffix())
+        } else {
+            write!(f, "{}{{", meta.name)?;
+            meta.fmt_labels(f, "")?;
+            writeln!(f, "}} {}{}", self.0, meta.timestamp_suffix())
+        }
     }
 }
 
@@ -286,58 +2389,187 @@ impl<'a, T: ?Sized> MetricWrapper<'a, T>
 where
     T: HistogramMetric,
 {
+    #[deprecated(note = "call `HistogramMetric::render_histogram` on the histogram instead")]
     pub fn render_histogram(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result {
         self.0.render_histogram(f, meta)
     }
-}
+}
+
+// These methods exist *only* if T is iterable with BorrowPair items.
+// Because they are inherent, Rust picks them BEFORE looking at RenderScalarFallback.
+impl<'a, T: ?Sized> MetricWrapper<'a, T>
+where
+    &'a T: IntoIterator,
+    <&'a T as IntoIterator>::Item: BorrowPair,
+{
+    // Only exists when Key and Value implement Display
+    pub fn render_with_label_attr(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+        label_name: &str,
+    ) -> fmt::Result
+    where
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Key: Display,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: Display,
+    {
+        let mut header = FamilyHeader::start(f, meta, meta.kind)?;
+
+        let by_key = |item: &<&'a T as IntoIterator>::Item| item.borrow_pair().0.to_string();
+        render_ordered(self.0, meta.sorted, by_key, |item| {
+            let (k, v) = item.borrow_pair();
+            if meta.skips(v) {
+                return Ok(());
+            }
+            header.write(f)?;
+            write!(f, "{}{{", meta.name)?;
+            meta.fmt_labels(
+                f,
+                format_args!("{}=\"{}\"", label_name, meta.label_value(&k.to_string())),
+            )?;
+            writeln!(f, "}} {}{}", v, meta.timestamp_suffix())
+        })
+    }
+
+    // Only exists when Key implements Label and Value implements Display
+    pub fn render_with_struct_key(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result
+    where
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Key: Label,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: Display,
+    {
+        let mut header = FamilyHeader::start(f, meta, meta.kind)?;
+
+        let by_labels = |item: &<&'a T as IntoIterator>::Item| {
+            DisplayLabels::escaped(item.borrow_pair().0).to_string()
+        };
+        render_ordered(self.0, meta.sorted, by_labels, |item| {
+            let (k, v) = item.borrow_pair();
+            if meta.skips(v) {
+                return Ok(());
+            }
+            header.write(f)?;
+            write!(f, "{}{{", meta.name)?;
+            meta.fmt_labels(f, meta.label_set(k))?;
+            writeln!(f, "}} {}{}", v, meta.timestamp_suffix())
+        })
+    }
 
-// These methods exist *only* if T is iterable with BorrowPair items.
-// Because they are inherent, Rust picks them BEFORE looking at RenderScalarFallback.
-impl<'a, T: ?Sized> MetricWrapper<'a, T>
-where
-    &'a T: IntoIterator,
-    <&'a T as IntoIterator>::Item: BorrowPair,
-{
-    // Only exists when Key implements Display
-    pub fn render_with_label_attr(
+    // Only exists when Key is a tuple with one element per label name
+    pub fn render_with_label_names<const N: usize>(
         &self,
         f: &mut Formatter,
         meta: &MetricMetadata,
-        label_name: &str,
+        label_names: &[&str; N],
     ) -> fmt::Result
     where
-        <<&'a T as IntoIterator>::Item as BorrowPair>::Key: Display,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Key: TupleLabelValues<N>,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: Display,
     {
-        writeln!(f, "# HELP {} {}", meta.name, meta.help)?;
-        writeln!(f, "# TYPE {} {}", meta.name, meta.kind)?;
+        let mut header = FamilyHeader::start(f, meta, meta.kind)?;
 
-        for item in self.0 {
+        let by_values = |item: &<&'a T as IntoIterator>::Item| {
+            let k = item.borrow_pair().0;
+            DisplayFn(|f: &mut Formatter<'_>| k.fmt_label_values(label_names, f)).to_string()
+        };
+        render_ordered(self.0, meta.sorted, by_values, |item| {
             let (k, v) = item.borrow_pair();
-            writeln!(
+            if meta.skips(v) {
+                return Ok(());
+            }
+            header.write(f)?;
+            write!(f, "{}{{", meta.name)?;
+            meta.fmt_labels(
                 f,
-                "{}{{{}=\"{}\"}} {}",
-                meta.name,
-                label_name,
-                escape_label_value(&k.to_string()),
-                v
+                DisplayFn(|f: &mut Formatter<'_>| {
+                    if meta.raw_labels {
+                        k.fmt_label_values_raw(label_names, f)
+                    } else {
+                        k.fmt_label_values(label_names, f)
+                    }
+                }),
             )?;
-        }
-        Ok(())
+            writeln!(f, "}} {}{}", v, meta.timestamp_suffix())
+        })
     }
 
-    // Only exists when Key implements Label
-    pub fn render_with_struct_key(&self, f: &mut Formatter, meta: &MetricMetadata) -> fmt::Result
+    // Only exists when Key implements Display and Value is a MetricGroup
+    pub fn render_group_with_label_attr(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+        label_name: &str,
+    ) -> fmt::Result
+    where
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Key: Display,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: MetricGroup,
+    {
+        self.render_group(f, meta, |k, f| {
+            write!(f, "{}=\"{}\"", label_name, meta.label_value(&k.to_string()))
+        })
+    }
+
+    // Only exists when Key implements Label and Value is a MetricGroup
+    pub fn render_group_with_struct_key(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+    ) -> fmt::Result
     where
         <<&'a T as IntoIterator>::Item as BorrowPair>::Key: Label,
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: MetricGroup,
     {
-        writeln!(f, "# HELP {} {}", meta.name, meta.help)?;
-        writeln!(f, "# TYPE {} {}", meta.name, meta.kind)?;
+        self.render_group(f, meta, |k, f| meta.label_set(k).fmt(f))
+    }
 
-        for item in self.0 {
-            let (k, v) = item.borrow_pair();
-            write!(f, "{}{{", meta.name)?;
-            k.fmt_labels(f)?;
-            writeln!(f, "}} {}", v)?;
+    // One family per group member, each iterating over the whole collection
+    fn render_group(
+        &self,
+        f: &mut Formatter,
+        meta: &MetricMetadata,
+        fmt_key: impl Fn(
+            &<<&'a T as IntoIterator>::Item as BorrowPair>::Key,
+            &mut Formatter,
+        ) -> fmt::Result,
+    ) -> fmt::Result
+    where
+        <<&'a T as IntoIterator>::Item as BorrowPair>::Value: MetricGroup,
+    {
+        type Value<'a, T> = <<&'a T as IntoIterator>::Item as BorrowPair>::Value;
+
+        for (index, member) in Value::<T>::MEMBERS.iter().enumerate() {
+            let name = group_member_name(meta.name, member, meta.total_suffix);
+            let member_meta = MetricMetadata {
+                name: &name,
+                help: member.help,
+                kind: member.kind,
+                timestamp: meta.timestamp,
+                unit: None,
+                deprecated: None,
+                const_labels: meta.const_labels,
+                skip_zero: meta.skip_zero,
+                raw_labels: meta.raw_labels,
+                sorted: meta.sorted,
+                max_label_len: meta.max_label_len,
+                no_header: false,
+                total_suffix: false,
+            };
+            let mut header = FamilyHeader::start(f, &member_meta, member.kind)?;
+
+            let by_key = |item: &<&'a T as IntoIterator>::Item| {
+                let k = item.borrow_pair().0;
+                DisplayFn(|f: &mut Formatter<'_>| fmt_key(k, f)).to_string()
+            };
+            render_ordered(self.0, meta.sorted, by_key, |item| {
+                let (k, v) = item.borrow_pair();
+                let value = DisplayFn(|f: &mut Formatter<'_>| v.fmt_member(index, f));
+                if member_meta.skips(&value) {
+                    return Ok(());
+                }
+                header.write(f)?;
+                write!(f, "{}{{", name)?;
+                member_meta.fmt_labels(f, DisplayFn(|f: &mut Formatter<'_>| fmt_key(k, f)))?;
+                writeln!(f, "}} {}{}", value, meta.timestamp_suffix())
+            })?;
         }
         Ok(())
     }
@@ -362,6 +2594,14 @@ mod tests {
             escape_label_value("all\"three\\\nchars"),
             "all\\\"three\\\\\\nchars"
         );
+        assert_eq!(escape_label_value("\"edges\""), "\\\"edges\\\"");
+        assert_eq!(escape_label_value("\n\n"), "\\n\\n");
+        assert_eq!(
+            escape_label_value("zürich \"ß\" 日本"),
+            "zürich \\\"ß\\\" 日本"
+        );
+        assert_eq!(escape_label_value(""), "");
+        assert!(matches!(escape_label_value("zürich"), Cow::Borrowed(_)));
     }
 
     #[test]
@@ -377,6 +2617,282 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_tuple_labels() {
+        let values = ("GET", 200);
+        let labels = TupleLabels {
+            names: &["method", "status"],
+            values: &values,
+        };
+        assert_eq!(
+            DisplayLabels::escaped(&labels).to_string(),
+            r#"method="GET",status="200""#
+        );
+    }
+
+    #[test]
+    fn test_btreemap_labels() {
+        let labels = BTreeMap::from([
+            ("zone".to_string(), "1a".to_string()),
+            ("region".to_string(), "us-east".to_string()),
+        ]);
+        assert_eq!(
+            DisplayLabels::escaped(&labels).to_string(),
+            r#"region="us-east",zone="1a""#
+        );
+
+        let empty: BTreeMap<String, String> = BTreeMap::new();
+        assert_eq!(DisplayLabels::escaped(&empty).to_string(), "");
+    }
+
+    #[test]
+    fn test_vec_labels() {
+        let labels = vec![("zone", "1a"), ("region", "us-east")];
+        assert_eq!(
+            DisplayLabels::escaped(&labels).to_string(),
+            r#"zone="1a",region="us-east""#
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-escaping"))]
+    fn test_btreemap_labels_escaping() {
+        let labels = BTreeMap::from([("path", "C:\\dir")]);
+        assert_eq!(
+            DisplayLabels::escaped(&labels).to_string(),
+            r#"path="C:\\dir""#
+        );
+        assert_eq!(
+            DisplayLabels {
+                labels: &labels,
+                raw: true
+            }
+            .to_string(),
+            r#"path="C:\dir""#
+        );
+    }
+
+    #[test]
+    fn test_histogram_empty_runtime_labels() {
+        struct Render<'a>(&'a Histogram<BTreeMap<String, String>, 1>);
+
+        impl Display for Render<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let meta = MetricMetadata::builder("latency", None, "histogram").build();
+                self.0.render_histogram(f, &meta)
+            }
+        }
+
+        let mut hist = Histogram::new([1.0]);
+        hist.observe(BTreeMap::new(), 0.5);
+
+        assert_eq!(
+            Render(&hist).to_string(),
+            "# TYPE latency histogram\n\
+             latency_bucket{le=\"1.000\"} 1\n\
+             latency_bucket{le=\"+Inf\"} 1\n\
+             latency_sum{} 0.5\n\
+             latency_count{} 1\n"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "openmetrics"))]
+    fn test_metric_metadata_builder() {
+        let direct = MetricMetadata {
+            name: "requests",
+            help: Some("Total requests"),
+            kind: "counter",
+            timestamp: Some(1700000000000),
+            unit: Some("bytes"),
+            deprecated: Some("use http_requests"),
+            const_labels: &[],
+            skip_zero: false,
+            raw_labels: false,
+            sorted: false,
+            max_label_len: None,
+            no_header: false,
+            total_suffix: false,
+        };
+        let built = MetricMetadata::builder("requests", "Total requests", "counter")
+            .unit("bytes")
+            .timestamp(1700000000000)
+            .deprecated("use http_requests")
+            .build();
+        assert_eq!(built, direct);
+
+        let render = |meta: MetricMetadata<'_>| {
+            DisplayFn(|f: &mut Formatter<'_>| MetricWrapper(&3u64).render_scalar(f, &meta))
+                .to_string()
+        };
+        assert_eq!(render(built), render(direct));
+        assert_eq!(
+            render(built),
+            "# HELP requests Total requests (deprecated: use http_requests)\n\
+             # TYPE requests counter\n\
+             requests 3 1700000000000\n"
+        );
+
+        let plain = MetricMetadataBuilder::new("requests", "Total requests", "counter").build();
+        assert_eq!(
+            render(plain),
+            "# HELP requests Total requests\n# TYPE requests counter\nrequests 3\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "openmetrics")]
+    fn test_unit_line() {
+        let meta = MetricMetadata::builder("sent_bytes", "Bytes sent", "counter")
+            .unit("bytes")
+            .build();
+        let output =
+            DisplayFn(|f: &mut Formatter<'_>| MetricWrapper(&3u64).render_scalar(f, &meta))
+                .to_string();
+        assert_eq!(
+            output,
+            "# HELP sent_bytes Bytes sent\n\
+             # TYPE sent_bytes counter\n\
+             # UNIT sent_bytes bytes\n\
+             sent_bytes 3\n"
+        );
+    }
+
+    #[test]
+    fn test_is_zero() {
+        assert!(is_zero(&0u64));
+        assert!(is_zero(&0.0f64));
+        assert!(is_zero(&-0.0f64));
+        assert!(is_zero(&"0.000"));
+        assert!(!is_zero(&1u64));
+        assert!(!is_zero(&0.5f64));
+        assert!(!is_zero(&10u64));
+        assert!(!is_zero(&f64::NAN));
+        assert!(!is_zero(&""));
+        assert!(!is_zero(&"-"));
+    }
+
+    #[test]
+    fn test_skip_zero_scalar() {
+        let meta = MetricMetadata::builder("queue", "Queue depth", "gauge")
+            .skip_zero()
+            .build();
+        let render = |value: u64| {
+            DisplayFn(|f: &mut Formatter<'_>| MetricWrapper(&value).render_scalar(f, &meta))
+                .to_string()
+        };
+        assert_eq!(render(0), "");
+        assert_eq!(
+            render(2),
+            "# HELP queue Queue depth\n# TYPE queue gauge\nqueue 2\n"
+        );
+    }
+
+    #[test]
+    fn test_validate_label_name() {
+        assert!(validate_label_name("method"));
+        assert!(validate_label_name("_private"));
+        assert!(validate_label_name("status_2xx"));
+        assert!(validate_label_name("A"));
+
+        assert!(!validate_label_name(""));
+        assert!(!validate_label_name("2xx"));
+        assert!(!validate_label_name("http-method"));
+        assert!(!validate_label_name("has space"));
+        assert!(!validate_label_name("__reserved"));
+        assert!(!validate_label_name("__"));
+        assert!(!validate_label_name("métrique"));
+    }
+
+    #[test]
+    fn test_assert_valid_label_name() {
+        const _: () = assert_valid_label_name("event_type");
+        assert_valid_label_name("_private");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid label name")]
+    fn test_assert_valid_label_name_leading_digit() {
+        assert_valid_label_name("2xx");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid label name")]
+    fn test_assert_valid_label_name_reserved() {
+        assert_valid_label_name("__name__");
+    }
+
+    #[test]
+    fn test_validate_metric_name() {
+        assert!(validate_metric_name("requests"));
+        assert!(validate_metric_name("_private"));
+        assert!(validate_metric_name(":colon"));
+        assert!(validate_metric_name("job:requests_2xx"));
+
+        assert!(!validate_metric_name(""));
+        assert!(!validate_metric_name("2xx"));
+        assert!(!validate_metric_name("my-app"));
+        assert!(!validate_metric_name("app.cache"));
+    }
+
+    #[test]
+    fn test_validate_metric_name_checked() {
+        assert_eq!(validate_metric_name_checked("requests"), Ok(()));
+        assert_eq!(validate_metric_name_checked("job:requests:rate5m"), Ok(()));
+
+        let err = validate_metric_name_checked("2xx").unwrap_err();
+        assert_eq!(err, InvalidMetricName("2xx".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "invalid metric name '2xx': must match [a-zA-Z_:][a-zA-Z0-9_:]*"
+        );
+        assert_eq!(
+            validate_metric_name_checked(""),
+            Err(InvalidMetricName(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_validate_metric_prefix() {
+        assert!(validate_metric_prefix("app"));
+        assert!(validate_metric_prefix("app.cache"));
+        assert!(validate_metric_prefix("job:app"));
+        assert!(validate_metric_prefix("_app.x"));
+
+        assert!(!validate_metric_prefix(""));
+        assert!(!validate_metric_prefix("app_"));
+        assert!(!validate_metric_prefix("app_.cache"));
+        assert!(!validate_metric_prefix("app."));
+        assert!(!validate_metric_prefix(".app"));
+        assert!(!validate_metric_prefix("app.2xx"));
+        assert!(!validate_metric_prefix("my-app"));
+    }
+
+    #[test]
+    fn test_build_metric_name() {
+        assert_eq!(build_metric_name(&[], "requests", None, false), "requests");
+        assert_eq!(
+            build_metric_name(&["app", "cache"], "hits", None, false),
+            "app_cache_hits"
+        );
+        assert_eq!(
+            build_metric_name(&["app.cache"], "hits", None, true),
+            "app_cache_hits_total"
+        );
+        assert_eq!(
+            build_metric_name(&[], "hits_total", None, true),
+            "hits_total"
+        );
+        assert_eq!(
+            build_metric_name(&[], "uptime_seconds", Some("seconds"), false),
+            "uptime_seconds"
+        );
+        assert_eq!(
+            build_metric_name(&[], "sent_total", Some("bytes"), true),
+            "sent_bytes_total"
+        );
+    }
+
     #[test]
     fn test_linear_buckets_basic() {
         const BUCKETS: [f64; 5] = linear_buckets(0.1, 0.1);
@@ -407,6 +2923,263 @@ mod tests {
         const _: () = validate_histogram_buckets(&BUCKETS);
     }
 
+    #[test]
+    fn test_linear_buckets_range_basic() {
+        const BUCKETS: [f64; 5] = linear_buckets_range(0.0, 1.0);
+        assert_eq!(BUCKETS, [0.0, 0.25, 0.5, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn test_linear_buckets_range_single() {
+        const BUCKETS: [f64; 1] = linear_buckets_range(5.0, 1.0);
+        assert_eq!(BUCKETS, [5.0]);
+    }
+
+    #[test]
+    fn test_linear_buckets_range_end_is_exact() {
+        const BUCKETS: [f64; 4] = linear_buckets_range(0.1, 0.7);
+        assert!(within_epsilon(BUCKETS[1], 0.3));
+        assert!(within_epsilon(BUCKETS[2], 0.5));
+        assert_eq!(BUCKETS[3], 0.7);
+    }
+
+    #[test]
+    fn test_linear_buckets_range_with_validation() {
+        const BUCKETS: [f64; 10] = linear_buckets_range(0.01, 10.0);
+        const _: () = validate_histogram_buckets(&BUCKETS);
+    }
+
+    #[test]
+    #[should_panic(expected = "start < end")]
+    fn test_linear_buckets_range_reversed() {
+        linear_buckets_range::<3>(1.0, 0.0);
+    }
+
+    #[test]
+    fn test_validate_histogram_buckets_checked() {
+        assert_eq!(validate_histogram_buckets_checked(&[]), Ok(()));
+        assert_eq!(validate_histogram_buckets_checked(&[0.1, 0.5, 1.0]), Ok(()));
+
+        assert_eq!(
+            validate_histogram_buckets_checked(&[0.1, 0.5, 0.5, 1.0]),
+            Err(HistogramBucketError::NotAscending {
+                index: 2,
+                previous: 0.5,
+                value: 0.5
+            })
+        );
+        assert_eq!(
+            validate_histogram_buckets_checked(&[1.0, 0.5]),
+            Err(HistogramBucketError::NotAscending {
+                index: 1,
+                previous: 1.0,
+                value: 0.5
+            })
+        );
+
+        assert_eq!(
+            validate_histogram_buckets_checked(&[0.1, f64::NAN, 1.0]),
+            Err(HistogramBucketError::NaN { index: 1 })
+        );
+        assert_eq!(
+            validate_histogram_buckets_checked(&[f64::NAN]),
+            Err(HistogramBucketError::NaN { index: 0 })
+        );
+
+        let err = validate_histogram_buckets_checked(&[1.0, 0.5]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "histogram buckets must be in strictly ascending order, but bucket 1 (0.5) \
+             is not greater than bucket 0 (1)"
+        );
+    }
+
+    #[test]
+    fn test_iter_buckets() {
+        let mut hist: Histogram<(), 3> = Histogram::new([0.5, 1.0, 2.0]);
+        hist.observe_many((), [0.1, 0.2, 1.5, 5.0]);
+        let data = &hist.data[&()];
+
+        assert_eq!(
+            data.iter_raw_buckets(&hist.buckets).collect::<Vec<_>>(),
+            [(0.5, 2), (1.0, 0), (2.0, 1)]
+        );
+        assert_eq!(
+            data.iter_cumulative_buckets(&hist.buckets)
+                .collect::<Vec<_>>(),
+            [(0.5, 2), (1.0, 2), (2.0, 3)]
+        );
+
+        let meta = MetricMetadata::builder("latency", None, "histogram").build();
+        let rendered =
+            DisplayFn(|f: &mut Formatter<'_>| hist.render_histogram(f, &meta)).to_string();
+        for (upper_bound, cumulative_count) in data.iter_cumulative_buckets(&hist.buckets) {
+            let line = format!(
+                "latency_bucket{{le=\"{:.3}\"}} {}\n",
+                upper_bound, cumulative_count
+            );
+            assert!(rendered.contains(&line), "{} not in {}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn test_histogram_data_eq() {
+        let data = |sum| HistogramData {
+            counts: [1, 0],
+            count: 1,
+            sum,
+        };
+
+        assert_eq!(data(0.5), data(0.5));
+        assert_ne!(data(0.5), data(1.5));
+        assert_eq!(data(f64::NAN), data(f64::NAN));
+        assert_ne!(data(0.0), data(-0.0));
+        assert_ne!(
+            data(0.5),
+            HistogramData {
+                counts: [0, 1],
+                ..data(0.5)
+            }
+        );
+    }
+
+    #[test]
+    fn test_labels_and_data_for() {
+        let mut hist: Histogram<&str, 2> = Histogram::new([0.1, 1.0]);
+        assert_eq!(hist.labels().count(), 0);
+        assert_eq!(hist.data_for(&"GET"), None);
+
+        hist.observe("GET", 0.05);
+        hist.observe("GET", 0.5);
+        hist.observe("POST", 2.0);
+        hist.zero_initialize("PUT");
+
+        let mut labels: Vec<&str> = hist.labels().copied().collect();
+        labels.sort();
+        assert_eq!(labels, ["GET", "POST", "PUT"]);
+
+        let get = hist.data_for(&"GET").unwrap();
+        assert_eq!(get.count, 2);
+        assert_eq!(get.counts, [1, 1]);
+        assert_eq!(hist.data_for(&"PUT").unwrap().count, 0);
+        assert_eq!(hist.data_for(&"DELETE"), None);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut hist: Histogram<&str, 1> = Histogram::new([1.0]);
+        hist.observe_many("GET", [0.5; 8]);
+        hist.observe_many("POST", [0.5; 6]);
+        hist.observe_many("PUT", [0.5; 5]);
+        hist.observe("DELETE", 0.5);
+        hist.zero_initialize("PATCH");
+
+        hist.retain(|_, data| data.count > 5);
+
+        let mut labels: Vec<&str> = hist.labels().copied().collect();
+        labels.sort();
+        assert_eq!(labels, ["GET", "POST"]);
+        assert_eq!(hist.data_for(&"GET").unwrap().count, 8);
+        assert_eq!(hist.data_for(&"PUT"), None);
+    }
+
+    #[test]
+    fn test_observe_many() {
+        let mut single: Histogram<&str, 2> = Histogram::new([0.5, 1.0]);
+        single.observe("a", 0.1);
+        single.observe("a", 0.5);
+        single.observe("a", 1.5);
+
+        let mut many: Histogram<&str, 2> = Histogram::new([0.5, 1.0]);
+        many.observe_many("a", [0.1, 0.5, 1.5]);
+
+        assert_eq!(many.data["a"], single.data["a"]);
+        assert_eq!(
+            many.data["a"],
+            HistogramData {
+                counts: [2, 0],
+                count: 3,
+                sum: 0.1 + 0.5 + 1.5,
+            }
+        );
+
+        // An empty batch still creates the series
+        many.observe_many("b", []);
+        assert_eq!(many.data["b"], HistogramData::default());
+    }
+
+    #[test]
+    fn test_try_observe() {
+        let mut hist: Histogram<(), 2> = Histogram::new([1.0, 2.0]);
+
+        assert_eq!(
+            hist.try_observe((), f64::NAN),
+            Err(ObserveError::ValueIsNaN)
+        );
+        assert_eq!(
+            hist.try_observe((), f64::NEG_INFINITY),
+            Err(ObserveError::ValueIsNegativeInfinity)
+        );
+        assert!(hist.data.is_empty());
+
+        assert_eq!(hist.try_observe((), 1.5), Ok(()));
+        assert_eq!(hist.try_observe((), f64::INFINITY), Ok(()));
+        assert_eq!(
+            hist.data[&()],
+            HistogramData {
+                counts: [0, 1],
+                count: 2,
+                sum: f64::INFINITY,
+            }
+        );
+
+        let mut hist: Histogram<(), 2> = Histogram::new([1.0, 2.0]);
+        hist.try_observe((), 0.5).unwrap();
+        assert_eq!(
+            hist.try_observe((), f64::NAN),
+            Err(ObserveError::ValueIsNaN)
+        );
+        let expected = HistogramData {
+            counts: [1, 0],
+            count: 1,
+            sum: 0.5,
+        };
+        assert_eq!(hist.data[&()], expected);
+
+        hist.data.get_mut(&()).unwrap().count = u64::MAX;
+        assert_eq!(
+            hist.try_observe((), 0.5),
+            Err(ObserveError::BucketCountOverflow)
+        );
+        assert_eq!(
+            hist.data[&()],
+            HistogramData {
+                count: u64::MAX,
+                ..expected
+            }
+        );
+    }
+
+    #[test]
+    fn test_default_buckets() {
+        const _: () = validate_histogram_buckets(&DEFAULT_HTTP_DURATION_BUCKETS);
+        const _: () = validate_histogram_buckets(&DEFAULT_FILE_SIZE_BUCKETS);
+        const _: () = validate_histogram_buckets(&default_rpc_duration_buckets());
+
+        assert_eq!(DEFAULT_HTTP_DURATION_BUCKETS.len(), 11);
+        assert_eq!(DEFAULT_HTTP_DURATION_BUCKETS[0], 0.005);
+        assert_eq!(DEFAULT_HTTP_DURATION_BUCKETS[10], 10.0);
+
+        assert_eq!(DEFAULT_FILE_SIZE_BUCKETS.len(), 8);
+        assert_eq!(DEFAULT_FILE_SIZE_BUCKETS[0], 1024.0);
+        assert_eq!(DEFAULT_FILE_SIZE_BUCKETS[7], 2.0 * 1024.0 * 1024.0 * 1024.0);
+
+        let rpc = default_rpc_duration_buckets();
+        assert_eq!(rpc.len(), 10);
+        assert_eq!(rpc[0], 0.001);
+        assert_eq!(rpc[9], 1.0);
+    }
+
     #[test]
     fn test_exponential_buckets_basic() {
         const BUCKETS: [f64; 5] = exponential_buckets(1.0, 2.0);
@@ -478,6 +3251,46 @@ mod tests {
         assert_eq!(data.counts[4], 1);
     }
 
+    #[test]
+    fn test_time() {
+        let mut hist = Histogram::new([0.0005, 1.0]);
+
+        let result = hist.time((), || {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            42
+        });
+
+        assert_eq!(result, 42);
+        let data = hist.data.get(&()).unwrap();
+        assert_eq!(data.count, 1);
+        assert_eq!(data.counts, [0, 1]);
+        assert!(data.sum >= 0.001);
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_time_async() {
+        use std::pin::pin;
+        use std::task::{Context, Poll, Waker};
+
+        let mut hist = Histogram::new([0.0005, 1.0]);
+
+        let result = {
+            let mut fut = pin!(hist.time_async((), async {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                42
+            }));
+            let mut cx = Context::from_waker(Waker::noop());
+            let Poll::Ready(result) = fut.as_mut().poll(&mut cx) else {
+                panic!("future without await points must complete on the first poll");
+            };
+            result
+        };
+
+        assert_eq!(result, 42);
+        assert_eq!(hist.data.get(&()).unwrap().counts, [0, 1]);
+    }
+
     #[test]
     fn test_exponential_buckets_integration() {
         let mut hist = Histogram::new(exponential_buckets::<5>(1.0, 2.0));
@@ -487,13 +3300,65 @@ mod tests {
         hist.observe((), 3.0);
         hist.observe((), 7.0);
 
-        let data = hist.data.get(&()).unwrap();
-        assert_eq!(data.count, 4);
-        assert_eq!(data.sum, 12.0);
-        assert_eq!(data.counts[0], 1);
-        assert_eq!(data.counts[1], 1);
-        assert_eq!(data.counts[2], 1);
-        assert_eq!(data.counts[3], 1);
-        assert_eq!(data.counts[4], 0);
+        assert_eq!(
+            hist.data[&()],
+            HistogramData {
+                counts: [1, 1, 1, 1, 0],
+                count: 4,
+                sum: 12.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_borrow_pair_btreemap() {
+        use std::collections::BTreeMap;
+
+        // Iterating `&BTreeMap` yields `(&K, &V)`, in key order
+        let map = BTreeMap::from([("b", 2), ("a", 1), ("c", 3)]);
+        let pairs: Vec<(&str, i32)> = map
+            .iter()
+            .map(|item| {
+                let (k, v) = item.borrow_pair();
+                (*k, *v)
+            })
+            .collect();
+        assert_eq!(pairs, [("a", 1), ("b", 2), ("c", 3)]);
+    }
+
+    #[test]
+    fn test_prometheus_metric_render_only() {
+        struct Up;
+
+        impl PrometheusMetric for Up {
+            fn render(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                writeln!(f, "up 1")
+            }
+        }
+
+        // Without its own `fmt_filtered`, the filter is ignored
+        let rendered = DisplayFn(|f: &mut Formatter<'_>| Up.fmt_filtered(f, &|_| false));
+        assert_eq!(rendered.to_string(), "up 1\n");
+        let metric: &dyn PrometheusMetric = &Up;
+        assert_eq!(metric.to_string(), "up 1\n");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_metric_wrapper_render_histogram_shims() {
+        let mut hist: Histogram<(), 1> = Histogram::new([1.0]);
+        hist.observe((), 0.5);
+        let meta = MetricMetadata::builder("latency", "Latency", "histogram").build();
+
+        let rendered =
+            DisplayFn(|f: &mut Formatter<'_>| MetricWrapper(&hist).render_histogram(f, &meta));
+        let expected = DisplayFn(|f: &mut Formatter<'_>| hist.render_histogram(f, &meta));
+        assert_eq!(rendered.to_string(), expected.to_string());
+
+        // Values that are not histograms render nothing
+        let rendered = DisplayFn(|f: &mut Formatter<'_>| {
+            RenderScalarFallback::render_histogram(&MetricWrapper(&3u64), f, &meta)
+        });
+        assert_eq!(rendered.to_string(), "");
     }
 }
@@ -0,0 +1,70 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Result, parse2};
+
+/// `#[global_metrics]`: keeps the struct as is and adds a process-global instance of it,
+/// set once with `init`/`try_init` and read with `get`.
+pub fn expand_global_metrics(args: TokenStream, input: TokenStream) -> Result<TokenStream> {
+    if !args.is_empty() {
+        return Err(Error::new_spanned(
+            args,
+            "global_metrics does not take arguments",
+        ));
+    }
+    let input: DeriveInput = parse2(input)?;
+
+    if !matches!(input.data, Data::Struct(_)) {
+        return Err(Error::new_spanned(
+            input,
+            "global_metrics can only be applied to structs",
+        ));
+    }
+    // A `static` has a single type, so it cannot hold every instantiation of a generic struct
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "global_metrics cannot be applied to generic structs",
+        ));
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let already_set = format!("{}::init called more than once", name);
+    let not_set = format!("{}::get called before {}::init", name, name);
+
+    Ok(quote! {
+        #input
+
+        const _: () = {
+            static GLOBAL: ::std::sync::OnceLock<#name> = ::std::sync::OnceLock::new();
+
+            impl #name {
+                /// Sets the process-global instance returned by `get`.
+                ///
+                /// # Panics
+                ///
+                /// Panics if the global instance was already set.
+                #vis fn init(metrics: #name) {
+                    if Self::try_init(metrics).is_err() {
+                        panic!(#already_set);
+                    }
+                }
+
+                /// Sets the process-global instance returned by `get`, or gives `metrics`
+                /// back if it was already set.
+                #vis fn try_init(metrics: #name) -> ::std::result::Result<(), #name> {
+                    GLOBAL.set(metrics)
+                }
+
+                /// Returns the process-global instance set by `init`.
+                ///
+                /// # Panics
+                ///
+                /// Panics if `init` was not called yet.
+                #vis fn get() -> &'static #name {
+                    GLOBAL.get().expect(#not_set)
+                }
+            }
+        };
+    })
+}
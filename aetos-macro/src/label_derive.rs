@@ -1,5 +1,7 @@
 use proc_macro2::TokenStream;
 use quote::quote;
+use syn::ext::IdentExt;
+use syn::spanned::Spanned;
 use syn::{Data, DeriveInput, Error, Fields, Result};
 
 pub fn expand_label_derive(input: DeriveInput) -> Result<TokenStream> {
@@ -23,35 +25,78 @@ pub fn expand_label_derive(input: DeriveInput) -> Result<TokenStream> {
         }
     };
 
+    let field_attrs = fields
+        .iter()
+        .map(parse_label_attrs)
+        .collect::<Result<Vec<_>>>()?;
+
+    // Field names, or their `rename`, become label names, so they must be valid
+    // Prometheus label names. `le` and `quantile` are allowed here, but must not be used
+    // on label types of histograms, where `le` collides with the bucket boundary label.
+    let mut field_names: Vec<String> = Vec::new();
+    for (field, attrs) in fields.iter().zip(&field_attrs) {
+        let ident = field.ident.as_ref().unwrap();
+        // Without the `r#` of raw identifiers
+        let (name, span) = match &attrs.rename {
+            Some(rename) => (rename.value(), rename.span()),
+            None => (ident.unraw().to_string(), ident.span()),
+        };
+        if !aetos_core::validate_label_name(&name) {
+            return Err(Error::new(
+                span,
+                "invalid label name: must match [a-zA-Z_][a-zA-Z0-9_]* and must not start with '__'",
+            ));
+        }
+        if field_names.contains(&name) {
+            return Err(Error::new(span, format!("duplicate label name '{}'", name)));
+        }
+        field_names.push(name);
+    }
+
     let field_idents: Vec<_> = fields.iter().map(|f| &f.ident).collect();
     let field_types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let max_lens: Vec<_> = field_attrs.iter().map(|attrs| attrs.max_len).collect();
 
-    let format_impl = if field_idents.is_empty() {
-        quote! {
-            Ok(())
+    // Body of `fmt_labels`, or of `fmt_labels_raw` when the values are not escaped
+    let format_body = |escape: bool| {
+        if field_idents.is_empty() {
+            return quote! {
+                Ok(())
+            };
         }
-    } else {
-        let first_field = &field_idents[0];
-        let rest_fields = &field_idents[1..];
+        let values: Vec<_> = field_idents
+            .iter()
+            .zip(&max_lens)
+            .map(|(ident, max_len)| {
+                let value = match max_len {
+                    Some(max_len) => quote! {
+                        ::aetos::core::truncate_label_value(&self.#ident.to_string(), #max_len)
+                    },
+                    None if escape => quote!(self.#ident.to_string()),
+                    None => return quote!(self.#ident),
+                };
+                if escape {
+                    quote!(::aetos::core::escape_label_value(&#value))
+                } else {
+                    value
+                }
+            })
+            .collect();
+        let first_value = &values[0];
+        let rest_values = &values[1..];
+        let first_name = &field_names[0];
+        let rest_names = &field_names[1..];
 
         quote! {
-            write!(
-                f,
-                "{}=\"{}\"",
-                stringify!(#first_field),
-                ::aetos::core::escape_label_value(&self.#first_field.to_string())
-            )?;
+            write!(f, "{}=\"{}\"", #first_name, #first_value)?;
             #(
-                write!(
-                    f,
-                    ",{}=\"{}\"",
-                    stringify!(#rest_fields),
-                    ::aetos::core::escape_label_value(&self.#rest_fields.to_string())
-                )?;
+                write!(f, ",{}=\"{}\"", #rest_names, #rest_values)?;
             )*
             Ok(())
         }
     };
+    let format_impl = format_body(true);
+    let format_raw_impl = format_body(false);
 
     let generics = &input.generics;
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
@@ -64,10 +109,12 @@ pub fn expand_label_derive(input: DeriveInput) -> Result<TokenStream> {
             predicates: Default::default(),
         });
 
+        // Spanned on the field type, so that a value type without `Display` is reported
+        // at the field instead of at the derive
         for ty in &field_types {
             where_clause
                 .predicates
-                .push(syn::parse_quote!(#ty: std::fmt::Display));
+                .push(syn::parse_quote_spanned!(ty.span()=> #ty: ::std::fmt::Display));
         }
 
         Some(where_clause)
@@ -78,6 +125,55 @@ pub fn expand_label_derive(input: DeriveInput) -> Result<TokenStream> {
             fn fmt_labels(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 #format_impl
             }
+
+            fn fmt_labels_raw(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                #format_raw_impl
+            }
+
+            fn label_names() -> &'static [&'static str] {
+                &[#(#field_names),*]
+            }
         }
     })
 }
+
+/// Options of a field, from `#[label(...)]`
+#[derive(Default)]
+struct LabelAttrs {
+    /// `max_len = N`: the value of the field is truncated to N characters
+    max_len: Option<usize>,
+    /// `rename = "..."`: the label name, instead of the field name
+    rename: Option<syn::LitStr>,
+}
+
+fn parse_label_attrs(field: &syn::Field) -> Result<LabelAttrs> {
+    let mut attrs = LabelAttrs::default();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("label"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("max_len") {
+                if attrs.max_len.is_some() {
+                    return Err(meta.error("duplicate 'max_len' attribute"));
+                }
+                let n: syn::LitInt = meta.value()?.parse()?;
+                let value: usize = n.base10_parse()?;
+                if value == 0 {
+                    return Err(Error::new_spanned(&n, "'max_len' must be at least 1"));
+                }
+                attrs.max_len = Some(value);
+            } else if meta.path.is_ident("rename") {
+                if attrs.rename.is_some() {
+                    return Err(meta.error("duplicate 'rename' attribute"));
+                }
+                attrs.rename = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("unknown attribute, expected 'max_len' or 'rename'"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
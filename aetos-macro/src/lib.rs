@@ -1,6 +1,7 @@
 //! Procedural macros for the aetos metrics library.
 //!
-//! This crate provides the `#[metrics]` attribute macro and `#[derive(Label)]` macro
+//! This crate provides the `#[metrics]` attribute macro, its `#[derive(Metrics)]` flavor,
+//! `#[global_metrics]`, and the `#[derive(Label)]` and `#[derive(MetricGroup)]` macros
 //! for generating Prometheus metrics rendering code.
 //!
 //! ## Label Validation
@@ -14,18 +15,28 @@
 //!        Labels are only supported on collection types that implement IntoIterator.
 //! ```
 //!
-//! **Note:** This validation only catches common primitive types.
-//! Type aliases and custom wrapper types are not detected and will silently ignore labels.
+//! Other types, such as aliases and newtypes, are checked by the generated code instead:
+//! the field type must implement `LabeledCollection`, i.e. its references must iterate
+//! over `(key, value)` pairs.
+//!
+//! Label names given via `label = "..."` are also checked at compile time: they must
+//! match `[a-zA-Z_][a-zA-Z0-9_]*`, must not start with the reserved `__` prefix, and must
+//! not be `le` or `quantile`. `#[derive(Label)]` applies the same charset check to field names
+//! and to their `#[label(rename = "...")]`.
 //!
 //! See the main `aetos` crate documentation for usage examples.
 
 use proc_macro::TokenStream;
 use syn::{DeriveInput, parse_macro_input};
 
+mod global_metrics_macro;
 mod label_derive;
+mod metric_group_derive;
 mod metrics_macro;
 
-#[proc_macro_derive(Label)]
+/// Implements `Label` for a struct with named fields, one label per field. A field
+/// marked `#[label(max_len = N)]` has its value truncated to N characters.
+#[proc_macro_derive(Label, attributes(label))]
 pub fn derive_label(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     label_derive::expand_label_derive(input)
@@ -33,9 +44,49 @@ pub fn derive_label(input: TokenStream) -> TokenStream {
         .into()
 }
 
+#[proc_macro_derive(MetricGroup, attributes(counter, gauge))]
+pub fn derive_metric_group(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    metric_group_derive::expand_metric_group_derive(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derive flavor of `#[metrics]`, which leaves the struct untouched. Struct arguments
+/// and field attributes are written as `#[metric(...)]`, with the metric kind first:
+/// `#[metric(counter, help = "...")]`.
+#[proc_macro_derive(Metrics, attributes(metric))]
+pub fn derive_metrics(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    metrics_macro::expand_metrics_derive(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
 #[proc_macro_attribute]
 pub fn metrics(args: TokenStream, input: TokenStream) -> TokenStream {
     metrics_macro::expand_metrics_macro(args.into(), input.into())
         .unwrap_or_else(|err| err.to_compile_error())
         .into()
 }
+
+/// Inert stand-in for `#[metrics]`: removes the metric attributes of the fields (and the
+/// `#[metric(...)]` attributes of `#[derive(Metrics)]`) and generates nothing, so that a
+/// struct applying `#[metrics]` through `cfg_attr` also compiles when it is not applied.
+/// Its arguments are ignored.
+#[proc_macro_attribute]
+pub fn metrics_stub(_args: TokenStream, input: TokenStream) -> TokenStream {
+    metrics_macro::expand_metrics_stub(input.into())
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Adds a process-global instance of a metrics struct: `Type::init(metrics)` sets it
+/// once, and `Type::get()` returns it from anywhere. Combine with `#[metrics]` or
+/// `#[derive(Metrics)]`.
+#[proc_macro_attribute]
+pub fn global_metrics(args: TokenStream, input: TokenStream) -> TokenStream {
+    global_metrics_macro::expand_global_metrics(args.into(), input.into())
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
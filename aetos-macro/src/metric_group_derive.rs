@@ -0,0 +1,119 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{Data, DeriveInput, Error, Fields, Result};
+
+use crate::metrics_macro::{MetricAttrs, StrValue, parse_metric_attrs};
+
+pub fn expand_metric_group_derive(input: DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    input,
+                    "MetricGroup can only be derived for structs with named fields",
+                ));
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                input,
+                "MetricGroup can only be derived for structs",
+            ));
+        }
+    };
+
+    let mut members = Vec::new();
+    let mut fmt_arms = Vec::new();
+    let mut seen_names = Vec::new();
+
+    for field in fields {
+        let mut kind = None;
+        let mut attrs = MetricAttrs::default();
+
+        for attr in &field.attrs {
+            let attr_kind = if attr.path().is_ident("counter") {
+                "counter"
+            } else if attr.path().is_ident("gauge") {
+                "gauge"
+            } else {
+                continue;
+            };
+            if kind.is_some() {
+                return Err(Error::new_spanned(
+                    attr,
+                    "a field can only have one of #[counter] or #[gauge]",
+                ));
+            }
+            parse_metric_attrs(attr, &mut attrs)?;
+            kind = Some(attr_kind);
+        }
+
+        let Some(kind) = kind else {
+            continue;
+        };
+
+        // The first unsupported key by name, so that the error does not depend on the
+        // iteration order of the map
+        if let Some((key, span)) = attrs
+            .spans
+            .iter()
+            .filter(|(key, _)| !matches!(**key, "help" | "name"))
+            .min_by_key(|(key, _)| **key)
+        {
+            return Err(Error::new(
+                *span,
+                format!(
+                    "MetricGroup fields only support 'help' and 'name', not '{}'",
+                    key
+                ),
+            ));
+        }
+
+        let ident = field.ident.as_ref().unwrap();
+        let member_name = attrs
+            .name_override
+            .unwrap_or_else(|| StrValue::Lit(ident.unraw().to_string()));
+        if let Some(seen) = seen_names.iter().find(|seen| **seen == member_name.key()) {
+            return Err(Error::new_spanned(
+                ident,
+                format!("duplicate member name '{}'", seen),
+            ));
+        }
+        seen_names.push(member_name.key());
+        let help = match &attrs.help {
+            Some(help) => quote! { Some(#help) },
+            None => quote! { None },
+        };
+        let index = members.len();
+
+        members.push(quote! {
+            ::aetos::core::GroupMember {
+                name: #member_name,
+                help: #help,
+                kind: #kind,
+            }
+        });
+        fmt_arms.push(quote! {
+            #index => write!(f, "{}", self.#ident),
+        });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::aetos::core::MetricGroup for #name #ty_generics #where_clause {
+            const MEMBERS: &'static [::aetos::core::GroupMember] = &[#(#members),*];
+
+            fn fmt_member(&self, index: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match index {
+                    #(#fmt_arms)*
+                    _ => Ok(()),
+                }
+            }
+        }
+    })
+}
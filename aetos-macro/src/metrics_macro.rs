@@ -1,42 +1,623 @@
-use proc_macro2::TokenStream;
-use quote::quote;
-use syn::{Data, DeriveInput, Error, Expr, ExprLit, Fields, Lit, Meta, Result, parse2};
+use proc_macro2::{Span, TokenStream};
+use quote::{ToTokens, quote, quote_spanned};
+use std::collections::HashMap;
+use syn::ext::IdentExt;
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Error, Expr, Fields, Meta, Result, parse2};
 
 #[derive(Debug)]
 enum MetricType {
     Counter,
     Gauge,
     Histogram,
+    /// A collection of `MetricGroup` values, rendered as one family per member
+    Group,
+    /// Another metrics struct, rendered through its own `Display` impl
+    Nested,
 }
 
 #[derive(Debug)]
 enum FieldType {
     SingleLabel { label_name: Option<String> },
+    MultiLabel { label_names: Vec<String> },
     Unspecified,
 }
 
+/// A type wrapping the value of a field, see `extract_inner_type`
+#[derive(Debug, Clone, Copy)]
+enum Wrapper {
+    /// `Arc<T>`, `Cow<T>` or a reference, reached through `Deref`
+    Deref,
+    Mutex,
+    RwLock,
+}
+
 #[derive(Debug)]
 struct MetricField {
-    ident: syn::Ident,
+    /// The field, by name or by position in a tuple struct
+    member: syn::Member,
+    /// The field type, without the `Option` wrapper if there is one and without the
+    /// `wrappers`
+    ty: syn::Type,
+    /// References, `Arc`, `Cow`, `Mutex` and `RwLock` around the value, outermost first,
+    /// peeled at render time by `unwrap_value`
+    wrappers: Vec<Wrapper>,
     field_type: FieldType,
     metric_type: MetricType,
-    help: String,
-    name_override: Option<String>,
+    help: Option<StrValue>,
+    name_override: Option<StrValue>,
+    /// `alias = "..."` or `alias = ["...", ...]`: other base names the family is also
+    /// rendered under
+    aliases: Vec<String>,
+    prefix: Option<StrValue>,
+    unit: Option<String>,
+    no_suffix: bool,
+    /// Skips the struct-level prefix
+    no_prefix: bool,
+    /// Omits zero samples, and the header of a family left without samples
+    skip_zero: bool,
+    /// Writes the label values of the samples without escaping them
+    raw_labels: bool,
+    /// `max_label_len = N`: truncates the values of the `label` label to N characters
+    max_label_len: Option<usize>,
+    /// The field is a `HashMap`, or has `sorted`: its samples are sorted by their labels
+    /// with the `sorted-output` feature
+    sorted: bool,
+    /// `buckets = [...]`: the bucket boundaries a histogram field is initialized with by
+    /// `default` and `generate_new`
+    buckets: Option<Expr>,
+    /// `#[dynamic(kind = "...")]`: a collection of `(name, value)` pairs, each rendered as
+    /// its own family named after its key
+    dynamic: bool,
+    /// `#[metric(kind = "untyped")]`: a gauge whose family is typed `untyped`
+    untyped: bool,
+    /// With `allow_shared_names`, the indices of the later fields rendering their samples
+    /// in the family of this field, under a single header
+    shared_with: Vec<usize>,
+    /// With `allow_shared_names`, the samples of this field are rendered in the family of
+    /// an earlier field with the same name
+    continues_family: bool,
+    optional: bool,
+    /// `#[cfg(...)]` attributes of the field, repeated on everything generated for it
+    cfgs: Vec<syn::Attribute>,
+    /// `compute = "method"`: the value is returned by `self.method()` at render time
+    /// instead of read from the field
+    compute: Option<syn::Ident>,
+    /// The field holds a closure, called at render time through `LazyMetric`
+    lazy: bool,
+    /// The field is a `std::sync::atomic` type, loaded at render time through `AtomicLoad`
+    atomic: bool,
+    /// The field is a `Duration` or `SystemTime`, or a collection of such values, rendered
+    /// in seconds through `Seconds`
+    seconds: bool,
+    /// The field is a `bool`, or a collection of `bool` values, rendered as `1`/`0`
+    /// through `Flag`
+    flag: bool,
+    /// `allow(naming)`: exempt from the `strict_naming` checks
+    allow_naming: bool,
+}
+
+impl MetricField {
+    /// The field name without the `r#` of raw identifiers. Positional fields have no
+    /// name and go by their `name` literal instead, with the colons identifiers cannot
+    /// hold replaced by underscores, or by `field_<index>` when `name` is an expression.
+    fn name(&self) -> String {
+        match (&self.member, &self.name_override) {
+            (syn::Member::Named(ident), _) => ident.unraw().to_string(),
+            (syn::Member::Unnamed(_), Some(StrValue::Lit(name))) => name.replace(':', "_"),
+            (syn::Member::Unnamed(index), _) => format!("field_{}", index.index),
+        }
+    }
+
+    /// Whether the family of this field is shared with other fields, and its header is
+    /// written apart from the samples
+    fn is_shared(&self) -> bool {
+        self.continues_family || !self.shared_with.is_empty()
+    }
+
+    /// The `# TYPE` of the family. Groups and nested fields have no single type.
+    fn kind(&self) -> &'static str {
+        match self.metric_type {
+            _ if self.untyped => "untyped",
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+            MetricType::Group => "group",
+            MetricType::Nested => "nested",
+        }
+    }
+}
+
+/// The field name for error messages: its identifier, or its position in a tuple struct
+fn member_name(member: &syn::Member) -> String {
+    match member {
+        syn::Member::Named(ident) => ident.unraw().to_string(),
+        syn::Member::Unnamed(index) => index.index.to_string(),
+    }
+}
+
+/// `field` as accessed on `self`, by name or by its position `index` in a tuple struct
+fn field_member(field: &syn::Field, index: usize) -> syn::Member {
+    match &field.ident {
+        Some(ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(syn::Index {
+            index: index as u32,
+            span: field.ty.span(),
+        }),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MetricAttrs {
+    pub help: Option<StrValue>,
+    pub name_override: Option<StrValue>,
+    pub aliases: Vec<String>,
+    pub label_override: Option<String>,
+    pub label_names: Option<(Vec<String>, Span)>,
+    pub prefix: Option<StrValue>,
+    pub unit: Option<String>,
+    pub no_suffix: bool,
+    pub no_prefix: bool,
+    pub skip_zero: bool,
+    pub raw_labels: bool,
+    /// `sorted`: the samples are sorted like those of a `HashMap`, for map types not
+    /// recognized by `is_hash_map`
+    pub sorted: bool,
+    pub max_label_len: Option<usize>,
+    pub buckets: Option<Expr>,
+    pub compute: Option<syn::Ident>,
+    /// `allow(naming)`: the name is exempt from the `strict_naming` checks
+    pub allow_naming: bool,
+    /// Span of each key that was set, for pointing errors at the offending key
+    pub spans: HashMap<&'static str, Span>,
+}
+
+/// A string attribute value: a literal, or an expression evaluating to `&'static str`,
+/// such as a const or `env!(...)`
+#[derive(Debug, Clone)]
+pub enum StrValue {
+    Lit(String),
+    Expr(Expr),
+}
+
+impl StrValue {
+    /// Parses the value of `meta`, checking literals with `validate`
+    fn parse(
+        meta: &syn::meta::ParseNestedMeta,
+        validate: impl Fn(&str) -> std::result::Result<(), String>,
+    ) -> Result<Self> {
+        match meta.value()?.parse()? {
+            Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            }) => {
+                validate(&s.value()).map_err(|msg| Error::new_spanned(&s, msg))?;
+                Ok(StrValue::Lit(s.value()))
+            }
+            Expr::Lit(lit) => Err(Error::new_spanned(
+                lit,
+                "expected a string literal or an expression evaluating to &'static str",
+            )),
+            expr => Ok(StrValue::Expr(expr)),
+        }
+    }
+
+    /// The literal value, or the tokens of the expression in braces. Two equal
+    /// expressions give the same key, which is enough to detect duplicate names written
+    /// the same way. An expression never matches a literal, even when it evaluates to
+    /// it, so such duplicates are not detected.
+    pub fn key(&self) -> String {
+        match self {
+            StrValue::Lit(s) => s.clone(),
+            StrValue::Expr(expr) => format!("{{{}}}", expr.to_token_stream()),
+        }
+    }
+}
+
+impl ToTokens for StrValue {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match self {
+            StrValue::Lit(s) => s.to_tokens(tokens),
+            StrValue::Expr(expr) => expr.to_tokens(tokens),
+        }
+    }
+}
+
+impl MetricAttrs {
+    /// Returns the span of the first of `keys` that was set
+    pub fn span_of(&self, keys: &[&str]) -> Option<Span> {
+        keys.iter().find_map(|key| self.spans.get(key).copied())
+    }
 }
 
 pub fn expand_metrics_macro(args: TokenStream, input: TokenStream) -> Result<TokenStream> {
+    if let Ok(method) = parse2::<syn::ImplItemFn>(input.clone()) {
+        return expand_metrics_method(args, method);
+    }
+    let input: DeriveInput = parse2(input)?;
+    let struct_attrs = parse_struct_attrs(args)?;
+    expand(input, struct_attrs, true)
+}
+
+/// `#[metrics_stub]`: removes the field attributes of `#[metrics]` and the `#[metric(...)]`
+/// attributes of `#[derive(Metrics)]`, and generates nothing else. The arguments are
+/// ignored, so they can be the same as those of `#[metrics]`.
+pub fn expand_metrics_stub(input: TokenStream) -> Result<TokenStream> {
     let mut input: DeriveInput = parse2(input)?;
-    let prefix = parse_struct_attrs(args)?;
+    input.attrs.retain(|attr| !attr.path().is_ident("metric"));
+    if let Data::Struct(ref mut data) = input.data {
+        for field in data.fields.iter_mut() {
+            field
+                .attrs
+                .retain(|attr| !FIELD_ATTRS.iter().any(|name| attr.path().is_ident(name)));
+        }
+    }
+    Ok(quote! { #input })
+}
+
+/// Arguments of `#[metrics(...)]` on a method, besides the `counter(...)`, `gauge(...)`
+/// and `histogram(...)` listing the fields to render. The others shape the generated
+/// struct, or need its field types.
+const METHOD_ATTR_KEYS: &[&str] = &[
+    "prefix",
+    "namespace",
+    "subsystem",
+    "convention",
+    "labels",
+    "strict_naming",
+];
+
+/// `#[metrics(...)]` on a method with an empty body, in an `impl` block: the body is
+/// generated, rendering the fields of `self` listed as `counter(field, ...)`,
+/// `gauge(field, ...)` or `histogram(field, ...)`, in that order. The method returns
+/// the output as a `String`, or as an `impl Display` borrowing `self`.
+///
+/// The field types are not known here, so the rendering relies on `MetricWrapper`
+/// alone: scalars and labeled collections work, while atomics, closures, `Duration`,
+/// `bool` and `Option` fields, which are detected from the field type on structs, do
+/// not.
+fn expand_metrics_method(args: TokenStream, mut method: syn::ImplItemFn) -> Result<TokenStream> {
+    if !method.block.stmts.is_empty() {
+        return Err(Error::new_spanned(
+            &method.block,
+            "the body of a #[metrics] method is generated, leave it empty",
+        ));
+    }
+    if !matches!(method.sig.receiver(), Some(receiver) if receiver.reference.is_some()) {
+        return Err(Error::new_spanned(
+            &method.sig,
+            "a #[metrics] method renders fields of self, so it needs a &self receiver",
+        ));
+    }
+    let into_string = match &method.sig.output {
+        syn::ReturnType::Type(_, ty) => is_type_named(ty, "String"),
+        syn::ReturnType::Default => {
+            return Err(Error::new_spanned(
+                &method.sig,
+                "a #[metrics] method must return String or impl Display",
+            ));
+        }
+    };
+
+    let metas = syn::parse::Parser::parse2(
+        syn::punctuated::Punctuated::<Meta, syn::Token![,]>::parse_terminated,
+        args,
+    )?;
+    let mut struct_args = Vec::new();
+    let mut fields = Vec::new();
+    for meta in metas {
+        let kind = match meta.path() {
+            path if path.is_ident("counter") => MetricType::Counter,
+            path if path.is_ident("gauge") => MetricType::Gauge,
+            path if path.is_ident("histogram") => MetricType::Histogram,
+            path if METHOD_ATTR_KEYS.iter().any(|key| path.is_ident(key)) => {
+                struct_args.push(meta);
+                continue;
+            }
+            path => {
+                return Err(Error::new_spanned(
+                    path,
+                    format!(
+                        "unknown attribute '{}', expected one of: counter, gauge, histogram, {}",
+                        path.to_token_stream().to_string().replace(' ', ""),
+                        METHOD_ATTR_KEYS.join(", ")
+                    ),
+                ));
+            }
+        };
+        fields.push(parse_method_metric(&meta, kind)?);
+    }
+    let mut struct_attrs = parse_struct_attrs(quote! { #(#struct_args),* })?;
+    struct_attrs.method = true;
+
+    let mut seen_names = Vec::new();
+    for field in &fields {
+        check_final_names(field, &struct_attrs)?;
+        if struct_attrs.strict_naming {
+            check_naming(field, &struct_attrs)?;
+        }
+        for name in std::iter::once(build_metric_name(field, &struct_attrs))
+            .chain(build_alias_names(field, &struct_attrs))
+        {
+            if seen_names.contains(&name) {
+                return Err(Error::new_spanned(
+                    &field.member,
+                    format!("duplicate metric name '{}'", name),
+                ));
+            }
+            seen_names.push(name);
+        }
+    }
+
+    let (const_label_names, const_label_values) = const_label_tokens(&struct_attrs);
+    let renders = fields.iter().map(|field| {
+        let member = &field.member;
+        let metric_name = metric_name_expr(field, &struct_attrs);
+        let aliases = alias_name_exprs(field, &struct_attrs);
+        let help = match &field.help {
+            Some(help) => quote! { Some(#help) },
+            None => quote! { None },
+        };
+        let unit = match &field.unit {
+            Some(unit) => quote! { Some(#unit) },
+            None => quote! { None },
+        };
+        let kind = field.kind();
+        let skip_zero = field.skip_zero;
+        let raw_labels = field.raw_labels;
+        let sorted = field.sorted;
+        let max_label_len = match field.max_label_len {
+            Some(max_len) => quote! { Some(#max_len) },
+            None => quote! { None },
+        };
+        // A label on a scalar would fall back to rendering it unlabeled
+        let label_check = match field.field_type {
+            FieldType::Unspecified => quote! {},
+            _ => quote_spanned! {member.span()=>
+                ::aetos::core::assert_labeled_value(&self.#member);
+            },
+        };
+        let render_call = render_call(field);
+        let meta = metadata_expr(
+            quote! { name },
+            help,
+            quote! { #kind },
+            [
+                ("unit", unit),
+                (
+                    "const_labels",
+                    quote! { &[#((#const_label_names, #const_label_values)),*] },
+                ),
+                ("skip_zero", quote! { #skip_zero }),
+                ("raw_labels", quote! { #raw_labels }),
+                ("sorted", quote! { #sorted }),
+                ("max_label_len", max_label_len),
+            ],
+        );
+        quote! {
+            {
+                #label_check
+                let wrapper = MetricWrapper(&self.#member);
+                for name in [#metric_name, #(#aliases),*] {
+                    let meta = #meta;
+                    #render_call?;
+                }
+            }
+        }
+    });
+
+    let output = if into_string {
+        quote! { ::std::string::ToString::to_string(&output) }
+    } else {
+        quote! { output }
+    };
+    method.block = syn::parse_quote! {{
+        let output = ::aetos::core::DisplayFn(move |f: &mut ::std::fmt::Formatter<'_>| {
+            #[allow(unused_imports)]
+            use ::aetos::core::{MetricMetadata, MetricWrapper, RenderScalarFallback};
+            #(#renders)*
+            Ok(())
+        });
+        #output
+    }};
+    Ok(quote! { #method })
+}
+
+/// Parses `counter(field, key = value, ...)` of a `#[metrics]` method: the field of
+/// `self` to render, then the keys of the metric attributes, except those needing the
+/// struct (`compute`, `buckets`)
+fn parse_method_metric(meta: &Meta, metric_type: MetricType) -> Result<MetricField> {
+    let Meta::List(list) = meta else {
+        return Err(Error::new_spanned(
+            meta,
+            "expected the field of self to render first, e.g. counter(requests, help = \"...\")",
+        ));
+    };
+    let mut member = None;
+    let mut attrs = MetricAttrs::default();
+    list.parse_nested_meta(|meta| {
+        if member.is_some() {
+            return parse_metric_key(&meta, &mut attrs);
+        }
+        if !(meta.input.is_empty() || meta.input.peek(syn::Token![,])) {
+            return Err(meta.error(
+                "expected the field of self to render first, e.g. counter(requests, help = \"...\")",
+            ));
+        }
+        member = Some(meta.path.require_ident()?.clone());
+        Ok(())
+    })?;
+    let Some(member) = member else {
+        return Err(Error::new_spanned(
+            meta,
+            "expected the field of self to render first, e.g. counter(requests, help = \"...\")",
+        ));
+    };
+
+    if let Some(span) = attrs.span_of(&["compute", "buckets"]) {
+        return Err(Error::new(
+            span,
+            "'compute' and 'buckets' are not supported on #[metrics] methods, which render \
+             fields of self",
+        ));
+    }
+    if let (MetricType::Histogram, Some(span)) = (&metric_type, attrs.span_of(&["label", "labels"]))
+    {
+        return Err(Error::new(
+            span,
+            "histogram metrics do not support 'label' attribute - labels are defined in the \
+             histogram type (e.g. Histogram<MyLabel, N>)",
+        ));
+    }
+    if let (Some(_), Some((_, span))) = (&attrs.label_override, &attrs.label_names) {
+        return Err(Error::new(
+            *span,
+            "'label' and 'labels' cannot be used together",
+        ));
+    }
+    if let Some(span) = attrs
+        .span_of(&["max_label_len"])
+        .filter(|_| attrs.label_override.is_none())
+    {
+        return Err(max_label_len_error(span));
+    }
+
+    Ok(MetricField {
+        member: syn::Member::Named(member),
+        ty: syn::parse_quote! { () },
+        wrappers: Vec::new(),
+        field_type: field_type(attrs.label_override, attrs.label_names),
+        metric_type,
+        help: attrs.help,
+        name_override: attrs.name_override,
+        aliases: attrs.aliases,
+        prefix: attrs.prefix,
+        unit: attrs.unit,
+        no_suffix: attrs.no_suffix,
+        no_prefix: attrs.no_prefix,
+        skip_zero: attrs.skip_zero,
+        raw_labels: attrs.raw_labels,
+        max_label_len: attrs.max_label_len,
+        sorted: attrs.sorted,
+        buckets: None,
+        dynamic: false,
+        untyped: false,
+        shared_with: Vec::new(),
+        continues_family: false,
+        optional: false,
+        cfgs: Vec::new(),
+        compute: None,
+        lazy: false,
+        atomic: false,
+        seconds: false,
+        flag: false,
+        allow_naming: attrs.allow_naming,
+    })
+}
+
+/// `#[derive(Metrics)]`: struct arguments and field attributes are written as
+/// `#[metric(...)]`, and the struct itself is left untouched.
+pub fn expand_metrics_derive(mut input: DeriveInput) -> Result<TokenStream> {
+    let mut args = Vec::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("metric"))
+    {
+        args.push(attr.parse_args::<TokenStream>()?);
+    }
+    let struct_attrs = parse_struct_attrs(quote! { #(#args),* })?;
+
+    if let Data::Struct(ref mut data) = input.data {
+        for field in data.fields.iter_mut() {
+            field.attrs = std::mem::take(&mut field.attrs)
+                .into_iter()
+                .map(field_attr_from_derive)
+                .collect::<Result<_>>()?;
+        }
+    }
+
+    expand(input, struct_attrs, false)
+}
+
+/// Rewrites a `#[metric(...)]` field attribute of the derive flavor into the attribute
+/// flavor: `#[metric(counter, help = "...")]` becomes `#[counter(help = "...")]` and
+/// `#[metric(no_default)]` and `#[metric(skip)]` become `#[metrics_field(...)]`.
+/// `#[metric(kind = "...", ...)]` is kept as is.
+fn field_attr_from_derive(attr: syn::Attribute) -> Result<syn::Attribute> {
+    if !attr.path().is_ident("metric") {
+        return Ok(attr);
+    }
+
+    let args: TokenStream = attr.parse_args()?;
+    let mut tokens = args.into_iter();
+    let kind = match tokens.next() {
+        Some(proc_macro2::TokenTree::Ident(kind)) => kind,
+        _ => {
+            return Err(Error::new_spanned(
+                &attr,
+                "expected the metric kind first: counter, gauge, histogram, group, nested, \
+                 dynamic, no_default or skip",
+            ));
+        }
+    };
+    // `#[metric(kind = "...", ...)]` is already the spelling of the attribute flavor
+    if kind == "kind" {
+        return Ok(attr);
+    }
+    let rest: TokenStream = match tokens.next() {
+        None => TokenStream::new(),
+        Some(proc_macro2::TokenTree::Punct(comma)) if comma.as_char() == ',' => tokens.collect(),
+        Some(other) => return Err(Error::new_spanned(other, "expected ','")),
+    };
+
+    match kind.to_string().as_str() {
+        "counter" | "gauge" | "histogram" | "group" | "nested" | "dynamic" if rest.is_empty() => {
+            Ok(syn::parse_quote! { #[#kind] })
+        }
+        "counter" | "gauge" | "histogram" | "group" | "nested" | "dynamic" => {
+            Ok(syn::parse_quote! { #[#kind(#rest)] })
+        }
+        "no_default" | "skip" if rest.is_empty() => {
+            Ok(syn::parse_quote! { #[metrics_field(#kind)] })
+        }
+        "no_default" | "skip" => Err(Error::new_spanned(
+            rest,
+            format!("'{}' takes no arguments", kind),
+        )),
+        _ => Err(Error::new_spanned(
+            &kind,
+            format!(
+                "unknown metric kind '{}', expected one of: counter, gauge, histogram, group, \
+                 nested, dynamic, no_default, skip",
+                kind
+            ),
+        )),
+    }
+}
 
+/// Shared by both flavors. The attribute flavor re-emits the struct without the field
+/// attributes consumed here; a derive cannot change the struct.
+fn expand(
+    mut input: DeriveInput,
+    mut struct_attrs: StructAttrs,
+    emit_struct: bool,
+) -> Result<TokenStream> {
     let name = &input.ident;
+    struct_attrs.generic = input
+        .generics
+        .params
+        .iter()
+        .any(|param| !matches!(param, syn::GenericParam::Lifetime(_)));
 
     let fields = match &input.data {
         Data::Struct(data) => match &data.fields {
             Fields::Named(fields) => &fields.named,
-            _ => {
+            Fields::Unnamed(fields) => &fields.unnamed,
+            Fields::Unit => {
                 return Err(Error::new_spanned(
                     input,
-                    "metrics can only be applied to structs with named fields",
+                    "metrics can only be applied to structs with fields",
                 ));
             }
         },
@@ -48,304 +629,3105 @@ pub fn expand_metrics_macro(args: TokenStream, input: TokenStream) -> Result<Tok
         }
     };
 
-    let mut metric_fields = Vec::new();
+    if let Some(ts_field) = &struct_attrs.timestamp {
+        if !fields.iter().any(|f| f.ident.as_ref() == Some(ts_field)) {
+            return Err(Error::new_spanned(
+                ts_field,
+                format!(
+                    "timestamp field '{}' does not exist in this struct",
+                    ts_field
+                ),
+            ));
+        }
+    }
+
+    let mut metric_fields = Vec::new();
+    let mut unmarked_warnings = Vec::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        let skip = parse_field_options(field)?.skip;
+        let metric_field = parse_field(field, index, &input.generics)?;
+        match metric_field {
+            Some(metric_field) if skip => {
+                return Err(Error::new_spanned(
+                    &metric_field.member,
+                    "'skip' cannot be used on a field with a metric attribute",
+                ));
+            }
+            Some(metric_field) => metric_fields.push(metric_field),
+            None if skip => {}
+            // The timestamp is read by the rendering
+            None if field.ident.is_some() && field.ident == struct_attrs.timestamp => {}
+            None => {
+                let member = field_member(field, index);
+                if struct_attrs.strict {
+                    return Err(Error::new_spanned(
+                        &member,
+                        format!(
+                            "field `{}` has no metric attribute; mark it \
+                             #[metrics_field(skip)] if it is not a metric",
+                            member_name(&member)
+                        ),
+                    ));
+                }
+                unmarked_warnings.push(unmarked_field_warning(field, &member));
+            }
+        }
+    }
+    metric_fields.append(&mut struct_attrs.extra);
+
+    for field in &metric_fields {
+        let label_names = match &field.field_type {
+            FieldType::SingleLabel { label_name } => {
+                vec![label_name.clone().unwrap_or_else(|| field.name())]
+            }
+            FieldType::MultiLabel { label_names } => label_names.clone(),
+            FieldType::Unspecified => Vec::new(),
+        };
+        if let Some((name, _)) = struct_attrs
+            .const_labels
+            .iter()
+            .find(|(name, _)| label_names.contains(&name.unraw().to_string()))
+        {
+            return Err(Error::new_spanned(
+                &field.member,
+                format!(
+                    "label '{}' of this field is already set on every metric by #[metrics(labels(...))]",
+                    name.unraw()
+                ),
+            ));
+        }
+    }
+
+    for field in &metric_fields {
+        check_final_names(field, &struct_attrs)?;
+    }
+    if struct_attrs.strict_naming {
+        for field in &metric_fields {
+            check_naming(field, &struct_attrs)?;
+        }
+    }
+
+    let mut seen_names: HashMap<String, usize> = HashMap::new();
+    let mut shared = Vec::new();
+    for (index, field) in metric_fields.iter().enumerate() {
+        // The names of dynamic fields are only known at runtime
+        if field.dynamic || matches!(field.metric_type, MetricType::Nested) {
+            continue;
+        }
+        let names = std::iter::once(build_metric_name(field, &struct_attrs))
+            .chain(build_alias_names(field, &struct_attrs));
+        for metric_name in names {
+            if let Some(&first) = seen_names.get(&metric_name) {
+                let first = &metric_fields[first];
+                if struct_attrs.allow_shared_names {
+                    check_shared_name(first, field, &metric_name)?;
+                    shared.push((seen_names[&metric_name], index));
+                    continue;
+                }
+                return Err(Error::new_spanned(
+                    &field.member,
+                    format!(
+                        "duplicate metric name '{}': fields '{}' and '{}' both export it",
+                        metric_name,
+                        member_name(&first.member),
+                        member_name(&field.member)
+                    ),
+                ));
+            }
+            seen_names.insert(metric_name, index);
+        }
+    }
+    for (first, index) in shared {
+        metric_fields[first].shared_with.push(index);
+        metric_fields[index].continues_family = true;
+    }
+    let group_checks = if input.generics.params.is_empty() {
+        generate_group_checks(&metric_fields, &struct_attrs)
+    } else {
+        // Const items cannot name the generic types of the fields
+        Vec::new()
+    };
+
+    let new_fn = generate_new_fn(&input, fields, &metric_fields, &struct_attrs)?;
+    let helper_methods = generate_helper_methods(&input, &metric_fields, &struct_attrs);
+
+    if let Data::Struct(ref mut data) = input.data {
+        for field in data.fields.iter_mut() {
+            field
+                .attrs
+                .retain(|attr| !FIELD_ATTRS.iter().any(|name| attr.path().is_ident(name)));
+        }
+    }
+
+    let original_struct = if emit_struct {
+        quote! { #input }
+    } else {
+        TokenStream::new()
+    };
+
+    let display_impl = generate_display_impl(name, &input.generics, &metric_fields, &struct_attrs)?;
+
+    let output = quote! {
+        #original_struct
+        #display_impl
+        #new_fn
+        #helper_methods
+        #(#group_checks)*
+        #(#unmarked_warnings)*
+    };
+    Ok(output)
+}
+
+/// Const items rejecting the families of `#[group]` fields named like another field or
+/// like a member of another group. The names of the members are only known from the
+/// `MetricGroup` impl, so the check runs when the items are evaluated, at compile time.
+///
+/// Fields with an expression in their name are left out, as their names are not known
+/// here.
+fn generate_group_checks(fields: &[MetricField], struct_attrs: &StructAttrs) -> Vec<TokenStream> {
+    let (groups, others): (Vec<&MetricField>, Vec<&MetricField>) = fields
+        .iter()
+        .filter(|field| has_literal_names(field, struct_attrs))
+        .partition(|field| matches!(field.metric_type, MetricType::Group));
+    let names: Vec<String> = others
+        .iter()
+        .flat_map(|field| {
+            std::iter::once(build_metric_name(field, struct_attrs))
+                .chain(build_alias_names(field, struct_attrs))
+        })
+        .collect();
+
+    let mut checks = Vec::new();
+    for (i, group) in groups.iter().enumerate() {
+        let ty = &group.ty;
+        let cfgs = &group.cfgs;
+        let name = build_metric_name(group, struct_attrs);
+        let total_suffix = group_total_suffix(group, struct_attrs);
+        checks.push(quote_spanned! {group.member.span()=>
+            #(#cfgs)*
+            const _: () = ::aetos::core::check_group_names::<#ty, _>(
+                #name,
+                #total_suffix,
+                &[#(#names),*],
+            );
+        });
+        for other in &groups[i + 1..] {
+            let other_ty = &other.ty;
+            let other_cfgs = &other.cfgs;
+            let other_name = build_metric_name(other, struct_attrs);
+            checks.push(quote_spanned! {other.member.span()=>
+                #(#cfgs)*
+                #(#other_cfgs)*
+                const _: () = ::aetos::core::check_group_pair::<#other_ty, _, #ty, _>(
+                    #other_name,
+                    #name,
+                    #total_suffix,
+                );
+            });
+        }
+    }
+    checks
+}
+
+/// Checks the final names of `field`, with its prefixes, when they are known here. A
+/// literal `name` or `alias` is only checked for its characters when parsed, as it may
+/// start with a digit after a prefix, e.g. `name = "5xx"` under `prefix = "app"`.
+fn check_final_names(field: &MetricField, struct_attrs: &StructAttrs) -> Result<()> {
+    if !has_literal_names(field, struct_attrs) {
+        return Ok(());
+    }
+    let names = std::iter::once(build_metric_name(field, struct_attrs))
+        .chain(build_alias_names(field, struct_attrs));
+    for name in names {
+        if !aetos_core::validate_metric_name(&name) {
+            return Err(Error::new_spanned(
+                &field.member,
+                format!(
+                    "invalid metric name '{}': must match [a-zA-Z_:][a-zA-Z0-9_:]*, add a \
+                     prefix or change the name",
+                    name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the names of `field` are only made of literals, and so known here. The names
+/// of dynamic and nested fields come from elsewhere.
+fn has_literal_names(field: &MetricField, struct_attrs: &StructAttrs) -> bool {
+    if field.dynamic || matches!(field.metric_type, MetricType::Nested) {
+        return false;
+    }
+    let (prefixes, _, _) = metric_name_parts(field, struct_attrs);
+    let base = base_name(field);
+    prefixes
+        .into_iter()
+        .chain([&base])
+        .all(|part| matches!(part, StrValue::Lit(_)))
+}
+
+/// With `strict_naming`, checks the names of `field` against the naming conventions,
+/// unless it is marked `allow(naming)`. Names built from expressions are only known at
+/// runtime and are not checked, nor are dynamic and nested fields, which have their own.
+fn check_naming(field: &MetricField, struct_attrs: &StructAttrs) -> Result<()> {
+    if field.allow_naming || field.dynamic || matches!(field.metric_type, MetricType::Nested) {
+        return Ok(());
+    }
+    let (prefixes, _, _) = metric_name_parts(field, struct_attrs);
+    let base = base_name(field);
+    if prefixes
+        .iter()
+        .copied()
+        .chain([&base])
+        .any(|part| matches!(part, StrValue::Expr(_)))
+    {
+        return Ok(());
+    }
+
+    let names = std::iter::once(build_metric_name(field, struct_attrs))
+        .chain(build_alias_names(field, struct_attrs));
+    for name in names {
+        if let Some(violation) = naming_violation(field, &name) {
+            return Err(Error::new_spanned(
+                &field.member,
+                format!(
+                    "{} (strict_naming); add 'allow(naming)' to the metric attribute to \
+                     accept it",
+                    violation
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The naming convention broken by `name`, the final name of `field`: no uppercase
+/// letters or double underscores, counters end with `_total`, and values in seconds with
+/// `_seconds` (before the `_total` of counters). The names of group members are not
+/// known here, so only the base name of groups is checked, for case and underscores.
+fn naming_violation(field: &MetricField, name: &str) -> Option<String> {
+    if name.chars().any(|c| c.is_ascii_uppercase()) {
+        return Some(format!("metric name '{}' contains uppercase letters", name));
+    }
+    if name.contains("__") {
+        return Some(format!("metric name '{}' contains '__'", name));
+    }
+    if matches!(field.metric_type, MetricType::Group) {
+        return None;
+    }
+    let is_counter = matches!(field.metric_type, MetricType::Counter);
+    if is_counter && !name.ends_with("_total") {
+        return Some(format!("counter '{}' does not end with '_total'", name));
+    }
+    let in_seconds = field.seconds || field.unit.as_deref() == Some("seconds");
+    let without_total = name.strip_suffix("_total").filter(|_| is_counter);
+    if in_seconds && !without_total.unwrap_or(name).ends_with("_seconds") {
+        return Some(format!(
+            "metric '{}' is in seconds but does not end with '_seconds'",
+            name
+        ));
+    }
+    None
+}
+
+/// With `allow_shared_names`, checks that `field` can render its samples in the family
+/// of `first`, which has the same name: both must be counters or gauges of the same
+/// kind and help text, rendered unconditionally under that single name.
+fn check_shared_name(first: &MetricField, field: &MetricField, metric_name: &str) -> Result<()> {
+    for field in [first, field] {
+        let reason = if !matches!(field.metric_type, MetricType::Counter | MetricType::Gauge) {
+            "only counters and gauges can share their name"
+        } else if !field.aliases.is_empty() {
+            "fields sharing their name cannot have aliases"
+        } else if field.skip_zero {
+            "fields sharing their name cannot use 'skip_zero', as the header is written \
+             before the samples of every field"
+        } else if !field.cfgs.is_empty() {
+            "fields sharing their name cannot be #[cfg]-gated"
+        } else if field.optional {
+            "fields sharing their name cannot be `Option`s, as the header is written even \
+             when every field is `None`"
+        } else {
+            continue;
+        };
+        return Err(Error::new_spanned(
+            &field.member,
+            format!("metric name '{}' is shared: {}", metric_name, reason),
+        ));
+    }
+    if first.kind() != field.kind() {
+        return Err(Error::new_spanned(
+            &field.member,
+            format!(
+                "metric name '{}' is shared by a {} and a {}, fields sharing their name must \
+                 have the same kind",
+                metric_name,
+                first.kind(),
+                field.kind()
+            ),
+        ));
+    }
+    let help = |field: &MetricField| field.help.as_ref().map(StrValue::key);
+    let unit = |field: &MetricField| field.unit.clone();
+    if help(first) != help(field) || unit(first) != unit(field) {
+        return Err(Error::new_spanned(
+            &field.member,
+            format!(
+                "fields '{}' and '{}' share the metric name '{}', so they must have the same \
+                 help text and unit",
+                member_name(&first.member),
+                member_name(&field.member),
+                metric_name
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Warns that `field` has no metric attribute. Proc macros cannot emit warnings on
+/// stable, so this uses a deprecated constant, spanned on the field.
+fn unmarked_field_warning(field: &syn::Field, member: &syn::Member) -> TokenStream {
+    let cfgs = cfg_attrs(field);
+    let note = format!(
+        "field `{}` has no metric attribute and will not be included in the Prometheus \
+         output; mark it #[metrics_field(skip)] if it is not a metric",
+        member_name(member)
+    );
+    let constant = quote_spanned! {member.span()=> no_metric_attribute };
+    quote! {
+        #(#cfgs)*
+        const _: () = {
+            #[deprecated(note = #note)]
+            #[allow(non_upper_case_globals)]
+            const no_metric_attribute: () = ();
+            #constant
+        };
+    }
+}
+
+#[derive(Debug, Default)]
+struct StructAttrs {
+    prefix: Option<StrValue>,
+    /// `namespace` and `subsystem`, an alternative to `prefix`: `namespace_subsystem_name`
+    namespace: Option<StrValue>,
+    subsystem: Option<StrValue>,
+    timestamp: Option<syn::Ident>,
+    /// `convention = "prometheus"`: counters get a `_total` suffix
+    prometheus_convention: bool,
+    /// `generate_new`: emit a `new()` constructor with default values
+    generate_new: bool,
+    /// `default`: emit `impl Default` and a `new()` calling it
+    default: bool,
+    /// `generate_methods` or `helpers`: emit `observe_*`, `inc_*`, `add_*` and `set_*`
+    /// helpers
+    generate_methods: bool,
+    /// `histogram_series`: list the `_bucket`, `_sum` and `_count` series of histograms
+    /// in `metric_names()`
+    histogram_series: bool,
+    /// `bound = "..."`: where-predicates replacing the inferred bounds of the rendering
+    /// impls
+    bound: Option<Vec<syn::WherePredicate>>,
+    /// `display = false`: skip the `Display` impl and emit `fmt_prometheus` and
+    /// `render_prometheus` instead
+    no_display: bool,
+    /// `extra(gauge(...), ...)`: computed metrics without a backing field, rendered
+    /// after the fields
+    extra: Vec<MetricField>,
+    /// `labels(name = "value", ...)`: label pairs appended to every sample
+    const_labels: Vec<(syn::Ident, StrValue)>,
+    /// `strict`: a field without a metric attribute is an error instead of a warning
+    strict: bool,
+    /// `allow_shared_names`: fields with the same metric name render their samples in a
+    /// single family instead of being an error
+    allow_shared_names: bool,
+    /// `strict_naming`: metric names breaking the naming conventions are an error, see
+    /// `naming_violation`
+    strict_naming: bool,
+    /// Not an argument: whether the generated functions are in an impl with type or
+    /// const parameters, whose instantiations cannot share a `static`, see `cached`
+    generic: bool,
+    /// Not an argument: whether the code is generated into the body of a method, whose
+    /// impl may have generics the macro cannot see
+    method: bool,
+}
+
+fn parse_struct_attrs(args: TokenStream) -> Result<StructAttrs> {
+    let mut attrs = StructAttrs::default();
+
+    let mut seen = Vec::new();
+
+    let parser = syn::meta::parser(|meta| {
+        let key = lookup_key(&meta, STRUCT_ATTR_KEYS)?;
+        if seen.contains(&key) {
+            return Err(meta.error(format!("duplicate '{}' argument", key)));
+        }
+        seen.push(key);
+
+        let is_flag = matches!(
+            key,
+            "generate_new"
+                | "default"
+                | "generate_methods"
+                | "helpers"
+                | "histogram_series"
+                | "strict"
+                | "allow_shared_names"
+                | "strict_naming"
+        );
+        if is_flag && meta.input.peek(syn::Token![=]) {
+            return Err(meta.error(format!("'{}' takes no value", key)));
+        }
+
+        let uses_namespace = seen.contains(&"namespace") || seen.contains(&"subsystem");
+        if uses_namespace && seen.contains(&"prefix") {
+            return Err(meta.error(
+                "'prefix' cannot be combined with 'namespace' or 'subsystem', use one or the other",
+            ));
+        }
+
+        if meta.path.is_ident("prefix") {
+            attrs.prefix = Some(StrValue::parse(&meta, validate_prefix)?);
+        } else if meta.path.is_ident("namespace") {
+            attrs.namespace = Some(StrValue::parse(&meta, validate_prefix)?);
+        } else if meta.path.is_ident("subsystem") {
+            attrs.subsystem = Some(StrValue::parse(&meta, validate_prefix)?);
+        } else if meta.path.is_ident("timestamp") {
+            let s: syn::LitStr = meta.value()?.parse()?;
+            attrs.timestamp = Some(s.parse()?);
+        } else if meta.path.is_ident("convention") {
+            let s: syn::LitStr = meta.value()?.parse()?;
+            if s.value() != "prometheus" {
+                return Err(Error::new_spanned(
+                    &s,
+                    "unknown convention, the only supported value is \"prometheus\"",
+                ));
+            }
+            attrs.prometheus_convention = true;
+        } else if meta.path.is_ident("generate_new") {
+            attrs.generate_new = true;
+        } else if meta.path.is_ident("default") {
+            attrs.default = true;
+        } else if meta.path.is_ident("generate_methods") || meta.path.is_ident("helpers") {
+            attrs.generate_methods = true;
+        } else if meta.path.is_ident("histogram_series") {
+            attrs.histogram_series = true;
+        } else if meta.path.is_ident("strict") {
+            attrs.strict = true;
+        } else if meta.path.is_ident("allow_shared_names") {
+            attrs.allow_shared_names = true;
+        } else if meta.path.is_ident("strict_naming") {
+            attrs.strict_naming = true;
+        } else if meta.path.is_ident("bound") {
+            let s: syn::LitStr = meta.value()?.parse()?;
+            let predicates = s.parse_with(
+                syn::punctuated::Punctuated::<syn::WherePredicate, syn::Token![,]>::parse_terminated,
+            )?;
+            attrs.bound = Some(predicates.into_iter().collect());
+        } else if meta.path.is_ident("display") {
+            let b: syn::LitBool = meta.value()?.parse()?;
+            attrs.no_display = !b.value;
+        } else if meta.path.is_ident("labels") {
+            meta.parse_nested_meta(|label| {
+                let name = label.path.require_ident()?.clone();
+                validate_label_name(&name.unraw().to_string())
+                    .map_err(|msg| Error::new_spanned(&name, msg))?;
+                if attrs.const_labels.iter().any(|(seen, _)| *seen == name) {
+                    return Err(label.error(format!("duplicate label '{}'", name)));
+                }
+                let value = StrValue::parse(&label, |_| Ok(()))?;
+                attrs.const_labels.push((name, value));
+                Ok(())
+            })?;
+        } else if meta.path.is_ident("extra") {
+            meta.parse_nested_meta(|kind| {
+                attrs.extra.push(parse_extra_metric(&kind)?);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    });
+    syn::parse::Parser::parse2(parser, args)?;
+
+    Ok(attrs)
+}
+
+/// Field attributes consumed by `#[metrics]`.
+const FIELD_ATTRS: &[&str] = &[
+    "counter",
+    "gauge",
+    "histogram",
+    "group",
+    "nested",
+    "dynamic",
+    "metric",
+    "metrics_field",
+];
+
+/// Arguments accepted by `#[metrics(...)]`.
+const STRUCT_ATTR_KEYS: &[&str] = &[
+    "prefix",
+    "namespace",
+    "subsystem",
+    "timestamp",
+    "convention",
+    "generate_new",
+    "default",
+    "generate_methods",
+    "helpers",
+    "histogram_series",
+    "bound",
+    "display",
+    "extra",
+    "labels",
+    "strict",
+    "allow_shared_names",
+    "strict_naming",
+];
+
+/// Returns the entry of `keys` matching the path of `meta`, or an error naming the
+/// unknown key and listing the accepted ones.
+fn lookup_key(meta: &syn::meta::ParseNestedMeta, keys: &[&'static str]) -> Result<&'static str> {
+    match keys.iter().copied().find(|key| meta.path.is_ident(key)) {
+        Some(key) => Ok(key),
+        None => {
+            let path = meta.path.to_token_stream().to_string().replace(' ', "");
+            Err(meta.error(format!(
+                "unknown attribute '{}', expected one of: {}",
+                path,
+                keys.join(", ")
+            )))
+        }
+    }
+}
+
+/// Generates `fn new()` for `#[metrics(generate_new)]`.
+///
+/// Histogram fields are initialized with their `buckets`, or with `new()` when they have
+/// none, and every other field with `Default`.
+/// Fields marked `#[metrics_field(no_default)]` become arguments of `new`, in
+/// declaration order.
+fn generate_new_fn(
+    input: &DeriveInput,
+    fields: &syn::punctuated::Punctuated<syn::Field, syn::Token![,]>,
+    metric_fields: &[MetricField],
+    struct_attrs: &StructAttrs,
+) -> Result<TokenStream> {
+    let mut params = Vec::new();
+    let mut inits = Vec::new();
+    let mut bucket_checks = Vec::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        let member = field_member(field, index);
+        let ty = &field.ty;
+        let cfgs = cfg_attrs(field);
+
+        if parse_field_options(field)?.no_default {
+            if struct_attrs.default {
+                return Err(Error::new_spanned(
+                    &member,
+                    "'no_default' fields cannot be initialized by #[metrics(default)], \
+                     use #[metrics(generate_new)] to take them as arguments of new()",
+                ));
+            }
+            if !struct_attrs.generate_new {
+                return Err(Error::new_spanned(
+                    &member,
+                    "'no_default' requires #[metrics(generate_new)] on the struct",
+                ));
+            }
+            // Positional fields become the arguments `field_<index>`
+            let param = match &field.ident {
+                Some(ident) => ident.clone(),
+                None => syn::Ident::new(&format!("field_{}", index), field.ty.span()),
+            };
+            params.push(quote! { #(#cfgs)* #param: #ty });
+            inits.push(quote! { #(#cfgs)* #member: #param });
+            continue;
+        }
+
+        let histogram = metric_fields
+            .iter()
+            .find(|f| f.member == member && matches!(f.metric_type, MetricType::Histogram));
+        if let Some(buckets) = histogram.and_then(|f| f.buckets.as_ref()) {
+            if !struct_attrs.default && !struct_attrs.generate_new {
+                return Err(Error::new_spanned(
+                    buckets,
+                    "'buckets' requires #[metrics(default)] or #[metrics(generate_new)] on \
+                     the struct, which initialize the histogram with them",
+                ));
+            }
+            // Validated in a const item like with `define_histogram!`, so that misordered
+            // buckets fail to compile even where `new` is never called
+            bucket_checks.push(quote_spanned! {buckets.span()=>
+                #(#cfgs)*
+                const _: () = ::aetos::core::validate_histogram_buckets(&#buckets);
+            });
+            inits.push(quote! { #(#cfgs)* #member: ::aetos::core::Histogram::new(#buckets) });
+        } else if histogram.is_some_and(|f| f.wrappers.is_empty())
+            && option_inner_type(ty).is_none()
+        {
+            // `Histogram::new` takes the buckets, only `define_histogram!` types have a
+            // `new()` without arguments
+            if is_raw_histogram(ty) && (struct_attrs.default || struct_attrs.generate_new) {
+                return Err(Error::new_spanned(
+                    ty,
+                    "a `Histogram<L, N>` field needs 'buckets = [...]' to be initialized by \
+                     #[metrics(default)] or #[metrics(generate_new)], or a type declared \
+                     with define_histogram!",
+                ));
+            }
+            inits.push(quote! { #(#cfgs)* #member: <#ty>::new() });
+        } else {
+            // Spanned on the type, so that a type without `Default` is reported at the
+            // field
+            let default = quote_spanned! {ty.span()=> ::std::default::Default::default() };
+            inits.push(quote! { #(#cfgs)* #member: #default });
+        }
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    if struct_attrs.default {
+        return Ok(quote! {
+            #(#bucket_checks)*
+
+            impl #impl_generics ::std::default::Default for #name #ty_generics #where_clause {
+                fn default() -> Self {
+                    Self {
+                        #(#inits),*
+                    }
+                }
+            }
+
+            impl #impl_generics #name #ty_generics #where_clause {
+                #vis fn new() -> Self {
+                    ::std::default::Default::default()
+                }
+            }
+        });
+    }
+
+    if !struct_attrs.generate_new {
+        return Ok(TokenStream::new());
+    }
+
+    Ok(quote! {
+        #(#bucket_checks)*
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[allow(clippy::new_without_default)]
+            #vis fn new(#(#params),*) -> Self {
+                Self {
+                    #(#inits),*
+                }
+            }
+        }
+    })
+}
+
+/// Generates the helpers for `#[metrics(generate_methods)]` (or `helpers`):
+/// `observe_<field>(label, value)` for histograms, `inc_<field>()` / `add_<field>(n)` for
+/// `u64` counters, `set_<field>(v)` for primitive gauges, and the same methods taking a
+/// key first for maps. `Option`, computed and closure fields get no helpers.
+///
+/// A helper named like an existing method of the struct fails to compile with a
+/// duplicate definition error.
+fn generate_helper_methods(
+    input: &DeriveInput,
+    metric_fields: &[MetricField],
+    struct_attrs: &StructAttrs,
+) -> TokenStream {
+    if !struct_attrs.generate_methods {
+        return TokenStream::new();
+    }
+
+    let vis = &input.vis;
+    let mut methods = Vec::new();
+
+    for field in metric_fields.iter().filter(|f| {
+        !f.optional && f.compute.is_none() && !f.lazy && !f.dynamic && f.wrappers.is_empty()
+    }) {
+        let member = &field.member;
+        let ty = &field.ty;
+        let cfgs = &field.cfgs;
+
+        match field.metric_type {
+            MetricType::Histogram => {
+                let observe = syn::Ident::new(&format!("observe_{}", field.name()), member.span());
+                methods.push(quote_spanned! {member.span()=>
+                    #(#cfgs)*
+                    #vis fn #observe(
+                        &mut self,
+                        label: <#ty as ::aetos::core::HistogramLabel>::Label,
+                        value: f64,
+                    ) {
+                        self.#member.observe(label, value);
+                    }
+                });
+            }
+            MetricType::Counter if is_u64(ty) => {
+                let inc = syn::Ident::new(&format!("inc_{}", field.name()), member.span());
+                let add = syn::Ident::new(&format!("add_{}", field.name()), member.span());
+                methods.push(quote_spanned! {member.span()=>
+                    #(#cfgs)*
+                    #vis fn #inc(&mut self) {
+                        self.#member += 1;
+                    }
+
+                    #(#cfgs)*
+                    #vis fn #add(&mut self, n: u64) {
+                        self.#member += n;
+                    }
+                });
+            }
+            MetricType::Gauge if is_known_scalar_primitive(ty) => {
+                let set = syn::Ident::new(&format!("set_{}", field.name()), member.span());
+                methods.push(quote_spanned! {member.span()=>
+                    #(#cfgs)*
+                    #vis fn #set(&mut self, v: #ty) {
+                        self.#member = v;
+                    }
+                });
+            }
+            MetricType::Counter | MetricType::Gauge => {
+                let Some((key, value)) = map_key_value(ty) else {
+                    continue;
+                };
+                if matches!(field.metric_type, MetricType::Counter) && is_u64(value) {
+                    let inc = syn::Ident::new(&format!("inc_{}", field.name()), member.span());
+                    let add = syn::Ident::new(&format!("add_{}", field.name()), member.span());
+                    methods.push(quote_spanned! {member.span()=>
+                        #(#cfgs)*
+                        #vis fn #inc(&mut self, key: impl Into<#key>) {
+                            *self.#member.entry(key.into()).or_default() += 1;
+                        }
+
+                        #(#cfgs)*
+                        #vis fn #add(&mut self, key: impl Into<#key>, n: u64) {
+                            *self.#member.entry(key.into()).or_default() += n;
+                        }
+                    });
+                } else if matches!(field.metric_type, MetricType::Gauge)
+                    && is_known_scalar_primitive(value)
+                {
+                    let set = syn::Ident::new(&format!("set_{}", field.name()), member.span());
+                    methods.push(quote_spanned! {member.span()=>
+                        #(#cfgs)*
+                        #vis fn #set(&mut self, key: impl Into<#key>, v: #value) {
+                            self.#member.insert(key.into(), v);
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // Not every helper is expected to be used
+    quote! {
+        #[allow(dead_code)]
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#methods)*
+        }
+    }
+}
+
+fn is_u64(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(type_path) if type_path.path.is_ident("u64"))
+}
+
+/// Returns the key and value types of a `HashMap`, `BTreeMap` or `IndexMap`, the
+/// collections with an `entry` API
+fn map_key_value(ty: &syn::Type) -> Option<(&syn::Type, &syn::Type)> {
+    let syn::Type::Path(type_path) = ty else {
+        return None;
+    };
+    let last_segment = type_path.path.segments.last()?;
+    if !["HashMap", "BTreeMap", "IndexMap"]
+        .iter()
+        .any(|name| last_segment.ident == name)
+    {
+        return None;
+    }
+    match collection_key_value(ty)? {
+        (key, Some(value)) => Some((key, value)),
+        (_, None) => None,
+    }
+}
+
+/// The flags of `#[metrics_field(...)]`
+#[derive(Default)]
+struct FieldOptions {
+    /// `no_default`: the field becomes an argument of `new()`
+    no_default: bool,
+    /// `skip`: the field is not a metric, and does not warn for having no metric attribute
+    skip: bool,
+}
+
+fn parse_field_options(field: &syn::Field) -> Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+    for attr in &field.attrs {
+        if attr.path().is_ident("metrics_field") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("no_default") {
+                    options.no_default = true;
+                    Ok(())
+                } else if meta.path.is_ident("skip") {
+                    options.skip = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown attribute"))
+                }
+            })?;
+        }
+    }
+    Ok(options)
+}
+
+/// `cfg_attr` is expanded after `#[metrics]` runs, so field attributes inside it would
+/// never be seen: the field would silently stop being a metric, or fail with an
+/// unhelpful "cannot find attribute" error.
+fn reject_cfg_attr_field_attrs(attr: &syn::Attribute) -> Result<()> {
+    let Ok(args) =
+        attr.parse_args_with(syn::punctuated::Punctuated::<Meta, syn::Token![,]>::parse_terminated)
+    else {
+        return Ok(());
+    };
+    // The first argument is the predicate
+    for meta in args.iter().skip(1) {
+        if let Some(name) = FIELD_ATTRS.iter().find(|name| meta.path().is_ident(name)) {
+            return Err(Error::new_spanned(
+                meta,
+                format!(
+                    "#[{}] cannot be applied through cfg_attr; put #[cfg(...)] on the field instead",
+                    name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_field(
+    field: &syn::Field,
+    index: usize,
+    generics: &syn::Generics,
+) -> Result<Option<MetricField>> {
+    let mut metric_type = None;
+    let mut attrs = MetricAttrs::default();
+    let mut dynamic = false;
+    let mut untyped = false;
+
+    for attr in &field.attrs {
+        if attr.path().is_ident("cfg_attr") {
+            reject_cfg_attr_field_attrs(attr)?;
+            continue;
+        }
+
+        let is_dynamic = attr.path().is_ident("dynamic");
+        let is_generic = attr.path().is_ident("metric");
+        let mut attr_type = if attr.path().is_ident("counter") {
+            MetricType::Counter
+        } else if attr.path().is_ident("gauge") {
+            MetricType::Gauge
+        } else if attr.path().is_ident("histogram") {
+            MetricType::Histogram
+        } else if attr.path().is_ident("group") {
+            MetricType::Group
+        } else if attr.path().is_ident("nested") {
+            MetricType::Nested
+        } else if is_dynamic || is_generic {
+            // Replaced by the `kind` of the attribute below
+            MetricType::Gauge
+        } else {
+            continue;
+        };
+
+        if metric_type.is_some() {
+            return Err(Error::new_spanned(
+                attr,
+                "a field can only have one of #[counter], #[gauge], #[histogram], #[group], \
+                 #[nested], #[dynamic] or #[metric]",
+            ));
+        }
+
+        if let MetricType::Nested = attr_type {
+            if !matches!(attr.meta, Meta::Path(_)) {
+                return Err(Error::new_spanned(
+                    attr,
+                    "nested fields take no arguments, names are defined by the inner struct",
+                ));
+            }
+        } else if is_dynamic {
+            dynamic = true;
+            attr_type = parse_dynamic_attrs(attr, &mut attrs)?;
+        } else if is_generic {
+            (attr_type, untyped) = parse_generic_attrs(attr, &mut attrs)?;
+        } else {
+            parse_metric_attrs(attr, &mut attrs)?;
+        }
+        metric_type = Some(attr_type);
+    }
+
+    let label_span = attrs.span_of(&["label", "labels"]);
+    let group_span = attrs.span_of(&["help", "unit", "labels"]);
+    let compute_span = attrs.span_of(&["compute"]);
+    let alias_span = attrs.span_of(&["alias"]);
+    let buckets_span = attrs.span_of(&["buckets"]);
+    let max_label_len_span = attrs.span_of(&["max_label_len"]);
+
+    let MetricAttrs {
+        help,
+        name_override,
+        aliases,
+        label_override,
+        label_names,
+        prefix,
+        unit,
+        no_suffix,
+        no_prefix,
+        skip_zero,
+        raw_labels,
+        sorted,
+        max_label_len,
+        buckets,
+        compute,
+        allow_naming,
+        spans: _,
+    } = attrs;
+
+    let metric_type = match metric_type {
+        Some(mt) => mt,
+        None => return Ok(None),
+    };
+
+    if let (MetricType::Group, Some(span)) = (&metric_type, alias_span) {
+        return Err(Error::new(span, "'alias' is not supported on groups"));
+    }
+
+    if let Some(span) = buckets_span {
+        if !matches!(metric_type, MetricType::Histogram) {
+            return Err(Error::new(
+                span,
+                "'buckets' is only supported on histograms",
+            ));
+        }
+        // `Option` fields are initialized to `None`, so the buckets would never be used
+        if option_inner_type(&field.ty).is_some() {
+            return Err(Error::new(
+                span,
+                "'buckets' is not supported on Option fields, which are initialized to None",
+            ));
+        }
+    }
+
+    if let Some(span) = compute_span {
+        if !matches!(metric_type, MetricType::Counter | MetricType::Gauge) {
+            return Err(Error::new(
+                span,
+                "'compute' is only supported on counters and gauges",
+            ));
+        }
+    }
+
+    let member = field_member(field, index);
+
+    // Nested structs name their own metrics, and dynamic fields take them from the data
+    if field.ident.is_none()
+        && name_override.is_none()
+        && !dynamic
+        && !matches!(metric_type, MetricType::Nested)
+    {
+        return Err(Error::new_spanned(
+            &field.ty,
+            "fields of tuple structs need a 'name', since there is no field name to derive \
+             the metric name from",
+        ));
+    }
+
+    if let (Some(_), Some((_, span))) = (&label_override, &label_names) {
+        return Err(Error::new(
+            *span,
+            "'label' and 'labels' cannot be used together",
+        ));
+    }
+
+    if let Some(span) = max_label_len_span.filter(|_| label_override.is_none()) {
+        return Err(max_label_len_error(span));
+    }
+
+    // Groups take help, kind and unit from the members of the MetricGroup
+    if let MetricType::Group = metric_type {
+        if let Some(span) = group_span {
+            return Err(Error::new(
+                span,
+                "group fields only support 'name', 'prefix' and 'label' - help texts and \
+                 units are defined on the fields of the MetricGroup type",
+            ));
+        }
+    }
+
+    // Validate that histograms don't use the label attribute
+    if let MetricType::Histogram = metric_type {
+        if let Some(span) = label_span {
+            return Err(Error::new(
+                span,
+                "histogram metrics do not support 'label' attribute - labels are defined in the histogram type (e.g. Histogram<MyLabel, N>)"
+            ));
+        }
+    }
+
+    // The field of a computed metric is never read, so `Option` and the wrappers have no
+    // meaning there
+    let optional = option_inner_type(&field.ty).is_some() && compute.is_none();
+    let (ty, wrappers) = match &compute {
+        Some(_) => (&field.ty, Vec::new()),
+        None => extract_inner_type(option_inner_type(&field.ty).unwrap_or(&field.ty))?,
+    };
+    let ty = ty.clone();
+
+    if let Some(span) = buckets_span.filter(|_| !wrappers.is_empty()) {
+        return Err(Error::new(
+            span,
+            "'buckets' is not supported on wrapped or borrowed fields, \
+             initialize the histogram yourself",
+        ));
+    }
+
+    // Validate that known scalar primitives don't use the label attribute
+    if !matches!(metric_type, MetricType::Histogram) {
+        if let Some(span) = label_span.filter(|_| is_known_scalar_primitive(&ty)) {
+            return Err(Error::new(
+                span,
+                "the 'label' attribute is not supported on scalar types like u64, f64, etc. \
+                 Labels are only supported on collection types that implement IntoIterator. \
+                 To use labels, change this field to Vec, HashMap, BTreeMap, or another iterable collection."
+            ));
+        }
+    }
+
+    if let Some((names, span)) = &label_names {
+        if let Some(arity) = tuple_key_arity(&ty) {
+            if arity != names.len() {
+                return Err(Error::new(
+                    *span,
+                    format!(
+                        "{} label names given, but the key of this field is a tuple of {} elements",
+                        names.len(),
+                        arity
+                    ),
+                ));
+            }
+        }
+    }
+
+    let field_type = field_type(label_override, label_names);
+    let lazy = matches!(metric_type, MetricType::Counter | MetricType::Gauge)
+        && compute.is_none()
+        && is_closure_type(&ty, generics);
+    let atomic = matches!(metric_type, MetricType::Counter | MetricType::Gauge)
+        && compute.is_none()
+        && is_atomic_type(&ty);
+    let is_flag = |ty: &syn::Type| {
+        matches!(metric_type, MetricType::Counter | MetricType::Gauge) && is_type_named(ty, "bool")
+    };
+    let flag = compute.is_none()
+        && (is_flag(&ty)
+            || collection_key_value(&ty).is_some_and(|(_, value)| value.is_some_and(is_flag)));
+    let seconds = compute.is_none()
+        && (is_seconds_type(&ty, &metric_type)
+            || collection_key_value(&ty).is_some_and(|(_, value)| {
+                value.is_some_and(|value| is_seconds_type(value, &metric_type))
+            }));
+
+    Ok(Some(MetricField {
+        member,
+        field_type,
+        metric_type,
+        help,
+        name_override,
+        aliases,
+        prefix,
+        unit,
+        no_suffix,
+        no_prefix,
+        skip_zero,
+        raw_labels,
+        max_label_len,
+        sorted: sorted || is_hash_map(&ty),
+        buckets,
+        dynamic,
+        untyped,
+        shared_with: Vec::new(),
+        continues_family: false,
+        ty,
+        wrappers,
+        optional,
+        cfgs: cfg_attrs(field),
+        compute,
+        lazy,
+        atomic,
+        seconds,
+        flag,
+        allow_naming,
+    }))
+}
+
+// Label keys and tuple keys format their own values, see `#[label(max_len = N)]`
+fn max_label_len_error(span: Span) -> Error {
+    Error::new(
+        span,
+        "'max_label_len' requires 'label'; for a key type deriving Label, use \
+         #[label(max_len = N)] on its fields",
+    )
+}
+
+fn field_type(
+    label_override: Option<String>,
+    label_names: Option<(Vec<String>, Span)>,
+) -> FieldType {
+    match (label_override, label_names) {
+        (Some(label_name), _) => FieldType::SingleLabel {
+            label_name: Some(label_name),
+        },
+        (None, Some((label_names, _))) => FieldType::MultiLabel { label_names },
+        (None, None) => FieldType::Unspecified,
+    }
+}
+
+/// Parses one `counter(...)` or `gauge(...)` of `#[metrics(extra(...))]`. The metric is
+/// named after its `compute` method unless `name` is given.
+fn parse_extra_metric(kind: &syn::meta::ParseNestedMeta) -> Result<MetricField> {
+    let metric_type = if kind.path.is_ident("counter") {
+        MetricType::Counter
+    } else if kind.path.is_ident("gauge") {
+        MetricType::Gauge
+    } else {
+        return Err(kind.error("extra metrics must be a 'counter' or a 'gauge'"));
+    };
+
+    let mut attrs = MetricAttrs::default();
+    kind.parse_nested_meta(|meta| parse_metric_key(&meta, &mut attrs))?;
+
+    if let (Some(_), Some((_, span))) = (&attrs.label_override, &attrs.label_names) {
+        return Err(Error::new(
+            *span,
+            "'label' and 'labels' cannot be used together",
+        ));
+    }
+    if let Some(span) = attrs.span_of(&["buckets"]) {
+        return Err(Error::new(
+            span,
+            "'buckets' is only supported on histograms",
+        ));
+    }
+    if let Some(span) = attrs
+        .span_of(&["max_label_len"])
+        .filter(|_| attrs.label_override.is_none())
+    {
+        return Err(max_label_len_error(span));
+    }
+    let Some(compute) = attrs.compute else {
+        return Err(kind.error("extra metrics need a 'compute' method"));
+    };
+
+    Ok(MetricField {
+        member: syn::Member::Named(compute.clone()),
+        ty: syn::parse_quote! { () },
+        wrappers: Vec::new(),
+        field_type: field_type(attrs.label_override, attrs.label_names),
+        metric_type,
+        help: attrs.help,
+        name_override: attrs.name_override,
+        aliases: attrs.aliases,
+        prefix: attrs.prefix,
+        unit: attrs.unit,
+        no_suffix: attrs.no_suffix,
+        no_prefix: attrs.no_prefix,
+        skip_zero: attrs.skip_zero,
+        raw_labels: attrs.raw_labels,
+        max_label_len: attrs.max_label_len,
+        sorted: attrs.sorted,
+        buckets: None,
+        dynamic: false,
+        untyped: false,
+        shared_with: Vec::new(),
+        continues_family: false,
+        optional: false,
+        cfgs: Vec::new(),
+        compute: Some(compute),
+        lazy: false,
+        atomic: false,
+        seconds: false,
+        flag: false,
+        allow_naming: attrs.allow_naming,
+    })
+}
+
+/// Whether `ty` is one of the `std::sync::atomic` integers or `AtomicBool`, going by the
+/// last path segment so that `AtomicU64` and `std::sync::atomic::AtomicU64` both match
+fn is_atomic_type(ty: &syn::Type) -> bool {
+    const ATOMICS: &[&str] = &[
+        "AtomicU8",
+        "AtomicU16",
+        "AtomicU32",
+        "AtomicU64",
+        "AtomicUsize",
+        "AtomicI8",
+        "AtomicI16",
+        "AtomicI32",
+        "AtomicI64",
+        "AtomicIsize",
+        "AtomicBool",
+    ];
+    matches!(ty, syn::Type::Path(p) if p.qself.is_none() && p.path.segments.last().is_some_and(|seg| {
+        seg.arguments.is_none() && ATOMICS.iter().any(|atomic| seg.ident == atomic)
+    }))
+}
+
+/// Whether a metric of `ty` is rendered in seconds: a `Duration` counter or gauge, or a
+/// `SystemTime` gauge. Goes by the last path segment like `is_atomic_type`.
+fn is_seconds_type(ty: &syn::Type, metric_type: &MetricType) -> bool {
+    match metric_type {
+        MetricType::Counter => is_type_named(ty, "Duration"),
+        MetricType::Gauge => is_type_named(ty, "Duration") || is_type_named(ty, "SystemTime"),
+        _ => false,
+    }
+}
+
+/// Whether the last path segment of `ty` is `name`, without generic arguments
+fn is_type_named(ty: &syn::Type, name: &str) -> bool {
+    matches!(ty, syn::Type::Path(p) if p.qself.is_none() && p.path.segments.last().is_some_and(|seg| {
+        seg.ident == name && seg.arguments.is_none()
+    }))
+}
+
+/// Whether `ty` is a `HashMap`, whose iteration order changes from run to run. Goes by
+/// the last path segment like `is_atomic_type`, so other hash maps (`FxHashMap`,
+/// `AHashMap`), aliases and `compute` methods need `sorted`.
+fn is_hash_map(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(p) if p.qself.is_none() && p.path.segments.last().is_some_and(|seg| seg.ident == "HashMap"))
+}
+
+/// Whether `ty` is spelled `Histogram<..>`, i.e. `aetos::core::Histogram` rather than a
+/// type declared with `define_histogram!`. Aliases are not detected.
+fn is_raw_histogram(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Path(p) if p.qself.is_none() && p.path.segments.last().is_some_and(|seg| {
+        seg.ident == "Histogram" && matches!(seg.arguments, syn::PathArguments::AngleBracketed(_))
+    }))
+}
+
+/// Whether `ty` is a closure returning the value of the metric: `Box`, `Arc`, `Rc` or a
+/// reference to `dyn Fn() -> T`, or a type parameter bounded by `Fn() -> T`.
+fn is_closure_type(ty: &syn::Type, generics: &syn::Generics) -> bool {
+    let is_fn_bound = |bound: &syn::TypeParamBound| {
+        matches!(bound, syn::TypeParamBound::Trait(t) if t.path.segments.last().is_some_and(|seg| {
+            seg.ident == "Fn" && matches!(seg.arguments, syn::PathArguments::Parenthesized(_))
+        }))
+    };
+    let is_dyn_fn = |ty: &syn::Type| matches!(ty, syn::Type::TraitObject(obj) if obj.bounds.iter().any(is_fn_bound));
+
+    match ty {
+        syn::Type::Reference(reference) => is_dyn_fn(&reference.elem),
+        syn::Type::Path(type_path) if type_path.qself.is_none() => {
+            if let Some(ident) = type_path.path.get_ident() {
+                let param_bounds = generics
+                    .type_params()
+                    .filter(|param| &param.ident == ident)
+                    .flat_map(|param| &param.bounds);
+                let where_bounds = generics
+                    .where_clause
+                    .iter()
+                    .flat_map(|clause| &clause.predicates)
+                    .filter_map(|predicate| match predicate {
+                        syn::WherePredicate::Type(p) => Some(p),
+                        _ => None,
+                    })
+                    .filter(
+                        |p| matches!(&p.bounded_ty, syn::Type::Path(b) if b.path.is_ident(ident)),
+                    )
+                    .flat_map(|p| &p.bounds);
+                return param_bounds.chain(where_bounds).any(is_fn_bound);
+            }
+
+            let Some(last_segment) = type_path.path.segments.last() else {
+                return false;
+            };
+            if !["Box", "Arc", "Rc"]
+                .iter()
+                .any(|name| last_segment.ident == name)
+            {
+                return false;
+            }
+            let syn::PathArguments::AngleBracketed(args) = &last_segment.arguments else {
+                return false;
+            };
+            matches!(args.args.first(), Some(syn::GenericArgument::Type(inner)) if is_dyn_fn(inner))
+        }
+        _ => false,
+    }
+}
+
+fn cfg_attrs(field: &syn::Field) -> Vec<syn::Attribute> {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .cloned()
+        .collect()
+}
+
+/// Returns the number of elements of the key, if the key of a collection type is
+/// written as a tuple: `HashMap<(A, B), V>`, `BTreeMap<(A, B), V>`, `Vec<((A, B), V)>`
+/// or `[((A, B), V); N]`.
+fn tuple_key_arity(ty: &syn::Type) -> Option<usize> {
+    if let Some(inner) = option_inner_type(ty) {
+        return tuple_key_arity(inner);
+    }
+
+    match collection_key_value(ty)?.0 {
+        syn::Type::Tuple(tuple) => Some(tuple.elems.len()),
+        _ => None,
+    }
+}
+
+/// Returns the key and value types of a collection type written as a map
+/// (`HashMap<K, V>`, `BTreeMap<K, V>`, ...), `Vec<(K, V)>`, `[(K, V); N]` or `[(K, V)]`.
+/// The value is `None` for other generic types with a single argument.
+fn collection_key_value(ty: &syn::Type) -> Option<(&syn::Type, Option<&syn::Type>)> {
+    let pair = match ty {
+        syn::Type::Path(type_path) => {
+            let last_segment = type_path.path.segments.last()?;
+            let syn::PathArguments::AngleBracketed(args) = &last_segment.arguments else {
+                return None;
+            };
+            let mut types = args.args.iter().filter_map(|arg| match arg {
+                syn::GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            });
+            let first = types.next()?;
+            if last_segment.ident != "Vec" {
+                return Some((first, types.next()));
+            }
+            first
+        }
+        syn::Type::Array(array) => &array.elem,
+        syn::Type::Slice(slice) => &slice.elem,
+        _ => return None,
+    };
+
+    // `(K, V)`
+    match pair {
+        syn::Type::Tuple(tuple) if tuple.elems.len() == 2 => {
+            Some((&tuple.elems[0], Some(&tuple.elems[1])))
+        }
+        _ => None,
+    }
+}
+
+/// Peels references, `Arc<T>`, `Cow<T>`, `Mutex<T>` and `RwLock<T>` off `ty`, returning
+/// the innermost type and the wrappers, outermost first. Goes by the last path segment
+/// like `is_atomic_type`.
+///
+/// Locks are taken with `lock()` and `read()`, which only return a `LockResult` for the
+/// `std::sync` ones. A lock spelled with another path, e.g. `parking_lot::Mutex` or
+/// `tokio::sync::RwLock`, is an error; a bare `Mutex` imported from another crate cannot
+/// be told apart and fails to compile in the generated code.
+fn extract_inner_type(mut ty: &syn::Type) -> Result<(&syn::Type, Vec<Wrapper>)> {
+    let mut wrappers = Vec::new();
+    loop {
+        let (wrapper, inner) = match ty {
+            syn::Type::Reference(reference) => (Wrapper::Deref, &*reference.elem),
+            syn::Type::Path(type_path) => {
+                let Some(last_segment) = type_path.path.segments.last() else {
+                    break;
+                };
+                let wrapper = if last_segment.ident == "Arc" || last_segment.ident == "Cow" {
+                    Wrapper::Deref
+                } else if last_segment.ident == "Mutex" {
+                    check_std_lock(type_path)?;
+                    Wrapper::Mutex
+                } else if last_segment.ident == "RwLock" {
+                    check_std_lock(type_path)?;
+                    Wrapper::RwLock
+                } else {
+                    break;
+                };
+                let syn::PathArguments::AngleBracketed(args) = &last_segment.arguments else {
+                    break;
+                };
+                // After the lifetime of `Cow<'a, T>`
+                let Some(inner) = args.args.iter().find_map(|arg| match arg {
+                    syn::GenericArgument::Type(ty) => Some(ty),
+                    _ => None,
+                }) else {
+                    break;
+                };
+                (wrapper, inner)
+            }
+            _ => break,
+        };
+        // `Arc<dyn Fn() -> T>` and `&dyn Fn() -> T` are closures, called through
+        // `LazyMetric`
+        if matches!(inner, syn::Type::TraitObject(_)) {
+            break;
+        }
+        wrappers.push(wrapper);
+        ty = inner;
+    }
+    Ok((ty, wrappers))
+}
+
+/// Rejects a lock spelled with a path other than `Mutex`, `sync::Mutex` or
+/// `std::sync::Mutex` (and the same for `RwLock`)
+fn check_std_lock(type_path: &syn::TypePath) -> Result<()> {
+    let segments: Vec<String> = type_path
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    let is_std = match segments.as_slice() {
+        [_] => type_path.path.leading_colon.is_none(),
+        [sync, _] => sync == "sync" && type_path.path.leading_colon.is_none(),
+        [std, sync, _] => std == "std" && sync == "sync",
+        _ => false,
+    };
+    if is_std {
+        return Ok(());
+    }
+    let lock = segments.join("::");
+    Err(Error::new_spanned(
+        type_path,
+        format!(
+            "unsupported lock type '{}': only std::sync::{} is taken when rendering, \
+             use a 'compute' method to read other locks",
+            lock,
+            segments.last().unwrap()
+        ),
+    ))
+}
+
+/// Statements rebinding `value`, a reference to the field, to a reference to the value
+/// inside its wrappers. The guards of the locks live until the end of the enclosing block,
+/// and a poisoned lock is read anyway, like the registries do.
+fn unwrap_value(field: &MetricField) -> TokenStream {
+    let mut statements = TokenStream::new();
+    for (index, wrapper) in field.wrappers.iter().enumerate() {
+        let guard = quote::format_ident!("__aetos_guard_{}", index);
+        statements.extend(match wrapper {
+            Wrapper::Deref => quote! {
+                let value = &**value;
+            },
+            Wrapper::Mutex => quote! {
+                let #guard = value.lock().unwrap_or_else(::std::sync::PoisonError::into_inner);
+                let value = &*#guard;
+            },
+            Wrapper::RwLock => quote! {
+                let #guard = value.read().unwrap_or_else(::std::sync::PoisonError::into_inner);
+                let value = &*#guard;
+            },
+        });
+    }
+    statements
+}
+
+/// Returns the inner type if `ty` is written as `Option<T>`.
+/// Like `is_known_scalar_primitive`, this works on the syntax only, so aliases
+/// of `Option` are not detected.
+fn option_inner_type(ty: &syn::Type) -> Option<&syn::Type> {
+    if let syn::Type::Path(type_path) = ty {
+        if let Some(last_segment) = type_path.path.segments.last() {
+            if last_segment.ident == "Option" {
+                if let syn::PathArguments::AngleBracketed(args) = &last_segment.arguments {
+                    if let Some(syn::GenericArgument::Type(inner)) = args.args.first() {
+                        return Some(inner);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Checks if a type is a known scalar primitive that doesn't support labels.
+/// Returns true for common numeric primitives: u64, f64, i32, etc., also when
+/// wrapped in `Option`.
+/// Note: This doesn't catch all scalar types (custom wrappers, type aliases),
+/// but catches the most common cases.
+fn is_known_scalar_primitive(ty: &syn::Type) -> bool {
+    if let Some(inner) = option_inner_type(ty) {
+        return is_known_scalar_primitive(inner);
+    }
+    if let syn::Type::Path(type_path) = ty {
+        if let Some(last_segment) = type_path.path.segments.last() {
+            let type_name = last_segment.ident.to_string();
+            return matches!(
+                type_name.as_str(),
+                "u64" | "i64" | "u32" | "i32" | "u16" | "i16" | "u8" | "i8" |
+                "f64" | "f32" | "usize" | "isize" | "bool"
+            );
+        }
+    }
+    false
+}
+
+pub fn parse_metric_attrs(attr: &syn::Attribute, attrs: &mut MetricAttrs) -> Result<()> {
+    if let Meta::Path(_) = attr.meta {
+        // Bare `#[counter]` without arguments
+        return Ok(());
+    }
+
+    attr.parse_nested_meta(|meta| parse_metric_key(&meta, attrs))
+}
+
+/// Parses one `key = value` of a metric attribute into `attrs`
+fn parse_metric_key(meta: &syn::meta::ParseNestedMeta, attrs: &mut MetricAttrs) -> Result<()> {
+    let key = lookup_key(meta, METRIC_ATTR_KEYS)?;
+    if attrs.spans.insert(key, meta.path.span()).is_some() {
+        return Err(meta.error(format!("duplicate '{}' attribute", key)));
+    }
+
+    if meta.path.is_ident("help") {
+        attrs.help = Some(StrValue::parse(meta, validate_help)?);
+        Ok(())
+    } else if meta.path.is_ident("name") {
+        attrs.name_override = Some(StrValue::parse(meta, validate_name)?);
+        Ok(())
+    } else if meta.path.is_ident("label") {
+        let value = meta.value()?;
+        let s: syn::LitStr = value.parse()?;
+        validate_label_name(&s.value()).map_err(|msg| Error::new_spanned(&s, msg))?;
+        attrs.label_override = Some(s.value());
+        Ok(())
+    } else if meta.path.is_ident("labels") {
+        let value = meta.value()?;
+        let array: syn::ExprArray = value.parse()?;
+        let mut names = Vec::new();
+        for elem in &array.elems {
+            let Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            }) = elem
+            else {
+                return Err(Error::new_spanned(elem, "expected a string literal"));
+            };
+            validate_label_name(&s.value()).map_err(|msg| Error::new_spanned(s, msg))?;
+            names.push(s.value());
+        }
+        if names.len() < 2 {
+            return Err(Error::new_spanned(
+                &array,
+                "'labels' needs at least two label names, use 'label' for a single one",
+            ));
+        }
+        attrs.label_names = Some((names, array.span()));
+        Ok(())
+    } else if meta.path.is_ident("unit") {
+        let value = meta.value()?;
+        let s: syn::LitStr = value.parse()?;
+        if !BASE_UNITS.contains(&s.value().as_str()) {
+            return Err(Error::new_spanned(
+                &s,
+                format!(
+                    "unknown unit '{}', expected one of: {}",
+                    s.value(),
+                    BASE_UNITS.join(", ")
+                ),
+            ));
+        }
+        attrs.unit = Some(s.value());
+        Ok(())
+    } else if meta.path.is_ident("no_suffix") {
+        attrs.no_suffix = true;
+        Ok(())
+    } else if meta.path.is_ident("no_prefix") {
+        attrs.no_prefix = true;
+        Ok(())
+    } else if meta.path.is_ident("skip_zero") {
+        attrs.skip_zero = true;
+        Ok(())
+    } else if meta.path.is_ident("raw_labels") {
+        attrs.raw_labels = true;
+        Ok(())
+    } else if meta.path.is_ident("sorted") {
+        attrs.sorted = true;
+        Ok(())
+    } else if meta.path.is_ident("max_label_len") {
+        let n: syn::LitInt = meta.value()?.parse()?;
+        let max_len = n.base10_parse()?;
+        if max_len == 0 {
+            return Err(Error::new_spanned(&n, "'max_label_len' must be at least 1"));
+        }
+        attrs.max_label_len = Some(max_len);
+        Ok(())
+    } else if meta.path.is_ident("prefix") {
+        attrs.prefix = Some(StrValue::parse(meta, validate_prefix)?);
+        Ok(())
+    } else if meta.path.is_ident("alias") {
+        match meta.value()?.parse()? {
+            Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            }) => {
+                validate_name(&s.value()).map_err(|msg| Error::new_spanned(&s, msg))?;
+                attrs.aliases.push(s.value());
+            }
+            Expr::Array(array) if !array.elems.is_empty() => {
+                for elem in &array.elems {
+                    let Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(s),
+                        ..
+                    }) = elem
+                    else {
+                        return Err(Error::new_spanned(elem, "expected a string literal"));
+                    };
+                    validate_name(&s.value()).map_err(|msg| Error::new_spanned(s, msg))?;
+                    attrs.aliases.push(s.value());
+                }
+            }
+            expr => {
+                return Err(Error::new_spanned(
+                    expr,
+                    "expected a string literal or an array of string literals",
+                ));
+            }
+        }
+        Ok(())
+    } else if meta.path.is_ident("buckets") {
+        attrs.buckets = Some(meta.value()?.parse()?);
+        Ok(())
+    } else if meta.path.is_ident("compute") {
+        let s: syn::LitStr = meta.value()?.parse()?;
+        attrs.compute = Some(s.parse()?);
+        Ok(())
+    } else if meta.path.is_ident("allow") {
+        meta.parse_nested_meta(|lint| {
+            if !lint.path.is_ident("naming") {
+                return Err(lint.error("unknown lint, the only supported value is 'naming'"));
+            }
+            attrs.allow_naming = true;
+            Ok(())
+        })
+    } else {
+        unreachable!("all keys of METRIC_ATTR_KEYS are handled")
+    }
+}
+
+/// Keys accepted by `#[counter(...)]`, `#[gauge(...)]`, `#[histogram(...)]` and `#[group(...)]`.
+const METRIC_ATTR_KEYS: &[&str] = &[
+    "help",
+    "name",
+    "label",
+    "labels",
+    "unit",
+    "no_suffix",
+    "no_prefix",
+    "skip_zero",
+    "raw_labels",
+    "sorted",
+    "max_label_len",
+    "prefix",
+    "compute",
+    "alias",
+    "buckets",
+    "allow",
+];
+
+/// Keys accepted by `#[dynamic(...)]`. The metric names come from the data, so the keys
+/// shaping a single name, its labels or its value are not accepted.
+const DYNAMIC_ATTR_KEYS: &[&str] = &[
+    "kind",
+    "help",
+    "prefix",
+    "no_prefix",
+    "no_suffix",
+    "skip_zero",
+];
+
+/// Parses `#[dynamic(kind = "...", ...)]` into `attrs`, returning the kind
+fn parse_dynamic_attrs(attr: &syn::Attribute, attrs: &mut MetricAttrs) -> Result<MetricType> {
+    let mut kind = None;
+    if !matches!(attr.meta, Meta::Path(_)) {
+        attr.parse_nested_meta(|meta| {
+            if lookup_key(&meta, DYNAMIC_ATTR_KEYS)? != "kind" {
+                return parse_metric_key(&meta, attrs);
+            }
+            if kind.is_some() {
+                return Err(meta.error("duplicate 'kind' attribute"));
+            }
+            let s: syn::LitStr = meta.value()?.parse()?;
+            kind = Some(match s.value().as_str() {
+                "counter" => MetricType::Counter,
+                "gauge" => MetricType::Gauge,
+                _ => return Err(Error::new_spanned(&s, "expected 'counter' or 'gauge'")),
+            });
+            Ok(())
+        })?;
+    }
+    kind.ok_or_else(|| {
+        Error::new_spanned(
+            attr,
+            "dynamic fields need a kind, e.g. #[dynamic(kind = \"gauge\")]",
+        )
+    })
+}
+
+/// Kinds accepted by `#[metric(kind = "...")]`
+const GENERIC_KINDS: &[&str] = &["counter", "gauge", "histogram", "untyped"];
+
+/// Parses `#[metric(kind = "...", ...)]`, the spelling of `#[counter(...)]`,
+/// `#[gauge(...)]` and `#[histogram(...)]` taking the kind as a value, into `attrs`.
+/// Returns the kind, and whether it is `untyped`, which renders like a gauge.
+fn parse_generic_attrs(
+    attr: &syn::Attribute,
+    attrs: &mut MetricAttrs,
+) -> Result<(MetricType, bool)> {
+    let mut kind = None;
+    if !matches!(attr.meta, Meta::Path(_)) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("kind") {
+                return parse_metric_key(&meta, attrs);
+            }
+            if kind.is_some() {
+                return Err(meta.error("duplicate 'kind' attribute"));
+            }
+            let s: syn::LitStr = meta.value()?.parse()?;
+            kind = Some(match s.value().as_str() {
+                "counter" => (MetricType::Counter, false),
+                "gauge" => (MetricType::Gauge, false),
+                "histogram" => (MetricType::Histogram, false),
+                "untyped" => (MetricType::Gauge, true),
+                "summary" => {
+                    return Err(Error::new_spanned(
+                        &s,
+                        "summaries are not supported, use kind = \"histogram\"",
+                    ));
+                }
+                other => {
+                    return Err(Error::new_spanned(
+                        &s,
+                        format!(
+                            "unknown metric kind '{}', expected one of: {}",
+                            other,
+                            GENERIC_KINDS.join(", ")
+                        ),
+                    ));
+                }
+            });
+            Ok(())
+        })?;
+    }
+    kind.ok_or_else(|| {
+        Error::new_spanned(
+            attr,
+            format!(
+                "#[metric] needs a kind, one of: {}",
+                GENERIC_KINDS.join(", ")
+            ),
+        )
+    })
+}
+
+/// Base units recommended by the Prometheus naming conventions.
+const BASE_UNITS: &[&str] = &[
+    "seconds", "bytes", "ratio", "meters", "grams", "celsius", "volts", "amperes", "joules",
+];
+
+/// Prefixes are joined to the rest of the name with `_`, so a trailing `_` is rejected
+/// rather than producing a double underscore. Each dot-separated part is checked.
+fn validate_prefix(s: &str) -> std::result::Result<(), String> {
+    for part in s.split('.') {
+        if part.is_empty() {
+            return Err("prefix parts must not be empty".to_string());
+        }
+        if part.ends_with('_') {
+            return Err(format!(
+                "prefix '{}' must not end with '_', a separator is added automatically",
+                s
+            ));
+        }
+        if !aetos_core::validate_metric_name(part) {
+            return Err(format!(
+                "invalid prefix '{}': must match [a-zA-Z_:][a-zA-Z0-9_:]*, with '.' as separator",
+                s
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the characters of a literal `name` or `alias`. It may start with a digit when
+/// the field has a prefix, so the final name is checked once built, see
+/// `check_final_names`.
+fn validate_name(s: &str) -> std::result::Result<(), String> {
+    // Valid after a prefix and its `_` separator
+    if s.is_empty() || !aetos_core::validate_metric_name(&format!("_{}", s)) {
+        return Err("invalid metric name: must match [a-zA-Z0-9_:]+".to_string());
+    }
+    Ok(())
+}
+
+/// Checks a literal `help`: line feeds and backslashes are escaped in the output, but
+/// other control characters would end up verbatim in the `# HELP` line
+fn validate_help(s: &str) -> std::result::Result<(), String> {
+    match s
+        .chars()
+        .enumerate()
+        .find(|&(_, c)| c.is_control() && c != '\n')
+    {
+        Some((position, c)) => Err(format!(
+            "help text contains the control character U+{:04X} at position {} (counting \
+             characters from 0); only line feeds are allowed",
+            c as u32, position
+        )),
+        None => Ok(()),
+    }
+}
+
+fn validate_label_name(s: &str) -> std::result::Result<(), &'static str> {
+    if s == "le" {
+        return Err("the label name 'le' is reserved for histogram bucket boundaries");
+    }
+    if s == "quantile" {
+        return Err("the label name 'quantile' is reserved for summary quantiles");
+    }
+    if s.starts_with("__") {
+        return Err("label names starting with '__' are reserved for internal use");
+    }
+    if !aetos_core::validate_label_name(s) {
+        return Err("invalid label name: must match [a-zA-Z_][a-zA-Z0-9_]*");
+    }
+    Ok(())
+}
+
+/// Renders the family of the field at `index`: its `render_field` arm, or with
+/// `allow_shared_names`, the header of the family followed by the samples of every field
+/// sharing it.
+fn render_family(field: &MetricField, index: usize, struct_attrs: &StructAttrs) -> TokenStream {
+    if field.shared_with.is_empty() {
+        return quote! {
+            ::aetos::core::RenderField::render_field(self, #index, f)?;
+        };
+    }
+
+    let name = metric_name_expr(field, struct_attrs);
+    let kind = field.kind();
+    let help = match &field.help {
+        Some(help) => quote! { Some(#help) },
+        None => quote! { None },
+    };
+    let unit = match &field.unit {
+        Some(unit) => quote! { Some(#unit) },
+        None => quote! { None },
+    };
+    let indices = std::iter::once(index).chain(field.shared_with.iter().copied());
+    let meta = metadata_expr(name, help, quote! { #kind }, [("unit", unit)]);
+    quote! {
+        let meta = #meta;
+        meta.write_header(f)?;
+        #(::aetos::core::RenderField::render_field(self, #indices, f)?;)*
+    }
+}
+
+/// Renders the families of a `#[dynamic]` field passing `filter`, a `&dyn Fn(&str) -> bool`
+/// in scope of the generated code. The names are only known at runtime, so the prefixes
+/// are joined to each key there, and the entries named like a family of `metric_names()`
+/// are skipped there.
+fn render_dynamic_call(
+    field: &MetricField,
+    struct_attrs: &StructAttrs,
+    filter: TokenStream,
+) -> TokenStream {
+    let member = &field.member;
+    let (prefixes, _, total_suffix) = metric_name_parts(field, struct_attrs);
+    let kind = field.kind();
+    let help = match &field.help {
+        Some(help) => quote! { Some(#help) },
+        None => quote! { None },
+    };
+    let timestamp = match &struct_attrs.timestamp {
+        Some(ts_field) => quote! { ::std::option::Option::<u64>::from(self.#ts_field) },
+        None => quote! { None },
+    };
+    let (const_label_names, const_label_values) = const_label_tokens(struct_attrs);
+    let skip_zero = field.skip_zero;
+
+    // Spanned on the field type, so that a field that is not a collection of
+    // `(name, value)` pairs fails to compile at the field
+    let render = quote_spanned! {field.ty.span()=>
+        ::aetos::core::render_dynamic(value, f, &[#(#prefixes),*], #total_suffix, &meta, &filter)?;
+    };
+    let unwrap = unwrap_value(field);
+    let render = if field.optional {
+        quote! {
+            if let Some(value) = &self.#member {
+                #unwrap
+                #render
+            }
+        }
+    } else {
+        quote! {
+            let value = &self.#member;
+            #unwrap
+            #render
+        }
+    };
+    let meta = metadata_expr(
+        quote! { "" },
+        help,
+        quote! { #kind },
+        [
+            ("timestamp", timestamp),
+            (
+                "const_labels",
+                quote! { &[#((#const_label_names, #const_label_values)),*] },
+            ),
+            ("skip_zero", quote! { #skip_zero }),
+        ],
+    );
+    quote! {
+        {
+            let meta = #meta;
+            // Entries named like a family of another field would render it twice
+            let filter: &dyn Fn(&str) -> bool = #filter;
+            let filter = |name: &str| !Self::metric_names().contains(&name) && filter(name);
+            #render
+        }
+    }
+}
+
+/// The call rendering a counter, gauge, histogram or group family from `wrapper`, a
+/// `MetricWrapper` of the value, and `meta`
+fn render_call(field: &MetricField) -> TokenStream {
+    match field.metric_type {
+        // Called through the trait, spanned on the field type, so that using
+        // `#[histogram]` on a non-histogram type fails to compile at the field
+        MetricType::Histogram => quote_spanned! {field.ty.span()=>
+            ::aetos::core::HistogramMetric::render_histogram(wrapper.0, f, &meta)
+        },
+        MetricType::Nested => unreachable!("nested fields are rendered by the caller"),
+        MetricType::Group => match &field.field_type {
+            FieldType::SingleLabel { label_name } => {
+                let label_name = label_name.clone().unwrap_or_else(|| field.name());
+                quote! { wrapper.render_group_with_label_attr(f, &meta, #label_name) }
+            }
+            _ => quote! { wrapper.render_group_with_struct_key(f, &meta) },
+        },
+        _ => match &field.field_type {
+            FieldType::SingleLabel { label_name } => {
+                let label_name = label_name.clone().unwrap_or_else(|| field.name());
+                quote! { wrapper.render_with_label_attr(f, &meta, #label_name) }
+            }
+            FieldType::MultiLabel { label_names } => {
+                quote! { wrapper.render_with_label_names(f, &meta, &[#(#label_names),*]) }
+            }
+            FieldType::Unspecified => quote! { wrapper.render_with_struct_key(f, &meta) },
+        },
+    }
+}
+
+fn generate_display_impl(
+    name: &syn::Ident,
+    generics: &syn::Generics,
+    fields: &[MetricField],
+    struct_attrs: &StructAttrs,
+) -> Result<TokenStream> {
+    let mut fmt_arms = Vec::new();
+    let mut fmt_calls = Vec::new();
+
+    let timestamp = match &struct_attrs.timestamp {
+        Some(ts_field) => quote! { ::std::option::Option::<u64>::from(self.#ts_field) },
+        None => quote! { None },
+    };
+    let (const_label_names, const_label_values) = const_label_tokens(struct_attrs);
+
+    for (index, field) in fields.iter().enumerate() {
+        let member = &field.member;
+        let cfgs = &field.cfgs;
+
+        let metric_name = metric_name_expr(field, struct_attrs);
+        let aliases = alias_name_exprs(field, struct_attrs);
+        let skip_zero = field.skip_zero;
+        let raw_labels = field.raw_labels;
+        let sorted = field.sorted;
+        let no_header = field.is_shared();
+        let total_suffix = group_total_suffix(field, struct_attrs);
+        let max_label_len = match field.max_label_len {
+            Some(max_len) => quote! { Some(#max_len) },
+            None => quote! { None },
+        };
+        let help = match &field.help {
+            Some(help) => quote! { Some(#help) },
+            None => quote! { None },
+        };
+        let unit = match &field.unit {
+            Some(unit) => quote! { Some(#unit) },
+            None => quote! { None },
+        };
+        let metric_type_str = field.kind();
+        let meta = metadata_expr(
+            quote! { name },
+            help,
+            quote! { #metric_type_str },
+            [
+                ("timestamp", timestamp.clone()),
+                ("unit", unit),
+                (
+                    "const_labels",
+                    quote! { &[#((#const_label_names, #const_label_values)),*] },
+                ),
+                ("skip_zero", quote! { #skip_zero }),
+                ("raw_labels", quote! { #raw_labels }),
+                ("sorted", quote! { #sorted }),
+                ("max_label_len", max_label_len),
+                ("no_header", quote! { #no_header }),
+                ("total_suffix", quote! { #total_suffix }),
+            ],
+        );
+
+        // `None` fields render nothing at all, not even HELP/TYPE
+        let wrapper_init = if let Some(compute) = &field.compute {
+            quote! {
+                let value = self.#compute();
+                let wrapper = MetricWrapper(&value);
+            }
+        } else if field.lazy || field.atomic || field.seconds || field.flag {
+            let unwrap = unwrap_value(field);
+            let value = if field.optional {
+                quote! {
+                    let Some(value) = &self.#member else {
+                        return Ok(());
+                    };
+                    #unwrap
+                }
+            } else {
+                quote! {
+                    let value = &self.#member;
+                    #unwrap
+                }
+            };
+            let loaded = if field.atomic {
+                quote! { ::aetos::core::AtomicLoad::load_relaxed(value) }
+            } else if field.seconds {
+                quote! { ::aetos::core::Seconds(value) }
+            } else if field.flag {
+                quote! { ::aetos::core::Flag(value) }
+            } else if matches!(&field.ty, syn::Type::Path(p) if p.path.get_ident().is_some()) {
+                // A type parameter bounded by `Fn`
+                quote! { ::aetos::core::LazyMetric(value) }
+            } else {
+                // `&Arc<dyn Fn()>` and `&Rc<dyn Fn()>` are not callable, `&dyn Fn()` is
+                quote! { ::aetos::core::LazyMetric(&**value) }
+            };
+            quote! {
+                #value
+                let value = #loaded;
+                let wrapper = MetricWrapper(&value);
+            }
+        } else if field.optional {
+            let unwrap = unwrap_value(field);
+            quote! {
+                let Some(value) = &self.#member else {
+                    return Ok(());
+                };
+                #unwrap
+                let wrapper = MetricWrapper(value);
+            }
+        } else if !field.wrappers.is_empty() {
+            let unwrap = unwrap_value(field);
+            quote! {
+                let value = &self.#member;
+                #unwrap
+                let wrapper = MetricWrapper(value);
+            }
+        } else {
+            quote! {
+                let wrapper = MetricWrapper(&self.#member);
+            }
+        };
+
+        if field.dynamic {
+            let render = render_dynamic_call(field, struct_attrs, quote! { filter });
+            fmt_calls.push(quote! {
+                #(#cfgs)*
+                #render
+            });
+            continue;
+        }
+
+        if let MetricType::Nested = field.metric_type {
+            let unwrap = unwrap_value(field);
+            let call = quote! {
+                #unwrap
+                ::aetos::core::PrometheusMetric::fmt_filtered(value, f, filter)?;
+            };
+            fmt_calls.push(if field.optional {
+                quote! {
+                    #(#cfgs)*
+                    if let Some(value) = &self.#member {
+                        #call
+                    }
+                }
+            } else {
+                quote! {
+                    #(#cfgs)*
+                    {
+                        let value = &self.#member;
+                        #call
+                    }
+                }
+            });
+            continue;
+        }
+
+        let render_call = render_call(field);
+
+        // Without this, a label on a non-collection type silently falls back to
+        // `RenderScalarFallback` and is ignored
+        let label_check = match (&field.metric_type, &field.field_type) {
+            (
+                MetricType::Counter | MetricType::Gauge,
+                FieldType::SingleLabel { .. } | FieldType::MultiLabel { .. },
+            ) => match &field.compute {
+                // The return type of the method is only known to the compiler, so the
+                // check is on the computed value
+                Some(compute) => quote_spanned! {compute.span()=>
+                    ::aetos::core::assert_labeled_value(&value);
+                },
+                None => {
+                    let ty = &field.ty;
+                    quote_spanned! {ty.span()=>
+                        ::aetos::core::assert_labeled_collection::<#ty>();
+                    }
+                }
+            },
+            _ => quote! {},
+        };
+
+        let arm = quote! {
+            #(#cfgs)*
+            #index => {
+                #wrapper_init
+                #label_check
+
+                // The family is rendered once under its name, then once per alias
+                for name in [#metric_name, #(#aliases),*] {
+                    let meta = #meta;
+                    #render_call?;
+                }
+                Ok(())
+            }
+        };
+
+        // Histograms are matched by their base name, groups by the name of any member,
+        // and aliased families by their name or any alias
+        let ty = &field.ty;
+        let passes = match field.metric_type {
+            MetricType::Group => quote! {
+                ::aetos::core::group_metric_names::<#ty, _>(#metric_name, #total_suffix)
+                    .any(|name| filter(&name))
+            },
+            _ => quote! { filter(#metric_name) #(|| filter(#aliases))* },
+        };
 
-    for field in fields {
-        if let Some(metric_field) = parse_field(field)? {
-            metric_fields.push(metric_field);
+        fmt_arms.push(arm);
+        if field.continues_family {
+            continue;
         }
+        let render = render_family(field, index, struct_attrs);
+        fmt_calls.push(quote! {
+            #(#cfgs)*
+            if #passes {
+                #render
+            }
+        });
     }
 
-    if let Data::Struct(ref mut data) = input.data {
-        if let Fields::Named(ref mut fields) = data.fields {
-            for field in &mut fields.named {
-                field.attrs.retain(|attr| {
-                    !attr.path().is_ident("counter")
-                        && !attr.path().is_ident("gauge")
-                        && !attr.path().is_ident("histogram")
-                });
+    let metric_names = generate_metric_names(fields, struct_attrs);
+    let describe = generate_describe(fields, struct_attrs);
+    let catalog = generate_catalog(fields, struct_attrs);
+    let render_one = generate_render_one(fields, struct_attrs);
+    let prefix_checks = prefix_checks(fields, struct_attrs);
+    let size_hint = generate_size_hint(fields, struct_attrs);
+
+    let generics = bounded_generics(generics, fields, struct_attrs);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    // With `display = false` the struct keeps `Display` free for its own use, and the
+    // Prometheus rendering is reachable through inherent methods instead
+    let (render_methods, display) = if struct_attrs.no_display {
+        let methods = quote! {
+            /// Writes every metric family in the Prometheus text format.
+            pub fn fmt_prometheus(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                ::aetos::core::PrometheusMetric::render(self, f)
             }
-        }
-    }
 
-    let original_struct = quote! {
-        #input
+            /// Renders every metric family in the Prometheus text format.
+            pub fn render_prometheus(&self) -> String {
+                ::aetos::core::DisplayFn(|f: &mut std::fmt::Formatter<'_>| self.fmt_prometheus(f))
+                    .to_string()
+            }
+        };
+        (methods, quote! {})
+    } else {
+        let display = quote! {
+            impl #impl_generics std::fmt::Display for #name #ty_generics #where_clause {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    ::aetos::core::PrometheusMetric::render(self, f)
+                }
+            }
+        };
+        (quote! {}, display)
     };
 
-    let display_impl =
-        generate_display_impl(name, &input.generics, &metric_fields, prefix.as_deref())?;
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #metric_names
 
-    let output = quote! {
-        #original_struct
-        #display_impl
-    };
-    Ok(output)
-}
+            #describe
 
-fn parse_struct_attrs(args: TokenStream) -> Result<Option<String>> {
-    if args.is_empty() {
-        return Ok(None);
-    }
+            #catalog
 
-    let meta: Meta = parse2(args)?;
+            #render_one
 
-    match meta {
-        Meta::List(list) => {
-            for nested in list.parse_args_with(
-                syn::punctuated::Punctuated::<Meta, syn::Token![,]>::parse_terminated,
-            )? {
-                if let Meta::NameValue(nv) = nested {
-                    if nv.path.is_ident("prefix") {
-                        if let Expr::Lit(ExprLit {
-                            lit: Lit::Str(s), ..
-                        }) = &nv.value
-                        {
-                            return Ok(Some(s.value()));
-                        }
-                    }
-                }
-            }
+            #render_methods
         }
-        Meta::NameValue(nv) => {
-            if nv.path.is_ident("prefix") {
-                if let Expr::Lit(ExprLit {
-                    lit: Lit::Str(s), ..
-                }) = &nv.value
-                {
-                    return Ok(Some(s.value()));
+
+        impl #impl_generics ::aetos::core::RenderField for #name #ty_generics #where_clause {
+            fn render_field(
+                &self,
+                index: usize,
+                f: &mut std::fmt::Formatter<'_>,
+            ) -> std::fmt::Result {
+                #[allow(unused_imports)]
+                use ::aetos::core::{MetricWrapper, MetricMetadata, RenderScalarFallback};
+
+                match index {
+                    #(#fmt_arms)*
+                    _ => Ok(()),
                 }
             }
         }
-        _ => {}
-    }
 
-    Ok(None)
-}
+        #display
 
-fn parse_field(field: &syn::Field) -> Result<Option<MetricField>> {
-    let mut metric_type = None;
-    let mut help = None;
-    let mut name_override = None;
-    let mut label_override = None;
+        impl #impl_generics ::aetos::core::PrometheusMetric for #name #ty_generics #where_clause {
+            fn render(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                ::aetos::core::PrometheusMetric::fmt_filtered(self, f, &|_| true)
+            }
 
-    for attr in &field.attrs {
-        if attr.path().is_ident("counter") {
-            metric_type = Some(MetricType::Counter);
-            parse_metric_attrs(attr, &mut help, &mut name_override, &mut label_override)?;
-        } else if attr.path().is_ident("gauge") {
-            metric_type = Some(MetricType::Gauge);
-            parse_metric_attrs(attr, &mut help, &mut name_override, &mut label_override)?;
-        } else if attr.path().is_ident("histogram") {
-            metric_type = Some(MetricType::Histogram);
-            parse_metric_attrs(attr, &mut help, &mut name_override, &mut label_override)?;
+            fn fmt_filtered(
+                &self,
+                f: &mut std::fmt::Formatter<'_>,
+                filter: &dyn Fn(&str) -> bool,
+            ) -> std::fmt::Result {
+                #prefix_checks
+                #(#fmt_calls)*
+                Ok(())
+            }
+
+            #size_hint
         }
-    }
+    })
+}
 
-    let metric_type = match metric_type {
-        Some(mt) => mt,
-        None => return Ok(None),
+/// Adds the bounds needed to render the fields whose type uses a type parameter of the
+/// struct, like serde does. `#[metrics(bound = "...")]` replaces the inferred bounds.
+///
+/// Scalars get `Display` and histograms `HistogramMetric`. For collections, the key and
+/// value types get the bounds of the label style in use. Nested fields and collections
+/// of other shapes get no bound. Every type parameter must be `'static` either way, as
+/// the generated functions cache their values by the `TypeId` of the struct.
+fn bounded_generics(
+    generics: &syn::Generics,
+    fields: &[MetricField],
+    struct_attrs: &StructAttrs,
+) -> syn::Generics {
+    let mut generics = generics.clone();
+    let params: Vec<syn::Ident> = generics.type_params().map(|p| p.ident.clone()).collect();
+    let mut predicates: Vec<syn::WherePredicate> = match &struct_attrs.bound {
+        Some(bound) => bound.clone(),
+        None => {
+            let params: Vec<&syn::Ident> = params.iter().collect();
+            fields
+                .iter()
+                .flat_map(|field| field_bounds(field, &params))
+                .collect()
+        }
     };
+    predicates.extend(params.iter().map(|param| -> syn::WherePredicate {
+        syn::parse_quote! { #param: 'static }
+    }));
+    generics.make_where_clause().predicates.extend(predicates);
+    generics
+}
 
-    let help = help.ok_or_else(|| {
-        Error::new_spanned(field, "counter/gauge attribute requires 'help' parameter")
-    })?;
+/// The bounds `field` needs to be rendered, see `bounded_generics`
+fn field_bounds(field: &MetricField, params: &[&syn::Ident]) -> Vec<syn::WherePredicate> {
+    // Closures are bounded by the struct itself
+    if field.compute.is_some() || field.lazy || field.dynamic {
+        return Vec::new();
+    }
+    let ty = &field.ty;
+    let used: Vec<&syn::Ident> = params
+        .iter()
+        .copied()
+        .filter(|param| mentions(ty.to_token_stream(), param))
+        .collect();
+    if used.is_empty() {
+        return Vec::new();
+    }
 
-    let ident = field.ident.as_ref().unwrap().clone();
+    let is_param =
+        matches!(ty, syn::Type::Path(p) if p.qself.is_none() && p.path.get_ident().is_some());
 
-    // Validate that histograms don't use the label attribute
-    if let MetricType::Histogram = metric_type {
-        if label_override.is_some() {
-            return Err(Error::new_spanned(
-                field,
-                "histogram metrics do not support 'label' attribute - labels are defined in the histogram type (e.g. Histogram<MyLabel, N>)"
-            ));
+    let (key_bound, value_bound) = match (&field.metric_type, &field.field_type) {
+        (MetricType::Nested, _) => return Vec::new(),
+        (MetricType::Histogram, _) => {
+            return vec![syn::parse_quote! { #ty: ::aetos::core::HistogramMetric }];
         }
+        (MetricType::Counter | MetricType::Gauge, FieldType::Unspecified) if is_param => {
+            return vec![syn::parse_quote! { #ty: ::std::fmt::Display }];
+        }
+        (MetricType::Group, FieldType::SingleLabel { .. }) => (
+            quote! { ::std::fmt::Display },
+            quote! { ::aetos::core::MetricGroup },
+        ),
+        (MetricType::Group, _) => (
+            quote! { ::aetos::core::Label },
+            quote! { ::aetos::core::MetricGroup },
+        ),
+        (_, FieldType::SingleLabel { .. }) => (
+            quote! { ::std::fmt::Display },
+            quote! { ::std::fmt::Display },
+        ),
+        (_, FieldType::MultiLabel { label_names }) => {
+            let n = label_names.len();
+            (
+                quote! { ::aetos::core::TupleLabelValues<#n> },
+                quote! { ::std::fmt::Display },
+            )
+        }
+        (_, FieldType::Unspecified) => (
+            quote! { ::aetos::core::Label },
+            quote! { ::std::fmt::Display },
+        ),
+    };
+
+    let Some((key, Some(value))) = collection_key_value(ty) else {
+        return Vec::new();
+    };
+    let mut bounds: Vec<syn::WherePredicate> = vec![syn::parse_quote! { #key: #key_bound }];
+    // Values rendered through `Seconds` or `Flag` do not need `Display`
+    if !field.seconds && !field.flag {
+        bounds.push(syn::parse_quote! { #value: #value_bound });
     }
+    bounds
+}
 
-    // Validate that known scalar primitives don't use the label attribute
-    if !matches!(metric_type, MetricType::Histogram) {
-        if label_override.is_some() && is_known_scalar_primitive(&field.ty) {
-            return Err(Error::new_spanned(
-                field,
-                "the 'label' attribute is not supported on scalar types like u64, f64, etc. \
-                 Labels are only supported on collection types that implement IntoIterator. \
-                 To use labels, change this field to Vec, HashMap, BTreeMap, or another iterable collection."
-            ));
-        }
+/// Whether `tokens` contain `ident`
+fn mentions(tokens: TokenStream, ident: &syn::Ident) -> bool {
+    tokens.into_iter().any(|tt| match tt {
+        proc_macro2::TokenTree::Ident(i) => i == *ident,
+        proc_macro2::TokenTree::Group(group) => mentions(group.stream(), ident),
+        _ => false,
+    })
+}
+
+/// Generates `metric_names()`, listing the final name of every metric family.
+///
+/// Nested structs contribute their own names and groups one name per member. With
+/// `histogram_series`, histograms also list their `_bucket`, `_sum` and `_count` series.
+fn generate_metric_names(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let mut pushes = Vec::new();
+
+    for field in fields
+        .iter()
+        .filter(|field| !field.dynamic && !field.continues_family)
+    {
+        let cfgs = &field.cfgs;
+        let ty = &field.ty;
+        let name = metric_name_expr(field, struct_attrs);
+        let total_suffix = group_total_suffix(field, struct_attrs);
+        let aliases = alias_name_exprs(field, struct_attrs);
+
+        let push = match field.metric_type {
+            MetricType::Nested => quote! {
+                names.extend(<#ty>::metric_names().iter().map(|name| name.to_string()));
+            },
+            MetricType::Group => quote! {
+                names.extend(::aetos::core::group_metric_names::<#ty, _>(#name, #total_suffix));
+            },
+            MetricType::Histogram if struct_attrs.histogram_series => quote! {
+                for name in [#name, #(#aliases),*] {
+                    let name: &str = name;
+                    names.push(name.to_string());
+                    for suffix in ["_bucket", "_sum", "_count"] {
+                        names.push(format!("{}{}", name, suffix));
+                    }
+                }
+            },
+            _ => quote! {
+                names.push(::std::string::String::from(#name));
+                #(names.push(::std::string::String::from(#aliases));)*
+            },
+        };
+        pushes.push(quote! {
+            #(#cfgs)*
+            {
+                #push
+            }
+        });
     }
 
-    let field_type = match label_override {
-        Some(label_name) => FieldType::SingleLabel {
-            label_name: Some(label_name),
+    let names = cached(
+        struct_attrs,
+        quote! { ::std::vec::Vec<&'static str> },
+        quote! {
+            let mut names: ::std::vec::Vec<::std::string::String> = ::std::vec::Vec::new();
+            #(#pushes)*
+            names
+                .into_iter()
+                .map(|name| -> &'static str { name.leak() })
+                .collect()
         },
-        None => FieldType::Unspecified,
-    };
+    );
 
-    Ok(Some(MetricField {
-        ident,
-        field_type,
-        metric_type,
-        help,
-        name_override,
-    }))
+    quote! {
+        /// The names of all metric families rendered by this struct, in output order.
+        /// The families of `#[dynamic]` fields are not listed, as their names come from the data.
+        pub fn metric_names() -> &'static [&'static str] {
+            #names
+        }
+    }
 }
 
-/// Checks if a type is a known scalar primitive that doesn't support labels.
-/// Returns true for common numeric primitives: u64, f64, i32, etc.
-/// Note: This doesn't catch all scalar types (custom wrappers, type aliases),
-/// but catches the most common cases.
-fn is_known_scalar_primitive(ty: &syn::Type) -> bool {
-    if let syn::Type::Path(type_path) = ty {
-        if let Some(last_segment) = type_path.path.segments.last() {
-            let type_name = last_segment.ident.to_string();
-            return matches!(
-                type_name.as_str(),
-                "u64" | "i64" | "u32" | "i32" | "u16" | "i16" | "u8" | "i8" |
-                "f64" | "f32" | "usize" | "isize" | "bool"
+/// Generates `describe()`, the name, kind, help and unit of every metric family.
+///
+/// Entries follow declaration order. Nested structs contribute their own entries and
+/// groups one entry per member. No entry has a timestamp, as it is a per-instance value.
+/// Dynamic fields have no entries, as their names come from the data, and a family shared
+/// by several fields has one.
+fn generate_describe(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let mut pushes = Vec::new();
+
+    for field in fields
+        .iter()
+        .filter(|field| !field.dynamic && !field.continues_family)
+    {
+        let cfgs = &field.cfgs;
+        let ty = &field.ty;
+        let name = metric_name_expr(field, struct_attrs);
+        let total_suffix = group_total_suffix(field, struct_attrs);
+        let help = match &field.help {
+            Some(help) => quote! { Some(#help) },
+            None => quote! { None },
+        };
+        let unit = match &field.unit {
+            Some(unit) => quote! { Some(#unit) },
+            None => quote! { None },
+        };
+
+        let push = match field.metric_type {
+            MetricType::Nested => quote! {
+                entries.extend(<#ty>::describe().iter().map(|meta| {
+                    (meta.name.to_string(), meta.help, meta.kind, meta.unit, meta.const_labels)
+                }));
+            },
+            MetricType::Group => quote! {
+                let name: &str = #name;
+                for member in ::aetos::core::group_members::<#ty, _>() {
+                    entries.push((
+                        ::aetos::core::group_member_name(name, member, #total_suffix),
+                        member.help,
+                        member.kind,
+                        None,
+                        const_labels,
+                    ));
+                }
+            },
+            _ => {
+                let kind = field.kind();
+                let aliases = alias_name_exprs(field, struct_attrs);
+                quote! {
+                    for name in [#name, #(#aliases),*] {
+                        entries.push((
+                            ::std::string::String::from(name),
+                            #help,
+                            #kind,
+                            #unit,
+                            const_labels,
+                        ));
+                    }
+                }
+            }
+        };
+        pushes.push(quote! {
+            #(#cfgs)*
+            {
+                #push
+            }
+        });
+    }
+
+    let (const_label_names, const_label_values) = const_label_tokens(struct_attrs);
+    let entry_meta = metadata_expr(
+        quote! { name },
+        quote! { *help },
+        quote! { kind },
+        [
+            ("unit", quote! { *unit }),
+            ("const_labels", quote! { const_labels }),
+        ],
+    );
+    let metadata = cached(
+        struct_attrs,
+        quote! { ::std::vec::Vec<::aetos::core::MetricMetadata<'static>> },
+        quote! {
+            #[allow(unused_variables)]
+            let const_labels: Labels =
+                ::std::vec![#((#const_label_names, #const_label_values)),*].leak();
+            let mut entries: ::std::vec::Vec<Entry> = ::std::vec::Vec::new();
+            #(#pushes)*
+            let entries: &'static [Entry] = entries.leak();
+            entries
+                .iter()
+                .map(|(name, help, kind, unit, const_labels)| #entry_meta)
+                .collect()
+        },
+    );
+
+    quote! {
+        /// The metadata of all metric families rendered by this struct, in output order
+        pub fn describe() -> &'static [::aetos::core::MetricMetadata<'static>] {
+            type Labels = &'static [(&'static str, &'static str)];
+            type Entry = (
+                ::std::string::String,
+                ::std::option::Option<&'static str>,
+                &'static str,
+                ::std::option::Option<&'static str>,
+                Labels,
             );
+            #metadata
         }
     }
-    false
 }
 
-fn parse_metric_attrs(
-    attr: &syn::Attribute,
-    help: &mut Option<String>,
-    name_override: &mut Option<String>,
-    label_override: &mut Option<String>,
-) -> Result<()> {
-    attr.parse_nested_meta(|meta| {
-        if meta.path.is_ident("help") {
-            let value = meta.value()?;
-            let s: syn::LitStr = value.parse()?;
-            *help = Some(s.value());
-            Ok(())
-        } else if meta.path.is_ident("name") {
-            let value = meta.value()?;
-            let s: syn::LitStr = value.parse()?;
-            *name_override = Some(s.value());
-            Ok(())
-        } else if meta.path.is_ident("label") {
-            let value = meta.value()?;
-            let s: syn::LitStr = value.parse()?;
-            *label_override = Some(s.value());
-            Ok(())
-        } else {
-            Err(meta.error("unknown attribute"))
-        }
-    })
+/// A `MetricMetadata` expression, built with `MetricMetadata::builder` and then with
+/// `fields` assigned, as the struct is `#[non_exhaustive]`. `help` is an `Option<&str>`.
+fn metadata_expr<const N: usize>(
+    name: TokenStream,
+    help: TokenStream,
+    kind: TokenStream,
+    fields: [(&str, TokenStream); N],
+) -> TokenStream {
+    let assignments = fields.into_iter().map(|(field, value)| {
+        let field = quote::format_ident!("{}", field);
+        quote! { meta.#field = #value; }
+    });
+    quote! {{
+        let mut meta = ::aetos::core::MetricMetadata::builder(#name, #help, #kind).build();
+        #(#assignments)*
+        meta
+    }}
 }
 
-fn generate_display_impl(
-    name: &syn::Ident,
-    generics: &syn::Generics,
-    fields: &[MetricField],
-    prefix: Option<&str>,
-) -> Result<TokenStream> {
-    let mut fmt_methods = Vec::new();
-    let mut fmt_calls = Vec::new();
+/// A `&'static` reference to the value of type `ty` built by `init`, which is evaluated
+/// once. In generic impls, where a `static` would be shared by every instantiation, the
+/// value is cached per instantiation in a `TypeCache`.
+fn cached(struct_attrs: &StructAttrs, ty: TokenStream, init: TokenStream) -> TokenStream {
+    if struct_attrs.generic {
+        quote! {{
+            static __AETOS_CACHE: ::aetos::core::TypeCache<#ty> = ::aetos::core::TypeCache::new();
+            __AETOS_CACHE.get_or_init::<Self>(|| { #init })
+        }}
+    } else {
+        quote! {{
+            static __AETOS_CACHE: ::std::sync::OnceLock<#ty> = ::std::sync::OnceLock::new();
+            __AETOS_CACHE.get_or_init(|| { #init })
+        }}
+    }
+}
 
-    for field in fields {
-        let method_name = syn::Ident::new(&format!("fmt_{}", field.ident), field.ident.span());
-        let field_ident = &field.ident;
+/// Generates `metrics_catalog()` and `metrics_markdown()`, listing the families with
+/// their final name, type, help and label names, in declaration order.
+///
+/// Label names come from `label`/`labels`, or from `Label::label_names()` of the key
+/// type of collections and of the label type of histograms. Dynamic fields, whose
+/// families are only known at runtime, are not listed.
+fn generate_catalog(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let (const_label_names, _) = const_label_tokens(struct_attrs);
+    let mut pushes = Vec::new();
 
-        let metric_name = build_metric_name(&field.ident, field.name_override.as_deref(), prefix);
-        let help = &field.help;
-        let metric_type_str = match field.metric_type {
-            MetricType::Counter => "counter",
-            MetricType::Gauge => "gauge",
-            MetricType::Histogram => "histogram",
+    for field in fields
+        .iter()
+        .filter(|field| !field.dynamic && !field.continues_family)
+    {
+        let cfgs = &field.cfgs;
+        let ty = &field.ty;
+        let name = metric_name_expr(field, struct_attrs);
+        let total_suffix = group_total_suffix(field, struct_attrs);
+        let help = match &field.help {
+            Some(help) => quote! { Some(#help) },
+            None => quote! { None },
         };
 
-        let method_impl = match field.metric_type {
-            MetricType::Histogram => {
+        let push = match field.metric_type {
+            MetricType::Nested => quote! {
+                entries.extend(<#ty>::metrics_catalog());
+            },
+            MetricType::Group => {
+                let labels = catalog_label_names(field);
                 quote! {
-                    fn #method_name(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                        use ::aetos::core::{MetricWrapper, MetricMetadata, RenderScalarFallback};
-
-                        let meta = MetricMetadata {
-                            name: #metric_name,
+                    let name: &str = #name;
+                    for member in ::aetos::core::group_members::<#ty, _>() {
+                        entries.push(::aetos::core::CatalogEntry {
+                            name: ::aetos::core::group_member_name(name, member, #total_suffix),
+                            kind: member.kind,
+                            labels: with_const_labels(#labels),
+                            help: member.help,
+                        });
+                    }
+                }
+            }
+            _ => {
+                let kind = field.kind();
+                let aliases = alias_name_exprs(field, struct_attrs);
+                // A shared family has the labels of all its fields
+                let labels = field
+                    .shared_with
+                    .iter()
+                    .map(|&index| &fields[index])
+                    .chain(std::iter::once(field))
+                    .map(catalog_label_names);
+                quote! {
+                    let parts: &[&[&'static str]] = &[#(#labels),*];
+                    let mut labels: ::std::vec::Vec<&'static str> = ::std::vec::Vec::new();
+                    for label in parts.concat() {
+                        if !labels.contains(&label) {
+                            labels.push(label);
+                        }
+                    }
+                    for name in [#name, #(#aliases),*] {
+                        entries.push(::aetos::core::CatalogEntry {
+                            name: ::std::string::String::from(name),
+                            kind: #kind,
+                            labels: with_const_labels(&labels),
                             help: #help,
-                            kind: "histogram",
-                        };
-
-                        let wrapper = MetricWrapper(&self.#field_ident);
-                        wrapper.render_histogram(f, &meta)
+                        });
                     }
                 }
             }
-            _ => match &field.field_type {
-                FieldType::SingleLabel { label_name } => {
-                    let label_name = label_name
-                        .clone()
-                        .unwrap_or_else(|| field.ident.to_string());
+        };
+        pushes.push(quote! {
+            #(#cfgs)*
+            {
+                #push
+            }
+        });
+    }
 
-                    quote! {
-                        fn #method_name(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                            use ::aetos::core::{MetricWrapper, MetricMetadata, RenderScalarFallback};
+    quote! {
+        /// The metric families rendered by this struct, with their label names, in
+        /// declaration order
+        pub fn metrics_catalog() -> ::std::vec::Vec<::aetos::core::CatalogEntry> {
+            #[allow(unused)]
+            fn with_const_labels(labels: &[&'static str]) -> ::std::vec::Vec<&'static str> {
+                let mut labels = labels.to_vec();
+                labels.extend_from_slice(&[#(#const_label_names),*]);
+                labels
+            }
+            #[allow(unused_mut)]
+            let mut entries = ::std::vec::Vec::new();
+            #(#pushes)*
+            entries
+        }
 
-                            let meta = MetricMetadata {
-                                name: #metric_name,
-                                help: #help,
-                                kind: #metric_type_str,
-                            };
+        /// The metric families rendered by this struct as a markdown table, one row per
+        /// family
+        pub fn metrics_markdown() -> ::std::string::String {
+            ::aetos::core::catalog_markdown(&Self::metrics_catalog())
+        }
+    }
+}
 
-                            let wrapper = MetricWrapper(&self.#field_ident);
-                            wrapper.render_with_label_attr(f, &meta, #label_name)
-                        }
+/// The label names of the samples of a field, as a `&'static [&'static str]`
+/// expression. Collections whose key type is not visible in the field type have none.
+fn catalog_label_names(field: &MetricField) -> TokenStream {
+    let ty = &field.ty;
+    match &field.field_type {
+        FieldType::SingleLabel { label_name } => {
+            let label_name = label_name.clone().unwrap_or_else(|| field.name());
+            quote! { &[#label_name] }
+        }
+        FieldType::MultiLabel { label_names } => quote! { &[#(#label_names),*] },
+        FieldType::Unspecified => match field.metric_type {
+            MetricType::Histogram => quote! {
+                <#ty as ::aetos::core::HistogramMetric>::label_names()
+            },
+            MetricType::Group => quote! { ::aetos::core::key_label_names::<#ty, _>() },
+            _ if !field.lazy && matches!(collection_key_value(ty), Some((_, Some(_)))) => {
+                quote! { ::aetos::core::key_label_names::<#ty, _>() }
+            }
+            _ => quote! { &[] },
+        },
+    }
+}
+
+/// Generates `PrometheusMetric::size_hint()`, a lower bound of the output length.
+///
+/// Every family writes its header lines, even when empty, but only scalar fields are
+/// known to write a sample. `Option` and `skip_zero` fields and groups and dynamic
+/// fields, whose names are not known here, count as 0, and nested fields as their own
+/// hint. A family shared by several fields counts once.
+fn generate_size_hint(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let mut terms = Vec::new();
+
+    for field in fields {
+        let cfgs = &field.cfgs;
+        let member = &field.member;
+
+        let term = match field.metric_type {
+            MetricType::Nested if field.optional => {
+                let unwrap = unwrap_value(field);
+                quote! {
+                    if let Some(value) = &self.#member {
+                        #unwrap
+                        hint += ::aetos::core::PrometheusMetric::size_hint(value);
+                    }
+                }
+            }
+            MetricType::Nested => {
+                let unwrap = unwrap_value(field);
+                quote! {
+                    {
+                        let value = &self.#member;
+                        #unwrap
+                        hint += ::aetos::core::PrometheusMetric::size_hint(value);
                     }
                 }
-                FieldType::Unspecified => {
+            }
+            MetricType::Group => continue,
+            _ if field.optional || field.skip_zero || field.dynamic || field.continues_family => {
+                continue;
+            }
+            _ => {
+                let metric_name = metric_name_expr(field, struct_attrs);
+                let aliases = alias_name_exprs(field, struct_attrs);
+                let kind = field.kind();
+                // `# HELP <name> <help>\n` and the other header lines
+                let help = field.help.as_ref().map(|help| {
+                    quote! { hint += 9 + name.len() + #help.len(); }
+                });
+                let unit = field.unit.as_ref().map(|unit| {
                     quote! {
-                        fn #method_name(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                            use ::aetos::core::{MetricWrapper, MetricMetadata, RenderScalarFallback};
-
-                            let meta = MetricMetadata {
-                                name: #metric_name,
-                                help: #help,
-                                kind: #metric_type_str,
-                            };
-
-                            let wrapper = MetricWrapper(&self.#field_ident);
-                            wrapper.render_with_struct_key(f, &meta)
+                        if ::aetos::core::UNIT_LINES {
+                            hint += 9 + name.len() + #unit.len();
                         }
                     }
+                });
+                // `<name> <value>\n`, with a value of at least 0 characters
+                let is_scalar =
+                    matches!(field.metric_type, MetricType::Counter | MetricType::Gauge)
+                        && field.compute.is_none()
+                        && (field.lazy
+                            || field.atomic
+                            || is_seconds_type(&field.ty, &field.metric_type)
+                            || is_known_scalar_primitive(&field.ty));
+                let sample = is_scalar.then(|| quote! { hint += name.len() + 2; });
+                quote! {
+                    for name in [#metric_name, #(#aliases),*] {
+                        let name: &str = name;
+                        #help
+                        hint += 9 + name.len() + #kind.len();
+                        #unit
+                        #sample
+                    }
                 }
-            },
+            }
         };
+        terms.push(quote! {
+            #(#cfgs)*
+            {
+                #term
+            }
+        });
+    }
 
-        fmt_methods.push(method_impl);
-        fmt_calls.push(quote! { self.#method_name(f)?; });
+    quote! {
+        fn size_hint(&self) -> usize {
+            #[allow(unused_mut)]
+            let mut hint = 0;
+            #(#terms)*
+            hint
+        }
     }
+}
 
-    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+/// The names and values of the `#[metrics(labels(...))]` pairs, for building a
+/// `&[(name, value)]` slice
+fn const_label_tokens(struct_attrs: &StructAttrs) -> (Vec<String>, Vec<&StrValue>) {
+    struct_attrs
+        .const_labels
+        .iter()
+        .map(|(name, value)| (name.unraw().to_string(), value))
+        .unzip()
+}
 
-    Ok(quote! {
-        impl #impl_generics #name #ty_generics #where_clause {
-            #(#fmt_methods)*
+/// Generates `render_one()`, rendering the metric family with the given final name.
+///
+/// Histograms also match their `_bucket`, `_sum` and `_count` series, and groups render
+/// all their members when any of them matches. Nested structs are searched recursively,
+/// and dynamic fields by the names of their current entries. A known name whose `Option`
+/// field is `None` renders as an empty string.
+fn generate_render_one(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let mut checks = Vec::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        // Rendered with the first field of their family
+        if field.continues_family {
+            continue;
         }
+        let cfgs = &field.cfgs;
+        let ty = &field.ty;
+        let member = &field.member;
+        let metric_name = metric_name_expr(field, struct_attrs);
+        let aliases = alias_name_exprs(field, struct_attrs);
+        let alias_count = aliases.len();
 
-        impl #impl_generics std::fmt::Display for #name #ty_generics #where_clause {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                #(#fmt_calls)*
-                Ok(())
+        let check = match field.metric_type {
+            _ if field.dynamic => {
+                let render = render_dynamic_call(field, struct_attrs, quote! { &|n| n == name });
+                quote! {
+                    let output = ::aetos::core::DisplayFn(|f: &mut ::std::fmt::Formatter<'_>| {
+                        #render
+                        Ok(())
+                    })
+                    .to_string();
+                    if !output.is_empty() {
+                        return Some(output);
+                    }
+                }
+            }
+            MetricType::Nested => {
+                let unwrap = unwrap_value(field);
+                let value = if field.optional {
+                    quote! {
+                        let Some(value) = &self.#member else {
+                            return <#ty>::metric_names()
+                                .contains(&name)
+                                .then(::std::string::String::new);
+                        };
+                        #unwrap
+                    }
+                } else {
+                    quote! {
+                        let value = &self.#member;
+                        #unwrap
+                    }
+                };
+                quote! {
+                    if <#ty>::metric_names().contains(&name) {
+                        #value
+                        return value.render_one(name);
+                    }
+                }
+            }
+            _ => {
+                let total_suffix = group_total_suffix(field, struct_attrs);
+                let matches = match field.metric_type {
+                    MetricType::Histogram => quote! {
+                        name == metric_name
+                            || ["_bucket", "_sum", "_count"]
+                                .iter()
+                                .any(|suffix| name.strip_suffix(suffix) == Some(metric_name))
+                    },
+                    MetricType::Group => quote! {
+                        ::aetos::core::group_metric_names::<#ty, _>(metric_name, #total_suffix)
+                            .any(|member| member == name)
+                    },
+                    _ => quote! { name == metric_name },
+                };
+                let render = render_family(field, index, struct_attrs);
+                // An alias renders the whole family, under every name
+                quote! {
+                    let names: [&str; 1 + #alias_count] = [#metric_name, #(#aliases),*];
+                    for metric_name in names {
+                        if #matches {
+                            let output = ::aetos::core::DisplayFn(|f: &mut ::std::fmt::Formatter<'_>| {
+                                #render
+                                Ok(())
+                            });
+                            return Some(output.to_string());
+                        }
+                    }
+                }
+            }
+        };
+        checks.push(quote! {
+            #(#cfgs)*
+            {
+                #check
             }
+        });
+    }
+
+    quote! {
+        /// Renders only the metric family named `name` (its final name), or returns
+        /// `None` when no field renders it
+        pub fn render_one(&self, name: &str) -> ::std::option::Option<::std::string::String> {
+            #(#checks)*
+            None
         }
+    }
+}
 
-        impl #impl_generics ::aetos::core::PrometheusMetric for #name #ty_generics #where_clause {}
-    })
+/// Const assertions validating the prefixes given as expressions, which cannot be
+/// checked when expanding. Each prefix is checked once.
+fn prefix_checks(fields: &[MetricField], struct_attrs: &StructAttrs) -> TokenStream {
+    let struct_prefixes = [
+        &struct_attrs.namespace,
+        &struct_attrs.subsystem,
+        &struct_attrs.prefix,
+    ];
+    let field_prefixes = fields.iter().map(|field| &field.prefix);
+
+    let checks = struct_prefixes
+        .into_iter()
+        .chain(field_prefixes)
+        .filter_map(|prefix| match prefix {
+            Some(StrValue::Expr(expr)) => Some(quote_spanned! {expr.span()=>
+                const _: () = assert!(
+                    ::aetos::core::validate_metric_prefix(#expr),
+                    "invalid metric prefix: every '.'-separated part must match \
+                     [a-zA-Z_:][a-zA-Z0-9_:]* and must not end with '_'"
+                );
+            }),
+            _ => None,
+        });
+    quote! { #(#checks)* }
+}
+
+/// The prefixes, unit and `_total` suffix of the metric names of `field`, shared by its
+/// base name and its aliases.
+///
+/// The struct prefix (or namespace and subsystem) is skipped for fields with `no_prefix`. With
+/// `convention = "prometheus"`, counters get a `_total` suffix unless the field opts
+/// out with `no_suffix`.
+fn metric_name_parts<'a>(
+    field: &'a MetricField,
+    struct_attrs: &'a StructAttrs,
+) -> (Vec<&'a StrValue>, Option<&'a str>, bool) {
+    let struct_prefix = [
+        &struct_attrs.namespace,
+        &struct_attrs.subsystem,
+        &struct_attrs.prefix,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|_| !field.no_prefix);
+    let prefixes = struct_prefix.chain(&field.prefix).collect();
+    let total_suffix = struct_attrs.prometheus_convention
+        && matches!(field.metric_type, MetricType::Counter)
+        && !field.no_suffix;
+    (prefixes, field.unit.as_deref(), total_suffix)
+}
+
+/// Whether the counter members of `field`, a group, get a `_total` suffix: with
+/// `convention = "prometheus"`, like counter fields
+fn group_total_suffix(field: &MetricField, struct_attrs: &StructAttrs) -> bool {
+    struct_attrs.prometheus_convention && matches!(field.metric_type, MetricType::Group)
+}
+
+/// The base name of `field`: its `name`, or the field name
+fn base_name(field: &MetricField) -> StrValue {
+    field
+        .name_override
+        .clone()
+        .unwrap_or_else(|| StrValue::Lit(field.name()))
+}
+
+/// The metric name, with expressions standing in as their tokens. Only used to detect
+/// duplicate names.
+fn build_metric_name(field: &MetricField, struct_attrs: &StructAttrs) -> String {
+    build_metric_name_with_base(field, struct_attrs, base_name(field))
+}
+
+/// The metric names of the aliases of `field`, built like its name
+fn build_alias_names(field: &MetricField, struct_attrs: &StructAttrs) -> Vec<String> {
+    field
+        .aliases
+        .iter()
+        .map(|alias| build_metric_name_with_base(field, struct_attrs, StrValue::Lit(alias.clone())))
+        .collect()
 }
 
-fn build_metric_name(
-    ident: &syn::Ident,
-    name_override: Option<&str>,
-    prefix: Option<&str>,
+fn build_metric_name_with_base(
+    field: &MetricField,
+    struct_attrs: &StructAttrs,
+    base: StrValue,
 ) -> String {
-    let ident_string = ident.to_string();
-    let base_name = name_override.unwrap_or(&ident_string);
+    let (prefixes, unit, total_suffix) = metric_name_parts(field, struct_attrs);
+    let prefixes: Vec<String> = prefixes.iter().map(|p| p.key()).collect();
+    let prefixes: Vec<&str> = prefixes.iter().map(String::as_str).collect();
+    aetos_core::build_metric_name(&prefixes, &base.key(), unit, total_suffix)
+}
+
+/// Returns an expression evaluating to the metric name, as a `&'static str`.
+///
+/// When every part is a literal the name is a string literal. Otherwise it is built on
+/// first use and kept in a static, except in method bodies, which cannot tell whether
+/// a static would be shared by several types. There it is built on every call, and
+/// borrows a temporary.
+fn metric_name_expr(field: &MetricField, struct_attrs: &StructAttrs) -> TokenStream {
+    metric_name_expr_with_base(field, struct_attrs, base_name(field))
+}
 
-    match prefix {
-        Some(p) => format!("{}_{}", p, base_name),
-        None => base_name.to_string(),
+/// Returns expressions evaluating to the metric names of the aliases of `field`
+fn alias_name_exprs(field: &MetricField, struct_attrs: &StructAttrs) -> Vec<TokenStream> {
+    field
+        .aliases
+        .iter()
+        .map(|alias| metric_name_expr_with_base(field, struct_attrs, StrValue::Lit(alias.clone())))
+        .collect()
+}
+
+fn metric_name_expr_with_base(
+    field: &MetricField,
+    struct_attrs: &StructAttrs,
+    base: StrValue,
+) -> TokenStream {
+    let (prefixes, unit, total_suffix) = metric_name_parts(field, struct_attrs);
+
+    let has_expr = prefixes
+        .iter()
+        .copied()
+        .chain([&base])
+        .any(|part| matches!(part, StrValue::Expr(_)));
+    if !has_expr {
+        let name = build_metric_name_with_base(field, struct_attrs, base);
+        return quote! { #name };
+    }
+
+    let unit = match unit {
+        Some(unit) => quote! { Some(#unit) },
+        None => quote! { None },
+    };
+
+    let name = quote! {
+        ::aetos::core::build_metric_name(&[#(#prefixes),*], #base, #unit, #total_suffix)
+    };
+    if struct_attrs.method {
+        return quote! { (#name).as_str() };
     }
+    let name = cached(struct_attrs, quote! { ::std::string::String }, name);
+    quote! { (#name).as_str() }
 }
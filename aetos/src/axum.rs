@@ -0,0 +1,43 @@
+//! An Axum handler serving metrics in the Prometheus text format.
+//!
+//! ```
+//! use aetos::axum::prometheus_handler;
+//! use aetos::metrics;
+//! use axum::{Router, routing::get};
+//! use std::sync::Arc;
+//! use std::sync::atomic::AtomicU64;
+//!
+//! #[metrics(prefix = "app")]
+//! struct AppMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: AtomicU64,
+//! }
+//!
+//! let metrics = Arc::new(AppMetrics { requests: AtomicU64::new(0) });
+//! let app: Router = Router::new().route("/metrics", get(prometheus_handler(metrics)));
+//! ```
+
+use ::axum::handler::Handler;
+use ::axum::http::header;
+use std::sync::Arc;
+
+use crate::core::{PROMETHEUS_CONTENT_TYPE, PrometheusMetric};
+
+/// Returns a handler rendering `metrics` on every request, with the
+/// [`PROMETHEUS_CONTENT_TYPE`] content type.
+pub fn prometheus_handler<T, S>(metrics: Arc<T>) -> impl Handler<((),), S>
+where
+    T: PrometheusMetric + Send + Sync + 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    move || {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            let metrics: &(dyn PrometheusMetric + Send + Sync) = &*metrics;
+            (
+                [(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+                metrics.to_string(),
+            )
+        }
+    }
+}
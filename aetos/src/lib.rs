@@ -42,6 +42,12 @@
 //! };
 //!
 //! println!("{}", metrics);
+//!
+//! // Every metric family the struct exports, e.g. to check a scrape in integration tests
+//! assert_eq!(
+//!     MyMetrics::metric_names(),
+//!     ["myapp_requests_total", "myapp_events", "myapp_http_requests"]
+//! );
 //! ```
 //!
 //! This outputs:
@@ -61,15 +67,376 @@
 //!
 //! ## Collection Types
 //!
-//! Labeled metrics accept anything that implements `IntoIterator<&(K, V)>` or `IntoIterator<(&K, &V)>` (Vec, HashMap, BTreeMap, slices, etc.).
+//! Labeled metrics accept anything that implements `IntoIterator<&(K, V)>` or `IntoIterator<(&K, &V)>` (Vec, HashMap, BTreeMap, slices, fixed-size arrays, etc.).
 //!
 //! - Single label: `K` implements `Display`
 //! - Multiple labels: `K` implements `Label`
+//! - Multiple labels, shorthand: `K` is a tuple of `Display` values, named with `labels = ["method", "status"]`
+//! - Multiple labels, unnamed: `K` is a tuple of 1 to 3 `Display` values without `labels`, rendered as `field_0`, `field_1`, ...
+//! - Multiple labels known only at runtime: `K` is a `BTreeMap<String, String>` (rendered in key order) or a `Vec<(String, String)>`
+//!
+//! Label names passed via `label = "..."` are validated at compile time. They must match
+//! `[a-zA-Z_][a-zA-Z0-9_]*`, must not start with `__`, and must not be one of the reserved
+//! names `le` (histogram buckets) or `quantile` (summaries). Field names of `#[derive(Label)]`
+//! structs, or their `#[label(rename = "...")]`, are label names too; avoid `le` in label
+//! types used by histograms:
+//!
+//! ```compile_fail
+//! use aetos::metrics;
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[counter(help = "Starts with a digit", label = "2xx")]
+//!     responses: Vec<(String, u64)>,
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! use aetos::metrics;
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[counter(help = "Contains a hyphen", label = "http-method")]
+//!     requests: Vec<(String, u64)>,
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! use aetos::metrics;
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[counter(help = "Reserved prefix", label = "__name")]
+//!     requests: Vec<(String, u64)>,
+//! }
+//! ```
+//!
+//! ## Metric Groups
+//!
+//! When several values are tracked per key, derive `MetricGroup` on the value type and
+//! mark the collection with `#[group]`. Each member becomes its own family, named
+//! `<field>_<member>`, sharing the labels of the key:
+//!
+//! ```
+//! use aetos::{metrics, Label, MetricGroup};
+//! use std::collections::HashMap;
+//!
+//! #[derive(Label, Hash, Eq, PartialEq)]
+//! struct EndpointLabel {
+//!     path: &'static str,
+//! }
+//!
+//! #[derive(MetricGroup)]
+//! struct EndpointStats {
+//!     #[counter(help = "Requests per endpoint")]
+//!     requests: u64,
+//!     #[counter(help = "Errors per endpoint")]
+//!     errors: u64,
+//! }
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     // renders `endpoint_requests{path="..."}` and `endpoint_errors{path="..."}`
+//!     #[group]
+//!     endpoint: HashMap<EndpointLabel, EndpointStats>,
+//! }
+//! ```
+//!
+//! Groups keyed by a single `Display` value take `label = "..."` like other metrics.
+//!
+//! ## Nested Metrics
+//!
+//! Metrics structs can be composed: a field marked `#[nested]` is rendered through its own
+//! `Display` impl, keeping its own prefix. `Option` nested fields are skipped when `None`.
+//!
+//! ```
+//! use aetos::metrics;
+//!
+//! #[metrics(prefix = "cache")]
+//! struct CacheMetrics {
+//!     #[counter(help = "Cache hits")]
+//!     hits: u64,
+//! }
+//!
+//! #[metrics(prefix = "app")]
+//! struct AppMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: u64,
+//!
+//!     // renders `cache_hits`
+//!     #[nested]
+//!     cache: CacheMetrics,
+//! }
+//! ```
+//!
+//! ## Dynamic Metric Names
+//!
+//! A field marked `#[dynamic(kind = "gauge")]` (or `"counter"`) holds `(name, value)` pairs,
+//! each rendered as its own family named after its key, e.g. values reported by plugins.
+//! Prefixes apply, and characters not allowed in metric names are replaced with `_`. When
+//! several keys give the same name, the last one in iteration order wins.
+//!
+//! ```
+//! use aetos::metrics;
+//!
+//! #[metrics(prefix = "plugin")]
+//! struct PluginMetrics {
+//!     // `("heap.size", 1024.0)` renders `plugin_heap_size 1024`
+//!     #[dynamic(kind = "gauge", help = "Value reported by a plugin")]
+//!     values: Vec<(String, f64)>,
+//! }
+//! ```
+//!
+//! ## Optional Metrics
+//!
+//! Fields of type `Option<T>` render nothing (not even `# HELP`/`# TYPE`) when `None`, and render the inner value as usual when `Some`. This is useful for metrics that only exist after some subsystem has been initialized.
+//!
+//! ## Computed Metrics
+//!
+//! Counters and gauges with `compute = "method"` render the value returned by
+//! `self.method()` at render time instead of the field. Metrics that need no field at all
+//! go in `extra(...)` on the struct, and are named after their method unless `name` is
+//! given:
+//!
+//! ```
+//! use aetos::metrics;
+//! use std::collections::VecDeque;
+//!
+//! #[metrics(extra(gauge(name = "hit_ratio", help = "Share of hits", compute = "hit_ratio")))]
+//! struct Metrics {
+//!     #[counter(help = "Cache hits")]
+//!     hits: u64,
+//!     #[counter(help = "Cache lookups")]
+//!     lookups: u64,
+//!     // renders `queue 2`
+//!     #[gauge(help = "Queue length", compute = "queue_len")]
+//!     queue: VecDeque<u32>,
+//! }
+//!
+//! impl Metrics {
+//!     fn queue_len(&self) -> usize {
+//!         self.queue.len()
+//!     }
+//!
+//!     fn hit_ratio(&self) -> f64 {
+//!         self.hits as f64 / self.lookups as f64
+//!     }
+//! }
+//!
+//! let m = Metrics { hits: 1, lookups: 2, queue: VecDeque::from([1, 2]) };
+//! assert!(m.to_string().contains("queue 2\n"));
+//! assert!(m.to_string().contains("hit_ratio 0.5\n"));
+//! ```
+//!
+//! Fields holding a closure, such as `Box<dyn Fn() -> f64 + Send + Sync>` or a type
+//! parameter bounded by `Fn() -> f64`, are called once per render:
+//!
+//! ```
+//! use aetos::metrics;
+//! use std::time::Instant;
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[gauge(help = "Seconds since start", unit = "seconds")]
+//!     uptime: Box<dyn Fn() -> f64 + Send + Sync>,
+//! }
+//!
+//! let start = Instant::now();
+//! let m = Metrics { uptime: Box::new(move || start.elapsed().as_secs_f64()) };
+//! assert!(m.to_string().contains("uptime_seconds "));
+//! ```
+//!
+//! Counters and gauges can also be `std::sync::atomic` integers or `AtomicBool`. They are
+//! loaded with `Ordering::Relaxed` at render time, so they can be updated from several
+//! threads through a shared reference:
+//!
+//! ```
+//! use aetos::metrics;
+//! use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: AtomicU64,
+//!     #[gauge(help = "Whether the service is up")]
+//!     up: AtomicBool,
+//! }
+//!
+//! let m = Metrics { requests: AtomicU64::new(0), up: AtomicBool::new(true) };
+//! std::thread::scope(|s| {
+//!     s.spawn(|| m.requests.fetch_add(1, Ordering::Relaxed));
+//! });
+//! assert!(m.to_string().contains("requests 1\n"));
+//! assert!(m.to_string().contains("up 1\n"));
+//! ```
+//!
+//! `bool` fields, and collections with `bool` values, are rendered as `1` or `0`.
+//!
+//! `Duration` fields, and collections with `Duration` values, are rendered in seconds.
+//! `SystemTime` gauges are rendered as seconds since the unix epoch, negative for times
+//! before it:
+//!
+//! ```
+//! use aetos::metrics;
+//! use std::collections::HashMap;
+//! use std::time::{Duration, SystemTime};
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[gauge(help = "Time since start", unit = "seconds")]
+//!     uptime: Duration,
+//!     #[gauge(help = "Time per stage", label = "stage")]
+//!     stages: HashMap<&'static str, Duration>,
+//!     #[gauge(help = "Last successful sync")]
+//!     last_sync: SystemTime,
+//! }
+//!
+//! let m = Metrics {
+//!     uptime: Duration::from_millis(1500),
+//!     stages: HashMap::from([("parse", Duration::from_millis(250))]),
+//!     last_sync: SystemTime::UNIX_EPOCH + Duration::from_millis(1_699_999_999_123),
+//! };
+//! assert!(m.to_string().contains("uptime_seconds 1.5\n"));
+//! assert!(m.to_string().contains("stages{stage=\"parse\"} 0.25\n"));
+//! assert!(m.to_string().contains("last_sync 1699999999.123\n"));
+//! ```
 //!
 //! ## Override Metric Names
 //!
 //! Use the `name` attribute to export a different metric name than the field name (see Quick Start example).
 //!
+//! Two fields exporting the same final name (after applying `prefix` and `name`) is a
+//! compile error:
+//!
+//! ```compile_fail
+//! use aetos::metrics;
+//!
+//! #[metrics]
+//! struct Metrics {
+//!     #[counter(help = "Total requests", name = "requests")]
+//!     requests_total: u64,
+//!
+//!     #[counter(help = "Requests")]
+//!     requests: u64,
+//! }
+//! ```
+//!
+//! ## Derive Flavor
+//!
+//! `#[metrics]` re-emits the struct without its metric attributes. `#[derive(Metrics)]`
+//! generates the same code without touching the struct, so it composes with other derives
+//! and attribute macros. Struct arguments and field attributes are written as
+//! `#[metric(...)]`, with the metric kind first:
+//!
+//! ```
+//! use aetos::Metrics;
+//!
+//! #[derive(Metrics, Clone)]
+//! #[metric(prefix = "app")]
+//! struct AppMetrics {
+//!     #[metric(counter, help = "Total requests")]
+//!     requests: u64,
+//!
+//!     #[metric(gauge, help = "Open connections")]
+//!     connections: u64,
+//! }
+//!
+//! let metrics = AppMetrics { requests: 10, connections: 2 };
+//! assert!(metrics.to_string().contains("app_requests 10\n"));
+//! ```
+//!
+//! To generate the metrics code only in some builds, apply `#[metrics]` (or the derive)
+//! through `cfg_attr`, and `#[metrics_stub]` otherwise. The stub only removes the metric
+//! attributes, which would be unknown without the macro, and ignores its arguments:
+//!
+//! ```
+//! #[cfg_attr(feature = "metrics", aetos::metrics(prefix = "app"))]
+//! #[cfg_attr(not(feature = "metrics"), aetos::metrics_stub(prefix = "app"))]
+//! struct AppMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: u64,
+//! }
+//!
+//! let metrics = AppMetrics { requests: 10 };
+//! assert_eq!(metrics.requests, 10);
+//! ```
+//!
+//! ## Filtered Rendering
+//!
+//! `Filtered` renders only the metric families whose final name passes a predicate, e.g.
+//! to serve a subset of the metrics on another endpoint. Histograms are matched by their
+//! base name, and a group is rendered whole when any of its members matches.
+//!
+//! ```
+//! use aetos::{Filtered, metrics};
+//!
+//! #[metrics(prefix = "app")]
+//! struct AppMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: u64,
+//!
+//!     #[gauge(help = "Open connections")]
+//!     connections: u64,
+//! }
+//!
+//! let metrics = AppMetrics { requests: 10, connections: 2 };
+//! let slo = Filtered(&metrics, |name: &str| name == "app_requests");
+//! assert_eq!(
+//!     slo.to_string(),
+//!     "# HELP app_requests Total requests\n# TYPE app_requests counter\napp_requests 10\n"
+//! );
+//! ```
+//!
+//! ## Registries
+//!
+//! `MetricRegistry` renders several metrics structs of different types one after the
+//! other, in registration order. `SyncMetricRegistry` does the same behind a `RwLock`, so
+//! that metrics can be registered from any thread, e.g. into a `static`:
+//!
+//! ```
+//! use aetos::{SyncMetricRegistry, metrics};
+//!
+//! #[metrics(prefix = "http")]
+//! struct HttpMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: u64,
+//! }
+//!
+//! #[metrics(prefix = "db")]
+//! struct DbMetrics {
+//!     #[gauge(help = "Open connections")]
+//!     connections: u64,
+//! }
+//!
+//! static REGISTRY: SyncMetricRegistry = SyncMetricRegistry::new();
+//!
+//! REGISTRY.register(Box::new(HttpMetrics { requests: 10 }));
+//! REGISTRY.register(Box::new(DbMetrics { connections: 2 }));
+//!
+//! let output = REGISTRY.render_all();
+//! assert!(output.contains("http_requests 10\n"));
+//! assert!(output.contains("db_connections 2\n"));
+//! ```
+//!
+//! `#[global_metrics]` gives a metrics struct a single process-global instance, set once
+//! with `init` and read from anywhere with `get`:
+//!
+//! ```
+//! use aetos::{global_metrics, metrics};
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//!
+//! #[global_metrics]
+//! #[metrics(prefix = "app")]
+//! struct AppMetrics {
+//!     #[counter(help = "Total requests")]
+//!     requests: AtomicU64,
+//! }
+//!
+//! AppMetrics::init(AppMetrics { requests: AtomicU64::new(0) });
+//! AppMetrics::get().requests.fetch_add(1, Ordering::Relaxed);
+//! assert!(AppMetrics::get().to_string().contains("app_requests 1\n"));
+//! ```
+//!
 //! ## Histograms
 //!
 //! Histograms track value distributions across predefined buckets:
@@ -118,19 +485,67 @@
 //! ```
 //!
 //! ```
+//! use aetos::{define_histogram, linear_buckets_range};
+//!
+//! define_histogram!(QueueDepth<()> = linear_buckets_range::<5>(0.0, 100.0));
+//! // Generates buckets: [0.0, 25.0, 50.0, 75.0, 100.0]
+//! ```
+//!
+//! Common bucket layouts are predefined:
+//!
+//! ```
+//! use aetos::{define_histogram, DEFAULT_HTTP_DURATION_BUCKETS};
+//!
+//! define_histogram!(HttpDuration<()> = DEFAULT_HTTP_DURATION_BUCKETS);
+//! ```
+//!
+//! ```
 //! use aetos::{define_histogram, exponential_buckets};
 //!
 //! define_histogram!(ResponseSize<()> = exponential_buckets::<8>(0.001, 2.0));
 //! // Generates buckets: [0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064, 0.128]
 //! ```
+//!
+//! A histogram used once can take its buckets on the field instead, with the same
+//! compile-time check. `#[metrics(default)]` or `#[metrics(generate_new)]` initializes
+//! the field with them:
+//!
+//! ```
+//! use aetos::core::Histogram;
+//! use aetos::{exponential_buckets, metrics};
+//!
+//! #[metrics(default)]
+//! struct Metrics {
+//!     #[histogram(help = "Response time", buckets = [0.1, 0.5, 1.0])]
+//!     response_time: Histogram<(), 3>,
+//!
+//!     #[histogram(help = "Response size", buckets = exponential_buckets::<4>(256.0, 4.0))]
+//!     response_size: Histogram<(), 4>,
+//! }
+//!
+//! let mut m = Metrics::default();
+//! m.response_time.observe((), 0.25);
+//! ```
+
+#[cfg(feature = "axum")]
+pub mod axum;
+
+#[cfg(feature = "tower")]
+pub mod tower;
 
 #[doc(hidden)]
-pub use aetos_macro::{Label, metrics};
+pub use aetos_macro::{Label, MetricGroup, Metrics, global_metrics, metrics, metrics_stub};
 
 #[doc(hidden)]
 pub use aetos_core as core;
 
-pub use aetos_core::{exponential_buckets, linear_buckets};
+pub use aetos_core::{
+    AtomicLoad, DEFAULT_FILE_SIZE_BUCKETS, DEFAULT_HTTP_DURATION_BUCKETS, Filtered, Flag,
+    HistogramBucketError, InvalidMetricName, LazyMetric, MetricMetadata, MetricMetadataBuilder,
+    MetricRegistry, ObserveError, PROMETHEUS_CONTENT_TYPE, Seconds, SyncMetricRegistry, ToSeconds,
+    default_rpc_duration_buckets, exponential_buckets, linear_buckets, linear_buckets_range,
+    validate_histogram_buckets_checked, validate_metric_name_checked,
+};
 
 /// Defines a histogram type with compile-time validated bucket boundaries.
 ///
@@ -142,8 +557,13 @@ pub use aetos_core::{exponential_buckets, linear_buckets};
 ///
 /// ```text
 /// define_histogram!(HistogramName<LabelType> = [bucket1, bucket2, ...]);
+/// define_histogram!(pub HistogramName<LabelType> = [bucket1, bucket2, ...]);
 /// ```
 ///
+/// The generated struct takes the optional visibility (`pub`, `pub(crate)`, ...) and is
+/// private otherwise. Doc comments and other attributes before the name are forwarded to
+/// the struct.
+///
 /// # Examples
 ///
 /// Define a histogram with custom labels:
@@ -166,6 +586,28 @@ pub use aetos_core::{exponential_buckets, linear_buckets};
 /// define_histogram!(ResponseTime<()> = [0.1, 0.5, 1.0]);
 /// ```
 ///
+/// The bucket boundaries are available through `buckets()`:
+/// ```
+/// use aetos::define_histogram;
+///
+/// define_histogram!(ResponseTime<()> = [0.1, 0.5, 1.0]);
+///
+/// assert_eq!(ResponseTime::buckets(), &[0.1, 0.5, 1.0]);
+/// ```
+///
+/// Define a histogram usable from other modules:
+/// ```
+/// mod metrics {
+///     aetos::define_histogram!(
+///         /// Time to handle a request, in seconds
+///         pub(crate) RequestLatency<()> = [0.1, 0.5, 1.0]
+///     );
+/// }
+///
+/// let mut latency = metrics::RequestLatency::new();
+/// latency.observe((), 0.3);
+/// ```
+///
 /// Invalid bucket ordering fails at compile time:
 /// ```compile_fail
 /// use aetos::define_histogram;
@@ -189,17 +631,25 @@ pub use aetos_core::{exponential_buckets, linear_buckets};
 /// ```
 #[macro_export]
 macro_rules! define_histogram {
-    ($name:ident < $label:ty > = $buckets:expr) => {
+    ($(#[$attr:meta])* $vis:vis $name:ident < $label:ty > = $buckets:expr) => {
         const _: () = {
             $crate::core::validate_histogram_buckets(&$buckets);
         };
 
+        $(#[$attr])*
         #[derive(Clone, Debug)]
-        struct $name($crate::core::Histogram<$label, { $buckets.len() }>);
+        $vis struct $name($crate::core::Histogram<$label, { $buckets.len() }>);
 
         impl $name {
+            /// Creates an empty histogram
             pub fn new() -> Self {
-                Self($crate::core::Histogram::new($buckets))
+                Self($crate::core::Histogram::new(*Self::buckets()))
+            }
+
+            /// The upper bounds of the buckets, without the implicit `+Inf` bucket
+            pub const fn buckets() -> &'static [f64; { $buckets.len() }] {
+                const BUCKETS: [f64; { $buckets.len() }] = $buckets;
+                &BUCKETS
             }
         }
 
@@ -222,6 +672,10 @@ macro_rules! define_histogram {
             }
         }
 
+        impl $crate::core::HistogramLabel for $name {
+            type Label = $label;
+        }
+
         impl $crate::core::HistogramMetric for $name {
             fn render_histogram(
                 &self,
@@ -230,6 +684,43 @@ macro_rules! define_histogram {
             ) -> std::fmt::Result {
                 self.0.render_histogram(f, meta)
             }
+
+            fn label_names() -> &'static [&'static str] {
+                <$label as $crate::core::Label>::label_names()
+            }
         }
     };
 }
+
+/// Defines several histogram types in one call, separated by semicolons.
+///
+/// Each definition accepts the same syntax as [`define_histogram!`] and expands to the
+/// same code.
+///
+/// # Examples
+///
+/// ```
+/// use aetos::{define_histograms, Label};
+///
+/// #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+/// pub struct RequestLabel {
+///     pub method: &'static str,
+/// }
+///
+/// define_histograms! {
+///     /// Time to handle a request, in seconds
+///     pub RequestLatency<RequestLabel> = [0.05, 0.1, 0.5];
+///     pub ResponseSize<()> = [100.0, 1000.0];
+/// }
+///
+/// let mut latency = RequestLatency::new();
+/// latency.observe(RequestLabel { method: "GET" }, 0.07);
+/// ```
+#[macro_export]
+macro_rules! define_histograms {
+    ($($(#[$attr:meta])* $vis:vis $name:ident < $label:ty > = $buckets:expr);* $(;)?) => {
+        $(
+            $crate::define_histogram!($(#[$attr])* $vis $name<$label> = $buckets);
+        )*
+    };
+}
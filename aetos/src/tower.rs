@@ -0,0 +1,203 @@
+//! Tower middleware recording the count and latency of HTTP requests.
+//!
+//! [`MetricsLayer`] wraps a service taking `http::Request`s, such as an Axum router or a
+//! Hyper service. Once the response of the inner service is ready, it calls
+//! [`HttpMetrics::record_request`] with the method, path, status and latency of the
+//! request. Requests failing with an error of the inner service have no status and are
+//! not recorded.
+//!
+//! `record_request` takes `&self`, so the metrics struct is usually behind a `Mutex`:
+//!
+//! ```
+//! use aetos::core::PrometheusMetric;
+//! use aetos::tower::{HttpMetrics, MetricsLayer};
+//! use aetos::{Label, define_histogram, metrics};
+//! use std::collections::HashMap;
+//! use std::fmt;
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+//! struct Route {
+//!     method: String,
+//!     path: String,
+//! }
+//!
+//! #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+//! struct Response {
+//!     method: String,
+//!     path: String,
+//!     status: u16,
+//! }
+//!
+//! define_histogram!(Latency<Route> = [0.005, 0.05, 0.5, 5.0]);
+//!
+//! #[metrics(prefix = "http", default)]
+//! struct Http {
+//!     #[counter(help = "Total HTTP requests")]
+//!     requests: HashMap<Response, u64>,
+//!
+//!     #[histogram(help = "HTTP request latency", unit = "seconds")]
+//!     request_duration: Latency,
+//! }
+//!
+//! #[derive(Default)]
+//! struct SharedHttp(Mutex<Http>);
+//!
+//! impl PrometheusMetric for SharedHttp {
+//!     fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+//!         self.0.lock().unwrap().render(f)
+//!     }
+//!
+//!     fn fmt_filtered(
+//!         &self,
+//!         f: &mut fmt::Formatter<'_>,
+//!         filter: &dyn Fn(&str) -> bool,
+//!     ) -> fmt::Result {
+//!         self.0.lock().unwrap().fmt_filtered(f, filter)
+//!     }
+//! }
+//!
+//! impl HttpMetrics for SharedHttp {
+//!     fn record_request(&self, method: &str, path: &str, status: u16, duration: f64) {
+//!         let mut http = self.0.lock().unwrap();
+//!         let (method, path) = (method.to_string(), path.to_string());
+//!         let route = Route { method: method.clone(), path: path.clone() };
+//!         *http.requests.entry(Response { method, path, status }).or_default() += 1;
+//!         http.request_duration.observe(route, duration);
+//!     }
+//! }
+//!
+//! let metrics = Arc::new(SharedHttp::default());
+//! let layer = MetricsLayer::new(metrics.clone());
+//! ```
+//!
+//! The path is the raw path of the request URI. Services with path parameters should
+//! map it to the matched route in `record_request`, to keep the number of series bounded.
+
+use http::{Method, Request, Response};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::core::PrometheusMetric;
+
+/// Metrics updated by [`MetricsLayer`] once per completed request.
+pub trait HttpMetrics {
+    /// Records one request. `duration` is the time from the call of the service to its
+    /// response being ready, in seconds.
+    fn record_request(&self, method: &str, path: &str, status: u16, duration: f64);
+}
+
+/// A Tower layer calling [`HttpMetrics::record_request`] for every request served by the
+/// wrapped service.
+pub struct MetricsLayer<M> {
+    metrics: Arc<M>,
+}
+
+impl<M> MetricsLayer<M> {
+    pub fn new(metrics: Arc<M>) -> Self
+    where
+        M: PrometheusMetric + HttpMetrics,
+    {
+        Self { metrics }
+    }
+}
+
+// Not derived, so that `M` does not need to be `Clone`
+impl<M> Clone for MetricsLayer<M> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl<S, M> Layer<S> for MetricsLayer<M> {
+    type Service = MetricsService<S, M>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+/// The service returned by [`MetricsLayer`].
+pub struct MetricsService<S, M> {
+    inner: S,
+    metrics: Arc<M>,
+}
+
+impl<S: Clone, M> Clone for MetricsService<S, M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl<S, M, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S, M>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    M: HttpMetrics,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, M>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        ResponseFuture {
+            start: Instant::now(),
+            inner: self.inner.call(req),
+            metrics: Arc::clone(&self.metrics),
+            method,
+            path,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// The response future of [`MetricsService`].
+    pub struct ResponseFuture<F, M> {
+        #[pin]
+        inner: F,
+        metrics: Arc<M>,
+        method: Method,
+        path: String,
+        start: Instant,
+    }
+}
+
+impl<F, M, ResBody, E> Future for ResponseFuture<F, M>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    M: HttpMetrics,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.inner.poll(cx));
+        if let Ok(response) = &result {
+            this.metrics.record_request(
+                this.method.as_str(),
+                this.path,
+                response.status().as_u16(),
+                this.start.elapsed().as_secs_f64(),
+            );
+        }
+        Poll::Ready(result)
+    }
+}
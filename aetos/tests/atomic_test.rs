@@ -1,6 +1,6 @@
 use aetos::metrics;
 use std::fmt;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 // Wrapper type that implements Display for AtomicU64
 struct AtomicMetric(AtomicU64);
@@ -30,6 +30,88 @@ fn test_atomic_u64_wrapper() {
     assert!(output.contains("requests 42\n"));
 }
 
+#[test]
+fn test_atomic_fields() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: AtomicU64,
+        #[gauge(help = "Open connections")]
+        connections: AtomicUsize,
+        #[gauge(help = "Queue depth")]
+        queue: std::sync::atomic::AtomicU32,
+        #[gauge(help = "Clock drift")]
+        drift: AtomicI64,
+        #[gauge(help = "Whether the service is up")]
+        up: AtomicBool,
+        #[gauge(help = "Optional atomic")]
+        optional: Option<AtomicU32>,
+    }
+
+    let m = TestMetrics {
+        requests: AtomicU64::new(42),
+        connections: AtomicUsize::new(3),
+        queue: AtomicU32::new(7),
+        drift: AtomicI64::new(-5),
+        up: AtomicBool::new(true),
+        optional: None,
+    };
+
+    let output = m.to_string();
+
+    assert!(output.contains("# TYPE requests counter\n"));
+    assert!(output.contains("requests 42\n"));
+    assert!(output.contains("connections 3\n"));
+    assert!(output.contains("queue 7\n"));
+    assert!(output.contains("drift -5\n"));
+    assert!(output.contains("up 1\n"));
+    assert!(!output.contains("optional"));
+
+    m.up.store(false, Ordering::Relaxed);
+    m.requests.fetch_add(1, Ordering::Relaxed);
+    let output = m.to_string();
+    assert!(output.contains("up 0\n"));
+    assert!(output.contains("requests 43\n"));
+}
+
+#[test]
+fn test_atomic_fields_multithreaded() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: AtomicU64,
+        #[gauge(help = "In-flight requests")]
+        in_flight: AtomicI64,
+    }
+
+    let m = TestMetrics {
+        requests: AtomicU64::new(0),
+        in_flight: AtomicI64::new(0),
+    };
+
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    m.in_flight.fetch_add(1, Ordering::Relaxed);
+                    m.requests.fetch_add(1, Ordering::Relaxed);
+                    m.in_flight.fetch_sub(1, Ordering::Relaxed);
+                }
+            });
+        }
+        // Rendering concurrently with the writers only sees a snapshot
+        s.spawn(|| {
+            for _ in 0..100 {
+                assert!(m.to_string().contains("# TYPE requests counter\n"));
+            }
+        });
+    });
+
+    let output = m.to_string();
+    assert!(output.contains("requests 8000\n"));
+    assert!(output.contains("in_flight 0\n"));
+}
+
 #[test]
 fn test_custom_display_type() {
     use std::fmt;
@@ -0,0 +1,71 @@
+#![cfg(feature = "axum")]
+
+use aetos::axum::prometheus_handler;
+use aetos::{PROMETHEUS_CONTENT_TYPE, metrics};
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::http::{Request, StatusCode, header};
+use axum::routing::get;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tower::ServiceExt;
+
+#[metrics(prefix = "app")]
+struct AppMetrics {
+    #[counter(help = "Total requests")]
+    requests: AtomicU64,
+}
+
+#[tokio::test]
+async fn test_prometheus_handler() {
+    let metrics = Arc::new(AppMetrics {
+        requests: AtomicU64::new(0),
+    });
+    let app = Router::new().route("/metrics", get(prometheus_handler(metrics.clone())));
+
+    metrics.requests.fetch_add(3, Ordering::Relaxed);
+    let response = app
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/plain; version=0.0.4; charset=utf-8"
+    );
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        PROMETHEUS_CONTENT_TYPE
+    );
+
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(
+        std::str::from_utf8(&body).unwrap(),
+        "# HELP app_requests Total requests\n# TYPE app_requests counter\napp_requests 3\n"
+    );
+}
+
+#[tokio::test]
+async fn test_prometheus_handler_with_state() {
+    #[derive(Clone)]
+    struct State;
+
+    let metrics = Arc::new(AppMetrics {
+        requests: AtomicU64::new(1),
+    });
+    let app = Router::new()
+        .route("/metrics", get(prometheus_handler(metrics)))
+        .with_state(State);
+
+    let response = app
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(
+        std::str::from_utf8(&body)
+            .unwrap()
+            .contains("app_requests 1\n")
+    );
+}
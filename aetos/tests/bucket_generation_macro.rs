@@ -1,4 +1,5 @@
-use aetos::{define_histogram, exponential_buckets, linear_buckets};
+use aetos::core::HistogramData;
+use aetos::{define_histogram, define_histograms, exponential_buckets, linear_buckets};
 
 // Test using linear_buckets directly in the macro
 define_histogram!(LinearTest<()> = linear_buckets::<5>(0.1, 0.1));
@@ -9,6 +10,19 @@ define_histogram!(ExponentialTest<()> = exponential_buckets::<5>(1.0, 2.0));
 // Test that array literals still work
 define_histogram!(ArrayLiteralTest<()> = [0.1, 0.5, 1.0]);
 
+mod histograms {
+    use aetos::define_histogram;
+
+    define_histogram!(pub PublicTest<()> = [0.1, 1.0]);
+    define_histogram!(pub(crate) CrateTest<()> = [0.5]);
+
+    define_histogram!(
+        /// Documented histogram
+        #[allow(clippy::all)]
+        pub DocumentedTest<()> = [0.5]
+    );
+}
+
 #[test]
 fn test_linear_buckets_in_macro() {
     let mut hist = LinearTest::new();
@@ -31,11 +45,14 @@ fn test_exponential_buckets_in_macro() {
     hist.observe((), 1.5);
     hist.observe((), 3.0);
 
-    let data = hist.data.get(&()).unwrap();
-    assert_eq!(data.count, 3);
-    assert_eq!(data.counts[0], 1);
-    assert_eq!(data.counts[1], 1);
-    assert_eq!(data.counts[2], 1);
+    assert_eq!(
+        hist.data[&()],
+        HistogramData {
+            counts: [1, 1, 1, 0, 0],
+            count: 3,
+            sum: 5.0,
+        }
+    );
 }
 
 #[test]
@@ -43,6 +60,91 @@ fn test_array_literal_still_works() {
     let mut hist = ArrayLiteralTest::new();
     hist.observe((), 0.25);
 
+    assert_eq!(
+        hist.data[&()],
+        HistogramData {
+            counts: [0, 1, 0],
+            count: 1,
+            sum: 0.25,
+        }
+    );
+}
+
+#[test]
+fn test_visibility_in_macro() {
+    use aetos::metrics;
+    use histograms::{CrateTest, PublicTest};
+
+    #[metrics]
+    struct Metrics {
+        #[histogram(help = "Public")]
+        public: PublicTest,
+
+        #[histogram(help = "Crate")]
+        krate: CrateTest,
+    }
+
+    let mut m = Metrics {
+        public: PublicTest::new(),
+        krate: CrateTest::default(),
+    };
+    m.public.observe((), 0.5);
+    m.krate.observe((), 0.25);
+
+    let output = m.to_string();
+    assert!(output.contains("public_bucket{le=\"1.000\"} 1\n"));
+    assert!(output.contains("krate_bucket{le=\"0.500\"} 1\n"));
+}
+
+#[test]
+fn test_buckets_in_macro() {
+    assert_eq!(LinearTest::buckets().len(), 5);
+    assert!((LinearTest::buckets()[4] - 0.5).abs() < f64::EPSILON);
+    assert_eq!(ExponentialTest::buckets(), &[1.0, 2.0, 4.0, 8.0, 16.0]);
+    assert_eq!(ArrayLiteralTest::buckets(), &[0.1, 0.5, 1.0]);
+
+    const FIRST: f64 = ArrayLiteralTest::buckets()[0];
+    assert_eq!(FIRST, 0.1);
+}
+
+#[test]
+fn test_time_in_macro() {
+    define_histogram!(Timed<()> = [0.0005, 1.0]);
+
+    let mut hist = Timed::new();
+    hist.time((), || std::thread::sleep(std::time::Duration::from_millis(1)));
+
     let data = hist.data.get(&()).unwrap();
     assert_eq!(data.count, 1);
+    assert_eq!(data.counts, [0, 1]);
+}
+
+#[test]
+fn test_attributes_in_macro() {
+    let mut hist = histograms::DocumentedTest::new();
+    hist.observe((), 0.25);
+    assert_eq!(hist.data.get(&()).unwrap().counts, [1]);
+}
+
+define_histograms! {
+    BatchLinear<()> = linear_buckets::<3>(1.0, 1.0);
+    /// Documented batch histogram
+    pub BatchArray<()> = [0.1, 0.5];
+    pub(crate) BatchExponential<()> = exponential_buckets::<2>(1.0, 10.0)
+}
+
+#[test]
+fn test_batch_macro() {
+    let mut linear = BatchLinear::new();
+    linear.observe((), 2.5);
+    assert_eq!(linear.data.get(&()).unwrap().counts, [0, 0, 1]);
+
+    let mut array = BatchArray::new();
+    array.observe((), 0.05);
+    assert_eq!(array.data.get(&()).unwrap().counts, [1, 0]);
+
+    let mut exponential = BatchExponential::default();
+    exponential.observe((), 5.0);
+    assert_eq!(exponential.data.get(&()).unwrap().counts, [0, 1]);
+    assert_eq!(BatchExponential::buckets(), &[1.0, 10.0]);
 }
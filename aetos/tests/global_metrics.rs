@@ -0,0 +1,84 @@
+use aetos::{Metrics, global_metrics, metrics};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[global_metrics]
+#[metrics(prefix = "app")]
+struct AppMetrics {
+    #[counter(help = "Total requests")]
+    requests: AtomicU64,
+}
+
+#[test]
+fn test_global_metrics() {
+    AppMetrics::init(AppMetrics {
+        requests: AtomicU64::new(0),
+    });
+
+    let first = AppMetrics::get();
+    let second = AppMetrics::get();
+    assert!(std::ptr::eq(first, second));
+
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| AppMetrics::get().requests.fetch_add(1, Ordering::Relaxed));
+        }
+    });
+    assert!(AppMetrics::get().to_string().contains("app_requests 4\n"));
+
+    let again = AppMetrics {
+        requests: AtomicU64::new(7),
+    };
+    let rejected = AppMetrics::try_init(again).unwrap_err();
+    assert_eq!(rejected.requests.load(Ordering::Relaxed), 7);
+    assert!(std::ptr::eq(AppMetrics::get(), first));
+}
+
+#[global_metrics]
+#[metrics]
+struct TwiceMetrics {
+    #[gauge(help = "Value")]
+    value: u64,
+}
+
+#[test]
+#[should_panic(expected = "TwiceMetrics::init called more than once")]
+fn test_global_metrics_init_twice() {
+    TwiceMetrics::init(TwiceMetrics { value: 1 });
+    TwiceMetrics::init(TwiceMetrics { value: 2 });
+}
+
+#[global_metrics]
+#[metrics]
+struct UnsetMetrics {
+    #[gauge(help = "Value")]
+    value: u64,
+}
+
+#[test]
+#[should_panic(expected = "UnsetMetrics::get called before UnsetMetrics::init")]
+fn test_global_metrics_get_before_init() {
+    UnsetMetrics::get();
+}
+
+// The attribute also works after `#[metrics]`, and with the derive flavor
+#[metrics]
+#[global_metrics]
+struct AfterMetrics {
+    #[gauge(help = "Value")]
+    value: u64,
+}
+
+#[global_metrics]
+#[derive(Metrics)]
+struct DerivedMetrics {
+    #[metric(gauge, help = "Value")]
+    value: u64,
+}
+
+#[test]
+fn test_global_metrics_attribute_order() {
+    AfterMetrics::init(AfterMetrics { value: 1 });
+    DerivedMetrics::init(DerivedMetrics { value: 2 });
+    assert!(AfterMetrics::get().to_string().contains("value 1\n"));
+    assert!(DerivedMetrics::get().to_string().contains("value 2\n"));
+}
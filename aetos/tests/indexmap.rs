@@ -0,0 +1,49 @@
+#![cfg(feature = "indexmap")]
+
+use aetos::metrics;
+use indexmap::IndexMap;
+
+#[test]
+fn test_indexmap_single_label() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by path", label = "path")]
+        requests: IndexMap<&'static str, u64>,
+    }
+
+    let mut requests = IndexMap::new();
+    requests.insert("/z", 1);
+    requests.insert("/a", 2);
+    requests.insert("/m", 3);
+
+    let m = TestMetrics { requests };
+    assert_eq!(
+        m.to_string(),
+        "# HELP requests Requests by path\n\
+         # TYPE requests counter\n\
+         requests{path=\"/z\"} 1\n\
+         requests{path=\"/a\"} 2\n\
+         requests{path=\"/m\"} 3\n"
+    );
+}
+
+#[test]
+fn test_indexmap_runtime_labels() {
+    #[metrics]
+    struct TestMetrics {
+        #[gauge(help = "Build info")]
+        build_info: Vec<(IndexMap<String, String>, u64)>,
+    }
+
+    let labels = IndexMap::from([
+        ("version".to_string(), "1.2.3".to_string()),
+        ("commit".to_string(), "abc".to_string()),
+    ]);
+    let m = TestMetrics {
+        build_info: vec![(labels, 1)],
+    };
+    assert!(
+        m.to_string()
+            .contains("build_info{version=\"1.2.3\",commit=\"abc\"} 1\n")
+    );
+}
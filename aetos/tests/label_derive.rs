@@ -85,6 +85,25 @@ fn test_three_fields_no_trailing_comma() {
     assert!(!result.ends_with(','));
 }
 
+#[test]
+fn test_raw_identifier_fields() {
+    #[derive(Label)]
+    struct RawLabel {
+        r#type: &'static str,
+        r#loop: u32,
+        r#match: bool,
+    }
+
+    let label = RawLabel {
+        r#type: "disk",
+        r#loop: 3,
+        r#match: true,
+    };
+
+    let result = format!("{}", MockFormatter(&label));
+    assert_eq!(result, r#"type="disk",loop="3",match="true""#);
+}
+
 struct MockFormatter<'a, T: LabelTrait>(&'a T);
 
 impl<'a, T: LabelTrait> std::fmt::Display for MockFormatter<'a, T> {
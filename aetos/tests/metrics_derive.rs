@@ -0,0 +1,116 @@
+use aetos::{Metrics, define_histogram};
+use serde::Serialize;
+use std::collections::HashMap;
+
+define_histogram!(Latency<()> = [0.5]);
+
+#[derive(Metrics, Serialize)]
+#[metric(prefix = "app", convention = "prometheus")]
+struct AppMetrics {
+    #[metric(counter, help = "Total requests")]
+    requests: u64,
+
+    #[metric(gauge, help = "Connections by pool", label = "pool")]
+    #[serde(rename = "conns")]
+    connections: HashMap<String, u64>,
+
+    #[metric(histogram, help = "Latency")]
+    #[serde(skip)]
+    latency: Latency,
+
+    // Not a metric, but still serialized
+    #[metric(skip)]
+    version: &'static str,
+}
+
+#[test]
+fn test_derive_renders_like_the_attribute() {
+    let mut latency = Latency::new();
+    latency.observe((), 0.25);
+    let m = AppMetrics {
+        requests: 3,
+        connections: HashMap::from([("db".to_string(), 2)]),
+        latency,
+        version: "1.0",
+    };
+
+    let output = m.to_string();
+    assert!(output.contains("# HELP app_requests_total Total requests\n"));
+    assert!(output.contains("app_requests_total 3\n"));
+    assert!(output.contains("app_connections{pool=\"db\"} 2\n"));
+    assert!(output.contains("app_latency_count{} 1\n"));
+
+    assert_eq!(
+        AppMetrics::metric_names(),
+        ["app_requests_total", "app_connections", "app_latency"]
+    );
+}
+
+#[test]
+fn test_derive_with_serde() {
+    let m = AppMetrics {
+        requests: 3,
+        connections: HashMap::from([("db".to_string(), 2)]),
+        latency: Latency::new(),
+        version: "1.0",
+    };
+
+    assert_eq!(
+        serde_json::to_string(&m).unwrap(),
+        r#"{"requests":3,"conns":{"db":2},"version":"1.0"}"#
+    );
+}
+
+#[test]
+fn test_derive_struct_arguments() {
+    #[derive(Metrics)]
+    #[metric(namespace = "app")]
+    #[metric(subsystem = "http", generate_new, generate_methods)]
+    struct HttpMetrics {
+        #[metric(counter, help = "Requests")]
+        requests: u64,
+
+        #[metric(gauge, help = "Workers")]
+        #[metric(no_default)]
+        workers: u64,
+
+        #[metric(nested)]
+        pool: PoolMetrics,
+    }
+
+    #[derive(Metrics, Default)]
+    #[metric(prefix = "pool")]
+    struct PoolMetrics {
+        #[metric(gauge)]
+        size: u64,
+    }
+
+    let mut m = HttpMetrics::new(4);
+    m.inc_requests();
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_http_requests Requests\n\
+         # TYPE app_http_requests counter\n\
+         app_http_requests 1\n\
+         # HELP app_http_workers Workers\n\
+         # TYPE app_http_workers gauge\n\
+         app_http_workers 4\n\
+         # TYPE pool_size gauge\n\
+         pool_size 0\n"
+    );
+}
+
+#[test]
+fn test_derive_generic_kind() {
+    #[derive(Metrics)]
+    struct Generic {
+        #[metric(kind = "counter", help = "Requests")]
+        requests: u64,
+    }
+
+    assert_eq!(
+        Generic { requests: 3 }.to_string(),
+        "# HELP requests Requests\n# TYPE requests counter\nrequests 3\n"
+    );
+}
@@ -332,17 +332,19 @@ fn test_btreemap_single_label() {
         events: BTreeMap<String, u64>,
     }
 
+    // Inserted out of order: a `BTreeMap` iterates in key order
     let mut events = BTreeMap::new();
-    events.insert("stake".to_string(), 10);
     events.insert("unstake".to_string(), 5);
+    events.insert("stake".to_string(), 10);
 
     let m = TestMetrics { events };
-    let output = m.to_string();
-
-    assert!(output.contains("# HELP events Events by type\n"));
-    assert!(output.contains("# TYPE events counter\n"));
-    assert!(output.contains(r#"events{event_type="stake"} 10"#));
-    assert!(output.contains(r#"events{event_type="unstake"} 5"#));
+    assert_eq!(
+        m.to_string(),
+        "# HELP events Events by type\n\
+         # TYPE events counter\n\
+         events{event_type=\"stake\"} 10\n\
+         events{event_type=\"unstake\"} 5\n"
+    );
 }
 
 #[test]
@@ -361,29 +363,106 @@ fn test_btreemap_multi_label() {
         requests: BTreeMap<MetricLabels, u64>,
     }
 
+    let label = |region: &str, zone: &str| MetricLabels {
+        region: region.to_string(),
+        zone: zone.to_string(),
+    };
     let mut requests = BTreeMap::new();
-    requests.insert(
-        MetricLabels {
-            region: "us-east".to_string(),
-            zone: "1a".to_string(),
-        },
-        200,
+    requests.insert(label("us-west", "2b"), 150);
+    requests.insert(label("us-east", "1b"), 20);
+    requests.insert(label("us-east", "1a"), 200);
+
+    let m = TestMetrics { requests };
+    assert_eq!(
+        m.to_string(),
+        "# HELP requests Requests by region and zone\n\
+         # TYPE requests counter\n\
+         requests{region=\"us-east\",zone=\"1a\"} 200\n\
+         requests{region=\"us-east\",zone=\"1b\"} 20\n\
+         requests{region=\"us-west\",zone=\"2b\"} 150\n"
     );
-    requests.insert(
-        MetricLabels {
-            region: "us-west".to_string(),
-            zone: "2b".to_string(),
-        },
-        150,
+}
+
+#[test]
+fn test_btreemap_key_order() {
+    use std::collections::BTreeMap;
+
+    #[derive(Label, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Status {
+        code: u16,
+        method: &'static str,
+    }
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Responses by status")]
+        responses: BTreeMap<Status, u64>,
+        #[gauge(help = "Workers by shard", label = "shard")]
+        workers: BTreeMap<u8, u64>,
+    }
+
+    // The samples follow the `Ord` of the keys, not the order of the rendered labels
+    // (`"10"` sorts before `"9"` as a string): a `BTreeMap` is never re-sorted
+    let m = TestMetrics {
+        responses: BTreeMap::from([
+            (
+                Status {
+                    code: 500,
+                    method: "GET",
+                },
+                1,
+            ),
+            (
+                Status {
+                    code: 200,
+                    method: "POST",
+                },
+                2,
+            ),
+            (
+                Status {
+                    code: 200,
+                    method: "GET",
+                },
+                3,
+            ),
+        ]),
+        workers: BTreeMap::from([(10, 4), (9, 5)]),
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP responses Responses by status\n\
+         # TYPE responses counter\n\
+         responses{code=\"200\",method=\"GET\"} 3\n\
+         responses{code=\"200\",method=\"POST\"} 2\n\
+         responses{code=\"500\",method=\"GET\"} 1\n\
+         # HELP workers Workers by shard\n\
+         # TYPE workers gauge\n\
+         workers{shard=\"9\"} 5\n\
+         workers{shard=\"10\"} 4\n"
     );
+}
 
-    let m = TestMetrics { requests };
-    let output = m.to_string();
+#[test]
+fn test_array_single_label() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by method", label = "method")]
+        requests: [(&'static str, u64); 3],
+    }
 
-    assert!(output.contains("# HELP requests Requests by region and zone\n"));
-    assert!(output.contains("# TYPE requests counter\n"));
-    assert!(output.contains(r#"requests{region="us-east",zone="1a"} 200"#));
-    assert!(output.contains(r#"requests{region="us-west",zone="2b"} 150"#));
+    let m = TestMetrics {
+        requests: [("GET", 10), ("POST", 5), ("DELETE", 1)],
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP requests Requests by method\n\
+         # TYPE requests counter\n\
+         requests{method=\"GET\"} 10\n\
+         requests{method=\"POST\"} 5\n\
+         requests{method=\"DELETE\"} 1\n"
+    );
 }
 
 #[test]
@@ -483,3 +562,3676 @@ fn test_histogram_unlabeled() {
     assert!(output.contains(r#"response_time_sum{} 0.56"#));
     assert!(output.contains(r#"response_time_count{} 3"#));
 }
+
+#[test]
+fn test_option_scalar() {
+    #[metrics]
+    struct TestMetrics {
+        #[gauge(help = "Open connections")]
+        connections: Option<u64>,
+    }
+
+    let m = TestMetrics { connections: None };
+    assert_eq!(m.to_string(), "");
+
+    let m = TestMetrics {
+        connections: Some(42),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP connections Open connections\n"));
+    assert!(output.contains("# TYPE connections gauge\n"));
+    assert!(output.contains("connections 42\n"));
+}
+
+#[test]
+fn test_option_labeled() {
+    use std::collections::HashMap;
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by method", label = "method")]
+        requests: Option<HashMap<String, u64>>,
+    }
+
+    let m = TestMetrics { requests: None };
+    assert_eq!(m.to_string(), "");
+
+    let m = TestMetrics {
+        requests: Some(HashMap::from([("GET".to_string(), 100)])),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP requests Requests by method\n"));
+    assert!(output.contains("# TYPE requests counter\n"));
+    assert!(output.contains(r#"requests{method="GET"} 100"#));
+}
+
+#[test]
+fn test_option_histogram() {
+    define_histogram!(PoolWait<()> = [0.1, 0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[histogram(help = "Pool wait time")]
+        pool_wait: Option<PoolWait>,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        pool_wait: None,
+    };
+    let output = m.to_string();
+    assert!(output.contains("requests 1\n"));
+    assert!(!output.contains("pool_wait"));
+
+    let mut pool_wait = PoolWait::default();
+    pool_wait.observe((), 0.2);
+    let m = TestMetrics {
+        requests: 1,
+        pool_wait: Some(pool_wait),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# TYPE pool_wait histogram\n"));
+    assert!(output.contains(r#"pool_wait_bucket{le="0.500"} 1"#));
+    assert!(output.contains(r#"pool_wait_count{} 1"#));
+}
+
+#[test]
+fn test_timestamp() {
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct MethodLabel {
+        method: &'static str,
+    }
+
+    define_histogram!(Latency<MethodLabel> = [0.1, 0.5]);
+
+    #[metrics(prefix = "app", timestamp = "collected_at")]
+    struct TestMetrics {
+        collected_at: u64,
+
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[counter(help = "Events by type", label = "event_type")]
+        events: Vec<(&'static str, u64)>,
+
+        #[counter(help = "Requests by method")]
+        by_method: Vec<(MethodLabel, u64)>,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+    }
+
+    let mut m = TestMetrics {
+        collected_at: 1708000000000,
+        requests: 42,
+        events: vec![("add", 3)],
+        by_method: vec![(MethodLabel { method: "GET" }, 7)],
+        latency: Latency::default(),
+    };
+    m.latency.observe(MethodLabel { method: "GET" }, 0.25);
+
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP app_requests Total requests\n"));
+    assert!(output.contains("# TYPE app_requests counter\n"));
+    assert!(output.contains("app_requests 42 1708000000000\n"));
+    assert!(output.contains("app_events{event_type=\"add\"} 3 1708000000000\n"));
+    assert!(output.contains("app_by_method{method=\"GET\"} 7 1708000000000\n"));
+    assert!(output.contains("app_latency_bucket{method=\"GET\",le=\"0.100\"} 0 1708000000000\n"));
+    assert!(output.contains("app_latency_bucket{method=\"GET\",le=\"0.500\"} 1 1708000000000\n"));
+    assert!(output.contains("app_latency_bucket{method=\"GET\",le=\"+Inf\"} 1 1708000000000\n"));
+    assert!(output.contains("app_latency_sum{method=\"GET\"} 0.25 1708000000000\n"));
+    assert!(output.contains("app_latency_count{method=\"GET\"} 1 1708000000000\n"));
+
+    for line in output.lines().filter(|l| !l.starts_with('#')) {
+        assert!(
+            line.ends_with(" 1708000000000"),
+            "missing timestamp: {}",
+            line
+        );
+    }
+}
+
+#[test]
+fn test_optional_timestamp() {
+    #[metrics(timestamp = "collected_at")]
+    struct TestMetrics {
+        collected_at: Option<u64>,
+
+        #[gauge(help = "Current value")]
+        value: u64,
+    }
+
+    let m = TestMetrics {
+        collected_at: None,
+        value: 5,
+    };
+    assert!(m.to_string().contains("value 5\n"));
+
+    let m = TestMetrics {
+        collected_at: Some(1000),
+        value: 5,
+    };
+    assert!(m.to_string().contains("value 5 1000\n"));
+}
+
+#[test]
+fn test_help_optional() {
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter]
+        requests: u64,
+
+        #[gauge(name = "temp")]
+        temperature: f64,
+
+        #[histogram]
+        latency: Latency,
+    }
+
+    let mut m = TestMetrics {
+        requests: 1,
+        temperature: 2.5,
+        latency: Latency::default(),
+    };
+    m.latency.observe((), 0.1);
+
+    assert_eq!(
+        m.to_string(),
+        "# TYPE requests counter\n\
+         requests 1\n\
+         # TYPE temp gauge\n\
+         temp 2.5\n\
+         # TYPE latency histogram\n\
+         latency_bucket{le=\"0.500\"} 1\n\
+         latency_bucket{le=\"+Inf\"} 1\n\
+         latency_sum{} 0.1\n\
+         latency_count{} 1\n"
+    );
+}
+
+#[test]
+fn test_help_exact_output() {
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[counter(help = "Events by type", label = "event_type")]
+        events: Vec<(&'static str, u64)>,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        events: vec![("add", 2)],
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_requests Total requests\n\
+         # TYPE app_requests counter\n\
+         app_requests 1\n\
+         # HELP app_events Events by type\n\
+         # TYPE app_events counter\n\
+         app_events{event_type=\"add\"} 2\n"
+    );
+}
+
+#[test]
+fn test_option_mixed_fields() {
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[counter(help = "Cache hits")]
+        cache_hits: Option<u64>,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        cache_hits: None,
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_requests Total requests\n\
+         # TYPE app_requests counter\n\
+         app_requests 1\n"
+    );
+
+    let m = TestMetrics {
+        requests: 1,
+        cache_hits: Some(42),
+    };
+    assert!(m.to_string().ends_with(
+        "# HELP app_cache_hits Cache hits\n\
+         # TYPE app_cache_hits counter\n\
+         app_cache_hits 42\n"
+    ));
+}
+
+#[test]
+fn test_field_prefix() {
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Cache hits", prefix = "cache")]
+        hits: u64,
+
+        #[counter(
+            help = "Cache evictions",
+            prefix = "cache.lru",
+            name = "evictions_total"
+        )]
+        evictions: u64,
+
+        #[counter(help = "Total requests")]
+        requests: u64,
+    }
+
+    let m = TestMetrics {
+        hits: 1,
+        evictions: 2,
+        requests: 3,
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP app_cache_hits Cache hits\n"));
+    assert!(output.contains("app_cache_hits 1\n"));
+    assert!(output.contains("# TYPE app_cache_lru_evictions_total counter\n"));
+    assert!(output.contains("app_cache_lru_evictions_total 2\n"));
+    assert!(output.contains("app_requests 3\n"));
+}
+
+#[test]
+fn test_field_prefix_without_struct_prefix() {
+    #[metrics]
+    struct TestMetrics {
+        #[gauge(help = "Pool size", prefix = "db")]
+        pool_size: u64,
+    }
+
+    let m = TestMetrics { pool_size: 4 };
+    assert!(m.to_string().contains("db_pool_size 4\n"));
+}
+
+#[test]
+fn test_dotted_prefix() {
+    #[metrics(prefix = "app.http")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+    }
+
+    let m = TestMetrics { requests: 5 };
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP app_http_requests Total requests\n"));
+    assert!(output.contains("app_http_requests 5\n"));
+}
+
+#[test]
+fn test_tuple_labels() {
+    use std::collections::HashMap;
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "HTTP requests", labels = ["method", "status"])]
+        http_requests: Vec<((&'static str, u16), u64)>,
+
+        #[counter(help = "Bytes by region and zone", labels = ["region", "zone", "kind"])]
+        bytes: HashMap<(String, String, String), u64>,
+    }
+
+    let m = TestMetrics {
+        http_requests: vec![(("GET", 200), 5), (("POST", 404), 1)],
+        bytes: HashMap::from([(
+            (
+                "us-east".to_string(),
+                "1a".to_string(),
+                "egress".to_string(),
+            ),
+            1024,
+        )]),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# HELP http_requests HTTP requests\n"));
+    assert!(output.contains("# TYPE http_requests counter\n"));
+    assert!(output.contains("http_requests{method=\"GET\",status=\"200\"} 5\n"));
+    assert!(output.contains("http_requests{method=\"POST\",status=\"404\"} 1\n"));
+    assert!(output.contains("bytes{region=\"us-east\",zone=\"1a\",kind=\"egress\"} 1024\n"));
+}
+
+#[test]
+fn test_tuple_keys_without_label_names() {
+    use std::collections::HashMap;
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by host and port")]
+        requests: HashMap<(String, u16), u64>,
+
+        #[counter(help = "Errors by code")]
+        errors: Vec<((u16,), u64)>,
+
+        #[counter(help = "Bytes by region, zone and kind")]
+        bytes: Vec<((&'static str, &'static str, &'static str), u64)>,
+    }
+
+    let m = TestMetrics {
+        requests: HashMap::from([(("db".to_string(), 5432), 3)]),
+        errors: vec![((500,), 2)],
+        bytes: vec![(("us-east", "1a", "egress"), 1024)],
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP requests Requests by host and port\n\
+         # TYPE requests counter\n\
+         requests{field_0=\"db\",field_1=\"5432\"} 3\n\
+         # HELP errors Errors by code\n\
+         # TYPE errors counter\n\
+         errors{field_0=\"500\"} 2\n\
+         # HELP bytes Bytes by region, zone and kind\n\
+         # TYPE bytes counter\n\
+         bytes{field_0=\"us-east\",field_1=\"1a\",field_2=\"egress\"} 1024\n"
+    );
+}
+
+#[test]
+#[cfg(not(feature = "no-escaping"))]
+fn test_tuple_labels_escaping() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Test", labels = ["a", "b"])]
+        test: Vec<((&'static str, &'static str), u64)>,
+    }
+
+    let m = TestMetrics {
+        test: vec![(("has\"quote", "back\\slash"), 1)],
+    };
+    assert!(
+        m.to_string()
+            .contains(r#"test{a="has\"quote",b="back\\slash"} 1"#)
+    );
+}
+
+#[test]
+#[cfg(not(feature = "openmetrics"))]
+fn test_unit() {
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Bytes sent", unit = "bytes")]
+        sent: u64,
+
+        #[gauge(help = "Uptime", name = "custom", unit = "seconds")]
+        uptime: f64,
+
+        #[gauge(help = "Already suffixed", unit = "bytes")]
+        heap_bytes: u64,
+    }
+
+    let m = TestMetrics {
+        sent: 10,
+        uptime: 1.5,
+        heap_bytes: 4096,
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_sent_bytes Bytes sent\n\
+         # TYPE app_sent_bytes counter\n\
+         app_sent_bytes 10\n\
+         # HELP app_custom_seconds Uptime\n\
+         # TYPE app_custom_seconds gauge\n\
+         app_custom_seconds 1.5\n\
+         # HELP app_heap_bytes Already suffixed\n\
+         # TYPE app_heap_bytes gauge\n\
+         app_heap_bytes 4096\n"
+    );
+}
+
+#[test]
+fn test_prometheus_convention_total_suffix() {
+    #[metrics(prefix = "app", convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = "Requests")]
+        requests: u64,
+
+        #[counter(help = "Already suffixed", name = "errors_total")]
+        errors: u64,
+
+        #[counter(help = "Bytes sent", unit = "bytes")]
+        sent: u64,
+
+        #[counter(help = "Legacy name", no_suffix)]
+        legacy: u64,
+
+        #[gauge(help = "Gauges are untouched")]
+        in_flight: u64,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        errors: 2,
+        sent: 3,
+        legacy: 4,
+        in_flight: 5,
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# TYPE app_requests_total counter\n"));
+    assert!(output.contains("app_requests_total 1\n"));
+    assert!(output.contains("app_errors_total 2\n"));
+    assert!(!output.contains("_total_total"));
+    assert!(output.contains("app_sent_bytes_total 3\n"));
+    assert!(output.contains("app_legacy 4\n"));
+    assert!(output.contains("app_in_flight 5\n"));
+}
+
+#[test]
+fn test_no_total_suffix_by_default() {
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests")]
+        requests: u64,
+    }
+
+    let m = TestMetrics { requests: 1 };
+    let output = m.to_string();
+
+    assert!(output.contains("requests 1\n"));
+    assert!(!output.contains("requests_total"));
+}
+
+#[test]
+fn test_runtime_label_sets() {
+    use std::collections::{BTreeMap, HashMap};
+
+    define_histogram!(Latency<BTreeMap<String, String>> = [0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by configured labels")]
+        requests: HashMap<BTreeMap<String, String>, u64>,
+
+        #[counter(help = "Jobs by ordered labels")]
+        jobs: Vec<(Vec<(&'static str, &'static str)>, u64)>,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+    }
+
+    let labels = BTreeMap::from([
+        ("team".to_string(), "infra".to_string()),
+        ("env".to_string(), "prod".to_string()),
+    ]);
+
+    let mut m = TestMetrics {
+        requests: HashMap::from([(labels.clone(), 3)]),
+        jobs: vec![(vec![("queue", "default"), ("kind", "email")], 1)],
+        latency: Latency::default(),
+    };
+    m.latency.observe(labels, 0.1);
+    m.latency.observe(BTreeMap::new(), 0.1);
+
+    let output = m.to_string();
+
+    assert!(output.contains(r#"requests{env="prod",team="infra"} 3"#));
+    assert!(output.contains(r#"jobs{queue="default",kind="email"} 1"#));
+    assert!(output.contains(r#"latency_bucket{env="prod",team="infra",le="0.500"} 1"#));
+    assert!(output.contains(r#"latency_bucket{le="0.500"} 1"#));
+    assert!(output.contains(r#"latency_bucket{le="+Inf"} 1"#));
+    assert!(output.contains("latency_sum{} 0.1\n"));
+    assert!(!output.contains("{,"));
+}
+
+#[test]
+fn test_metric_group() {
+    use aetos::MetricGroup;
+    use std::collections::BTreeMap;
+
+    #[derive(Label, PartialEq, Eq, PartialOrd, Ord)]
+    struct EndpointLabel {
+        path: &'static str,
+    }
+
+    #[derive(MetricGroup)]
+    struct EndpointStats {
+        #[counter(help = "Requests per endpoint")]
+        requests: u64,
+
+        #[counter(help = "Errors per endpoint", name = "errors_total")]
+        errors: u64,
+
+        #[gauge]
+        in_flight: u64,
+
+        // not exported
+        #[allow(dead_code)]
+        last_seen: u64,
+    }
+
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[group]
+        endpoint: BTreeMap<EndpointLabel, EndpointStats>,
+
+        #[group(name = "backend", label = "host")]
+        backends: Vec<(&'static str, EndpointStats)>,
+    }
+
+    let stats = |requests, errors, in_flight| EndpointStats {
+        requests,
+        errors,
+        in_flight,
+        last_seen: 0,
+    };
+
+    let m = TestMetrics {
+        endpoint: BTreeMap::from([
+            (EndpointLabel { path: "/a" }, stats(10, 1, 2)),
+            (EndpointLabel { path: "/b" }, stats(20, 0, 0)),
+        ]),
+        backends: vec![("db1", stats(5, 0, 1))],
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_endpoint_requests Requests per endpoint\n\
+         # TYPE app_endpoint_requests counter\n\
+         app_endpoint_requests{path=\"/a\"} 10\n\
+         app_endpoint_requests{path=\"/b\"} 20\n\
+         # HELP app_endpoint_errors_total Errors per endpoint\n\
+         # TYPE app_endpoint_errors_total counter\n\
+         app_endpoint_errors_total{path=\"/a\"} 1\n\
+         app_endpoint_errors_total{path=\"/b\"} 0\n\
+         # TYPE app_endpoint_in_flight gauge\n\
+         app_endpoint_in_flight{path=\"/a\"} 2\n\
+         app_endpoint_in_flight{path=\"/b\"} 0\n\
+         # HELP app_backend_requests Requests per endpoint\n\
+         # TYPE app_backend_requests counter\n\
+         app_backend_requests{host=\"db1\"} 5\n\
+         # HELP app_backend_errors_total Errors per endpoint\n\
+         # TYPE app_backend_errors_total counter\n\
+         app_backend_errors_total{host=\"db1\"} 0\n\
+         # TYPE app_backend_in_flight gauge\n\
+         app_backend_in_flight{host=\"db1\"} 1\n"
+    );
+}
+
+#[test]
+fn test_nested_metrics() {
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Cache hits")]
+        hits: u64,
+    }
+
+    #[metrics(prefix = "db")]
+    struct DbMetrics {
+        #[gauge(help = "Open connections")]
+        connections: u64,
+    }
+
+    #[metrics(prefix = "app")]
+    struct AppMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[nested]
+        cache: CacheMetrics,
+
+        #[nested]
+        db: Option<DbMetrics>,
+    }
+
+    let m = AppMetrics {
+        requests: 1,
+        cache: CacheMetrics { hits: 2 },
+        db: None,
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_requests Total requests\n\
+         # TYPE app_requests counter\n\
+         app_requests 1\n\
+         # HELP cache_hits Cache hits\n\
+         # TYPE cache_hits counter\n\
+         cache_hits 2\n"
+    );
+
+    let m = AppMetrics {
+        requests: 1,
+        cache: CacheMetrics { hits: 2 },
+        db: Some(DbMetrics { connections: 3 }),
+    };
+    let inner = format!(
+        "{}{}",
+        CacheMetrics { hits: 2 },
+        DbMetrics { connections: 3 }
+    );
+    assert!(m.to_string().ends_with(&inner));
+}
+
+#[test]
+fn test_generate_new() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.1, 0.5]);
+
+    #[metrics(generate_new)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Temperature")]
+        temperature: f64,
+
+        #[counter(help = "Events by type", label = "event_type")]
+        events: HashMap<String, u64>,
+
+        #[histogram(help = "Request latency")]
+        latency: Latency,
+
+        #[gauge(help = "Set after startup")]
+        uptime: Option<u64>,
+    }
+
+    let mut m = TestMetrics::new();
+    assert_eq!(
+        m.to_string(),
+        "# HELP requests Total requests\n\
+         # TYPE requests counter\n\
+         requests 0\n\
+         # HELP temperature Temperature\n\
+         # TYPE temperature gauge\n\
+         temperature 0\n\
+         # HELP events Events by type\n\
+         # TYPE events counter\n\
+         # HELP latency Request latency\n\
+         # TYPE latency histogram\n"
+    );
+
+    m.latency.observe((), 0.3);
+    let output = m.to_string();
+    assert!(output.contains("latency_bucket{le=\"0.100\"} 0\n"));
+    assert!(output.contains("latency_bucket{le=\"0.500\"} 1\n"));
+    assert!(output.contains("latency_count{} 1\n"));
+}
+
+#[test]
+fn test_generate_new_no_default() {
+    #[metrics(generate_new)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Configured workers")]
+        #[metrics_field(no_default)]
+        workers: u64,
+
+        #[gauge(help = "Pool size")]
+        #[metrics_field(no_default)]
+        pool_size: u64,
+    }
+
+    let m = TestMetrics::new(8, 16);
+    let output = m.to_string();
+
+    assert!(output.contains("requests 0\n"));
+    assert!(output.contains("workers 8\n"));
+    assert!(output.contains("pool_size 16\n"));
+}
+
+#[test]
+fn test_generate_methods() {
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct EndpointLabel {
+        endpoint: &'static str,
+    }
+
+    define_histogram!(Latency<EndpointLabel> = [0.1, 1.0]);
+
+    #[metrics(generate_methods)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[histogram(help = "Request latency")]
+        latency: Latency,
+    }
+
+    let mut m = TestMetrics {
+        requests: 0,
+        latency: Latency::new(),
+    };
+
+    m.inc_requests();
+    m.inc_requests();
+    m.add_requests(5);
+    assert_eq!(m.requests, 7);
+
+    let label = EndpointLabel { endpoint: "/api" };
+    m.observe_latency(label.clone(), 0.5);
+    m.observe_latency(label.clone(), 2.0);
+
+    assert_eq!(
+        m.latency.data[&label],
+        aetos::core::HistogramData {
+            counts: [0, 1],
+            count: 2,
+            sum: 2.5,
+        }
+    );
+
+    let output = m.to_string();
+    assert!(output.contains("requests 7\n"));
+    assert!(output.contains("latency_count{endpoint=\"/api\"} 2\n"));
+}
+
+#[test]
+fn test_cfg_fields() {
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(generate_new, generate_methods)]
+    struct TestMetrics {
+        #[counter(help = "Enabled")]
+        #[cfg(test)]
+        enabled: u64,
+
+        #[counter(help = "Disabled")]
+        #[cfg(not(test))]
+        disabled: u64,
+
+        #[histogram(help = "Disabled latency")]
+        #[cfg(not(test))]
+        latency: Latency,
+
+        #[gauge(help = "Disabled argument")]
+        #[cfg(not(test))]
+        #[metrics_field(no_default)]
+        workers: u64,
+    }
+
+    let mut m = TestMetrics::new();
+    m.inc_enabled();
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP enabled Enabled\n\
+         # TYPE enabled counter\n\
+         enabled 1\n"
+    );
+}
+
+#[test]
+fn test_raw_identifier_fields() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(prefix = "app", generate_methods)]
+    struct TestMetrics {
+        #[counter(help = "Requests by type")]
+        r#type: u64,
+
+        #[gauge(help = "Iterations by kind", label = "kind")]
+        r#loop: HashMap<String, u64>,
+
+        #[histogram(help = "Match latency")]
+        r#match: Latency,
+    }
+
+    let mut m = TestMetrics {
+        r#type: 0,
+        r#loop: HashMap::from([("busy".to_string(), 2)]),
+        r#match: Latency::new(),
+    };
+    m.inc_type();
+    m.observe_match((), 0.1);
+
+    let output = m.to_string();
+    assert!(output.contains("# HELP app_type Requests by type\n"));
+    assert!(output.contains("app_type 1\n"));
+    assert!(output.contains("app_loop{kind=\"busy\"} 2\n"));
+    assert!(output.contains("app_match_count{} 1\n"));
+    assert!(!output.contains("r#"));
+}
+
+#[test]
+fn test_const_expression_attributes() {
+    use aetos::MetricGroup;
+
+    const HELP_REQUESTS: &str = "Total requests";
+    const PREFIX: &str = "app.http";
+
+    mod names {
+        pub const ERRORS: &str = "failures";
+    }
+
+    #[metrics(prefix = PREFIX, convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = HELP_REQUESTS)]
+        requests: u64,
+
+        #[counter(help = "Errors", name = names::ERRORS)]
+        errors: u64,
+
+        #[gauge(help = concat!("Crate ", env!("CARGO_PKG_NAME")), prefix = env!("CARGO_PKG_NAME"))]
+        info: u64,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        errors: 2,
+        info: 3,
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_http_requests_total Total requests\n\
+         # TYPE app_http_requests_total counter\n\
+         app_http_requests_total 1\n\
+         # HELP app_http_failures_total Errors\n\
+         # TYPE app_http_failures_total counter\n\
+         app_http_failures_total 2\n\
+         # HELP app_http_aetos_info Crate aetos\n\
+         # TYPE app_http_aetos_info gauge\n\
+         app_http_aetos_info 3\n"
+    );
+    // Names built at runtime are cached, rendering again gives the same output
+    assert_eq!(m.to_string(), m.to_string());
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = HELP_REQUESTS, name = names::ERRORS)]
+        errors: u64,
+    }
+
+    #[metrics]
+    struct GroupMetrics {
+        #[group(label = "path")]
+        endpoint: Vec<(&'static str, Stats)>,
+    }
+
+    let m = GroupMetrics {
+        endpoint: vec![("/", Stats { errors: 4 })],
+    };
+    assert!(
+        m.to_string()
+            .contains("# HELP endpoint_failures Total requests\n")
+    );
+    assert!(m.to_string().contains("endpoint_failures{path=\"/\"} 4\n"));
+}
+
+#[test]
+#[cfg(not(feature = "openmetrics"))]
+fn test_no_prefix() {
+    #[metrics(prefix = "myapp")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Start time of the process", unit = "seconds", no_prefix)]
+        process_start_time: u64,
+
+        #[gauge(help = "Open connections", no_prefix, prefix = "db")]
+        connections: u64,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        process_start_time: 1700000000,
+        connections: 4,
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP myapp_requests Total requests\n\
+         # TYPE myapp_requests counter\n\
+         myapp_requests 1\n\
+         # HELP process_start_time_seconds Start time of the process\n\
+         # TYPE process_start_time_seconds gauge\n\
+         process_start_time_seconds 1700000000\n\
+         # HELP db_connections Open connections\n\
+         # TYPE db_connections gauge\n\
+         db_connections 4\n"
+    );
+}
+
+#[test]
+fn test_namespace_and_subsystem() {
+    #[metrics(namespace = "myapp", subsystem = "http")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Pool connections", prefix = "pool")]
+        connections: u64,
+    }
+
+    #[metrics(subsystem = "http")]
+    struct SubsystemOnly {
+        #[counter(help = "Total requests")]
+        requests: u64,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        connections: 2,
+    };
+    let output = m.to_string();
+    assert!(output.contains("# HELP myapp_http_requests Total requests\n"));
+    assert!(output.contains("myapp_http_requests 1\n"));
+    assert!(output.contains("myapp_http_pool_connections 2\n"));
+
+    let m = SubsystemOnly { requests: 3 };
+    assert!(m.to_string().contains("http_requests 3\n"));
+}
+
+#[test]
+fn test_metric_names() {
+    use aetos::MetricGroup;
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    const NAME: &str = "failures";
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Hits")]
+        hits: u64,
+        #[counter(help = "Misses")]
+        misses: u64,
+    }
+
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Evictions")]
+        evictions: u64,
+    }
+
+    #[metrics(prefix = "app", convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[counter(help = "Errors", name = NAME)]
+        errors: u64,
+
+        #[gauge(help = "Uptime", unit = "seconds")]
+        uptime: Option<f64>,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+
+        #[group(label = "path")]
+        endpoint: HashMap<String, Stats>,
+
+        #[nested]
+        cache: CacheMetrics,
+    }
+
+    assert_eq!(
+        TestMetrics::metric_names(),
+        [
+            "app_requests_total",
+            "app_failures_total",
+            "app_uptime_seconds",
+            "app_latency",
+            "app_endpoint_hits_total",
+            "app_endpoint_misses_total",
+            "cache_evictions",
+        ]
+    );
+
+    #[metrics(histogram_series)]
+    struct SeriesMetrics {
+        #[histogram(help = "Latency")]
+        latency: Latency,
+    }
+
+    assert_eq!(
+        SeriesMetrics::metric_names(),
+        ["latency", "latency_bucket", "latency_sum", "latency_count"]
+    );
+}
+
+#[test]
+fn test_describe() {
+    use aetos::MetricGroup;
+    use aetos::core::MetricMetadata;
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Hits")]
+        hits: u64,
+        #[gauge]
+        size: u64,
+    }
+
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Evictions")]
+        evictions: u64,
+    }
+
+    #[metrics(prefix = "app", convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Uptime", unit = "seconds")]
+        uptime: Option<f64>,
+
+        #[histogram(name = "request_latency")]
+        latency: Latency,
+
+        #[group(label = "path")]
+        endpoint: HashMap<String, Stats>,
+
+        #[nested]
+        cache: CacheMetrics,
+    }
+
+    let meta = |name, help: Option<&'static str>, kind, unit: Option<&'static str>| {
+        let meta = MetricMetadata::builder(name, help, kind);
+        match unit {
+            Some(unit) => meta.unit(unit).build(),
+            None => meta.build(),
+        }
+    };
+    assert_eq!(
+        TestMetrics::describe(),
+        [
+            meta(
+                "app_requests_total",
+                Some("Total requests"),
+                "counter",
+                None
+            ),
+            meta(
+                "app_uptime_seconds",
+                Some("Uptime"),
+                "gauge",
+                Some("seconds")
+            ),
+            meta("app_request_latency", None, "histogram", None),
+            meta("app_endpoint_hits_total", Some("Hits"), "counter", None),
+            meta("app_endpoint_size", None, "gauge", None),
+            meta("cache_evictions", Some("Evictions"), "counter", None),
+        ]
+    );
+}
+
+#[test]
+fn test_render_one() {
+    use aetos::MetricGroup;
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter]
+        hits: u64,
+        #[gauge]
+        size: u64,
+    }
+
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Evictions")]
+        evictions: u64,
+    }
+
+    #[metrics(prefix = "app", convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Uptime")]
+        uptime: Option<f64>,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+
+        #[group(label = "path")]
+        endpoint: HashMap<String, Stats>,
+
+        #[nested]
+        cache: CacheMetrics,
+    }
+
+    let mut latency = Latency::new();
+    latency.observe((), 0.25);
+    let m = TestMetrics {
+        requests: 3,
+        uptime: None,
+        latency,
+        endpoint: HashMap::from([("/".to_string(), Stats { hits: 1, size: 2 })]),
+        cache: CacheMetrics { evictions: 4 },
+    };
+
+    assert_eq!(
+        m.render_one("app_requests_total").unwrap(),
+        "# HELP app_requests_total Total requests\n\
+         # TYPE app_requests_total counter\n\
+         app_requests_total 3\n"
+    );
+    assert_eq!(m.render_one("app_requests"), None);
+
+    // Known name, but the value is `None`
+    assert_eq!(m.render_one("app_uptime").unwrap(), "");
+
+    let latency = m.render_one("app_latency").unwrap();
+    assert!(latency.starts_with("# HELP app_latency Latency\n"));
+    assert!(latency.contains("app_latency_count{} 1\n"));
+    assert_eq!(m.render_one("app_latency_bucket").unwrap(), latency);
+    assert_eq!(m.render_one("app_latency_sum").unwrap(), latency);
+    assert_eq!(m.render_one("app_latency_count").unwrap(), latency);
+    assert_eq!(m.render_one("app_latency_total"), None);
+
+    let endpoint = m.render_one("app_endpoint_size").unwrap();
+    assert!(endpoint.contains("app_endpoint_hits_total{path=\"/\"} 1\n"));
+    assert!(endpoint.contains("app_endpoint_size{path=\"/\"} 2\n"));
+    assert_eq!(m.render_one("app_endpoint"), None);
+
+    assert_eq!(
+        m.render_one("cache_evictions").unwrap(),
+        "# HELP cache_evictions Evictions\n\
+         # TYPE cache_evictions counter\n\
+         cache_evictions 4\n"
+    );
+    assert_eq!(m.render_one("unknown"), None);
+}
+
+#[test]
+fn test_filtered() {
+    use aetos::{Filtered, MetricGroup};
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter]
+        hits: u64,
+        #[gauge]
+        size: u64,
+    }
+
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Evictions")]
+        evictions: u64,
+        #[counter(help = "Misses")]
+        misses: u64,
+    }
+
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Uptime")]
+        uptime: f64,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+
+        #[group(label = "path")]
+        endpoint: HashMap<String, Stats>,
+
+        #[nested]
+        cache: CacheMetrics,
+    }
+
+    let mut latency = Latency::new();
+    latency.observe((), 0.25);
+    let m = TestMetrics {
+        requests: 3,
+        uptime: 1.5,
+        latency,
+        endpoint: HashMap::from([("/".to_string(), Stats { hits: 1, size: 2 })]),
+        cache: CacheMetrics {
+            evictions: 4,
+            misses: 5,
+        },
+    };
+
+    assert_eq!(Filtered(&m, |_: &str| true).to_string(), m.to_string());
+    assert_eq!(Filtered(&m, |_: &str| false).to_string(), "");
+
+    let slo = ["app_requests", "app_latency", "cache_misses"];
+    let output = Filtered(&m, |name: &str| slo.contains(&name)).to_string();
+    assert!(output.contains("app_requests 3\n"));
+    assert!(output.contains("app_latency_count{} 1\n"));
+    assert!(output.contains("cache_misses 5\n"));
+    assert!(!output.contains("app_uptime"));
+    assert!(!output.contains("app_endpoint"));
+    assert!(!output.contains("cache_evictions"));
+
+    // Histograms only match their base name
+    let output = Filtered(&m, |name: &str| name == "app_latency_bucket").to_string();
+    assert_eq!(output, "");
+
+    // Groups are rendered whole when any member matches
+    let output = Filtered(&m, |name: &str| name == "app_endpoint_hits").to_string();
+    assert!(output.contains("app_endpoint_hits{path=\"/\"} 1\n"));
+    assert!(output.contains("app_endpoint_size{path=\"/\"} 2\n"));
+}
+
+#[test]
+fn test_field_methods_do_not_clash() {
+    // The generated rendering code lives in trait impls, so the inherent namespace is
+    // free for the user
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Uptime")]
+        uptime: f64,
+    }
+
+    impl TestMetrics {
+        fn fmt_requests(&self) -> String {
+            format!("{} requests", self.requests)
+        }
+
+        fn fmt_uptime(&self) -> String {
+            format!("up for {}s", self.uptime)
+        }
+
+        fn render_field(&self, index: usize) -> Option<u64> {
+            (index == 0).then_some(self.requests)
+        }
+    }
+
+    let m = TestMetrics {
+        requests: 3,
+        uptime: 1.5,
+    };
+    assert_eq!(m.fmt_requests(), "3 requests");
+    assert_eq!(m.fmt_uptime(), "up for 1.5s");
+    assert_eq!(m.render_field(0), Some(3));
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_requests Total requests\n\
+         # TYPE app_requests counter\n\
+         app_requests 3\n\
+         # HELP app_uptime Uptime\n\
+         # TYPE app_uptime gauge\n\
+         app_uptime 1.5\n"
+    );
+}
+
+#[test]
+fn test_generic_struct() {
+    use aetos::MetricGroup;
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter]
+        hits: u64,
+    }
+
+    #[derive(MetricGroup)]
+    struct Ratios {
+        #[gauge]
+        hits: f64,
+    }
+
+    #[derive(Label)]
+    struct PathLabel {
+        path: &'static str,
+    }
+
+    #[metrics]
+    struct TestMetrics<T, K, L, H, G> {
+        #[gauge(help = "Value")]
+        value: T,
+
+        #[counter(help = "By key", label = "key")]
+        by_key: HashMap<K, T>,
+
+        #[counter(help = "By tuple", labels = ["a", "b"])]
+        by_tuple: Vec<((K, K), T)>,
+
+        #[counter(help = "By label")]
+        by_label: Vec<(L, T)>,
+
+        #[histogram(help = "Latency")]
+        latency: H,
+
+        #[group(label = "key")]
+        stats: HashMap<K, G>,
+    }
+
+    let m = TestMetrics {
+        value: 1u64,
+        by_key: HashMap::from([("a", 2u64)]),
+        by_tuple: vec![(("x", "y"), 3)],
+        by_label: vec![(PathLabel { path: "/" }, 4)],
+        latency: Latency::new(),
+        stats: HashMap::from([("a", Stats { hits: 5 })]),
+    };
+    let output = m.to_string();
+    assert!(output.contains("value 1\n"));
+    assert!(output.contains("by_key{key=\"a\"} 2\n"));
+    assert!(output.contains("by_tuple{a=\"x\",b=\"y\"} 3\n"));
+    assert!(output.contains("by_label{path=\"/\"} 4\n"));
+    assert!(output.contains("# TYPE latency histogram\n"));
+    assert!(output.contains("stats_hits{key=\"a\"} 5\n"));
+
+    let m = TestMetrics {
+        value: 1.5f64,
+        by_key: HashMap::from([(7u8, 2.5f64)]),
+        by_tuple: vec![((1u8, 2u8), 3.5)],
+        by_label: vec![((), 4.5)],
+        latency: Latency::new(),
+        stats: HashMap::from([(7u8, Ratios { hits: 0.5 })]),
+    };
+    let output = m.to_string();
+    assert!(output.contains("value 1.5\n"));
+    assert!(output.contains("by_key{key=\"7\"} 2.5\n"));
+    assert!(output.contains("by_tuple{a=\"1\",b=\"2\"} 3.5\n"));
+    assert!(output.contains("by_label{} 4.5\n"));
+    assert!(output.contains("stats_hits{key=\"7\"} 0.5\n"));
+}
+
+#[test]
+fn test_generic_struct_explicit_bound() {
+    use std::fmt::{self, Display};
+
+    struct Celsius<T>(T);
+
+    impl<T: Display> Display for Celsius<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    // Nothing is inferred for `Celsius<T>`, which is not a collection
+    #[metrics(bound = "T: Display")]
+    struct TestMetrics<T> {
+        #[gauge(help = "Temperature")]
+        temperature: Celsius<T>,
+    }
+
+    let m = TestMetrics {
+        temperature: Celsius(21.5),
+    };
+    assert!(m.to_string().contains("temperature 21.5\n"));
+
+    let m = TestMetrics {
+        temperature: Celsius(21),
+    };
+    assert!(m.to_string().contains("temperature 21\n"));
+}
+
+#[test]
+fn test_display_false() {
+    use aetos::Filtered;
+
+    #[metrics(prefix = "app", display = false)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+        #[gauge(help = "Active connections")]
+        connections: u64,
+    }
+
+    impl std::fmt::Display for TestMetrics {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} requests", self.requests)
+        }
+    }
+
+    #[metrics]
+    struct Outer {
+        #[nested]
+        inner: TestMetrics,
+    }
+
+    let m = TestMetrics {
+        requests: 7,
+        connections: 2,
+    };
+
+    assert_eq!(m.to_string(), "7 requests");
+    let output = m.render_prometheus();
+    assert!(output.contains("# TYPE app_requests counter\napp_requests 7\n"));
+    assert!(output.contains("app_connections 2\n"));
+
+    let filtered = Filtered(&m, |name: &str| name == "app_connections").to_string();
+    assert!(!filtered.contains("app_requests"));
+    assert!(filtered.contains("app_connections 2\n"));
+
+    let outer = Outer { inner: m };
+    assert_eq!(outer.to_string(), output);
+}
+
+#[test]
+fn test_computed_metrics() {
+    use std::collections::VecDeque;
+
+    #[metrics(
+        prefix = "app",
+        generate_new,
+        extra(
+            gauge(name = "hit_ratio", help = "Share of hits", compute = "hit_ratio"),
+            counter(help = "Jobs by state", label = "state", compute = "jobs_by_state"),
+        )
+    )]
+    struct TestMetrics {
+        #[counter(help = "Cache hits")]
+        hits: u64,
+        #[counter(help = "Cache lookups")]
+        lookups: u64,
+        #[gauge(help = "Queue length", compute = "queue_len")]
+        queue: VecDeque<u32>,
+    }
+
+    impl TestMetrics {
+        fn queue_len(&self) -> usize {
+            self.queue.len()
+        }
+
+        fn hit_ratio(&self) -> f64 {
+            self.hits as f64 / self.lookups as f64
+        }
+
+        fn jobs_by_state(&self) -> Vec<(&'static str, u64)> {
+            vec![("done", self.lookups)]
+        }
+    }
+
+    let mut m = TestMetrics::new();
+    m.hits = 1;
+    m.lookups = 4;
+    m.queue.extend([1, 2, 3]);
+
+    let output = m.to_string();
+    assert!(
+        output.contains("# HELP app_queue Queue length\n# TYPE app_queue gauge\napp_queue 3\n")
+    );
+    assert!(output.contains("# TYPE app_hit_ratio gauge\napp_hit_ratio 0.25\n"));
+    assert!(output.contains("app_jobs_by_state{state=\"done\"} 4\n"));
+    assert!(output.ends_with("app_jobs_by_state{state=\"done\"} 4\n"));
+    assert_eq!(
+        TestMetrics::metric_names(),
+        [
+            "app_hits",
+            "app_lookups",
+            "app_queue",
+            "app_hit_ratio",
+            "app_jobs_by_state"
+        ]
+    );
+    assert_eq!(
+        m.render_one("app_hit_ratio").unwrap(),
+        "# HELP app_hit_ratio Share of hits\n# TYPE app_hit_ratio gauge\napp_hit_ratio 0.25\n"
+    );
+}
+
+#[test]
+fn test_closure_fields() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    #[metrics(prefix = "app")]
+    struct TestMetrics<F: Fn() -> f64> {
+        #[gauge(help = "Seconds since start", unit = "seconds")]
+        uptime: Box<dyn Fn() -> f64 + Send + Sync>,
+        #[counter(help = "Scrapes")]
+        scrapes: Box<dyn Fn() -> u64 + Send + Sync>,
+        #[gauge(help = "Load")]
+        load: F,
+        #[gauge(help = "Not started")]
+        pending: Option<Box<dyn Fn() -> u64 + Send + Sync>>,
+    }
+
+    let start = Instant::now();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&calls);
+    let m = TestMetrics {
+        uptime: Box::new(move || start.elapsed().as_secs_f64()),
+        scrapes: Box::new(move || counted.fetch_add(1, Ordering::Relaxed) as u64 + 1),
+        load: || 0.5,
+        pending: None,
+    };
+
+    let output = m.to_string();
+    let uptime = output
+        .lines()
+        .find_map(|line| line.strip_prefix("app_uptime_seconds "))
+        .unwrap();
+    assert!(uptime.parse::<f64>().unwrap() >= 0.0);
+    assert!(output.contains("app_scrapes 1\n"));
+    assert!(output.contains("app_load 0.5\n"));
+    assert!(!output.contains("app_pending"));
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+    assert!(m.to_string().contains("app_scrapes 2\n"));
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_helpers() {
+    use std::collections::{BTreeMap, HashMap};
+
+    #[metrics(helpers)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+
+        #[gauge(help = "Temperature")]
+        temperature: f64,
+
+        #[counter(help = "Events by type", label = "type")]
+        events: BTreeMap<String, u64>,
+
+        #[gauge(help = "Queue depth by name", label = "queue")]
+        depth: HashMap<&'static str, i64>,
+    }
+
+    let mut m = TestMetrics {
+        requests: 0,
+        temperature: 0.0,
+        events: BTreeMap::new(),
+        depth: HashMap::new(),
+    };
+
+    m.inc_requests();
+    m.add_requests(2);
+    m.set_temperature(21.5);
+    m.inc_events("click");
+    m.add_events("click", 2);
+    m.inc_events(String::from("scroll"));
+    m.set_depth("jobs", 4);
+    m.set_depth("jobs", 3);
+
+    assert_eq!(m.requests, 3);
+    assert_eq!(m.temperature, 21.5);
+    assert_eq!(
+        m.events,
+        BTreeMap::from([("click".to_string(), 3), ("scroll".to_string(), 1)])
+    );
+    assert_eq!(m.depth, HashMap::from([("jobs", 3)]));
+
+    let output = m.to_string();
+    assert!(output.contains("events{type=\"click\"} 3\n"));
+    assert!(output.contains("depth{queue=\"jobs\"} 3\n"));
+}
+
+#[test]
+fn test_const_labels() {
+    use aetos::MetricGroup;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct EndpointLabel {
+        endpoint: &'static str,
+    }
+
+    define_histogram!(Latency<EndpointLabel> = [0.5]);
+    define_histogram!(QueueTime<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter]
+        hits: u64,
+    }
+
+    #[metrics(prefix = "app", labels(service = "my-svc", env = "prod"))]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+        #[gauge(help = "Sessions by user", label = "user")]
+        sessions: HashMap<String, u64>,
+        #[counter(help = "Requests by endpoint")]
+        by_endpoint: Vec<(EndpointLabel, u64)>,
+        #[counter(help = "Runtime labels")]
+        runtime: Vec<(BTreeMap<String, String>, u64)>,
+        #[gauge(help = "Requests by method and status", labels = ["method", "status"])]
+        by_status: HashMap<(&'static str, u16), u64>,
+        #[histogram(help = "Latency")]
+        latency: Latency,
+        #[histogram(help = "Queue time")]
+        queue_time: QueueTime,
+        #[group(label = "path")]
+        cache: HashMap<&'static str, Stats>,
+    }
+
+    let mut latency = Latency::new();
+    latency.observe(EndpointLabel { endpoint: "/" }, 0.1);
+    let mut queue_time = QueueTime::new();
+    queue_time.observe((), 1.0);
+
+    let m = TestMetrics {
+        requests: 3,
+        sessions: HashMap::from([("alice".to_string(), 1)]),
+        by_endpoint: vec![(EndpointLabel { endpoint: "/api" }, 2)],
+        runtime: vec![(BTreeMap::new(), 4)],
+        by_status: HashMap::from([(("GET", 200), 5)]),
+        latency,
+        queue_time,
+        cache: HashMap::from([("/", Stats { hits: 6 })]),
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_requests Total requests\n\
+         # TYPE app_requests counter\n\
+         app_requests{service=\"my-svc\",env=\"prod\"} 3\n\
+         # HELP app_sessions Sessions by user\n\
+         # TYPE app_sessions gauge\n\
+         app_sessions{user=\"alice\",service=\"my-svc\",env=\"prod\"} 1\n\
+         # HELP app_by_endpoint Requests by endpoint\n\
+         # TYPE app_by_endpoint counter\n\
+         app_by_endpoint{endpoint=\"/api\",service=\"my-svc\",env=\"prod\"} 2\n\
+         # HELP app_runtime Runtime labels\n\
+         # TYPE app_runtime counter\n\
+         app_runtime{service=\"my-svc\",env=\"prod\"} 4\n\
+         # HELP app_by_status Requests by method and status\n\
+         # TYPE app_by_status gauge\n\
+         app_by_status{method=\"GET\",status=\"200\",service=\"my-svc\",env=\"prod\"} 5\n\
+         # HELP app_latency Latency\n\
+         # TYPE app_latency histogram\n\
+         app_latency_bucket{endpoint=\"/\",service=\"my-svc\",env=\"prod\",le=\"0.500\"} 1\n\
+         app_latency_bucket{endpoint=\"/\",service=\"my-svc\",env=\"prod\",le=\"+Inf\"} 1\n\
+         app_latency_sum{endpoint=\"/\",service=\"my-svc\",env=\"prod\"} 0.1\n\
+         app_latency_count{endpoint=\"/\",service=\"my-svc\",env=\"prod\"} 1\n\
+         # HELP app_queue_time Queue time\n\
+         # TYPE app_queue_time histogram\n\
+         app_queue_time_bucket{service=\"my-svc\",env=\"prod\",le=\"0.500\"} 0\n\
+         app_queue_time_bucket{service=\"my-svc\",env=\"prod\",le=\"+Inf\"} 1\n\
+         app_queue_time_sum{service=\"my-svc\",env=\"prod\"} 1\n\
+         app_queue_time_count{service=\"my-svc\",env=\"prod\"} 1\n\
+         # TYPE app_cache_hits counter\n\
+         app_cache_hits{path=\"/\",service=\"my-svc\",env=\"prod\"} 6\n"
+    );
+
+    assert!(
+        TestMetrics::describe()
+            .iter()
+            .all(|meta| meta.const_labels == [("service", "my-svc"), ("env", "prod")])
+    );
+}
+
+#[test]
+#[cfg(not(feature = "no-escaping"))]
+fn test_const_labels_escaping() {
+    use std::collections::HashMap;
+
+    #[metrics(labels(region = "eu\"west"))]
+    struct TestMetrics {
+        #[gauge(help = "Sessions by user", label = "user")]
+        sessions: HashMap<String, u64>,
+    }
+
+    let m = TestMetrics {
+        sessions: HashMap::from([("a\"b\\c\nd".to_string(), 1)]),
+    };
+    assert!(
+        m.to_string()
+            .contains("sessions{user=\"a\\\"b\\\\c\\nd\",region=\"eu\\\"west\"} 1\n")
+    );
+}
+
+#[test]
+fn test_default() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(default)]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+        #[gauge(help = "Temperature")]
+        temperature: f64,
+        #[counter(help = "Events by type", label = "type")]
+        events: HashMap<String, u64>,
+        #[histogram(help = "Latency")]
+        latency: Latency,
+        #[gauge(help = "Optional")]
+        optional: Option<u64>,
+        #[metrics_field(skip)]
+        name: &'static str,
+    }
+
+    let mut m = TestMetrics::default();
+    assert_eq!(m.requests, 0);
+    assert_eq!(m.temperature, 0.0);
+    assert!(m.events.is_empty());
+    assert!(m.latency.data.is_empty());
+    assert_eq!(m.optional, None);
+    assert_eq!(m.name, "");
+
+    // The defaulted histogram has the buckets of its type
+    m.latency.observe((), 0.1);
+    assert!(m.to_string().contains("latency_bucket{le=\"0.500\"} 1\n"));
+    assert_eq!(
+        TestMetrics::new().to_string(),
+        TestMetrics::default().to_string()
+    );
+    assert!(TestMetrics::new().to_string().contains("requests 0\n"));
+}
+
+#[test]
+fn test_size_hint() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(prefix = "cache")]
+    struct CacheMetrics {
+        #[counter(help = "Cache hits")]
+        hits: u64,
+    }
+
+    #[metrics(prefix = "app", labels(service = "api"))]
+    struct TestMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+        #[gauge(unit = "seconds")]
+        uptime: f64,
+        #[counter(help = "Events by type", label = "type")]
+        events: HashMap<String, u64>,
+        #[histogram(help = "Latency")]
+        latency: Latency,
+        #[gauge(help = "Optional")]
+        optional: Option<u64>,
+        #[nested]
+        cache: CacheMetrics,
+    }
+
+    #[metrics]
+    struct Scalars {
+        #[counter]
+        a: u64,
+        #[gauge(help = "B")]
+        b: f64,
+    }
+
+    let mut m = TestMetrics {
+        requests: 0,
+        uptime: 0.0,
+        events: HashMap::new(),
+        latency: Latency::new(),
+        optional: None,
+        cache: CacheMetrics { hits: 0 },
+    };
+    let hint = m.size_hint();
+    assert!(hint > 0);
+    assert!(hint <= m.to_string().len());
+
+    m.events.insert("click".to_string(), 123);
+    m.latency.observe((), 0.1);
+    m.optional = Some(7);
+    assert_eq!(m.size_hint(), hint);
+    assert!(m.size_hint() <= m.to_string().len());
+
+    let s = Scalars { a: 1, b: 2.0 };
+    assert!(s.size_hint() <= s.to_string().len());
+    // Every value is a single character, which the hint leaves out
+    assert_eq!(s.size_hint(), s.to_string().len() - 2);
+}
+
+#[test]
+fn test_alias() {
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(prefix = "http", convention = "prometheus")]
+    struct TestMetrics {
+        #[counter(help = "Total requests", name = "requests_total", alias = "reqs")]
+        requests: u64,
+        #[histogram(help = "Latency", alias = ["lat", "time"])]
+        latency: Latency,
+    }
+
+    let mut m = TestMetrics {
+        requests: 4,
+        latency: Latency::new(),
+    };
+    m.latency.observe((), 0.1);
+
+    let output = m.to_string();
+    assert!(output.contains(
+        "# HELP http_requests_total Total requests\n\
+         # TYPE http_requests_total counter\n\
+         http_requests_total 4\n\
+         # HELP http_reqs_total Total requests\n\
+         # TYPE http_reqs_total counter\n\
+         http_reqs_total 4\n"
+    ));
+    for name in ["http_latency", "http_lat", "http_time"] {
+        assert!(output.contains(&format!("{}_count{{}} 1\n", name)));
+    }
+
+    assert_eq!(
+        TestMetrics::metric_names(),
+        [
+            "http_requests_total",
+            "http_reqs_total",
+            "http_latency",
+            "http_lat",
+            "http_time"
+        ]
+    );
+    assert_eq!(TestMetrics::describe().len(), 5);
+    assert_eq!(
+        m.render_one("http_reqs_total"),
+        m.render_one("http_requests_total")
+    );
+    assert!(
+        m.render_one("http_time_sum")
+            .unwrap()
+            .contains("http_latency_sum{} 0.1\n")
+    );
+    assert!(m.size_hint() <= output.len());
+}
+
+#[test]
+fn test_dyn_prometheus_metric() {
+    #[metrics(prefix = "http")]
+    struct HttpMetrics {
+        #[counter(help = "Total requests")]
+        requests: u64,
+    }
+
+    #[metrics(prefix = "db", display = false)]
+    struct DbMetrics {
+        #[gauge(help = "Open connections")]
+        connections: u64,
+    }
+
+    let registry: Vec<Box<dyn PrometheusMetric + Send + Sync>> = vec![
+        Box::new(HttpMetrics { requests: 3 }),
+        Box::new(DbMetrics { connections: 2 }),
+    ];
+
+    let output: String = registry.iter().map(|m| m.to_string()).collect();
+    assert_eq!(
+        output,
+        "# HELP http_requests Total requests\n\
+         # TYPE http_requests counter\n\
+         http_requests 3\n\
+         # HELP db_connections Open connections\n\
+         # TYPE db_connections gauge\n\
+         db_connections 2\n"
+    );
+
+    let single: &dyn PrometheusMetric = &HttpMetrics { requests: 1 };
+    assert_eq!(single.to_string(), HttpMetrics { requests: 1 }.to_string());
+}
+
+#[test]
+fn test_duration_fields() {
+    use std::collections::{BTreeMap, HashMap};
+    use std::time::Duration;
+
+    #[derive(Label, Hash, Eq, PartialEq, Ord, PartialOrd)]
+    struct Job {
+        name: &'static str,
+    }
+
+    #[metrics]
+    struct TestMetrics {
+        #[gauge(help = "Time since start", unit = "seconds")]
+        uptime: Duration,
+        #[counter(help = "Time spent busy")]
+        busy: std::time::Duration,
+        #[gauge(help = "Last backup duration")]
+        backup: Option<Duration>,
+        #[gauge(help = "Time per stage", label = "stage")]
+        stages: HashMap<String, Duration>,
+        #[gauge(help = "Time per job")]
+        jobs: BTreeMap<Job, Duration>,
+        #[gauge(help = "Time per route", labels = ["method", "path"])]
+        routes: Vec<((&'static str, &'static str), Duration)>,
+    }
+
+    let mut m = TestMetrics {
+        uptime: Duration::from_millis(1500),
+        busy: Duration::from_secs(2),
+        backup: None,
+        stages: HashMap::from([("parse".to_string(), Duration::from_millis(250))]),
+        jobs: BTreeMap::from([(Job { name: "sync" }, Duration::from_micros(1))]),
+        routes: vec![(("GET", "/"), Duration::from_secs(3))],
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("uptime_seconds 1.5\n"));
+    assert!(output.contains("busy 2\n"));
+    assert!(!output.contains("backup"));
+    assert!(output.contains("stages{stage=\"parse\"} 0.25\n"));
+    assert!(output.contains("jobs{name=\"sync\"} 0.000001\n"));
+    assert!(output.contains("routes{method=\"GET\",path=\"/\"} 3\n"));
+    assert!(m.size_hint() <= output.len());
+
+    m.backup = Some(Duration::from_millis(100));
+    assert!(m.to_string().contains("backup 0.1\n"));
+}
+
+#[test]
+fn test_duration_generic_key() {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[metrics]
+    struct TestMetrics<K: std::hash::Hash + Eq + std::fmt::Display> {
+        #[gauge(help = "Time per key", label = "key")]
+        times: HashMap<K, Duration>,
+    }
+
+    let m = TestMetrics {
+        times: HashMap::from([(7u32, Duration::from_millis(500))]),
+    };
+    assert!(m.to_string().contains("times{key=\"7\"} 0.5\n"));
+}
+
+#[test]
+fn test_system_time_gauges() {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[metrics(prefix = "process")]
+    struct TestMetrics {
+        #[gauge(
+            help = "Start time of the process",
+            name = "start_time",
+            unit = "seconds"
+        )]
+        start: SystemTime,
+        #[gauge(help = "Last successful sync")]
+        last_sync: std::time::SystemTime,
+        #[gauge(help = "Before the epoch")]
+        ancient: SystemTime,
+        #[gauge(help = "Last run per job", label = "job")]
+        last_run: HashMap<&'static str, SystemTime>,
+    }
+
+    let m = TestMetrics {
+        start: UNIX_EPOCH + Duration::from_millis(1_699_999_999_123),
+        last_sync: UNIX_EPOCH,
+        ancient: UNIX_EPOCH - Duration::from_millis(1500),
+        last_run: HashMap::from([("backup", UNIX_EPOCH + Duration::from_secs(1_700_000_000))]),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("# TYPE process_start_time_seconds gauge\n"));
+    assert!(output.contains("process_start_time_seconds 1699999999.123\n"));
+    assert!(output.contains("process_last_sync 0\n"));
+    // Times before the epoch are negative, not clamped
+    assert!(output.contains("process_ancient -1.5\n"));
+    assert!(output.contains("process_last_run{job=\"backup\"} 1700000000\n"));
+    assert!(m.size_hint() <= output.len());
+}
+
+#[test]
+fn test_bool_fields() {
+    use std::collections::{BTreeMap, HashMap};
+
+    #[metrics(generate_methods)]
+    struct TestMetrics {
+        #[gauge(help = "Whether the service is up")]
+        up: bool,
+        #[gauge(help = "Whether maintenance is on")]
+        maintenance: Option<bool>,
+        #[gauge(help = "Enabled feature flags", label = "flag")]
+        features: HashMap<String, bool>,
+        #[gauge(help = "Healthy dependencies", labels = ["kind", "name"])]
+        healthy: BTreeMap<(&'static str, &'static str), bool>,
+    }
+
+    let mut m = TestMetrics {
+        up: true,
+        maintenance: Some(false),
+        features: HashMap::from([("dark_mode".to_string(), true)]),
+        healthy: BTreeMap::from([(("db", "primary"), true), (("db", "replica"), false)]),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("up 1\n"));
+    assert!(output.contains("maintenance 0\n"));
+    assert!(output.contains("features{flag=\"dark_mode\"} 1\n"));
+    assert!(output.contains("healthy{kind=\"db\",name=\"primary\"} 1\n"));
+    assert!(output.contains("healthy{kind=\"db\",name=\"replica\"} 0\n"));
+    assert!(!output.contains("true") && !output.contains("false"));
+    assert!(m.size_hint() <= output.len());
+
+    m.set_up(false);
+    m.set_features("dark_mode", false);
+    let output = m.to_string();
+    assert!(output.contains("up 0\n"));
+    assert!(output.contains("features{flag=\"dark_mode\"} 0\n"));
+}
+
+#[test]
+fn test_skip_zero() {
+    use aetos::MetricGroup;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Method {
+        method: &'static str,
+    }
+
+    define_histogram!(Latency<Method> = [0.1, 1.0]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Hits")]
+        hits: u64,
+        #[counter(help = "Misses")]
+        misses: u64,
+    }
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests by status", label = "status", skip_zero)]
+        requests: BTreeMap<u16, u64>,
+        #[gauge(help = "Queue depth", skip_zero)]
+        queue: f64,
+        #[counter(help = "Errors by kind", label = "kind", skip_zero)]
+        errors: HashMap<&'static str, u64>,
+        #[counter(help = "Retries by kind", label = "kind")]
+        retries: HashMap<&'static str, u64>,
+        #[histogram(help = "Latency", skip_zero)]
+        latency: Latency,
+        #[group(label = "cache", skip_zero)]
+        cache: BTreeMap<&'static str, Stats>,
+    }
+
+    let mut latency = Latency::default();
+    latency.observe(Method { method: "GET" }, 0.5);
+    latency.observe_many(Method { method: "POST" }, []);
+
+    let mut m = TestMetrics {
+        requests: BTreeMap::from([(200, 5), (404, 0), (500, 0)]),
+        queue: -0.0,
+        errors: HashMap::from([("timeout", 0)]),
+        retries: HashMap::from([("timeout", 0)]),
+        latency,
+        cache: BTreeMap::from([
+            ("users", Stats { hits: 3, misses: 0 }),
+            ("posts", Stats { hits: 0, misses: 0 }),
+        ]),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains("requests{status=\"200\"} 5\n"));
+    assert!(!output.contains("status=\"404\""));
+    assert!(!output.contains("status=\"500\""));
+
+    // A family whose samples are all skipped renders nothing, not even its header
+    assert!(!output.contains("queue"));
+    assert!(!output.contains("errors"));
+
+    // Without `skip_zero`, zero samples are rendered as usual
+    assert!(output.contains("retries{kind=\"timeout\"} 0\n"));
+
+    assert!(output.contains("latency_count{method=\"GET\"} 1\n"));
+    assert!(!output.contains("POST"));
+
+    assert!(output.contains("cache_hits{cache=\"users\"} 3\n"));
+    assert!(!output.contains("cache=\"posts\""));
+    assert!(!output.contains("cache_misses"));
+    assert!(m.size_hint() <= output.len());
+
+    m.queue = 2.5;
+    m.errors.insert("timeout", 1);
+    let output = m.to_string();
+    assert!(output.contains("# TYPE queue gauge\nqueue 2.5\n"));
+    assert!(output.contains("# TYPE errors counter\nerrors{kind=\"timeout\"} 1\n"));
+}
+
+#[test]
+fn test_raw_labels() {
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Path {
+        path: &'static str,
+    }
+
+    define_histogram!(Latency<Path> = [0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Raw single label", label = "path", raw_labels)]
+        hits: HashMap<&'static str, u64>,
+
+        #[counter(help = "Raw label struct", raw_labels)]
+        requests: HashMap<Path, u64>,
+
+        #[counter(help = "Raw tuple labels", labels = ["dir", "file"], raw_labels)]
+        files: Vec<((&'static str, &'static str), u64)>,
+
+        #[counter(help = "Raw runtime labels", raw_labels)]
+        dynamic: Vec<(BTreeMap<&'static str, &'static str>, u64)>,
+
+        #[histogram(help = "Raw histogram labels", raw_labels)]
+        latency: Latency,
+
+        #[counter(help = "Escaped", label = "path")]
+        escaped: HashMap<&'static str, u64>,
+    }
+
+    let mut latency = Latency::default();
+    latency.observe(Path { path: r"C:\tmp" }, 0.1);
+
+    let m = TestMetrics {
+        hits: HashMap::from([(r"C:\tmp", 1)]),
+        requests: HashMap::from([(Path { path: r"C:\tmp" }, 2)]),
+        files: vec![((r"C:\tmp", "a\"b"), 3)],
+        dynamic: vec![(BTreeMap::from([("path", r"C:\tmp")]), 4)],
+        latency,
+        escaped: HashMap::from([(r"C:\tmp", 5)]),
+    };
+    let output = m.to_string();
+
+    assert!(output.contains(r#"hits{path="C:\tmp"} 1"#));
+    assert!(output.contains(r#"requests{path="C:\tmp"} 2"#));
+    assert!(output.contains(r#"files{dir="C:\tmp",file="a"b"} 3"#));
+    assert!(output.contains(r#"dynamic{path="C:\tmp"} 4"#));
+    assert!(output.contains(r#"latency_bucket{path="C:\tmp",le="0.500"} 1"#));
+    assert!(output.contains(r#"latency_count{path="C:\tmp"} 1"#));
+
+    // Fields without `raw_labels` are still escaped
+    #[cfg(not(feature = "no-escaping"))]
+    assert!(output.contains(r#"escaped{path="C:\\tmp"} 5"#));
+}
+
+#[test]
+#[cfg(feature = "sorted-output")]
+fn test_sorted_output() {
+    use std::collections::HashMap;
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Route {
+        path: &'static str,
+    }
+
+    define_histogram!(Latency<Route> = [0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Single label", label = "key")]
+        single: HashMap<&'static str, u64>,
+
+        #[counter(help = "Label struct")]
+        routes: HashMap<Route, u64>,
+
+        #[counter(help = "Tuple labels", labels = ["a", "b"])]
+        pairs: HashMap<(&'static str, u32), u64>,
+
+        #[histogram(help = "Latency")]
+        latency: Latency,
+
+        #[counter(help = "Vec keeps its order", label = "key")]
+        listed: Vec<(&'static str, u64)>,
+    }
+
+    let mut latency = Latency::default();
+    for path in ["/z", "/a", "/m"] {
+        latency.observe(Route { path }, 0.1);
+    }
+    let m = TestMetrics {
+        single: HashMap::from([("z", 1), ("a", 2), ("m", 3)]),
+        routes: HashMap::from([
+            (Route { path: "/z" }, 1),
+            (Route { path: "/a" }, 2),
+            (Route { path: "/m" }, 3),
+        ]),
+        pairs: HashMap::from([(("z", 1), 1), (("a", 2), 2), (("m", 3), 3)]),
+        latency,
+        listed: vec![("z", 1), ("a", 2), ("m", 3)],
+    };
+    let output = m.to_string();
+
+    let positions = |samples: [&str; 3]| samples.map(|sample| output.find(sample).unwrap());
+    let is_ascending = |[a, b, c]: [usize; 3]| a < b && b < c;
+
+    assert!(is_ascending(positions([
+        "single{key=\"a\"}",
+        "single{key=\"m\"}",
+        "single{key=\"z\"}",
+    ])));
+    assert!(is_ascending(positions([
+        "routes{path=\"/a\"}",
+        "routes{path=\"/m\"}",
+        "routes{path=\"/z\"}",
+    ])));
+    assert!(is_ascending(positions([
+        "pairs{a=\"a\",b=\"2\"}",
+        "pairs{a=\"m\",b=\"3\"}",
+        "pairs{a=\"z\",b=\"1\"}",
+    ])));
+    assert!(is_ascending(positions([
+        "latency_count{path=\"/a\"}",
+        "latency_count{path=\"/m\"}",
+        "latency_count{path=\"/z\"}",
+    ])));
+
+    // Collections with their own order are rendered in iteration order
+    assert!(is_ascending(positions([
+        "listed{key=\"z\"}",
+        "listed{key=\"a\"}",
+        "listed{key=\"m\"}",
+    ])));
+
+    // The same output on every render
+    assert_eq!(m.to_string(), output);
+}
+
+#[test]
+fn test_inline_histogram_buckets() {
+    use aetos::core::{Histogram, exponential_buckets, linear_buckets};
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Route {
+        path: &'static str,
+    }
+
+    #[metrics(default)]
+    struct TestMetrics {
+        #[histogram(help = "Latency", buckets = [0.05, 0.1, 0.5, 1.0])]
+        latency: Histogram<Route, 4>,
+
+        #[histogram(help = "Queue depth", buckets = linear_buckets::<3>(10.0, 10.0))]
+        depth: Histogram<(), 3>,
+
+        #[histogram(help = "Payload size", buckets = exponential_buckets::<2>(256.0, 4.0))]
+        size: Histogram<(), 2>,
+    }
+
+    #[metrics(generate_new)]
+    struct Constructed {
+        #[histogram(help = "Latency", buckets = [0.1, 1.0])]
+        latency: Histogram<(), 2>,
+    }
+
+    let mut m = TestMetrics::default();
+    m.latency.observe(Route { path: "/" }, 0.3);
+    m.depth.observe((), 15.0);
+    m.size.observe((), 300.0);
+    let output = m.to_string();
+
+    assert!(output.contains("latency_bucket{path=\"/\",le=\"0.100\"} 0\n"));
+    assert!(output.contains("latency_bucket{path=\"/\",le=\"0.500\"} 1\n"));
+    assert!(output.contains("depth_bucket{le=\"10.000\"} 0\n"));
+    assert!(output.contains("depth_bucket{le=\"20.000\"} 1\n"));
+    assert!(output.contains("depth_bucket{le=\"30.000\"} 1\n"));
+    assert!(output.contains("size_bucket{le=\"256.000\"} 0\n"));
+    assert!(output.contains("size_bucket{le=\"1024.000\"} 1\n"));
+
+    let mut constructed = Constructed::new();
+    constructed.latency.observe((), 0.5);
+    assert!(
+        constructed
+            .to_string()
+            .contains("latency_bucket{le=\"1.000\"} 1\n")
+    );
+}
+
+#[test]
+fn test_tuple_struct() {
+    use std::collections::HashMap;
+
+    #[metrics(prefix = "net", default, generate_methods)]
+    struct Throughput(
+        #[counter(name = "bytes_in", help = "Bytes received")] u64,
+        #[counter(name = "bytes_out", help = "Bytes sent")] u64,
+        #[gauge(name = "peers", help = "Peers by region", label = "region")]
+        HashMap<&'static str, i64>,
+    );
+
+    let mut m = Throughput::default();
+    m.add_bytes_in(100);
+    m.inc_bytes_out();
+    m.set_peers("eu", 3);
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP net_bytes_in Bytes received\n\
+         # TYPE net_bytes_in counter\n\
+         net_bytes_in 100\n\
+         # HELP net_bytes_out Bytes sent\n\
+         # TYPE net_bytes_out counter\n\
+         net_bytes_out 1\n\
+         # HELP net_peers Peers by region\n\
+         # TYPE net_peers gauge\n\
+         net_peers{region=\"eu\"} 3\n"
+    );
+    assert_eq!(m.0, 100);
+    assert_eq!(
+        Throughput::metric_names(),
+        ["net_bytes_in", "net_bytes_out", "net_peers"]
+    );
+}
+
+#[test]
+fn test_dynamic() {
+    use aetos::Filtered;
+    use std::collections::HashMap;
+
+    #[metrics(prefix = "lua", convention = "prometheus")]
+    struct Script {
+        #[gauge(help = "Loaded scripts")]
+        scripts: u32,
+        #[dynamic(kind = "gauge", help = "Value reported by a script")]
+        values: Vec<(String, f64)>,
+        #[dynamic(
+            kind = "counter",
+            help = "Event reported by a script",
+            prefix = "event"
+        )]
+        events: HashMap<&'static str, u64>,
+    }
+
+    let m = Script {
+        scripts: 2,
+        values: vec![
+            ("heap.size".to_string(), 1024.0),
+            ("5xx".to_string(), 3.0),
+            // Sanitized to the name above, and rendered with the last value
+            ("heap-size".to_string(), 2048.0),
+            // Named like the `scripts` field, skipped
+            ("scripts".to_string(), 5.0),
+        ],
+        events: HashMap::from([("reload", 4)]),
+    };
+
+    assert_eq!(
+        m.to_string(),
+        "# HELP lua_scripts Loaded scripts\n\
+         # TYPE lua_scripts gauge\n\
+         lua_scripts 2\n\
+         # HELP lua_5xx Value reported by a script\n\
+         # TYPE lua_5xx gauge\n\
+         lua_5xx 3\n\
+         # HELP lua_heap_size Value reported by a script\n\
+         # TYPE lua_heap_size gauge\n\
+         lua_heap_size 2048\n\
+         # HELP lua_event_reload_total Event reported by a script\n\
+         # TYPE lua_event_reload_total counter\n\
+         lua_event_reload_total 4\n"
+    );
+
+    // The names come from the data, so only the static families are listed
+    assert_eq!(Script::metric_names(), ["lua_scripts"]);
+    assert_eq!(
+        m.render_one("lua_heap_size").unwrap(),
+        "# HELP lua_heap_size Value reported by a script\n\
+         # TYPE lua_heap_size gauge\n\
+         lua_heap_size 2048\n"
+    );
+    assert_eq!(m.render_one("lua_heap"), None);
+    assert_eq!(
+        m.render_one("lua_scripts").unwrap(),
+        "# HELP lua_scripts Loaded scripts\n\
+         # TYPE lua_scripts gauge\n\
+         lua_scripts 2\n"
+    );
+    assert_eq!(
+        Filtered(&m, |name: &str| name.starts_with("lua_event")).to_string(),
+        "# HELP lua_event_reload_total Event reported by a script\n\
+         # TYPE lua_event_reload_total counter\n\
+         lua_event_reload_total 4\n"
+    );
+}
+
+#[test]
+fn test_max_label_len() {
+    use std::collections::BTreeMap;
+
+    #[derive(Label, PartialEq, Eq, PartialOrd, Ord)]
+    struct Query {
+        #[label(max_len = 8)]
+        sql: String,
+        db: &'static str,
+    }
+
+    #[metrics]
+    struct Db {
+        #[counter(help = "Queries", label = "query", max_label_len = 12)]
+        queries: BTreeMap<&'static str, u64>,
+        #[counter(help = "Slow queries")]
+        slow: BTreeMap<Query, u64>,
+    }
+
+    let m = Db {
+        queries: BTreeMap::from([
+            ("SELECT * FROM users WHERE id = 1", 3),
+            // Cut on a char boundary, never inside the UTF-8 sequence of `é`
+            ("SELECT 'café' FROM menu", 2),
+            ("BEGIN", 1),
+        ]),
+        slow: BTreeMap::from([(
+            Query {
+                sql: "DELETE FROM sessions".to_string(),
+                db: "main",
+            },
+            1,
+        )]),
+    };
+
+    let output = m.to_string();
+    assert!(output.contains("queries{query=\"BEGIN\"} 1\n"));
+    assert!(output.contains("queries{query=\"SELECT * FRO...#3bc4a70b\"} 3\n"));
+    assert!(output.contains("queries{query=\"SELECT 'café...#efa950b4\"} 2\n"));
+    assert!(output.contains("slow{sql=\"DELETE F...#9ac75203\",db=\"main\"} 1\n"));
+}
+
+#[test]
+fn test_max_label_len_collisions() {
+    use std::collections::BTreeMap;
+
+    #[metrics]
+    struct Db {
+        #[counter(help = "Queries", label = "query", max_label_len = 6)]
+        queries: BTreeMap<&'static str, u64>,
+    }
+
+    // Values sharing their first 6 characters stay distinct series, told apart by the
+    // hash of the whole value
+    let m = Db {
+        queries: BTreeMap::from([("SELECT a", 1), ("SELECT b", 2)]),
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP queries Queries\n\
+         # TYPE queries counter\n\
+         queries{query=\"SELECT...#8ce99d22\"} 1\n\
+         queries{query=\"SELECT...#8be99b8f\"} 2\n"
+    );
+}
+
+#[test]
+#[cfg(not(feature = "no-escaping"))]
+fn test_max_label_len_escaping() {
+    use std::collections::HashMap;
+
+    #[metrics]
+    struct Db {
+        #[counter(help = "Queries", label = "query", max_label_len = 4)]
+        queries: HashMap<&'static str, u64>,
+    }
+
+    // The value is truncated before escaping, so an escape sequence is never split
+    let m = Db {
+        queries: HashMap::from([("a\"b\"c", 1)]),
+    };
+    assert!(
+        m.to_string()
+            .contains("queries{query=\"a\\\"b\\\"...#d4bc1077\"} 1\n")
+    );
+}
+
+#[test]
+#[cfg(all(feature = "sorted-output", not(feature = "openmetrics")))]
+fn test_sorted_histogram_output() {
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Route {
+        method: &'static str,
+        path: &'static str,
+    }
+
+    define_histogram!(Latency<Route> = [0.1, 1.0]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[histogram(help = "Latency", unit = "seconds")]
+        latency: Latency,
+    }
+
+    let mut latency = Latency::default();
+    for (method, path, value) in [
+        ("POST", "/users", 0.5),
+        ("GET", "/users", 0.05),
+        ("GET", "/", 2.0),
+        ("GET", "/users", 0.5),
+    ] {
+        latency.observe(Route { method, path }, value);
+    }
+    let m = TestMetrics { latency };
+
+    // Series are ordered by their rendered label set, so the whole output is stable
+    let expected = "# HELP latency_seconds Latency\n\
+                    # TYPE latency_seconds histogram\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/\",le=\"0.100\"} 0\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/\",le=\"1.000\"} 0\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/\",le=\"+Inf\"} 1\n\
+                    latency_seconds_sum{method=\"GET\",path=\"/\"} 2\n\
+                    latency_seconds_count{method=\"GET\",path=\"/\"} 1\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/users\",le=\"0.100\"} 1\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/users\",le=\"1.000\"} 2\n\
+                    latency_seconds_bucket{method=\"GET\",path=\"/users\",le=\"+Inf\"} 2\n\
+                    latency_seconds_sum{method=\"GET\",path=\"/users\"} 0.55\n\
+                    latency_seconds_count{method=\"GET\",path=\"/users\"} 2\n\
+                    latency_seconds_bucket{method=\"POST\",path=\"/users\",le=\"0.100\"} 0\n\
+                    latency_seconds_bucket{method=\"POST\",path=\"/users\",le=\"1.000\"} 1\n\
+                    latency_seconds_bucket{method=\"POST\",path=\"/users\",le=\"+Inf\"} 1\n\
+                    latency_seconds_sum{method=\"POST\",path=\"/users\"} 0.5\n\
+                    latency_seconds_count{method=\"POST\",path=\"/users\"} 1\n";
+    assert_eq!(m.to_string(), expected);
+}
+
+#[test]
+fn test_generic_metric_attribute() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(prefix = "app", default)]
+    struct Dedicated {
+        #[counter(help = "Requests", label = "method")]
+        requests: HashMap<&'static str, u64>,
+        #[gauge(help = "Workers", unit = "bytes")]
+        memory: u64,
+        #[histogram(help = "Latency")]
+        latency: Latency,
+    }
+
+    #[metrics(prefix = "app", default)]
+    struct Generic {
+        #[metric(kind = "counter", help = "Requests", label = "method")]
+        requests: HashMap<&'static str, u64>,
+        #[metric(help = "Workers", kind = "gauge", unit = "bytes")]
+        memory: u64,
+        #[metric(kind = "histogram", help = "Latency")]
+        latency: Latency,
+    }
+
+    #[metrics]
+    struct Untyped {
+        #[metric(kind = "untyped", help = "Imported as is")]
+        legacy: f64,
+    }
+
+    let mut dedicated = Dedicated::default();
+    dedicated.requests.insert("GET", 2);
+    dedicated.memory = 64;
+    dedicated.latency.observe((), 0.1);
+    let mut generic = Generic::default();
+    generic.requests.insert("GET", 2);
+    generic.memory = 64;
+    generic.latency.observe((), 0.1);
+
+    assert_eq!(generic.to_string(), dedicated.to_string());
+    assert_eq!(Generic::metric_names(), Dedicated::metric_names());
+    assert_eq!(
+        Untyped { legacy: 1.5 }.to_string(),
+        "# HELP legacy Imported as is\n# TYPE legacy untyped\nlegacy 1.5\n"
+    );
+    assert_eq!(Untyped::describe()[0].kind, "untyped");
+}
+
+#[test]
+fn test_shared_names() {
+    use aetos::Filtered;
+
+    #[derive(Label)]
+    struct Pool {
+        pool: &'static str,
+    }
+
+    #[metrics(prefix = "db", allow_shared_names)]
+    struct Db {
+        #[gauge(help = "Open connections", name = "connections")]
+        pools: Vec<(Pool, u64)>,
+        #[gauge(help = "Queries")]
+        queries: u64,
+        #[gauge(help = "Open connections", name = "connections")]
+        default_pool: u64,
+    }
+
+    let m = Db {
+        pools: vec![(Pool { pool: "read" }, 4), (Pool { pool: "write" }, 1)],
+        queries: 7,
+        default_pool: 2,
+    };
+
+    // One header, with the samples of both fields together
+    let connections = "# HELP db_connections Open connections\n\
+                       # TYPE db_connections gauge\n\
+                       db_connections{pool=\"read\"} 4\n\
+                       db_connections{pool=\"write\"} 1\n\
+                       db_connections 2\n";
+    assert_eq!(
+        m.to_string(),
+        format!(
+            "{}# HELP db_queries Queries\n# TYPE db_queries gauge\ndb_queries 7\n",
+            connections
+        )
+    );
+    assert_eq!(Db::metric_names(), ["db_connections", "db_queries"]);
+    assert_eq!(Db::describe().len(), 2);
+    assert_eq!(m.render_one("db_connections").unwrap(), connections);
+    assert_eq!(
+        Filtered(&m, |name: &str| name == "db_connections").to_string(),
+        connections
+    );
+    assert!(m.size_hint() <= m.to_string().len());
+}
+
+#[test]
+fn test_label_is_empty() {
+    use aetos::core::Label;
+    use std::collections::BTreeMap;
+    use std::fmt;
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Route {
+        path: &'static str,
+    }
+
+    // A zero-sized label set that still writes a label
+    #[derive(Hash, Eq, PartialEq, Clone, Debug)]
+    struct Region;
+
+    impl Label for Region {
+        fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "region=\"eu\"")
+        }
+    }
+
+    // Keeps the default, but writes no label
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct NoLabels {}
+
+    assert!(().is_empty());
+    assert!(!Route { path: "/" }.is_empty());
+    assert!(!Region.is_empty());
+    assert!(!NoLabels {}.is_empty());
+    assert!(BTreeMap::<&str, &str>::new().is_empty());
+    assert!(!vec![("a", "b")].is_empty());
+
+    define_histogram!(Plain<()> = [1.0]);
+    define_histogram!(ByRoute<Route> = [1.0]);
+    define_histogram!(ByRegion<Region> = [1.0]);
+    define_histogram!(ByNothing<NoLabels> = [1.0]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[histogram(help = "Plain")]
+        plain: Plain,
+        #[histogram(help = "By route")]
+        by_route: ByRoute,
+        #[histogram(help = "By region")]
+        by_region: ByRegion,
+        #[histogram(help = "By nothing")]
+        by_nothing: ByNothing,
+    }
+
+    let mut m = TestMetrics {
+        plain: Plain::default(),
+        by_route: ByRoute::default(),
+        by_region: ByRegion::default(),
+        by_nothing: ByNothing::default(),
+    };
+    m.plain.observe((), 0.5);
+    m.by_route.observe(Route { path: "/" }, 0.5);
+    m.by_region.observe(Region, 0.5);
+    m.by_nothing.observe(NoLabels {}, 0.5);
+
+    let output = m.to_string();
+    assert!(output.contains("plain_bucket{le=\"1.000\"} 1\n"));
+    assert!(output.contains("by_route_bucket{path=\"/\",le=\"1.000\"} 1\n"));
+    assert!(output.contains("by_region_bucket{region=\"eu\",le=\"1.000\"} 1\n"));
+    assert!(output.contains("by_region_sum{region=\"eu\"} 0.5\n"));
+    assert!(output.contains("by_nothing_bucket{le=\"1.000\"} 1\n"));
+}
+
+#[test]
+fn test_metrics_markdown() {
+    use aetos::MetricGroup;
+    use aetos::core::CatalogEntry;
+    use std::collections::HashMap;
+
+    #[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+    struct Route {
+        method: &'static str,
+        r#type: &'static str,
+    }
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Requests")]
+        requests: u64,
+    }
+
+    define_histogram!(Latency<Route> = [0.1]);
+
+    #[metrics]
+    struct Inner {
+        #[gauge(help = "Inner")]
+        inner: u64,
+    }
+
+    #[metrics(prefix = "app", labels(region = "eu"))]
+    struct TestMetrics {
+        #[counter(help = "Handled | served\nrequests", alias = "served")]
+        handled: HashMap<Route, u64>,
+        #[gauge(label = "host")]
+        up: Vec<(&'static str, u64)>,
+        #[histogram(help = "Latency", unit = "seconds")]
+        latency: Latency,
+        #[group]
+        stats: HashMap<Route, Stats>,
+        #[nested]
+        nested: Inner,
+    }
+
+    let catalog = TestMetrics::metrics_catalog();
+    assert_eq!(
+        catalog[0],
+        CatalogEntry {
+            name: "app_handled".to_string(),
+            kind: "counter",
+            labels: vec!["method", "type", "region"],
+            help: Some("Handled | served\nrequests"),
+        }
+    );
+    assert_eq!(
+        TestMetrics::metrics_markdown(),
+        "| Name | Type | Labels | Help |\n\
+         | --- | --- | --- | --- |\n\
+         | `app_handled` | counter | `method`, `type`, `region` | Handled \\| served requests |\n\
+         | `app_served` | counter | `method`, `type`, `region` | Handled \\| served requests |\n\
+         | `app_up` | gauge | `host`, `region` |  |\n\
+         | `app_latency_seconds` | histogram | `method`, `type`, `region` | Latency |\n\
+         | `app_stats_requests` | counter | `method`, `type`, `region` | Requests |\n\
+         | `inner` | gauge |  | Inner |\n"
+    );
+}
+
+#[test]
+fn test_generic_struct_user_bounds() {
+    use std::fmt::{self, Display};
+
+    trait Service {
+        fn name(&self) -> &'static str;
+    }
+
+    struct Uptime(u64);
+
+    impl Display for Uptime {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0 * 60)
+        }
+    }
+
+    impl Service for Uptime {
+        fn name(&self) -> &'static str {
+            "uptime"
+        }
+    }
+
+    // The bounds written on the struct are kept next to the `T: Display` the macro adds
+    #[metrics]
+    struct Inline<T: Service> {
+        #[gauge(help = "Uptime in seconds")]
+        uptime: T,
+    }
+
+    #[metrics]
+    struct WithWhere<T, S>
+    where
+        T: Service,
+        S: Service + Display,
+    {
+        #[gauge(help = "Uptime in seconds")]
+        uptime: T,
+        #[gauge(help = "Backup uptime in seconds")]
+        backup: S,
+    }
+
+    let m = Inline { uptime: Uptime(2) };
+    assert_eq!(m.uptime.name(), "uptime");
+    assert_eq!(
+        m.to_string(),
+        "# HELP uptime Uptime in seconds\n# TYPE uptime gauge\nuptime 120\n"
+    );
+
+    let m = WithWhere {
+        uptime: Uptime(1),
+        backup: Uptime(3),
+    };
+    let output = m.to_string();
+    assert!(output.contains("uptime 60\n"));
+    assert!(output.contains("backup 180\n"));
+}
+
+#[test]
+fn test_strict_naming() {
+    use std::time::Duration;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[metrics(prefix = "app", strict_naming)]
+    struct TestMetrics {
+        #[counter(help = "Handled requests", name = "requests_total")]
+        requests: u64,
+        #[gauge(help = "Time since the last reload")]
+        since_reload_seconds: Duration,
+        #[histogram(help = "Request latency", unit = "seconds")]
+        latency: Latency,
+        #[counter(help = "Legacy name", name = "legacyRequests", allow(naming))]
+        legacy: u64,
+    }
+
+    #[metrics(convention = "prometheus", strict_naming)]
+    struct Conventional {
+        #[counter(help = "Time spent handling requests", unit = "seconds")]
+        busy: Duration,
+    }
+
+    let m = TestMetrics {
+        requests: 1,
+        since_reload_seconds: Duration::from_secs(2),
+        latency: Latency::new(),
+        legacy: 3,
+    };
+    let output = m.to_string();
+    assert!(output.contains("app_requests_total 1\n"));
+    assert!(output.contains("app_since_reload_seconds 2\n"));
+    assert!(output.contains("# TYPE app_latency_seconds histogram\n"));
+    assert!(output.contains("app_legacyRequests 3\n"));
+
+    let m = Conventional {
+        busy: Duration::from_millis(1500),
+    };
+    assert!(m.to_string().contains("busy_seconds_total 1.5\n"));
+}
+
+#[test]
+fn test_shared_wrappers() {
+    use aetos::MetricGroup;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Hits")]
+        hits: u64,
+    }
+
+    #[metrics]
+    struct Inner {
+        #[gauge(help = "Inner")]
+        inner: u64,
+    }
+
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Requests by method", label = "method")]
+        requests: Arc<Mutex<HashMap<String, u64>>>,
+        #[counter(help = "Shared counter")]
+        shared: Arc<AtomicU64>,
+        #[gauge(help = "Busy time")]
+        busy: Arc<RwLock<Duration>>,
+        #[gauge(help = "Load")]
+        load: Mutex<f64>,
+        #[gauge(help = "Pending")]
+        pending: Option<Arc<Mutex<u64>>>,
+        #[histogram(help = "Latency")]
+        latency: Arc<Mutex<Latency>>,
+        #[group(label = "cache")]
+        caches: Arc<RwLock<Vec<(&'static str, Stats)>>>,
+        #[gauge(help = "Uptime")]
+        uptime: Arc<dyn Fn() -> u64 + Send + Sync>,
+        #[nested]
+        nested: Arc<Inner>,
+    }
+
+    let requests = Arc::new(Mutex::new(HashMap::new()));
+    let shared = Arc::new(AtomicU64::new(0));
+    let m = TestMetrics {
+        requests: Arc::clone(&requests),
+        shared: Arc::clone(&shared),
+        busy: Arc::new(RwLock::new(Duration::from_millis(1500))),
+        load: Mutex::new(0.5),
+        pending: None,
+        latency: Arc::new(Mutex::new(Latency::new())),
+        caches: Arc::new(RwLock::new(vec![("users", Stats { hits: 3 })])),
+        uptime: Arc::new(|| 42),
+        nested: Arc::new(Inner { inner: 7 }),
+    };
+
+    // Updated through the handles kept elsewhere
+    requests.lock().unwrap().insert("GET".to_string(), 2);
+    shared.fetch_add(5, Ordering::Relaxed);
+    m.latency.lock().unwrap().observe((), 0.1);
+
+    let output = m.to_string();
+    assert!(output.contains("app_requests{method=\"GET\"} 2\n"));
+    assert!(output.contains("app_shared 5\n"));
+    assert!(output.contains("app_busy 1.5\n"));
+    assert!(output.contains("app_load 0.5\n"));
+    assert!(!output.contains("app_pending"));
+    assert!(output.contains("app_latency_bucket{le=\"0.500\"} 1\n"));
+    assert!(output.contains("app_caches_hits{cache=\"users\"} 3\n"));
+    assert!(output.contains("app_uptime 42\n"));
+    assert!(output.contains("inner 7\n"));
+    assert_eq!(
+        m.render_one("inner").unwrap(),
+        Inner { inner: 7 }.to_string()
+    );
+
+    // A poisoned lock is still rendered
+    let poisoned = Arc::clone(&requests);
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoned.lock().unwrap();
+        panic!("poison the lock");
+    })
+    .join();
+    assert!(m.to_string().contains("app_requests{method=\"GET\"} 2\n"));
+}
+
+#[test]
+fn test_help_escaping() {
+    use std::collections::HashMap;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    const MULTILINE: &str = "Queue depth\nper worker";
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = r"Files under C:\temp")]
+        files: u64,
+        #[gauge(help = "Depth by \"queue\"\nsampled every second", label = "queue")]
+        depth: HashMap<&'static str, u64>,
+        #[histogram(help = r"Latency of \\share")]
+        latency: Latency,
+        #[gauge(help = MULTILINE)]
+        workers: u64,
+    }
+
+    let m = TestMetrics {
+        files: 1,
+        depth: HashMap::from([("jobs", 2)]),
+        latency: Latency::new(),
+        workers: 3,
+    };
+    let output = m.to_string();
+    assert!(output.contains("# HELP files Files under C:\\\\temp\n"));
+    assert!(output.contains("# HELP depth Depth by \"queue\"\\nsampled every second\n"));
+    assert!(output.contains("# HELP latency Latency of \\\\\\\\share\n"));
+    assert!(output.contains("# HELP workers Queue depth\\nper worker\n"));
+    // Every line is a comment or a sample
+    assert!(
+        output
+            .lines()
+            .all(|line| { line.starts_with('#') || line.split_whitespace().count() == 2 })
+    );
+}
+
+#[test]
+fn test_metrics_method() {
+    use std::collections::HashMap;
+    use std::fmt::Display;
+
+    define_histogram!(Latency<()> = [0.5]);
+
+    struct Counter {
+        count: u64,
+        name: String,
+    }
+
+    impl Counter {
+        #[metrics(prefix = "svc", counter(count, help = "Items counted"))]
+        fn metrics(&self) -> String {}
+    }
+
+    struct Service {
+        requests: HashMap<&'static str, u64>,
+        latency: Latency,
+        workers: u32,
+    }
+
+    impl Service {
+        /// Renders the metrics of the service
+        #[metrics(
+            labels(service = "api"),
+            gauge(workers, help = "Workers", alias = "threads"),
+            counter(requests, help = "Requests by method", label = "method"),
+            histogram(latency, help = "Latency", unit = "seconds")
+        )]
+        pub fn render_metrics(&self) -> impl Display + '_ {}
+    }
+
+    let counter = Counter {
+        count: 3,
+        name: "jobs".to_string(),
+    };
+    assert_eq!(counter.name, "jobs");
+    assert_eq!(
+        counter.metrics(),
+        "# HELP svc_count Items counted\n# TYPE svc_count counter\nsvc_count 3\n"
+    );
+
+    let mut service = Service {
+        requests: HashMap::from([("GET", 2)]),
+        latency: Latency::new(),
+        workers: 4,
+    };
+    service.latency.observe((), 0.1);
+    let output = service.render_metrics().to_string();
+    assert!(output.starts_with("# HELP workers Workers\n# TYPE workers gauge\n"));
+    assert!(output.contains("workers{service=\"api\"} 4\n"));
+    assert!(output.contains("threads{service=\"api\"} 4\n"));
+    assert!(output.contains("requests{method=\"GET\",service=\"api\"} 2\n"));
+    assert!(output.contains("latency_seconds_bucket{service=\"api\",le=\"0.500\"} 1\n"));
+}
+
+#[test]
+fn test_reference_and_cow_fields() {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
+    #[metrics(prefix = "app")]
+    struct TestMetrics<'a> {
+        #[counter(help = "Requests by method", label = "method")]
+        requests: &'a HashMap<String, u64>,
+        #[gauge(help = "Depth by queue", label = "queue")]
+        depth: &'a [(&'a str, u64)],
+        #[gauge(help = "Workers by pool", label = "pool")]
+        workers: Cow<'a, [(String, u64)]>,
+        #[gauge(help = "Uptime")]
+        uptime: &'a u64,
+    }
+
+    let requests = HashMap::from([("GET".to_string(), 2)]);
+    let depth = [("jobs", 3)];
+    let workers = vec![("io".to_string(), 4)];
+    let uptime = 5;
+
+    let borrowed = TestMetrics {
+        requests: &requests,
+        depth: &depth,
+        workers: Cow::Borrowed(&workers),
+        uptime: &uptime,
+    }
+    .to_string();
+    let expected = "\
+# HELP app_requests Requests by method
+# TYPE app_requests counter
+app_requests{method=\"GET\"} 2
+# HELP app_depth Depth by queue
+# TYPE app_depth gauge
+app_depth{queue=\"jobs\"} 3
+# HELP app_workers Workers by pool
+# TYPE app_workers gauge
+app_workers{pool=\"io\"} 4
+# HELP app_uptime Uptime
+# TYPE app_uptime gauge
+app_uptime 5
+";
+    assert_eq!(borrowed, expected);
+
+    let owned = TestMetrics {
+        requests: &requests,
+        depth: &depth,
+        workers: Cow::Owned(workers.clone()),
+        uptime: &uptime,
+    }
+    .to_string();
+    assert_eq!(owned, expected);
+}
+
+#[test]
+fn test_shared_histogram_threads() {
+    use aetos::core::HistogramMetric;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    define_histogram!(Latency<()> = [0.1, 0.5]);
+
+    #[metrics]
+    struct TestMetrics {
+        #[histogram(help = "Latency")]
+        latency: Arc<Mutex<Latency>>,
+    }
+
+    // Through the `HistogramMetric` impls of the wrappers
+    #[metrics]
+    struct GenericMetrics<H: HistogramMetric> {
+        #[histogram(help = "Latency")]
+        latency: H,
+    }
+
+    let latency = Arc::new(Mutex::new(Latency::new()));
+    let m = TestMetrics {
+        latency: Arc::clone(&latency),
+    };
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let latency = Arc::clone(&latency);
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    latency.lock().unwrap().observe((), 0.2);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let output = m.to_string();
+    assert!(output.contains("latency_bucket{le=\"0.100\"} 0\n"));
+    assert!(output.contains("latency_bucket{le=\"0.500\"} 40\n"));
+    assert!(output.contains("latency_count{} 40\n"));
+
+    let generic = GenericMetrics { latency }.to_string();
+    assert_eq!(generic, output);
+
+    let rwlock = Arc::new(RwLock::new(Latency::new()));
+    rwlock.write().unwrap().observe((), 0.05);
+    let generic = GenericMetrics { latency: rwlock }.to_string();
+    assert!(generic.contains("latency_bucket{le=\"0.100\"} 1\n"));
+}
+
+#[test]
+fn test_label_rename() {
+    use aetos::core::Label as _;
+
+    #[derive(Label)]
+    struct Request {
+        #[label(rename = "method")]
+        http_method: String,
+        status: u32,
+        #[label(rename = "path", max_len = 4)]
+        request_path: &'static str,
+    }
+
+    #[metrics]
+    struct TestMetrics {
+        #[counter(help = "Requests")]
+        requests: Vec<(Request, u64)>,
+    }
+
+    let m = TestMetrics {
+        requests: vec![(
+            Request {
+                http_method: "GET".to_string(),
+                status: 200,
+                request_path: "/api/users",
+            },
+            3,
+        )],
+    };
+    assert!(
+        m.to_string()
+            .contains("requests{method=\"GET\",status=\"200\",path=\"/api...#8ce8650d\"} 3\n")
+    );
+    assert_eq!(Request::label_names(), ["method", "status", "path"]);
+}
+
+#[test]
+fn test_generic_metric_names_per_instantiation() {
+    use aetos::MetricGroup;
+
+    trait Source {
+        const NAME: &'static str;
+    }
+
+    struct Disk;
+    struct Network;
+
+    impl Source for Disk {
+        const NAME: &'static str = "disk_bytes";
+    }
+
+    impl Source for Network {
+        const NAME: &'static str = "network_bytes";
+    }
+
+    #[metrics]
+    struct Inner<S: Source> {
+        #[gauge(name = S::NAME, help = "Bytes read")]
+        bytes: u64,
+        #[metrics_field(skip)]
+        source: std::marker::PhantomData<S>,
+    }
+
+    #[metrics(prefix = "app")]
+    struct Outer<S: Source> {
+        #[nested]
+        inner: Inner<S>,
+    }
+
+    #[derive(MetricGroup)]
+    struct Reads {
+        #[counter(help = "Reads")]
+        reads: u64,
+    }
+
+    #[derive(MetricGroup)]
+    struct Writes {
+        #[counter(help = "Writes")]
+        writes: u64,
+    }
+
+    #[metrics]
+    struct Grouped<G: aetos::core::MetricGroup> {
+        #[group(label = "device")]
+        io: Vec<(&'static str, G)>,
+    }
+
+    // Whichever instantiation is called first, the other one keeps its own names
+    assert_eq!(Inner::<Disk>::metric_names(), ["disk_bytes"]);
+    assert_eq!(Inner::<Network>::metric_names(), ["network_bytes"]);
+    assert_eq!(Outer::<Network>::metric_names(), ["network_bytes"]);
+    assert_eq!(Outer::<Disk>::metric_names(), ["disk_bytes"]);
+    assert_eq!(Grouped::<Reads>::metric_names(), ["io_reads"]);
+    assert_eq!(Grouped::<Writes>::metric_names(), ["io_writes"]);
+}
+
+#[test]
+fn test_generic_describe_per_instantiation() {
+    #[derive(aetos::MetricGroup)]
+    struct Reads {
+        #[counter(help = "Reads")]
+        reads: u64,
+    }
+
+    #[derive(aetos::MetricGroup)]
+    struct Writes {
+        #[gauge(help = "Writes")]
+        writes: u64,
+    }
+
+    #[metrics(labels(host = "a"))]
+    struct Grouped<G: aetos::core::MetricGroup> {
+        #[group(label = "device")]
+        io: Vec<(&'static str, G)>,
+    }
+
+    let reads = Grouped::<Reads>::describe();
+    let writes = Grouped::<Writes>::describe();
+    assert_eq!(reads.len(), 1);
+    assert_eq!(writes.len(), 1);
+    assert_eq!(
+        (reads[0].name, reads[0].kind, reads[0].help),
+        ("io_reads", "counter", Some("Reads"))
+    );
+    assert_eq!(
+        (writes[0].name, writes[0].kind, writes[0].help),
+        ("io_writes", "gauge", Some("Writes"))
+    );
+    assert_eq!(writes[0].const_labels, [("host", "a")]);
+}
+
+#[test]
+fn test_generic_render_one_nested() {
+    trait Source {
+        const NAME: &'static str;
+    }
+
+    struct Disk;
+    struct Network;
+
+    impl Source for Disk {
+        const NAME: &'static str = "disk_bytes";
+    }
+
+    impl Source for Network {
+        const NAME: &'static str = "network_bytes";
+    }
+
+    #[metrics]
+    struct Inner<S: Source> {
+        #[gauge(name = S::NAME, help = "Bytes read")]
+        bytes: u64,
+        #[metrics_field(skip)]
+        source: std::marker::PhantomData<S>,
+    }
+
+    #[metrics]
+    struct Outer<S: Source> {
+        #[nested]
+        inner: Inner<S>,
+        #[nested]
+        backup: Option<Inner<S>>,
+    }
+
+    fn inner<S: Source>(bytes: u64) -> Inner<S> {
+        Inner {
+            bytes,
+            source: std::marker::PhantomData,
+        }
+    }
+    let disk = Outer::<Disk> {
+        inner: inner(1),
+        backup: None,
+    };
+    let network = Outer::<Network> {
+        inner: inner(2),
+        backup: None,
+    };
+
+    assert_eq!(
+        disk.render_one("disk_bytes").unwrap(),
+        "# HELP disk_bytes Bytes read\n# TYPE disk_bytes gauge\ndisk_bytes 1\n"
+    );
+    assert_eq!(
+        network.render_one("network_bytes").unwrap(),
+        "# HELP network_bytes Bytes read\n# TYPE network_bytes gauge\nnetwork_bytes 2\n"
+    );
+    assert_eq!(network.render_one("disk_bytes"), None);
+    assert_eq!(disk.render_one("network_bytes"), None);
+}
+
+#[test]
+fn test_manual_histogram_metric() {
+    use aetos::core::{HistogramMetric, MetricMetadata};
+    use std::fmt;
+
+    // Only `render_histogram` is required, as before the label type existed
+    struct Fixed;
+
+    impl HistogramMetric for Fixed {
+        fn render_histogram(&self, f: &mut fmt::Formatter, meta: &MetricMetadata) -> fmt::Result {
+            writeln!(f, "{}_count 3", meta.name)
+        }
+    }
+
+    #[metrics]
+    struct TestMetrics {
+        #[histogram(help = "Latency")]
+        latency: Fixed,
+    }
+
+    assert_eq!(
+        TestMetrics { latency: Fixed }.to_string(),
+        "latency_count 3\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "openmetrics")]
+fn test_unit_openmetrics() {
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Bytes sent", unit = "bytes")]
+        sent: u64,
+
+        #[gauge(help = "Open connections")]
+        connections: u64,
+    }
+
+    let m = TestMetrics {
+        sent: 10,
+        connections: 4,
+    };
+    let output = m.to_string();
+    assert_eq!(
+        output,
+        "# HELP app_sent_bytes Bytes sent\n\
+         # TYPE app_sent_bytes counter\n\
+         # UNIT app_sent_bytes bytes\n\
+         app_sent_bytes 10\n\
+         # HELP app_connections Open connections\n\
+         # TYPE app_connections gauge\n\
+         app_connections 4\n"
+    );
+    assert!(m.size_hint() <= output.len());
+}
+
+#[test]
+fn test_metric_group_prometheus_convention() {
+    use aetos::MetricGroup;
+
+    #[derive(MetricGroup)]
+    struct Stats {
+        #[counter(help = "Hits")]
+        hits: u64,
+
+        #[counter(help = "Misses", name = "misses_total")]
+        misses: u64,
+
+        #[gauge]
+        size: u64,
+    }
+
+    #[metrics(prefix = "app", convention = "prometheus")]
+    struct TestMetrics {
+        #[group(label = "cache")]
+        cache: Vec<(&'static str, Stats)>,
+    }
+
+    let m = TestMetrics {
+        cache: vec![(
+            "users",
+            Stats {
+                hits: 3,
+                misses: 1,
+                size: 10,
+            },
+        )],
+    };
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_cache_hits_total Hits\n\
+         # TYPE app_cache_hits_total counter\n\
+         app_cache_hits_total{cache=\"users\"} 3\n\
+         # HELP app_cache_misses_total Misses\n\
+         # TYPE app_cache_misses_total counter\n\
+         app_cache_misses_total{cache=\"users\"} 1\n\
+         # TYPE app_cache_size gauge\n\
+         app_cache_size{cache=\"users\"} 10\n"
+    );
+    assert_eq!(
+        TestMetrics::metric_names(),
+        [
+            "app_cache_hits_total",
+            "app_cache_misses_total",
+            "app_cache_size"
+        ]
+    );
+    assert_eq!(
+        TestMetrics::describe()
+            .iter()
+            .map(|meta| meta.name)
+            .collect::<Vec<_>>(),
+        TestMetrics::metric_names()
+    );
+    assert!(m.render_one("app_cache_hits_total").is_some());
+}
+
+#[test]
+#[cfg(feature = "sorted-output")]
+fn test_sorted_key() {
+    use std::collections::HashMap;
+
+    // Not recognized as a `HashMap` by the macro
+    type Sessions = HashMap<&'static str, u64>;
+
+    #[metrics(extra(counter(
+        help = "Logins by user",
+        label = "user",
+        compute = "logins",
+        sorted
+    )))]
+    struct TestMetrics {
+        #[gauge(help = "Sessions by user", label = "user", sorted)]
+        sessions: Sessions,
+    }
+
+    impl TestMetrics {
+        fn logins(&self) -> HashMap<&'static str, u64> {
+            self.sessions
+                .iter()
+                .map(|(user, n)| (*user, n * 2))
+                .collect()
+        }
+    }
+
+    let users = [
+        "erin", "bob", "dave", "alice", "carol", "frank", "grace", "heidi",
+    ];
+    let m = TestMetrics {
+        sessions: users.iter().map(|user| (*user, 1)).collect(),
+    };
+
+    let mut sorted = users;
+    sorted.sort();
+    let sessions: String = sorted
+        .iter()
+        .map(|user| format!("sessions{{user=\"{}\"}} 1\n", user))
+        .collect();
+    let logins: String = sorted
+        .iter()
+        .map(|user| format!("logins{{user=\"{}\"}} 2\n", user))
+        .collect();
+    assert_eq!(
+        m.to_string(),
+        format!(
+            "# HELP sessions Sessions by user\n\
+             # TYPE sessions gauge\n\
+             {}\
+             # HELP logins Logins by user\n\
+             # TYPE logins counter\n\
+             {}",
+            sessions, logins
+        )
+    );
+}
+
+#[test]
+fn test_name_starting_with_digit() {
+    // Valid once prefixed
+    #[metrics(prefix = "app")]
+    struct TestMetrics {
+        #[counter(help = "Server errors", name = "5xx", alias = "5xx_errors")]
+        server_errors: u64,
+    }
+
+    let m = TestMetrics { server_errors: 2 };
+    assert_eq!(
+        m.to_string(),
+        "# HELP app_5xx Server errors\n\
+         # TYPE app_5xx counter\n\
+         app_5xx 2\n\
+         # HELP app_5xx_errors Server errors\n\
+         # TYPE app_5xx_errors counter\n\
+         app_5xx_errors 2\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "sorted-output")]
+fn test_metrics_method_sorted() {
+    use std::collections::HashMap;
+
+    struct Pool {
+        connections: HashMap<&'static str, u64>,
+    }
+
+    impl Pool {
+        #[metrics(gauge(connections, help = "Connections by host", label = "host", sorted))]
+        fn metrics(&self) -> String {}
+    }
+
+    let hosts = ["db3", "db1", "db5", "db2", "db4", "db8", "db6", "db7"];
+    let pool = Pool {
+        connections: hosts.iter().map(|host| (*host, 1)).collect(),
+    };
+
+    let mut sorted = hosts;
+    sorted.sort();
+    let samples: String = sorted
+        .iter()
+        .map(|host| format!("connections{{host=\"{}\"}} 1\n", host))
+        .collect();
+    assert_eq!(
+        pool.metrics(),
+        format!(
+            "# HELP connections Connections by host\n\
+             # TYPE connections gauge\n\
+             {}",
+            samples
+        )
+    );
+}
+
+#[test]
+fn test_generic_metric_names_same_type_name() {
+    #[metrics]
+    struct Outer<G: aetos::core::MetricGroup> {
+        #[group(label = "endpoint")]
+        g: std::collections::HashMap<String, G>,
+    }
+
+    // Both instantiations have the `type_name` `Outer<..::S>`
+    let first = {
+        #[derive(aetos::MetricGroup)]
+        struct S {
+            #[counter(help = "First")]
+            first: u64,
+        }
+        Outer::<S>::metric_names()
+    };
+    let second = {
+        #[derive(aetos::MetricGroup)]
+        struct S {
+            #[counter(help = "Second")]
+            second: u64,
+        }
+        Outer::<S>::metric_names()
+    };
+    assert_eq!(first, ["g_first"]);
+    assert_eq!(second, ["g_second"]);
+}
+
+#[test]
+fn test_metrics_method_expression_name_with_lifetime() {
+    const NAME: &str = "connections";
+
+    struct Pool<'a> {
+        connections: &'a u64,
+    }
+
+    impl Pool<'_> {
+        #[metrics(gauge(connections, help = "Connections", prefix = "db", name = NAME))]
+        fn metrics(&self) -> String {}
+    }
+
+    let connections = 3;
+    let pool = Pool {
+        connections: &connections,
+    };
+    assert_eq!(
+        pool.metrics(),
+        "# HELP db_connections Connections\n\
+         # TYPE db_connections gauge\n\
+         db_connections 3\n"
+    );
+}
@@ -0,0 +1,152 @@
+use aetos::core::PrometheusMetric;
+use aetos::{Filtered, MetricRegistry, SyncMetricRegistry, define_histogram, metrics};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+define_histogram!(Latency<()> = [0.1, 1.0]);
+
+#[metrics(prefix = "http")]
+struct HttpMetrics {
+    #[counter(help = "Total requests", label = "method")]
+    requests: HashMap<&'static str, u64>,
+    #[histogram(help = "Request latency")]
+    latency: Latency,
+}
+
+#[metrics(prefix = "db")]
+struct DbMetrics {
+    #[gauge(help = "Open connections")]
+    connections: u32,
+}
+
+#[metrics]
+struct ProcessMetrics {
+    #[counter(help = "Handled signals")]
+    signals: AtomicU64,
+}
+
+fn http() -> HttpMetrics {
+    let mut latency = Latency::default();
+    latency.observe((), 0.5);
+    HttpMetrics {
+        requests: HashMap::from([("GET", 3)]),
+        latency,
+    }
+}
+
+#[test]
+fn test_registry_render_all() {
+    let mut registry = MetricRegistry::new();
+    assert!(registry.is_empty());
+    assert_eq!(registry.render_all(), "");
+
+    registry.register(Box::new(http()));
+    registry.register(Box::new(DbMetrics { connections: 4 }));
+    registry.register(Box::new(ProcessMetrics {
+        signals: AtomicU64::new(2),
+    }));
+    assert_eq!(registry.len(), 3);
+
+    let output = registry.render_all();
+    assert!(output.contains("http_requests{method=\"GET\"} 3\n"));
+    assert!(output.contains("# TYPE http_latency histogram\n"));
+    assert!(output.contains("db_connections 4\n"));
+    assert!(output.contains("signals 2\n"));
+
+    // Registration order is kept, and `Display` renders the same output
+    let http_at = output.find("http_requests").unwrap();
+    let db_at = output.find("db_connections").unwrap();
+    let signals_at = output.find("signals").unwrap();
+    assert!(http_at < db_at && db_at < signals_at);
+    assert_eq!(registry.to_string(), output);
+
+    let expected = [
+        http().to_string(),
+        DbMetrics { connections: 4 }.to_string(),
+        "# HELP signals Handled signals\n# TYPE signals counter\nsignals 2\n".to_string(),
+    ]
+    .concat();
+    assert_eq!(output, expected);
+    assert!(registry.size_hint() <= output.len());
+}
+
+#[test]
+fn test_registry_filtered() {
+    let mut registry = MetricRegistry::new();
+    registry.register(Box::new(http()));
+    registry.register(Box::new(DbMetrics { connections: 4 }));
+
+    let output = Filtered(&registry, |name: &str| name.starts_with("db_")).to_string();
+    assert_eq!(output, DbMetrics { connections: 4 }.to_string());
+}
+
+#[test]
+fn test_sync_registry_threads() {
+    let registry = SyncMetricRegistry::new();
+
+    std::thread::scope(|s| {
+        s.spawn(|| registry.register(Box::new(http())));
+        s.spawn(|| registry.register(Box::new(DbMetrics { connections: 4 })));
+        s.spawn(|| {
+            registry.register(Box::new(ProcessMetrics {
+                signals: AtomicU64::new(2),
+            }))
+        });
+        s.spawn(|| {
+            // Rendering while other threads register sees some prefix of the metrics
+            let _ = registry.render_all();
+        });
+    });
+
+    assert_eq!(registry.len(), 3);
+    let output = registry.render_all();
+    assert!(output.contains("http_requests{method=\"GET\"} 3\n"));
+    assert!(output.contains("db_connections 4\n"));
+    assert!(output.contains("signals 2\n"));
+    assert_eq!(registry.to_string(), output);
+}
+
+#[test]
+fn test_sync_registry_static() {
+    static REGISTRY: SyncMetricRegistry = SyncMetricRegistry::new();
+
+    let signals = ProcessMetrics {
+        signals: AtomicU64::new(0),
+    };
+    signals.signals.fetch_add(5, Ordering::Relaxed);
+    REGISTRY.register(Box::new(signals));
+
+    assert_eq!(
+        REGISTRY.render_all(),
+        "# HELP signals Handled signals\n# TYPE signals counter\nsignals 5\n"
+    );
+}
+
+#[test]
+fn test_registry_shared_arc() {
+    use std::sync::Arc;
+
+    fn serve<T: PrometheusMetric>(metrics: T) -> String {
+        Filtered(&metrics, |_: &str| true).to_string()
+    }
+
+    let process = Arc::new(ProcessMetrics {
+        signals: AtomicU64::new(0),
+    });
+    let mut registry = MetricRegistry::new();
+    registry.register(Box::new(Arc::clone(&process)));
+    let dyn_metric: Arc<dyn PrometheusMetric> = Arc::new(DbMetrics { connections: 4 });
+    registry.register(Box::new(dyn_metric));
+
+    // Updated through the handle kept outside of the registry
+    process.signals.fetch_add(3, Ordering::Relaxed);
+
+    let expected = [
+        "# HELP signals Handled signals\n# TYPE signals counter\nsignals 3\n".to_string(),
+        DbMetrics { connections: 4 }.to_string(),
+    ]
+    .concat();
+    assert_eq!(registry.render_all(), expected);
+    assert_eq!(serve(Arc::clone(&process)), process.to_string());
+    assert_eq!(Arc::clone(&process).size_hint(), process.size_hint());
+}
@@ -0,0 +1,135 @@
+#![cfg(feature = "tower")]
+
+use aetos::core::PrometheusMetric;
+use aetos::tower::{HttpMetrics, MetricsLayer};
+use aetos::{Label, define_histogram, metrics};
+use http::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tower::{Layer, ServiceExt, service_fn};
+
+#[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+struct Route {
+    method: String,
+    path: String,
+}
+
+#[derive(Label, Hash, Eq, PartialEq, Clone, Debug)]
+struct Status {
+    method: String,
+    path: String,
+    status: u16,
+}
+
+define_histogram!(Latency<Route> = [0.005, 0.05, 0.5, 5.0]);
+
+#[metrics(prefix = "http", default)]
+struct Http {
+    #[counter(help = "Total HTTP requests")]
+    requests: HashMap<Status, u64>,
+
+    #[histogram(help = "HTTP request latency", unit = "seconds")]
+    request_duration: Latency,
+}
+
+#[derive(Default)]
+struct SharedHttp(Mutex<Http>);
+
+impl PrometheusMetric for SharedHttp {
+    fn render(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.lock().unwrap().render(f)
+    }
+
+    fn fmt_filtered(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        filter: &dyn Fn(&str) -> bool,
+    ) -> fmt::Result {
+        self.0.lock().unwrap().fmt_filtered(f, filter)
+    }
+}
+
+impl fmt::Display for SharedHttp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(f)
+    }
+}
+
+impl HttpMetrics for SharedHttp {
+    fn record_request(&self, method: &str, path: &str, status: u16, duration: f64) {
+        let mut http = self.0.lock().unwrap();
+        let (method, path) = (method.to_string(), path.to_string());
+        let route = Route {
+            method: method.clone(),
+            path: path.clone(),
+        };
+        *http
+            .requests
+            .entry(Status {
+                method,
+                path,
+                status,
+            })
+            .or_default() += 1;
+        http.request_duration.observe(route, duration);
+    }
+}
+
+async fn handle(req: Request<()>) -> Result<Response<()>, Infallible> {
+    let status = match req.uri().path() {
+        "/missing" => StatusCode::NOT_FOUND,
+        _ => StatusCode::OK,
+    };
+    Ok(Response::builder().status(status).body(()).unwrap())
+}
+
+fn request(method: &str, path: &str) -> Request<()> {
+    Request::builder()
+        .method(method)
+        .uri(path)
+        .body(())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_metrics_layer_records_requests() {
+    let metrics = Arc::new(SharedHttp::default());
+    let service = MetricsLayer::new(metrics.clone()).layer(service_fn(handle));
+
+    let response = service
+        .clone()
+        .oneshot(request("GET", "/users?page=2"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    service
+        .clone()
+        .oneshot(request("GET", "/users"))
+        .await
+        .unwrap();
+    service.oneshot(request("POST", "/missing")).await.unwrap();
+
+    let output = metrics.to_string();
+    assert!(output.contains("http_requests{method=\"GET\",path=\"/users\",status=\"200\"} 2\n"));
+    assert!(output.contains("http_requests{method=\"POST\",path=\"/missing\",status=\"404\"} 1\n"));
+    assert!(output.contains("# TYPE http_request_duration_seconds histogram\n"));
+    assert!(
+        output.contains("http_request_duration_seconds_count{method=\"GET\",path=\"/users\"} 2\n")
+    );
+    assert!(
+        output
+            .contains("http_request_duration_seconds_count{method=\"POST\",path=\"/missing\"} 1\n")
+    );
+}
+
+#[tokio::test]
+async fn test_metrics_layer_skips_errors() {
+    let metrics = Arc::new(SharedHttp::default());
+    let failing = service_fn(|_: Request<()>| async { Err::<Response<()>, _>("unavailable") });
+    let service = MetricsLayer::new(metrics.clone()).layer(failing);
+
+    assert!(service.oneshot(request("GET", "/")).await.is_err());
+    assert!(!metrics.to_string().contains("http_requests{"));
+}
@@ -0,0 +1,8 @@
+// Asserts the exact diagnostics of the macros. Regenerate the `.stderr` files with
+// `TRYBUILD=overwrite cargo test --test ui` after changing an error message.
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+    t.pass("tests/ui/pass/*.rs");
+}
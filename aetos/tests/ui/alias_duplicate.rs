@@ -0,0 +1,12 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Total requests", name = "http_requests_total", alias = "http_reqs")]
+    requests: u64,
+
+    #[counter(help = "Requests")]
+    http_reqs: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(default)]
+struct Metrics {
+    #[counter(help = "Requests", buckets = [0.1, 0.5])]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[cfg_attr(feature = "detailed", counter(help = "Requests"))]
+    requests: u64,
+}
+
+fn main() {}
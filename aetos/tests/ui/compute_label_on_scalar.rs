@@ -0,0 +1,15 @@
+use aetos::metrics;
+
+#[metrics(extra(gauge(help = "Queue length", label = "queue", compute = "queue_len")))]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+impl Metrics {
+    fn queue_len(&self) -> u64 {
+        0
+    }
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(labels(service = "api"))]
+struct Metrics {
+    #[counter(help = "Requests by service", label = "service")]
+    requests: std::collections::HashMap<String, u64>,
+}
+
+fn main() {}
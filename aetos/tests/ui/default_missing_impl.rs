@@ -0,0 +1,13 @@
+use aetos::metrics;
+
+struct Handle;
+
+#[metrics(default)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+    #[metrics_field(skip)]
+    handle: Handle,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::Metrics;
+
+#[derive(Metrics)]
+struct AppMetrics {
+    #[metric(summary, help = "Latency")]
+    latency: f64,
+}
+
+fn main() {}
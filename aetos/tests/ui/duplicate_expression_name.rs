@@ -0,0 +1,16 @@
+// Duplicates are also detected when the name is given as an expression
+
+use aetos::metrics;
+
+const NAME: &str = "requests";
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "A", name = NAME)]
+    a: u64,
+
+    #[counter(help = "B", name = NAME)]
+    b: u64,
+}
+
+fn main() {}
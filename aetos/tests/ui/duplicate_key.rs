@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", help = "Total requests")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests")]
+    #[gauge(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
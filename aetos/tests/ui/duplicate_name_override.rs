@@ -0,0 +1,14 @@
+// A `name` override colliding with another field's name
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Errors")]
+    errors: u64,
+
+    #[counter(help = "Failures", name = "errors")]
+    failures: u64,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+// A `name` override colliding with another field's name, under a prefix
+
+use aetos::metrics;
+
+#[metrics(prefix = "app")]
+struct Metrics {
+    #[counter(help = "Total")]
+    total: u64,
+
+    #[gauge(help = "Also total", name = "total")]
+    other: u64,
+}
+
+fn main() {}
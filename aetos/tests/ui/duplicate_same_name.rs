@@ -0,0 +1,14 @@
+// Two fields with the same `name` override
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "A", name = "requests")]
+    a: u64,
+
+    #[counter(help = "B", name = "requests")]
+    b: u64,
+}
+
+fn main() {}
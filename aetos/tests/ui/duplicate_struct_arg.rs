@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(prefix = "app", prefix = "other")]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
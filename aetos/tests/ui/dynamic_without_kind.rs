@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Plugins {
+    #[dynamic(help = "Value reported by a plugin")]
+    values: Vec<(String, f64)>,
+}
+
+fn main() {}
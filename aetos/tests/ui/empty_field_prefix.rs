@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", prefix = "")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(extra(gauge(name = "ratio", help = "Hit ratio")))]
+struct Metrics {
+    #[counter(help = "Hits")]
+    hits: u64,
+}
+
+fn main() {}
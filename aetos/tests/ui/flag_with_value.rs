@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(generate_new = true)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use aetos::{define_histogram, metrics};
+
+define_histogram!(Latency<()> = [0.5]);
+
+#[metrics]
+struct Metrics {
+    #[metric(kind = "histogram", help = "Latency", label = "route")]
+    latency: Latency,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[metric(kind = "summary", help = "Latency")]
+    latency: u64,
+}
+
+fn main() {}
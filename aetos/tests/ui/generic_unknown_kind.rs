@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[metric(kind = "meter", help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+#[aetos::global_metrics]
+#[aetos::metrics]
+struct Metrics<T: std::fmt::Display> {
+    #[gauge(help = "Value")]
+    value: T,
+}
+
+fn main() {}
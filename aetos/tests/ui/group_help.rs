@@ -0,0 +1,15 @@
+use aetos::{MetricGroup, metrics};
+
+#[derive(MetricGroup)]
+struct Stats {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+#[metrics]
+struct Metrics {
+    #[group(label = "path", help = "Stats")]
+    stats: Vec<(String, Stats)>,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use aetos::MetricGroup;
+
+#[derive(MetricGroup)]
+struct Stats {
+    #[counter]
+    hits: u64,
+
+    #[counter(name = "hits")]
+    cache_hits: u64,
+}
+
+fn main() {}
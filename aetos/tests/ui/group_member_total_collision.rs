@@ -0,0 +1,19 @@
+use aetos::{MetricGroup, metrics};
+use std::collections::HashMap;
+
+#[derive(MetricGroup)]
+struct Stats {
+    #[counter]
+    hits: u64,
+
+    #[counter]
+    hits_total: u64,
+}
+
+#[metrics(prefix = "app", convention = "prometheus")]
+struct Metrics {
+    #[group(label = "path")]
+    cache: HashMap<&'static str, Stats>,
+}
+
+fn main() {}
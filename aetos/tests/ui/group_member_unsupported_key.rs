@@ -0,0 +1,9 @@
+use aetos::MetricGroup;
+
+#[derive(MetricGroup)]
+struct Endpoint {
+    #[counter(help = "Requests", alias = "reqs", skip_zero, compute = "nope", max_label_len = 3)]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use aetos::{MetricGroup, metrics};
+use std::collections::HashMap;
+
+#[derive(MetricGroup)]
+struct Stats {
+    #[counter]
+    hits: u64,
+}
+
+#[metrics(prefix = "app")]
+struct Metrics {
+    #[counter(help = "Cache hits")]
+    cache_hits: u64,
+
+    #[group(label = "path")]
+    cache: HashMap<&'static str, Stats>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests\tby method")]
+    requests: u64,
+}
+
+fn main() {}
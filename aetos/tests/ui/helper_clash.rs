@@ -0,0 +1,15 @@
+use aetos::metrics;
+
+#[metrics(helpers)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+impl Metrics {
+    fn inc_requests(&mut self) {
+        self.requests += 2;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use aetos::core::Histogram;
+use aetos::metrics;
+
+#[metrics(default)]
+struct Metrics {
+    #[histogram(help = "Latency", buckets = [0.5, 0.1, 1.0])]
+    latency: Histogram<(), 3>,
+}
+
+fn main() {}
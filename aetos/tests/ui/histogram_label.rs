@@ -0,0 +1,11 @@
+use aetos::{define_histogram, metrics};
+
+define_histogram!(Latency<()> = [0.1, 0.5]);
+
+#[metrics]
+struct Metrics {
+    #[histogram(help = "Latency", label = "endpoint")]
+    latency: Latency,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+// `#[histogram]` requires a type implementing `HistogramMetric`
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[histogram(help = "Latency")]
+    latency: u64,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+// Also when the field is optional
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[histogram(help = "Latency")]
+    latency: Option<f64>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use aetos::core::Histogram;
+use aetos::metrics;
+
+#[metrics(default)]
+struct Metrics {
+    #[histogram(help = "Latency")]
+    latency: Histogram<(), 3>,
+}
+
+fn main() {}
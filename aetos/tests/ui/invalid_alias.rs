@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", alias = ["requests", "5xx"])]
+    http_requests: u64,
+}
+
+fn main() {}
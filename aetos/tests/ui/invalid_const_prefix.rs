@@ -0,0 +1,11 @@
+use aetos::metrics;
+
+const PREFIX: &str = "app_";
+
+#[metrics(prefix = PREFIX)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(namespace = "myapp", subsystem = "http-server")]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
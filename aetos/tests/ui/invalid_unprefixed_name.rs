@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Server errors", name = "5xx")]
+    server_errors: u64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+// Label struct fields are label names, so the reserved `__` prefix is rejected
+
+use aetos::Label;
+
+#[derive(Label)]
+struct Labels {
+    __internal: String,
+}
+
+fn main() {}
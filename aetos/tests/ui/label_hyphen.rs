@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Contains a hyphen", label = "http-method")]
+    requests: Vec<(String, u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use aetos::Label;
+
+struct Region;
+
+#[derive(Label)]
+struct Labels {
+    method: &'static str,
+    region: Region,
+}
+
+fn main() {}
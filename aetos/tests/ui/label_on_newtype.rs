@@ -0,0 +1,20 @@
+// `label` on a newtype around a scalar
+
+use aetos::metrics;
+use std::fmt;
+
+struct MyCount(u64);
+
+impl fmt::Display for MyCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Count", label = "kind")]
+    count: MyCount,
+}
+
+fn main() {}
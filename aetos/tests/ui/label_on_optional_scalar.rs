@@ -0,0 +1,11 @@
+// `label` on an optional scalar
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Count", label = "kind")]
+    count: Option<u64>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use aetos::Label;
+
+#[derive(Label)]
+struct Labels {
+    method: &'static str,
+    #[label(rename = "method")]
+    http_method: &'static str,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::Label;
+
+#[derive(Label)]
+struct Labels {
+    #[label(rename = "http-method")]
+    http_method: &'static str,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Reserved prefix", label = "__name")]
+    requests: Vec<(String, u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Starts with a digit", label = "2xx")]
+    requests: Vec<(String, u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+// `labels` on an alias of a scalar
+
+use aetos::metrics;
+
+type Count = u64;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Count", labels = ["kind", "zone"])]
+    count: Count,
+}
+
+fn main() {}
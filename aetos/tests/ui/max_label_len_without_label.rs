@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", labels = ["method", "path"], max_label_len = 64)]
+    requests: Vec<((String, String), u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use aetos::metrics;
+
+struct Service {
+    requests: u64,
+}
+
+impl Service {
+    #[metrics(counter(help = "Requests"))]
+    fn metrics(&self) -> String {}
+}
+
+fn main() {
+    let _ = Service { requests: 0 }.requests;
+}
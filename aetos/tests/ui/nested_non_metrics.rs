@@ -0,0 +1,11 @@
+// `#[nested]` requires a `#[metrics]` struct
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[nested]
+    inner: u64,
+}
+
+fn main() {}
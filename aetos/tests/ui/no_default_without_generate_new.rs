@@ -0,0 +1,12 @@
+// `no_default` only applies to the `new()` generated by `generate_new`
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests")]
+    #[metrics_field(no_default)]
+    requests: u64,
+}
+
+fn main() {}
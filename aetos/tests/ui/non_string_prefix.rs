@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(prefix = 5)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
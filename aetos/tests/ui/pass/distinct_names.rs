@@ -0,0 +1,14 @@
+// Distinct names are accepted
+
+use aetos::metrics;
+
+#[metrics(prefix = "app")]
+struct Metrics {
+    #[counter(help = "Errors")]
+    errors: u64,
+
+    #[counter(help = "Failures", name = "failures_total")]
+    failures: u64,
+}
+
+fn main() {}
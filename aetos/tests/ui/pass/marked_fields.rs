@@ -0,0 +1,35 @@
+// Fields with a metric attribute, `skip` fields and the timestamp field do not warn
+#![deny(deprecated)]
+
+use aetos::{Metrics, metrics};
+
+#[metrics(timestamp = "updated_at")]
+struct Attribute {
+    #[counter(help = "Requests")]
+    requests: u64,
+    #[metrics_field(skip)]
+    version: &'static str,
+    updated_at: u64,
+}
+
+#[derive(Metrics)]
+struct Derive {
+    #[metric(gauge, help = "Connections")]
+    connections: u64,
+    #[metric(skip)]
+    version: &'static str,
+}
+
+fn main() {
+    let _ = Attribute {
+        requests: 0,
+        version: "1.0",
+        updated_at: 0,
+    }
+    .to_string();
+    let _ = Derive {
+        connections: 0,
+        version: "1.0",
+    }
+    .to_string();
+}
@@ -0,0 +1,46 @@
+// The same structs compile with `#[metrics]` applied through `cfg_attr`, and with
+// `#[metrics_stub]` in its place. `any()` is never set and `all()` always is.
+#![deny(deprecated)]
+
+use aetos::{define_histogram, metrics, metrics_stub};
+use std::collections::HashMap;
+
+define_histogram!(Latency<()> = [0.1]);
+
+#[cfg_attr(any(), metrics(prefix = "app"))]
+#[cfg_attr(all(), metrics_stub(prefix = "app"))]
+struct Attribute {
+    #[counter(help = "Requests", label = "method")]
+    requests: HashMap<&'static str, u64>,
+    #[histogram(help = "Latency", unit = "seconds")]
+    latency: Latency,
+    #[metrics_field(skip)]
+    version: &'static str,
+}
+
+#[cfg_attr(any(), derive(aetos::Metrics))]
+#[cfg_attr(all(), metrics_stub)]
+#[metric(prefix = "app")]
+struct Derive {
+    #[metric(gauge, help = "Connections")]
+    connections: u64,
+}
+
+// Both attributes are interchangeable
+#[cfg_attr(all(), metrics(prefix = "app"))]
+#[cfg_attr(any(), metrics_stub(prefix = "app"))]
+struct Enabled {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {
+    let attribute = Attribute {
+        requests: HashMap::new(),
+        latency: Latency::new(),
+        version: "1.0",
+    };
+    let _ = (attribute.requests, attribute.latency, attribute.version);
+    let _ = Derive { connections: 0 }.connections;
+    let _ = Enabled { requests: 0 }.to_string();
+}
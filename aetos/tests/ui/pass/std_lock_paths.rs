@@ -0,0 +1,23 @@
+use aetos::metrics;
+use std::sync::{self, Arc, Mutex};
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: Mutex<u64>,
+
+    #[counter(help = "Errors")]
+    errors: Arc<sync::Mutex<u64>>,
+
+    #[gauge(help = "Connections")]
+    connections: std::sync::RwLock<u64>,
+}
+
+fn main() {
+    let m = Metrics {
+        requests: Mutex::new(1),
+        errors: Arc::new(sync::Mutex::new(2)),
+        connections: std::sync::RwLock::new(3),
+    };
+    assert!(m.to_string().contains("connections 3\n"));
+}
@@ -0,0 +1,11 @@
+// `le` is reserved for histogram buckets
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", label = "le")]
+    requests: Vec<(String, u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+// `quantile` is reserved for summaries
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[gauge(help = "Latency", label = "quantile")]
+    latency: Vec<(f64, f64)>,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use aetos::metrics;
+
+#[metrics(allow_shared_names)]
+struct Metrics {
+    #[gauge(help = "Open connections", name = "connections")]
+    pools: Vec<(String, u64)>,
+    #[gauge(help = "Connections", name = "connections")]
+    default_pool: u64,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use aetos::metrics;
+
+#[metrics(allow_shared_names)]
+struct Metrics {
+    #[gauge(help = "Open connections", name = "connections")]
+    pools: Option<Vec<(String, u64)>>,
+    #[gauge(help = "Open connections", name = "connections")]
+    default_pool: Option<u64>,
+}
+
+fn main() {}
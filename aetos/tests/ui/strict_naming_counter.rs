@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(prefix = "app", strict_naming)]
+struct Metrics {
+    #[counter(help = "Handled requests")]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(strict_naming)]
+struct Metrics {
+    #[gauge(help = "Time since the last reload")]
+    since_reload: std::time::Duration,
+}
+
+fn main() {}
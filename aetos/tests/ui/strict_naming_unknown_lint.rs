@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(strict_naming)]
+struct Metrics {
+    #[counter(help = "Handled requests", allow(naming, suffix))]
+    requests: u64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Throughput(
+    #[counter(name = "bytes", help = "Bytes received")] u64,
+    #[counter(name = "bytes", help = "Bytes sent")] u64,
+);
+
+fn main() {}
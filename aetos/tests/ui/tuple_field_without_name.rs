@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Throughput(
+    #[counter(name = "bytes_in", help = "Bytes received")] u64,
+    #[counter(help = "Bytes sent")] u64,
+);
+
+fn main() {}
@@ -0,0 +1,11 @@
+// The number of `labels` must match the arity of the tuple key
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", labels = ["method", "status"])]
+    requests: Vec<((String, u16, bool), u64)>,
+}
+
+fn main() {}
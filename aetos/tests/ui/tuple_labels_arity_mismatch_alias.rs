@@ -0,0 +1,13 @@
+// The arity is also checked when the key type is hidden behind an alias
+
+use aetos::metrics;
+
+type Requests = Vec<((String, u16, bool), u64)>;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", labels = ["method", "status"])]
+    requests: Requests,
+}
+
+fn main() {}
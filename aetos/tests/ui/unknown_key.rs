@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests", lable = "method")]
+    requests: Vec<(String, u64)>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use aetos::metrics;
+
+#[metrics(prefx = "app")]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+}
+
+fn main() {}
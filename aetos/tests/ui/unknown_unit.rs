@@ -0,0 +1,11 @@
+// Units must be one of the Prometheus base units
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[gauge(help = "Uptime", unit = "minutes")]
+    uptime: f64,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use aetos::metrics;
+
+#[metrics(strict)]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+    // A typo of #[gauge]
+    connections: u64,
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+// The warning is denied, so that its text shows in the output
+#![deny(deprecated)]
+
+use aetos::metrics;
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: u64,
+    connections: u64,
+}
+
+fn main() {}
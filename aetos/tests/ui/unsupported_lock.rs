@@ -0,0 +1,14 @@
+use aetos::metrics;
+
+// Stands in for the crate, whose `lock()` does not return a `LockResult`
+mod parking_lot {
+    pub struct Mutex<T>(pub T);
+}
+
+#[metrics]
+struct Metrics {
+    #[counter(help = "Requests")]
+    requests: parking_lot::Mutex<u64>,
+}
+
+fn main() {}